@@ -20,7 +20,7 @@ use near_primitives::sharding::{
 };
 use near_primitives::syncing::{
     get_num_state_parts, ReceiptProofResponse, ShardStateSyncResponseHeader, StateHeaderKey,
-    StatePartKey, StateSyncDumpProgress,
+    StatePartKey, StateSyncDumpBackfill, StateSyncDumpProgress,
 };
 use near_primitives::transaction::{
     ExecutionOutcomeWithId, ExecutionOutcomeWithIdAndProof, ExecutionOutcomeWithProof,
@@ -872,6 +872,37 @@ impl ChainStore {
         store_update.set_ser(DBCol::BlockMisc, &key, &value)?;
         store_update.commit().map_err(|err| err.into())
     }
+
+    /// Constructs key 'STATE_SYNC_DUMP_BACKFILL:<ShardId>'.
+    fn state_sync_dump_backfill_key(shard_id: ShardId) -> Vec<u8> {
+        let mut key = b"STATE_SYNC_DUMP_BACKFILL:".to_vec();
+        key.extend(shard_id.to_le_bytes());
+        key
+    }
+
+    /// Retrieves STATE_SYNC_DUMP_BACKFILL for the given shard.
+    /// Returns an empty backfill if nothing is stored.
+    pub fn get_state_sync_dump_backfill(
+        &self,
+        shard_id: ShardId,
+    ) -> Result<StateSyncDumpBackfill, Error> {
+        Ok(self
+            .store
+            .get_ser(DBCol::BlockMisc, &ChainStore::state_sync_dump_backfill_key(shard_id))?
+            .unwrap_or_default())
+    }
+
+    /// Updates STATE_SYNC_DUMP_BACKFILL for the given shard.
+    pub fn set_state_sync_dump_backfill(
+        &self,
+        shard_id: ShardId,
+        value: &StateSyncDumpBackfill,
+    ) -> Result<(), Error> {
+        let mut store_update = self.store.store_update();
+        let key = ChainStore::state_sync_dump_backfill_key(shard_id);
+        store_update.set_ser(DBCol::BlockMisc, &key, value)?;
+        store_update.commit().map_err(|err| err.into())
+    }
 }
 
 impl ChainStoreAccess for ChainStore {
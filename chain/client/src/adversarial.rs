@@ -1,5 +1,6 @@
 #[cfg(feature = "test_features")]
 mod adv {
+    use crate::sync::state_dump_job::StateDumpJobs;
     use std::sync::atomic::Ordering;
 
     #[derive(Default)]
@@ -7,6 +8,7 @@ mod adv {
         disable_header_sync: std::sync::atomic::AtomicBool,
         disable_doomslug: std::sync::atomic::AtomicBool,
         is_archival: bool,
+        state_dump_jobs: StateDumpJobs,
     }
 
     #[derive(Default, Clone)]
@@ -36,6 +38,10 @@ mod adv {
         pub fn is_archival(&self) -> bool {
             self.0.is_archival
         }
+
+        pub fn state_dump_jobs(&self) -> &StateDumpJobs {
+            &self.0.state_dump_jobs
+        }
     }
 }
 
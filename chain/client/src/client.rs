@@ -65,6 +65,7 @@ use near_primitives::views::{CatchupStatusView, DroppedReason};
 use near_store::metadata::DbKind;
 use std::cmp::max;
 use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tracing::{debug, error, info, trace, warn};
@@ -154,6 +155,31 @@ pub struct Client {
     tier1_accounts_cache: Option<(EpochId, Arc<AccountKeys>)>,
     /// Used when it is needed to create flat storage in background for some shards.
     flat_storage_creator: Option<FlatStorageCreator>,
+    /// Set while a block is being produced or processed, including the
+    /// asynchronous application of its chunks, until it is postprocessed.
+    /// Shared with background jobs that need to give way to block production,
+    /// such as the state sync dump.
+    pub(crate) block_production_in_progress: Arc<AtomicBool>,
+}
+
+/// Raises the flag for the lifetime of the guard, and restores the previous
+/// value when dropped.
+struct BusyFlagGuard {
+    flag: Arc<AtomicBool>,
+    was_busy: bool,
+}
+
+impl BusyFlagGuard {
+    fn new(flag: Arc<AtomicBool>) -> Self {
+        let was_busy = flag.swap(true, Ordering::Relaxed);
+        Self { flag, was_busy }
+    }
+}
+
+impl Drop for BusyFlagGuard {
+    fn drop(&mut self) {
+        self.flag.store(self.was_busy, Ordering::Relaxed);
+    }
 }
 
 impl Client {
@@ -198,6 +224,7 @@ impl Client {
         validator_signer: Option<Arc<dyn ValidatorSigner>>,
         enable_doomslug: bool,
         rng_seed: RngSeed,
+        block_production_in_progress: Arc<AtomicBool>,
     ) -> Result<Self, Error> {
         let doomslug_threshold_mode = if enable_doomslug {
             DoomslugThresholdMode::TwoThirds
@@ -263,7 +290,7 @@ impl Client {
             config.state_sync_timeout,
             &config.chain_id,
             &config.state_sync.sync,
-        );
+        )?;
         let num_block_producer_seats = config.num_block_producer_seats as usize;
         let data_parts = epoch_manager.num_data_parts();
         let parity_parts = epoch_manager.num_total_parts() - data_parts;
@@ -319,6 +346,7 @@ impl Client {
             chunk_production_info: lru::LruCache::new(PRODUCTION_TIMES_CACHE_SIZE),
             tier1_accounts_cache: None,
             flat_storage_creator,
+            block_production_in_progress,
         })
     }
 
@@ -514,6 +542,7 @@ impl Client {
     /// Either returns produced block (not applied) or error.
     pub fn produce_block(&mut self, next_height: BlockHeight) -> Result<Option<Block>, Error> {
         let _span = tracing::debug_span!(target: "client", "produce_block", next_height).entered();
+        let _busy = BusyFlagGuard::new(self.block_production_in_progress.clone());
         let known_height = self.chain.store().get_latest_known()?.height;
 
         let validator_signer = self
@@ -1126,6 +1155,7 @@ impl Client {
         provenance: Provenance,
         apply_chunks_done_callback: DoneApplyChunkCallback,
     ) -> Result<(), near_chain::Error> {
+        self.block_production_in_progress.store(true, Ordering::Relaxed);
         let mut block_processing_artifacts = BlockProcessingArtifact::default();
 
         let result = {
@@ -1169,6 +1199,7 @@ impl Client {
             }
         }
 
+        self.update_block_processing_flag();
         result
     }
 
@@ -1208,9 +1239,18 @@ impl Client {
         }
         self.last_time_head_progress_made =
             max(self.chain.get_last_time_head_updated(), self.last_time_head_progress_made);
+        self.update_block_processing_flag();
         (accepted_blocks_hashes, errors)
     }
 
+    /// Keeps `block_production_in_progress` raised while any block is in
+    /// processing, i.e. until the application of its chunks finishes and it
+    /// gets postprocessed.
+    fn update_block_processing_flag(&self) {
+        self.block_production_in_progress
+            .store(self.chain.blocks_in_processing_len() > 0, Ordering::Relaxed);
+    }
+
     /// Process the result of block processing from chain, finish the steps that can't be done
     /// in chain, including
     ///  - sending challenges
@@ -1675,6 +1715,7 @@ impl Client {
             apply_chunks_done_callback,
         );
         self.process_block_processing_artifact(blocks_processing_artifacts);
+        self.update_block_processing_flag();
     }
 
     pub fn is_validator(&self, epoch_id: &EpochId, block_hash: &CryptoHash) -> bool {
@@ -2155,19 +2196,20 @@ impl Client {
             };
             let state_sync_timeout = self.config.state_sync_timeout;
             let epoch_id = self.chain.get_block(&sync_hash)?.header().epoch_id().clone();
+            if !self.catchup_state_syncs.contains_key(&sync_hash) {
+                let state_sync = StateSync::new(
+                    network_adapter1,
+                    state_sync_timeout,
+                    &self.config.chain_id,
+                    &self.config.state_sync.sync,
+                )?;
+                self.catchup_state_syncs.insert(
+                    sync_hash,
+                    (state_sync, new_shard_sync, BlocksCatchUpState::new(sync_hash, epoch_id)),
+                );
+            }
             let (state_sync, new_shard_sync, blocks_catch_up_state) =
-                self.catchup_state_syncs.entry(sync_hash).or_insert_with(|| {
-                    (
-                        StateSync::new(
-                            network_adapter1,
-                            state_sync_timeout,
-                            &self.config.chain_id,
-                            &self.config.state_sync.sync,
-                        ),
-                        new_shard_sync,
-                        BlocksCatchUpState::new(sync_hash, epoch_id),
-                    )
-                });
+                self.catchup_state_syncs.get_mut(&sync_hash).unwrap();
 
             debug!(
                 target: "client",
@@ -14,6 +14,8 @@ use crate::config_updater::ConfigUpdater;
 use crate::debug::new_network_info_view;
 use crate::info::{display_sync_status, InfoHelper};
 use crate::sync::state::{StateSync, StateSyncResult};
+#[cfg(feature = "test_features")]
+use crate::sync::state_dump_job::StateDumpJobProgress;
 use crate::{metrics, StatusResponse};
 use actix::dev::SendError;
 use actix::{Actor, Addr, Arbiter, AsyncContext, Context, Handler, Message};
@@ -60,6 +62,8 @@ use near_primitives::state_part::PartId;
 use near_primitives::static_clock::StaticClock;
 use near_primitives::syncing::StatePartKey;
 use near_primitives::types::BlockHeight;
+#[cfg(feature = "test_features")]
+use near_primitives::types::ShardId;
 use near_primitives::unwrap_or_return;
 use near_primitives::utils::{from_timestamp, MaybeValidated};
 use near_primitives::validator_signer::ValidatorSigner;
@@ -71,6 +75,7 @@ use rand::seq::SliceRandom;
 use rand::{thread_rng, Rng};
 use std::collections::HashMap;
 use std::fmt;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
@@ -305,8 +310,17 @@ pub enum NetworkAdversarialMessage {
     AdvDisableDoomslug,
     AdvGetSavedBlocks,
     AdvCheckStorageConsistency,
+    /// Starts dumping the state of the given shards as of the latest complete
+    /// epoch to the location of the state dump. Returns the id of the job.
+    AdvDumpState(Vec<ShardId>),
 }
 
+/// Returns the progress of a job started by `AdvDumpState`.
+#[cfg(feature = "test_features")]
+#[derive(actix::Message, Debug)]
+#[rtype(result = "Option<StateDumpJobProgress>")]
+pub struct AdvDumpStateProgress(pub u64);
+
 #[cfg(feature = "test_features")]
 impl Handler<WithSpanContext<NetworkAdversarialMessage>> for ClientActor {
     type Result = Option<u64>;
@@ -409,6 +423,38 @@ impl Handler<WithSpanContext<NetworkAdversarialMessage>> for ClientActor {
                     Some(store_validator.tests_done())
                 }
             }
+            NetworkAdversarialMessage::AdvDumpState(shard_ids) => {
+                info!(target: "adversary", ?shard_ids, "Dumping state");
+                let dump_config = match &this.client.config.state_sync.dump {
+                    Some(dump_config) => dump_config,
+                    None => {
+                        error!(target: "adversary", "Can't dump state, because 'config.state_sync.dump' is not set");
+                        return None;
+                    }
+                };
+                match this.adv.state_dump_jobs().start(&this.client.chain, dump_config, shard_ids) {
+                    Ok(job_id) => Some(job_id),
+                    Err(err) => {
+                        error!(target: "adversary", ?err, "Failed to start dumping state");
+                        None
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(feature = "test_features")]
+impl Handler<WithSpanContext<AdvDumpStateProgress>> for ClientActor {
+    type Result = Option<StateDumpJobProgress>;
+
+    fn handle(
+        &mut self,
+        msg: WithSpanContext<AdvDumpStateProgress>,
+        ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        self.wrap(msg, ctx, "AdvDumpStateProgress", |this, AdvDumpStateProgress(job_id)| {
+            this.adv.state_dump_jobs().progress(job_id)
         })
     }
 }
@@ -1650,7 +1696,15 @@ impl ClientActor {
                             }
                             _ => {
                                 let sync_hash = unwrap_and_report!(self.find_sync_hash());
-                                (sync_hash, HashMap::default(), true)
+                                match unwrap_and_report!(self
+                                    .client
+                                    .state_sync
+                                    .select_sync_hash(&self.client.chain, sync_hash))
+                                {
+                                    Some(sync_hash) => (sync_hash, HashMap::default(), true),
+                                    // Waiting for the dumped epochs to be looked up.
+                                    None => return,
+                                }
                             }
                         };
 
@@ -1986,6 +2040,7 @@ pub fn start_client(
     sender: Option<broadcast::Sender<()>>,
     adv: crate::adversarial::Controls,
     config_updater: Option<ConfigUpdater>,
+    block_production_in_progress: Arc<AtomicBool>,
 ) -> (Addr<ClientActor>, ArbiterHandle) {
     let client_arbiter = Arbiter::new();
     let client_arbiter_handle = client_arbiter.handle();
@@ -2001,6 +2056,7 @@ pub fn start_client(
         validator_signer.clone(),
         true,
         random_seed_from_thread(),
+        block_production_in_progress,
     )
     .unwrap();
     let client_addr = ClientActor::start_in_arbiter(&client_arbiter_handle, move |ctx| {
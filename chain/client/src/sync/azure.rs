@@ -0,0 +1,521 @@
+//! A minimal client of Azure Blob Storage, covering the requests needed to
+//! dump state parts to a container and to read them back.
+//!
+//! Requests are authorized with the access key of the storage account, with a
+//! shared access signature, or not at all, which works only for reading
+//! public containers.
+//! See <https://learn.microsoft.com/en-us/rest/api/storageservices/authorize-with-shared-key>.
+
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use reqwest::{Method, StatusCode};
+use sha2::Sha256;
+use std::ops::Range;
+use std::time::Duration;
+
+/// Version of the REST API of Azure Storage that the requests conform to.
+const AZURE_STORAGE_API_VERSION: &str = "2021-08-06";
+
+/// Environment variable with the access key of the storage account.
+pub const AZURE_STORAGE_ACCESS_KEY_ENV: &str = "AZURE_STORAGE_ACCESS_KEY";
+/// Environment variable with a connection string of the storage account. Used
+/// only if `AZURE_STORAGE_ACCESS_KEY` is not set.
+pub const AZURE_STORAGE_CONNECTION_STRING_ENV: &str = "AZURE_STORAGE_CONNECTION_STRING";
+
+#[derive(thiserror::Error, Debug)]
+pub enum AzureError {
+    #[error("Azure Blob Storage responded with status code {status_code}: {body}")]
+    Status { status_code: u16, body: String },
+    #[error("Request to Azure Blob Storage failed")]
+    Request(#[from] reqwest::Error),
+    #[error("Failed to parse a response of Azure Blob Storage")]
+    Xml(#[from] serde_xml_rs::Error),
+    #[error("Invalid URL of Azure Blob Storage: {0}")]
+    Url(String),
+    #[error("Invalid credentials of Azure Blob Storage: {0}")]
+    Credentials(String),
+}
+
+impl AzureError {
+    /// Status code of the response, if a response was received.
+    pub fn status_code(&self) -> Option<u16> {
+        match self {
+            AzureError::Status { status_code, .. } => Some(*status_code),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AzureCredentials {
+    /// Decoded access key of the storage account.
+    SharedKey(Vec<u8>),
+    /// Shared access signature, which is a query string without the leading `?`.
+    SharedAccessSignature(String),
+    /// Only public containers can be read.
+    Anonymous,
+}
+
+/// A blob returned by `AzureContainer::list_blobs_page()`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ListedBlob {
+    pub name: String,
+    pub content_length: u64,
+    pub last_modified: Option<std::time::SystemTime>,
+}
+
+/// A single page of the results of listing blobs.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ListBlobsPage {
+    pub blobs: Vec<ListedBlob>,
+    /// Prefixes of the names of blobs, up to and including the delimiter.
+    pub prefixes: Vec<String>,
+    /// Pass it to the next request to get the next page.
+    pub next_marker: Option<String>,
+}
+
+/// Properties of a blob returned by `AzureContainer::get_blob_properties()`.
+pub struct BlobProperties {
+    pub content_length: Option<u64>,
+    /// MD5 digest of the content. Blob Storage computes it for blobs uploaded
+    /// in a single request.
+    pub content_md5: Option<Vec<u8>>,
+}
+
+/// A container of a storage account.
+pub struct AzureContainer {
+    client: reqwest::Client,
+    account: String,
+    container: String,
+    /// For example `https://myaccount.blob.core.windows.net`.
+    endpoint: String,
+    credentials: AzureCredentials,
+}
+
+impl AzureContainer {
+    /// Credentials are taken from environment variables:
+    /// * `AZURE_STORAGE_ACCESS_KEY`
+    /// * `AZURE_STORAGE_CONNECTION_STRING`, if the access key is not set.
+    pub fn from_env(account: &str, container: &str) -> Result<Self, AzureError> {
+        if let Ok(access_key) = std::env::var(AZURE_STORAGE_ACCESS_KEY_ENV) {
+            let credentials = AzureCredentials::SharedKey(decode_access_key(&access_key)?);
+            return Self::new(account, container, None, credentials, None);
+        }
+        match std::env::var(AZURE_STORAGE_CONNECTION_STRING_ENV) {
+            Ok(connection_string) => {
+                let (endpoint, credentials) = parse_connection_string(&connection_string)?;
+                Self::new(account, container, endpoint, credentials, None)
+            }
+            Err(_) => Err(AzureError::Credentials(format!(
+                "neither {} nor {} is set",
+                AZURE_STORAGE_ACCESS_KEY_ENV, AZURE_STORAGE_CONNECTION_STRING_ENV
+            ))),
+        }
+    }
+
+    /// A connection without credentials, which can only read public containers.
+    /// Requests fail if they don't finish within `timeout`.
+    pub fn anonymous(
+        account: &str,
+        container: &str,
+        timeout: Duration,
+    ) -> Result<Self, AzureError> {
+        Self::new(account, container, None, AzureCredentials::Anonymous, Some(timeout))
+    }
+
+    /// `endpoint` defaults to the public endpoint of the storage account.
+    pub fn new(
+        account: &str,
+        container: &str,
+        endpoint: Option<String>,
+        credentials: AzureCredentials,
+        timeout: Option<Duration>,
+    ) -> Result<Self, AzureError> {
+        let mut client = reqwest::Client::builder();
+        if let Some(timeout) = timeout {
+            client = client.timeout(timeout);
+        }
+        Ok(Self {
+            client: client.build()?,
+            account: account.to_string(),
+            container: container.to_string(),
+            endpoint: endpoint
+                .unwrap_or_else(|| format!("https://{}.blob.core.windows.net", account)),
+            credentials,
+        })
+    }
+
+    /// Returns `None` if the blob doesn't exist.
+    pub async fn get_blob(&self, name: &str) -> Result<Option<Vec<u8>>, AzureError> {
+        let response = self.send(Method::GET, Some(name), &[], &[], None).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = check_status(response).await?;
+        Ok(Some(response.bytes().await?.to_vec()))
+    }
+
+    /// Reads bytes `range` of a blob. Returns `None` if the blob doesn't exist.
+    /// A range starting beyond the end of the blob is an error, a range
+    /// extending beyond it is truncated.
+    pub async fn get_blob_range(
+        &self,
+        name: &str,
+        range: Range<u64>,
+    ) -> Result<Option<Vec<u8>>, AzureError> {
+        // The end of the range is inclusive.
+        let range = format!("bytes={}-{}", range.start, range.end.saturating_sub(1));
+        let headers = [("x-ms-range", range.as_str())];
+        let response = self.send(Method::GET, Some(name), &[], &headers, None).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = check_status(response).await?;
+        Ok(Some(response.bytes().await?.to_vec()))
+    }
+
+    /// Creates a block blob, or replaces the existing blob.
+    pub async fn put_blob(&self, name: &str, data: Vec<u8>) -> Result<(), AzureError> {
+        let headers = [("x-ms-blob-type", "BlockBlob")];
+        let response = self.send(Method::PUT, Some(name), &[], &headers, Some(data)).await?;
+        check_status(response).await?;
+        Ok(())
+    }
+
+    /// Returns `None` if the blob doesn't exist.
+    pub async fn get_blob_properties(
+        &self,
+        name: &str,
+    ) -> Result<Option<BlobProperties>, AzureError> {
+        let response = self.send(Method::HEAD, Some(name), &[], &[], None).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = check_status(response).await?;
+        let header = |name: &str| {
+            response.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string)
+        };
+        let content_length = header("Content-Length").and_then(|value| value.parse().ok());
+        let content_md5 = header("Content-MD5")
+            .and_then(|value| base64::engine::general_purpose::STANDARD.decode(value).ok());
+        Ok(Some(BlobProperties { content_length, content_md5 }))
+    }
+
+    /// Deleting a missing blob is not an error.
+    pub async fn delete_blob(&self, name: &str) -> Result<(), AzureError> {
+        let response = self.send(Method::DELETE, Some(name), &[], &[], None).await?;
+        if response.status() != StatusCode::NOT_FOUND {
+            check_status(response).await?;
+        }
+        Ok(())
+    }
+
+    /// Lists blobs with names starting with `prefix`. If `delimiter` is given,
+    /// blobs with the delimiter in the rest of their names are grouped into
+    /// prefixes instead.
+    pub async fn list_blobs_page(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+        marker: Option<&str>,
+        max_results: Option<u32>,
+    ) -> Result<ListBlobsPage, AzureError> {
+        let max_results = max_results.map(|max_results| max_results.to_string());
+        let mut query = vec![("restype", "container"), ("comp", "list"), ("prefix", prefix)];
+        if let Some(delimiter) = delimiter {
+            query.push(("delimiter", delimiter));
+        }
+        if let Some(marker) = marker {
+            query.push(("marker", marker));
+        }
+        if let Some(max_results) = &max_results {
+            query.push(("maxresults", max_results));
+        }
+        let response = self.send(Method::GET, None, &query, &[], None).await?;
+        let response = check_status(response).await?;
+        parse_list_blobs_response(&response.text().await?)
+    }
+
+    /// Sends a request to the container, or to the blob `blob_name` in it.
+    /// `headers` are `x-ms-*` headers other than the date and the version.
+    async fn send(
+        &self,
+        method: Method,
+        blob_name: Option<&str>,
+        query: &[(&str, &str)],
+        headers: &[(&str, &str)],
+        body: Option<Vec<u8>>,
+    ) -> Result<reqwest::Response, AzureError> {
+        let url = match blob_name {
+            Some(blob_name) => format!("{}/{}/{}", self.endpoint, self.container, blob_name),
+            None => format!("{}/{}", self.endpoint, self.container),
+        };
+        let mut url = reqwest::Url::parse(&url).map_err(|err| AzureError::Url(err.to_string()))?;
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+        if let AzureCredentials::SharedAccessSignature(signature) = &self.credentials {
+            let query = match url.query() {
+                Some(query) => format!("{}&{}", query, signature),
+                None => signature.clone(),
+            };
+            url.set_query(Some(&query));
+        }
+
+        let date = chrono::Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        let mut ms_headers: Vec<(String, String)> =
+            headers.iter().map(|(name, value)| (name.to_lowercase(), value.to_string())).collect();
+        ms_headers.push(("x-ms-date".to_string(), date));
+        ms_headers.push(("x-ms-version".to_string(), AZURE_STORAGE_API_VERSION.to_string()));
+        ms_headers.sort();
+
+        let mut request = self.client.request(method.clone(), url.clone());
+        for (name, value) in &ms_headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        if let AzureCredentials::SharedKey(key) = &self.credentials {
+            // The path already starts with the account if a custom endpoint,
+            // such as the storage emulator, is used. It needs to be repeated
+            // then.
+            let resource = format!("/{}{}", self.account, url.path());
+            let content_length = body.as_ref().map_or(0, |body| body.len());
+            let string_to_sign =
+                string_to_sign(&method, content_length, &ms_headers, &resource, query);
+            request = request.header(
+                "Authorization",
+                format!("SharedKey {}:{}", self.account, sign(key, &string_to_sign)),
+            );
+        }
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+        Ok(request.send().await?)
+    }
+}
+
+/// Turns unsuccessful responses into errors.
+async fn check_status(response: reqwest::Response) -> Result<reqwest::Response, AzureError> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    let status_code = response.status().as_u16();
+    let body = response.text().await.unwrap_or_default();
+    Err(AzureError::Status { status_code, body })
+}
+
+fn decode_access_key(access_key: &str) -> Result<Vec<u8>, AzureError> {
+    base64::engine::general_purpose::STANDARD
+        .decode(access_key.trim())
+        .map_err(|err| AzureError::Credentials(format!("access key is not base64: {}", err)))
+}
+
+/// Returns the blob endpoint, if the connection string overrides it, and the
+/// credentials. Keys other than `AccountKey`, `SharedAccessSignature` and
+/// `BlobEndpoint` are ignored.
+fn parse_connection_string(
+    connection_string: &str,
+) -> Result<(Option<String>, AzureCredentials), AzureError> {
+    let mut endpoint = None;
+    let mut credentials = None;
+    for pair in connection_string.split(';').filter(|pair| !pair.trim().is_empty()) {
+        // Values may contain `=`, for example as the padding of the access key.
+        // The entry isn't printed, because it may contain a secret.
+        let (key, value) = pair
+            .split_once('=')
+            .ok_or_else(|| AzureError::Credentials("malformed connection string".to_string()))?;
+        match key.trim() {
+            "AccountKey" => {
+                credentials = Some(AzureCredentials::SharedKey(decode_access_key(value)?))
+            }
+            "SharedAccessSignature" => {
+                credentials = Some(AzureCredentials::SharedAccessSignature(
+                    value.trim().trim_start_matches('?').to_string(),
+                ))
+            }
+            "BlobEndpoint" => endpoint = Some(value.trim().trim_end_matches('/').to_string()),
+            _ => {}
+        }
+    }
+    let credentials = credentials.ok_or_else(|| {
+        AzureError::Credentials(
+            "connection string has neither AccountKey nor SharedAccessSignature".to_string(),
+        )
+    })?;
+    Ok((endpoint, credentials))
+}
+
+/// The string signed by the Shared Key authorization of the Blob service.
+/// Only the headers set by `AzureContainer::send()` are included, the other
+/// standard headers are empty.
+fn string_to_sign(
+    method: &Method,
+    content_length: usize,
+    ms_headers: &[(String, String)],
+    resource: &str,
+    query: &[(&str, &str)],
+) -> String {
+    // Empty content is signed as an empty string rather than `0`.
+    let content_length =
+        if content_length == 0 { String::new() } else { content_length.to_string() };
+    // Followed by Content-Encoding, Content-Language, Content-Length,
+    // Content-MD5, Content-Type, Date, If-Modified-Since, If-Match,
+    // If-None-Match, If-Unmodified-Since and Range.
+    let mut result = format!("{}\n\n\n{}\n\n\n\n\n\n\n\n\n", method.as_str(), content_length);
+    for (name, value) in ms_headers {
+        result.push_str(&format!("{}:{}\n", name, value));
+    }
+    result.push_str(resource);
+    let mut query = query.to_vec();
+    query.sort();
+    for (name, value) in query {
+        result.push_str(&format!("\n{}:{}", name.to_lowercase(), value));
+    }
+    result
+}
+
+fn sign(key: &[u8], string_to_sign: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(string_to_sign.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes())
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct EnumerationResultsXml {
+    #[serde(default)]
+    blobs: BlobsXml,
+    next_marker: Option<String>,
+}
+
+#[derive(serde::Deserialize, Default)]
+struct BlobsXml {
+    #[serde(rename = "$value", default)]
+    items: Vec<BlobItemXml>,
+}
+
+#[derive(serde::Deserialize)]
+enum BlobItemXml {
+    Blob(BlobXml),
+    BlobPrefix(BlobPrefixXml),
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct BlobXml {
+    name: String,
+    properties: BlobPropertiesXml,
+}
+
+#[derive(serde::Deserialize)]
+struct BlobPropertiesXml {
+    #[serde(rename = "Content-Length")]
+    content_length: u64,
+    #[serde(rename = "Last-Modified")]
+    last_modified: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct BlobPrefixXml {
+    name: String,
+}
+
+fn parse_list_blobs_response(body: &str) -> Result<ListBlobsPage, AzureError> {
+    let results: EnumerationResultsXml = serde_xml_rs::from_str(body)?;
+    let mut page = ListBlobsPage {
+        next_marker: results.next_marker.filter(|marker| !marker.is_empty()),
+        ..Default::default()
+    };
+    for item in results.blobs.items {
+        match item {
+            BlobItemXml::Blob(blob) => page.blobs.push(ListedBlob {
+                name: blob.name,
+                content_length: blob.properties.content_length,
+                last_modified: blob
+                    .properties
+                    .last_modified
+                    .and_then(|value| chrono::DateTime::parse_from_rfc2822(&value).ok())
+                    .map(std::time::SystemTime::from),
+            }),
+            BlobItemXml::BlobPrefix(prefix) => page.prefixes.push(prefix.name),
+        }
+    }
+    Ok(page)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_connection_string() {
+        let (endpoint, credentials) = parse_connection_string(
+            "DefaultEndpointsProtocol=https;AccountName=acc;AccountKey=a2V5;EndpointSuffix=core.windows.net",
+        )
+        .unwrap();
+        assert_eq!(endpoint, None);
+        assert_eq!(credentials, AzureCredentials::SharedKey(b"key".to_vec()));
+
+        let (endpoint, credentials) = parse_connection_string(
+            "BlobEndpoint=http://127.0.0.1:10000/devstoreaccount1/;SharedAccessSignature=sv=2021-08-06&sig=abc%3D",
+        )
+        .unwrap();
+        assert_eq!(endpoint.as_deref(), Some("http://127.0.0.1:10000/devstoreaccount1"));
+        assert_eq!(
+            credentials,
+            AzureCredentials::SharedAccessSignature("sv=2021-08-06&sig=abc%3D".to_string())
+        );
+
+        assert!(parse_connection_string("AccountName=acc").is_err());
+    }
+
+    #[test]
+    fn test_string_to_sign() {
+        let ms_headers = [
+            ("x-ms-date".to_string(), "Fri, 26 Jun 2015 23:39:12 GMT".to_string()),
+            ("x-ms-version".to_string(), "2015-02-21".to_string()),
+        ];
+        assert_eq!(
+            string_to_sign(
+                &Method::GET,
+                0,
+                &ms_headers,
+                "/myaccount/mycontainer",
+                &[("restype", "container"), ("comp", "list")],
+            ),
+            "GET\n\n\n\n\n\n\n\n\n\n\n\n\
+             x-ms-date:Fri, 26 Jun 2015 23:39:12 GMT\nx-ms-version:2015-02-21\n\
+             /myaccount/mycontainer\ncomp:list\nrestype:container"
+        );
+    }
+
+    #[test]
+    fn test_parse_list_blobs_response() {
+        let body = r#"<?xml version="1.0" encoding="utf-8"?>
+<EnumerationResults ServiceEndpoint="https://myaccount.blob.core.windows.net/" ContainerName="mycontainer">
+  <Prefix>chain_id=test/</Prefix>
+  <Delimiter>/</Delimiter>
+  <Blobs>
+    <Blob>
+      <Name>chain_id=test/state_part_000000_of_000002</Name>
+      <Properties>
+        <Last-Modified>Fri, 26 Jun 2015 23:39:12 GMT</Last-Modified>
+        <Content-Length>42</Content-Length>
+        <BlobType>BlockBlob</BlobType>
+      </Properties>
+    </Blob>
+    <BlobPrefix>
+      <Name>chain_id=test/epoch_height=1/</Name>
+    </BlobPrefix>
+  </Blobs>
+  <NextMarker>marker</NextMarker>
+</EnumerationResults>"#;
+        let page = parse_list_blobs_response(body).unwrap();
+        assert_eq!(page.blobs.len(), 1);
+        assert_eq!(page.blobs[0].name, "chain_id=test/state_part_000000_of_000002");
+        assert_eq!(page.blobs[0].content_length, 42);
+        assert!(page.blobs[0].last_modified.is_some());
+        assert_eq!(page.prefixes, vec!["chain_id=test/epoch_height=1/".to_string()]);
+        assert_eq!(page.next_marker.as_deref(), Some("marker"));
+    }
+}
@@ -1,4 +1,8 @@
+pub mod azure;
 pub mod block;
 pub mod epoch;
 pub mod header;
 pub mod state;
+#[cfg(feature = "test_features")]
+pub mod state_dump_job;
+pub mod state_parts_archive;
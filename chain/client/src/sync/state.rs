@@ -21,9 +21,10 @@
 //!
 
 use crate::metrics;
+use crate::sync::azure::{AzureContainer, AzureError};
 use ansi_term::Color::{Purple, Yellow};
 use ansi_term::Style;
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use futures::{future, FutureExt};
 use near_async::messaging::CanSendAsync;
 use near_chain::chain::{ApplyStatePartsRequest, StateSplitRequest};
@@ -39,7 +40,7 @@ use near_network::types::PeerManagerMessageRequest;
 use near_network::types::{
     HighestHeightPeerInfo, NetworkRequests, NetworkResponses, PeerManagerAdapter,
 };
-use near_primitives::hash::CryptoHash;
+use near_primitives::hash::{hash, CryptoHash};
 use near_primitives::shard_layout::ShardUId;
 use near_primitives::state_part::PartId;
 use near_primitives::static_clock::StaticClock;
@@ -47,12 +48,12 @@ use near_primitives::syncing::{get_num_state_parts, ShardStateSyncResponse};
 use near_primitives::types::{AccountId, EpochHeight, EpochId, ShardId, StateRoot};
 use rand::seq::SliceRandom;
 use rand::{thread_rng, Rng};
-use std::collections::HashMap;
-use std::io::Write;
-use std::ops::Add;
-use std::path::PathBuf;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::{Add, Range};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicI32, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration as TimeDuration;
 
 /// Maximum number of state parts to request per peer on each round when node is trying to download the state.
@@ -121,37 +122,391 @@ enum StateSyncInner {
         requests_remaining: Arc<AtomicI32>,
         /// Connection to the external storage.
         external: ExternalConnection,
+        /// Whether locations of parts start with the date of the sync block.
+        date_partitioned: bool,
+        /// Dumped epochs found in the external storage when choosing the
+        /// epoch to sync to.
+        dumped_epochs: Arc<Mutex<DumpedEpochsLookup>>,
+        /// Decodes the downloaded parts.
+        part_transform: Arc<dyn PartTransform>,
     },
 }
 
+/// At most this many of the newest dumped epochs of a shard are verified when
+/// choosing the epoch to sync to. Syncing to an even older epoch means too
+/// many blocks to catch up.
+const MAX_DUMPED_EPOCHS_TO_VERIFY: usize = 4;
+
+/// Progress of looking up the dumped epochs in the external storage.
+enum DumpedEpochsLookup {
+    NotStarted,
+    InProgress,
+    /// Dumped epochs of every shard, newest first.
+    Done(Result<HashMap<ShardId, Vec<DumpedEpoch>>, String>),
+}
+
+/// An epoch having a dump of a shard in the external storage.
+struct DumpedEpoch {
+    epoch_id: EpochId,
+    epoch_height: EpochHeight,
+    /// `Err` explains why the dump can't be used, see
+    /// `ExternalConnection::verify_dumped_epoch()`.
+    verified: Result<(), String>,
+}
+
+/// A state part file found in the external storage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListedStatePart {
+    pub file_name: String,
+    /// Size of the part in bytes, if the external storage reports it.
+    pub size: Option<u64>,
+    /// When the file was last written, if the external storage reports it.
+    pub last_modified: Option<std::time::SystemTime>,
+}
+
+/// Written to the external storage once all parts of an epoch are dumped.
+/// Lets a node whose progress is behind the external storage skip the epoch
+/// without obtaining any parts, and a restoring node tell a complete dump from
+/// one that is still in progress.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct StatePartsManifest {
+    pub num_parts: u64,
+    pub total_bytes: u64,
+    /// Merkle root of the hashes of the parts ordered by part id, see
+    /// `DumpConfig::compute_merkle_root`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merkle_root: Option<CryptoHash>,
+}
+
+/// Failure to list state parts in the external storage.
+#[derive(thiserror::Error, Debug)]
+pub enum ListStatePartsError {
+    /// Likely to succeed when retried, for example after a 5xx response or
+    /// a timeout.
+    #[error("Failed to list state parts, can be retried")]
+    Retryable(#[source] anyhow::Error),
+    /// Retrying will not help, for example if the bucket doesn't exist or the
+    /// access is denied. Usually means that the location is misconfigured.
+    #[error("Failed to list state parts, retrying will not help")]
+    Fatal(#[source] anyhow::Error),
+}
+
+impl ListStatePartsError {
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, ListStatePartsError::Fatal(_))
+    }
+
+    /// Value of the `kind` label of metrics.
+    pub fn label(&self) -> &'static str {
+        match self {
+            ListStatePartsError::Retryable(_) => "retryable",
+            ListStatePartsError::Fatal(_) => "fatal",
+        }
+    }
+
+    /// Client errors other than throttling are fatal.
+    fn from_status_code(status_code: u16, err: anyhow::Error) -> Self {
+        if (400..500).contains(&status_code) && status_code != 408 && status_code != 429 {
+            ListStatePartsError::Fatal(err)
+        } else {
+            ListStatePartsError::Retryable(err)
+        }
+    }
+
+    /// Errors without a response, such as timeouts and failed connections,
+    /// are retryable.
+    fn from_s3_error(err: s3::error::S3Error) -> Self {
+        let status_code = match &err {
+            s3::error::S3Error::Http(status_code, _) => Some(*status_code),
+            _ => None,
+        };
+        match status_code {
+            Some(status_code) => Self::from_status_code(status_code, err.into()),
+            None => ListStatePartsError::Retryable(err.into()),
+        }
+    }
+
+    /// Errors without a response are retryable.
+    fn from_azure_error(err: AzureError) -> Self {
+        match err.status_code() {
+            Some(status_code) => Self::from_status_code(status_code, err.into()),
+            None => ListStatePartsError::Retryable(err.into()),
+        }
+    }
+
+    fn from_io_error(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::NotFound => {
+                ListStatePartsError::Fatal(err.into())
+            }
+            _ => ListStatePartsError::Retryable(err.into()),
+        }
+    }
+}
+
 /// Connection to the external storage.
 #[derive(Clone)]
 pub enum ExternalConnection {
-    S3 { bucket: Arc<s3::Bucket> },
-    Filesystem { root_dir: PathBuf },
+    S3 {
+        bucket: Arc<s3::Bucket>,
+    },
+    /// If `fsync` is set, written files and their parent directories are
+    /// synced to disk before a write is considered complete.
+    Filesystem {
+        root_dir: PathBuf,
+        fsync: bool,
+    },
+    Azure {
+        container: Arc<AzureContainer>,
+    },
+    /// Writes every file to `path` as a frame: the length of the location of
+    /// the file as a little-endian u32, the location, the length of the data
+    /// as a little-endian u64, and the data. The file is opened on the first
+    /// write. A failed write may leave an incomplete frame behind, so all
+    /// writes after it fail, see `PipeFile::Broken`.
+    /// Nothing can be read back, so the storage looks empty, and everything
+    /// gets written without checking what is already stored.
+    Pipe {
+        path: PathBuf,
+        file: Arc<Mutex<PipeFile>>,
+    },
+}
+
+/// The file of `ExternalConnection::Pipe`.
+pub enum PipeFile {
+    /// Not opened yet, or failed to open.
+    Closed,
+    Open(std::fs::File),
+    /// A write failed, possibly in the middle of a frame. Anything written
+    /// after it would be misread by the reader, so the node needs to be
+    /// restarted, together with the reader, to continue dumping.
+    Broken(String),
 }
 
+/// How long to wait for a named pipe to be opened for reading before a write
+/// to it fails.
+const PIPE_OPEN_TIMEOUT: TimeDuration = TimeDuration::from_secs(10);
+
+/// Delay between attempts to open a named pipe, and between attempts to write
+/// to a full one.
+const PIPE_RETRY_DELAY: TimeDuration = TimeDuration::from_millis(10);
+
 impl ExternalConnection {
-    async fn get_part(self, shard_id: ShardId, location: &str) -> Result<Vec<u8>, anyhow::Error> {
+    /// Kind of the external storage, suitable for a metric label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ExternalConnection::S3 { .. } => "s3",
+            ExternalConnection::Filesystem { .. } => "filesystem",
+            ExternalConnection::Azure { .. } => "azure",
+            ExternalConnection::Pipe { .. } => "pipe",
+        }
+    }
+
+    /// Whether the stored files can't be listed or read, see
+    /// `ExternalConnection::Pipe`.
+    pub fn is_write_only(&self) -> bool {
+        matches!(self, ExternalConnection::Pipe { .. })
+    }
+
+    /// Reads a part, following the pointer if the part is stored as a pointer
+    /// to an identical part of another epoch, see `state_part_pointer()`.
+    pub async fn get_part(
+        self,
+        shard_id: ShardId,
+        location: &str,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        self.get_part_following_pointer(shard_id, location, None).await
+    }
+
+    /// Same as `get_part()`, but calls `progress` with the number of bytes
+    /// read so far and the size of the part while the part is being read, see
+    /// `get_object()`. A pointer gets read before the part it points to, and
+    /// its progress is reported too.
+    pub async fn get_part_with_progress(
+        self,
+        shard_id: ShardId,
+        location: &str,
+        progress: &mut (dyn FnMut(u64, u64) + Send),
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        self.get_part_following_pointer(shard_id, location, Some(progress)).await
+    }
+
+    async fn get_part_following_pointer(
+        &self,
+        shard_id: ShardId,
+        location: &str,
+        mut progress: Option<&mut (dyn FnMut(u64, u64) + Send)>,
+    ) -> Result<Vec<u8>, anyhow::Error> {
         let _timer = metrics::STATE_SYNC_EXTERNAL_PARTS_REQUEST_DELAY
             .with_label_values(&[&shard_id.to_string()])
             .start_timer();
+        let data = self.get_object(shard_id, location, &mut progress).await?;
+        match parse_state_part_pointer(&data) {
+            Some(target_location) => {
+                tracing::debug!(target: "sync", %shard_id, location, target_location, "Following a state part pointer");
+                self.get_object(shard_id, target_location, &mut progress).await
+            }
+            None => Ok(data),
+        }
+    }
+
+    /// If `progress` is set, S3 objects are streamed, which costs an extra
+    /// request to get the size of the object, and files are read chunk by
+    /// chunk. Azure blobs are read in a single request, so their progress is
+    /// only reported once they are read.
+    async fn get_object(
+        &self,
+        shard_id: ShardId,
+        location: &str,
+        progress: &mut Option<&mut (dyn FnMut(u64, u64) + Send)>,
+    ) -> Result<Vec<u8>, anyhow::Error> {
         match self {
-            ExternalConnection::S3 { bucket } => {
-                let response = bucket.get_object(location).await?;
-                tracing::debug!(target: "sync", %shard_id, location, response_code = response.status_code(), num_bytes = response.bytes().len(), "S3 request finished");
-                if response.status_code() == 200 {
-                    Ok(response.bytes().to_vec())
-                } else {
-                    Err(anyhow::anyhow!("Bad response status code: {}", response.status_code()))
+            ExternalConnection::S3 { bucket } => match progress {
+                None => {
+                    let response = bucket.get_object(location).await?;
+                    tracing::debug!(target: "sync", %shard_id, location, response_code = response.status_code(), num_bytes = response.bytes().len(), "S3 request finished");
+                    if response.status_code() == 200 {
+                        Ok(response.bytes().to_vec())
+                    } else {
+                        Err(anyhow::anyhow!("Bad response status code: {}", response.status_code()))
+                    }
                 }
-            }
-            ExternalConnection::Filesystem { root_dir } => {
+                Some(progress) => {
+                    let (head, status_code) = bucket.head_object(location).await?;
+                    if status_code != 200 {
+                        anyhow::bail!("Bad response status code: {status_code}");
+                    }
+                    let total = head.content_length.unwrap_or(0).max(0) as u64;
+                    let mut writer =
+                        ProgressWriter { data: vec![], total, progress: &mut **progress };
+                    let status_code = bucket.get_object_stream(location, &mut writer).await?;
+                    tracing::debug!(target: "sync", %shard_id, location, response_code = status_code, num_bytes = writer.data.len(), "S3 request finished");
+                    if status_code != 200 {
+                        anyhow::bail!("Bad response status code: {status_code}");
+                    }
+                    Ok(writer.data)
+                }
+            },
+            ExternalConnection::Filesystem { root_dir, .. } => {
                 let path = root_dir.join(location);
                 tracing::debug!(target: "sync", %shard_id, ?path, "Reading a file");
-                let data = std::fs::read(&path)?;
+                let progress = match progress {
+                    None => return Ok(std::fs::read(&path)?),
+                    Some(progress) => progress,
+                };
+                let mut file = std::fs::File::open(&path)?;
+                let total = file.metadata()?.len();
+                let mut data = Vec::with_capacity(total as usize);
+                let mut chunk = vec![0; TRANSFER_PROGRESS_CHUNK_SIZE];
+                loop {
+                    let num_bytes = file.read(&mut chunk)?;
+                    if num_bytes == 0 {
+                        return Ok(data);
+                    }
+                    data.extend_from_slice(&chunk[..num_bytes]);
+                    progress(data.len() as u64, total);
+                }
+            }
+            ExternalConnection::Azure { container } => {
+                let data = container.get_blob(location).await?;
+                tracing::debug!(target: "sync", %shard_id, location, num_bytes = data.as_ref().map(|data| data.len()), "Azure request finished");
+                let data =
+                    data.ok_or_else(|| anyhow::anyhow!("Blob {} doesn't exist", location))?;
+                if let Some(progress) = progress {
+                    progress(data.len() as u64, data.len() as u64);
+                }
                 Ok(data)
             }
+            ExternalConnection::Pipe { path, .. } => {
+                anyhow::bail!("{} can't be read from pipe {}", location, path.display())
+            }
+        }
+    }
+
+    /// Reads bytes `range` of a state part, following a state part pointer.
+    /// Lets tools inspect the beginning of a part without downloading all of
+    /// it. Fails if the range is empty or extends beyond the end of the part.
+    pub async fn get_state_part_range(
+        &self,
+        shard_id: ShardId,
+        location: &str,
+        range: Range<u64>,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        anyhow::ensure!(range.start < range.end, "Empty range {:?} of {}", range, location);
+        let target_location = self.get_state_part_pointer(shard_id, location).await;
+        let location = target_location.as_deref().unwrap_or(location);
+        let len = range.end - range.start;
+        let mut data = match self {
+            ExternalConnection::S3 { bucket } => {
+                // The end of the requested range is inclusive, and the client
+                // requires it to be greater than the start.
+                let end = (range.end - 1).max(range.start + 1);
+                let response = bucket.get_object_range(location, range.start, Some(end)).await?;
+                tracing::debug!(target: "sync", %shard_id, location, ?range, response_code = response.status_code(), "S3 range request finished");
+                if response.status_code() != 206 {
+                    anyhow::bail!("Bad response status code: {}", response.status_code());
+                }
+                response.bytes().to_vec()
+            }
+            ExternalConnection::Filesystem { root_dir, .. } => {
+                let path = root_dir.join(location);
+                tracing::debug!(target: "sync", %shard_id, ?path, ?range, "Reading a range of a file");
+                let mut file = std::fs::File::open(&path)?;
+                file.seek(SeekFrom::Start(range.start))?;
+                let mut data = vec![];
+                file.take(len).read_to_end(&mut data)?;
+                data
+            }
+            ExternalConnection::Azure { container } => container
+                .get_blob_range(location, range.clone())
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Blob {} doesn't exist", location))?,
+            ExternalConnection::Pipe { path, .. } => {
+                anyhow::bail!("{} can't be read from pipe {}", location, path.display())
+            }
+        };
+        data.truncate(len as usize);
+        anyhow::ensure!(
+            data.len() as u64 == len,
+            "Range {:?} is beyond the end of {}, which has {} bytes",
+            range,
+            location,
+            range.start + data.len() as u64
+        );
+        Ok(data)
+    }
+
+    /// Reads a whole file. Returns `None` if the file doesn't exist.
+    pub async fn get_file(
+        &self,
+        shard_id: ShardId,
+        location: &str,
+    ) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        match self {
+            ExternalConnection::S3 { bucket } => match bucket.get_object(location).await {
+                Ok(response) if response.status_code() == 200 => {
+                    Ok(Some(response.bytes().to_vec()))
+                }
+                Ok(response) if response.status_code() == 404 => Ok(None),
+                Ok(response) => {
+                    Err(anyhow::anyhow!("Bad response status code: {}", response.status_code()))
+                }
+                Err(s3::error::S3Error::Http(404, _)) => Ok(None),
+                Err(err) => Err(err.into()),
+            },
+            ExternalConnection::Filesystem { root_dir, .. } => {
+                let path = root_dir.join(location);
+                match tokio::fs::read(&path).await {
+                    Ok(data) => Ok(Some(data)),
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                        tracing::trace!(target: "state_sync_dump", shard_id, ?path, "File doesn't exist");
+                        Ok(None)
+                    }
+                    Err(err) => Err(err.into()),
+                }
+            }
+            ExternalConnection::Azure { container } => Ok(container.get_blob(location).await?),
+            ExternalConnection::Pipe { .. } => Ok(None),
         }
     }
 
@@ -160,29 +515,283 @@ impl ExternalConnection {
         state_part: &[u8],
         shard_id: ShardId,
         location: &str,
+    ) -> Result<(), anyhow::Error> {
+        self.put_object(state_part, shard_id, location, None).await
+    }
+
+    /// Same as `put_state_part()`, but calls `progress` with the number of
+    /// bytes written so far and the size of the part while the part is being
+    /// written. Files get written chunk by chunk. Other backends upload the
+    /// part in a single request, so that its digest stays comparable, see
+    /// `has_identical_state_part()`, and report the progress once it's
+    /// uploaded. Large parts can be uploaded with
+    /// `put_state_part_streamed_with_progress()` instead.
+    pub async fn put_state_part_with_progress(
+        &self,
+        state_part: &[u8],
+        shard_id: ShardId,
+        location: &str,
+        progress: &mut (dyn FnMut(u64, u64) + Send),
+    ) -> Result<(), anyhow::Error> {
+        self.put_object(state_part, shard_id, location, Some(progress)).await
+    }
+
+    async fn put_object(
+        &self,
+        state_part: &[u8],
+        shard_id: ShardId,
+        location: &str,
+        mut progress: Option<&mut (dyn FnMut(u64, u64) + Send)>,
     ) -> Result<(), anyhow::Error> {
         let _timer = metrics::STATE_SYNC_DUMP_PUT_OBJECT_ELAPSED
             .with_label_values(&[&shard_id.to_string()])
             .start_timer();
+        let total = state_part.len() as u64;
         match self {
             ExternalConnection::S3 { bucket } => {
                 bucket.put_object(&location, state_part).await?;
+                if let Some(progress) = progress.as_mut() {
+                    progress(total, total);
+                }
                 tracing::debug!(target: "state_sync_dump", shard_id, part_length = state_part.len(), ?location, "Wrote a state part to S3");
                 Ok(())
             }
-            ExternalConnection::Filesystem { root_dir } => {
+            ExternalConnection::Filesystem { root_dir, fsync } => {
                 let path = root_dir.join(location);
                 if let Some(parent_dir) = path.parent() {
                     std::fs::create_dir_all(parent_dir)?;
                 }
-                let mut file = std::fs::OpenOptions::new().write(true).create(true).open(&path)?;
-                file.write_all(state_part)?;
+                let mut file = std::fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&path)?;
+                match progress.as_mut() {
+                    None => file.write_all(state_part)?,
+                    Some(progress) => {
+                        let mut num_bytes = 0;
+                        for chunk in state_part.chunks(TRANSFER_PROGRESS_CHUNK_SIZE) {
+                            file.write_all(chunk)?;
+                            num_bytes += chunk.len() as u64;
+                            progress(num_bytes, total);
+                        }
+                    }
+                }
+                if *fsync {
+                    sync_file_and_parent_dir(&file, &path, shard_id)?;
+                }
                 tracing::debug!(target: "state_sync_dump", shard_id, part_length = state_part.len(), ?location, "Wrote a state part to a file");
                 Ok(())
             }
+            ExternalConnection::Azure { container } => {
+                container.put_blob(location, state_part.to_vec()).await?;
+                if let Some(progress) = progress.as_mut() {
+                    progress(total, total);
+                }
+                tracing::debug!(target: "state_sync_dump", shard_id, part_length = state_part.len(), ?location, "Wrote a state part to Azure");
+                Ok(())
+            }
+            ExternalConnection::Pipe { path, file } => {
+                write_to_pipe(path, file, location, state_part).await?;
+                if let Some(progress) = progress.as_mut() {
+                    progress(total, total);
+                }
+                tracing::debug!(target: "state_sync_dump", shard_id, part_length = state_part.len(), ?location, "Wrote a state part to a pipe");
+                Ok(())
+            }
         }
     }
 
+    /// Same as `put_state_part()`, but consumes the part from `reader` as it
+    /// gets uploaded instead of requiring the whole part in memory.
+    /// S3 uploads the part in chunks as a multipart upload, and files get
+    /// written chunk by chunk. A backend that can't stream needs to read the
+    /// part into memory and fall back to `put_state_part()`.
+    /// Returns the number of bytes written.
+    pub async fn put_state_part_streamed<R: tokio::io::AsyncRead + Unpin>(
+        &self,
+        reader: &mut R,
+        shard_id: ShardId,
+        location: &str,
+    ) -> Result<u64, anyhow::Error> {
+        let _timer = metrics::STATE_SYNC_DUMP_PUT_OBJECT_ELAPSED
+            .with_label_values(&[&shard_id.to_string()])
+            .start_timer();
+        match self {
+            ExternalConnection::S3 { bucket } => {
+                let mut reader = CountingReader { inner: reader, num_bytes: 0 };
+                let status_code = bucket.put_object_stream(&mut reader, location).await?;
+                if status_code != 200 {
+                    anyhow::bail!("Bad response status code: {status_code}");
+                }
+                tracing::debug!(target: "state_sync_dump", shard_id, part_length = reader.num_bytes, ?location, "Streamed a state part to S3");
+                Ok(reader.num_bytes)
+            }
+            ExternalConnection::Filesystem { root_dir, fsync } => {
+                let path = root_dir.join(location);
+                if let Some(parent_dir) = path.parent() {
+                    tokio::fs::create_dir_all(parent_dir).await?;
+                }
+                let mut file = tokio::fs::File::create(&path).await?;
+                let part_length = tokio::io::copy(reader, &mut file).await?;
+                tokio::io::AsyncWriteExt::flush(&mut file).await?;
+                if *fsync {
+                    let file = file.into_std().await;
+                    sync_file_and_parent_dir(&file, &path, shard_id)?;
+                }
+                tracing::debug!(target: "state_sync_dump", shard_id, part_length, ?location, "Streamed a state part to a file");
+                Ok(part_length)
+            }
+            ExternalConnection::Azure { container } => {
+                // Block blobs can be uploaded in blocks, but parts are small
+                // enough to be uploaded in a single request.
+                let mut state_part = vec![];
+                tokio::io::AsyncReadExt::read_to_end(reader, &mut state_part).await?;
+                let part_length = state_part.len() as u64;
+                container.put_blob(location, state_part).await?;
+                tracing::debug!(target: "state_sync_dump", shard_id, part_length, ?location, "Wrote a state part to Azure");
+                Ok(part_length)
+            }
+            ExternalConnection::Pipe { path, file } => {
+                // The frame starts with the length of the part.
+                let mut state_part = vec![];
+                tokio::io::AsyncReadExt::read_to_end(reader, &mut state_part).await?;
+                write_to_pipe(path, file, location, &state_part).await?;
+                let part_length = state_part.len() as u64;
+                tracing::debug!(target: "state_sync_dump", shard_id, part_length, ?location, "Wrote a state part to a pipe");
+                Ok(part_length)
+            }
+        }
+    }
+
+    /// Same as `put_state_part_streamed()`, but calls `progress` with the
+    /// number of bytes consumed from `reader` so far and `total`, the expected
+    /// size of the part, as the part gets uploaded.
+    pub async fn put_state_part_streamed_with_progress<R: tokio::io::AsyncRead + Unpin>(
+        &self,
+        reader: &mut R,
+        total: u64,
+        shard_id: ShardId,
+        location: &str,
+        progress: &mut (dyn FnMut(u64, u64) + Send),
+    ) -> Result<u64, anyhow::Error> {
+        let mut reader = ProgressReader { inner: reader, num_bytes: 0, total, progress };
+        self.put_state_part_streamed(&mut reader, shard_id, location).await
+    }
+
+    /// Checks whether a part identical to `state_part` is already stored at
+    /// `location`. S3 objects are compared by size and ETag, which is the MD5
+    /// digest of objects uploaded in one request. Objects uploaded in multiple
+    /// chunks have a different ETag and are never considered identical.
+    /// Azure blobs are compared by size and the MD5 digest, which Azure keeps
+    /// for blobs uploaded in one request.
+    /// Errors are treated as the part not being stored, so that the caller
+    /// falls back to uploading the part.
+    pub async fn has_identical_state_part(
+        &self,
+        state_part: &[u8],
+        shard_id: ShardId,
+        location: &str,
+    ) -> bool {
+        match self {
+            ExternalConnection::S3 { bucket } => match bucket.head_object(location).await {
+                Ok((head, 200)) => {
+                    let expected_e_tag = format!("{:x}", md5::compute(state_part));
+                    head.content_length == Some(state_part.len() as i64)
+                        && head.e_tag.as_deref().map(|e_tag| e_tag.trim_matches('"'))
+                            == Some(expected_e_tag.as_str())
+                }
+                Ok(_) => false,
+                Err(err) => {
+                    // Also happens if the object doesn't exist.
+                    tracing::trace!(target: "state_sync_dump", shard_id, ?location, ?err, "Failed to get metadata of an object");
+                    false
+                }
+            },
+            ExternalConnection::Filesystem { root_dir, .. } => {
+                let path = root_dir.join(location);
+                match tokio::fs::metadata(&path).await {
+                    Ok(metadata) if metadata.len() == state_part.len() as u64 => {}
+                    _ => return false,
+                }
+                match tokio::fs::read(&path).await {
+                    Ok(existing_part) => hash(&existing_part) == hash(state_part),
+                    Err(err) => {
+                        tracing::debug!(target: "state_sync_dump", shard_id, ?path, ?err, "Failed to read an existing part");
+                        false
+                    }
+                }
+            }
+            ExternalConnection::Azure { container } => {
+                match container.get_blob_properties(location).await {
+                    Ok(Some(properties)) => {
+                        properties.content_length == Some(state_part.len() as u64)
+                            && properties.content_md5.as_deref()
+                                == Some(md5::compute(state_part).0.as_slice())
+                    }
+                    Ok(None) => false,
+                    Err(err) => {
+                        tracing::trace!(target: "state_sync_dump", shard_id, ?location, ?err, "Failed to get properties of a blob");
+                        false
+                    }
+                }
+            }
+            ExternalConnection::Pipe { .. } => false,
+        }
+    }
+
+    /// Returns the location that the object at `location` points to, if it is
+    /// a state part pointer. Only objects small enough to be pointers are
+    /// read. Errors are treated as the object not being a pointer.
+    pub async fn get_state_part_pointer(
+        &self,
+        shard_id: ShardId,
+        location: &str,
+    ) -> Option<String> {
+        let size = match self {
+            ExternalConnection::S3 { bucket } => match bucket.head_object(location).await {
+                Ok((head, 200)) => head.content_length.map(|size| size as u64),
+                _ => None,
+            },
+            ExternalConnection::Filesystem { root_dir, .. } => {
+                tokio::fs::metadata(root_dir.join(location))
+                    .await
+                    .ok()
+                    .map(|metadata| metadata.len())
+            }
+            ExternalConnection::Azure { container } => {
+                match container.get_blob_properties(location).await {
+                    Ok(Some(properties)) => properties.content_length,
+                    _ => None,
+                }
+            }
+            ExternalConnection::Pipe { .. } => None,
+        };
+        if size? > MAX_STATE_PART_POINTER_LEN {
+            return None;
+        }
+        match self.get_file(shard_id, location).await {
+            Ok(Some(data)) => parse_state_part_pointer(&data).map(str::to_string),
+            Ok(None) => None,
+            Err(err) => {
+                tracing::debug!(target: "state_sync_dump", shard_id, ?location, ?err, "Failed to read a possible state part pointer");
+                None
+            }
+        }
+    }
+
+    /// Uploads an archive of all state parts, see `state_parts_archive` for
+    /// its format. The archive is streamed from the file.
+    pub async fn put_archive(
+        &self,
+        archive_path: &std::path::Path,
+        shard_id: ShardId,
+        location: &str,
+    ) -> Result<u64, anyhow::Error> {
+        let mut file = tokio::fs::File::open(archive_path).await?;
+        self.put_state_part_streamed(&mut file, shard_id, location).await
+    }
+
     fn extract_file_name_from_full_path(full_path: String) -> String {
         return Self::extract_file_name_from_path_buf(PathBuf::from(full_path));
     }
@@ -195,39 +804,571 @@ impl ExternalConnection {
         &self,
         shard_id: ShardId,
         directory_path: &str,
-    ) -> Result<Vec<String>, anyhow::Error> {
+    ) -> Result<Vec<ListedStatePart>, ListStatePartsError> {
         let _timer = metrics::STATE_SYNC_DUMP_LIST_OBJECT_ELAPSED
             .with_label_values(&[&shard_id.to_string()])
             .start_timer();
         match self {
             ExternalConnection::S3 { bucket } => {
                 let prefix = format!("{}/", directory_path);
-                let list_results = bucket.list(prefix.clone(), Some("/".to_string())).await?;
                 tracing::debug!(target: "state_sync_dump", shard_id, ?directory_path, "List state parts in s3");
-                let mut file_names = vec![];
-                for res in list_results {
-                    for obj in res.contents {
-                        file_names.push(Self::extract_file_name_from_full_path(obj.key))
+                // A single ListObjectsV2 response contains at most 1000 keys,
+                // while an epoch may have tens of thousands of parts.
+                list_all_pages(|continuation_token| {
+                    let prefix = prefix.clone();
+                    async move {
+                        let (page, status_code) = bucket
+                            .list_page(
+                                prefix,
+                                Some("/".to_string()),
+                                continuation_token,
+                                None,
+                                None,
+                            )
+                            .await
+                            .map_err(ListStatePartsError::from_s3_error)?;
+                        if status_code != 200 {
+                            return Err(ListStatePartsError::from_status_code(
+                                status_code,
+                                anyhow::anyhow!(
+                                    "Listing state parts failed with status code {status_code}"
+                                ),
+                            ));
+                        }
+                        let parts = page
+                            .contents
+                            .into_iter()
+                            .map(|obj| ListedStatePart {
+                                file_name: Self::extract_file_name_from_full_path(obj.key),
+                                size: Some(obj.size),
+                                last_modified: chrono::DateTime::parse_from_rfc3339(
+                                    &obj.last_modified,
+                                )
+                                .ok()
+                                .map(std::time::SystemTime::from),
+                            })
+                            .collect();
+                        let next_continuation_token =
+                            if page.is_truncated { page.next_continuation_token } else { None };
+                        Ok((parts, next_continuation_token))
                     }
-                }
-                Ok(file_names)
+                })
+                .await
             }
-            ExternalConnection::Filesystem { root_dir } => {
+            ExternalConnection::Pipe { .. } => Ok(vec![]),
+            ExternalConnection::Filesystem { root_dir, .. } => {
                 let path = root_dir.join(directory_path);
                 tracing::debug!(target: "state_sync_dump", shard_id, ?path, "List state parts in local directory");
-                std::fs::create_dir_all(&path)?;
-                let mut file_names = vec![];
-                let files = std::fs::read_dir(&path)?;
-                for file in files {
-                    let file_name = Self::extract_file_name_from_path_buf(file?.path());
-                    file_names.push(file_name);
+                std::fs::create_dir_all(&path).map_err(ListStatePartsError::from_io_error)?;
+                // `read_dir` yields entries lazily, so large directories are
+                // listed completely without being capped.
+                let mut parts = vec![];
+                for file in std::fs::read_dir(&path).map_err(ListStatePartsError::from_io_error)? {
+                    let file = file.map_err(ListStatePartsError::from_io_error)?;
+                    let metadata = file.metadata().map_err(ListStatePartsError::from_io_error)?;
+                    if !metadata.is_file() {
+                        continue;
+                    }
+                    parts.push(ListedStatePart {
+                        file_name: Self::extract_file_name_from_path_buf(file.path()),
+                        size: Some(metadata.len()),
+                        last_modified: metadata.modified().ok(),
+                    });
+                }
+                Ok(parts)
+            }
+            ExternalConnection::Azure { container } => {
+                let prefix = format!("{}/", directory_path);
+                tracing::debug!(target: "state_sync_dump", shard_id, ?directory_path, "List state parts in Azure");
+                list_all_pages(|marker| {
+                    let prefix = prefix.clone();
+                    async move {
+                        let page = container
+                            .list_blobs_page(&prefix, Some("/"), marker.as_deref(), None)
+                            .await
+                            .map_err(ListStatePartsError::from_azure_error)?;
+                        let parts = page
+                            .blobs
+                            .into_iter()
+                            .map(|blob| ListedStatePart {
+                                file_name: Self::extract_file_name_from_full_path(blob.name),
+                                size: Some(blob.content_length),
+                                last_modified: blob.last_modified,
+                            })
+                            .collect();
+                        Ok::<_, ListStatePartsError>((parts, page.next_marker))
+                    }
+                })
+                .await
+            }
+        }
+    }
+
+    /// Same as `list_state_parts()`, but a missing directory of the filesystem
+    /// is listed as empty instead of being created, so that listing a deleted
+    /// dump doesn't bring back its directory.
+    pub async fn list_existing_state_parts(
+        &self,
+        shard_id: ShardId,
+        directory_path: &str,
+    ) -> Result<Vec<ListedStatePart>, ListStatePartsError> {
+        if let ExternalConnection::Filesystem { root_dir, .. } = self {
+            if !root_dir.join(directory_path).exists() {
+                return Ok(vec![]);
+            }
+        }
+        self.list_state_parts(shard_id, directory_path).await
+    }
+
+    /// Yields the parts stored in `directory_path` as `(part_id, bytes)`,
+    /// ordered by part id. The directory is listed a page at a time, and a
+    /// part is downloaded only once the consumer polls for it, following the
+    /// pointers of incremental dumps. Parts need to be named by
+    /// `DefaultPartNaming`, whose zero-padded names are listed by the object
+    /// storages in the order of part ids. Listing stops at the first error.
+    pub fn stream_parts<'a>(
+        &'a self,
+        shard_id: ShardId,
+        directory_path: &'a str,
+    ) -> impl futures::Stream<Item = Result<(u64, Vec<u8>), anyhow::Error>> + 'a {
+        // `None` once the last page is listed, `Some(None)` for the first page.
+        let next_page: Option<Option<String>> = Some(None);
+        futures::stream::unfold(
+            (VecDeque::new(), next_page),
+            move |(mut parts, mut next_page)| async move {
+                loop {
+                    if let Some((part_id, location)) = parts.pop_front() {
+                        let part = self.clone().get_part(shard_id, &location).await;
+                        return Some((part.map(|part| (part_id, part)), (parts, next_page)));
+                    }
+                    let continuation_token = next_page.take()?;
+                    match self.list_parts_page(shard_id, directory_path, continuation_token).await {
+                        Ok((page, next_continuation_token)) => {
+                            parts.extend(page);
+                            next_page = next_continuation_token.map(Some);
+                        }
+                        Err(err) => return Some((Err(err), (parts, None))),
+                    }
+                }
+            },
+        )
+    }
+
+    /// Lists a page of part files in `directory_path`, as part ids and
+    /// locations sorted by part id, and the token of the next page if any.
+    /// A directory of the filesystem is listed as a single page.
+    async fn list_parts_page(
+        &self,
+        shard_id: ShardId,
+        directory_path: &str,
+        continuation_token: Option<String>,
+    ) -> Result<(Vec<(u64, String)>, Option<String>), anyhow::Error> {
+        let prefix = format!("{}/", directory_path);
+        let (locations, next_continuation_token) = match self {
+            ExternalConnection::S3 { bucket } => {
+                let (page, status_code) = bucket
+                    .list_page(prefix, Some("/".to_string()), continuation_token, None, None)
+                    .await?;
+                if status_code != 200 {
+                    anyhow::bail!("Listing state parts failed with status code {status_code}");
+                }
+                let locations = page.contents.into_iter().map(|obj| obj.key).collect::<Vec<_>>();
+                (locations, if page.is_truncated { page.next_continuation_token } else { None })
+            }
+            ExternalConnection::Pipe { .. } => (vec![], None),
+            ExternalConnection::Filesystem { root_dir, .. } => {
+                let mut locations = vec![];
+                for file in std::fs::read_dir(root_dir.join(directory_path))? {
+                    let file = file?;
+                    if file.metadata()?.is_file() {
+                        locations.push(format!(
+                            "{}{}",
+                            prefix,
+                            Self::extract_file_name_from_path_buf(file.path())
+                        ));
+                    }
+                }
+                (locations, None)
+            }
+            ExternalConnection::Azure { container } => {
+                let page = container
+                    .list_blobs_page(&prefix, Some("/"), continuation_token.as_deref(), None)
+                    .await?;
+                (page.blobs.into_iter().map(|blob| blob.name).collect(), page.next_marker)
+            }
+        };
+        let mut parts: Vec<(u64, String)> = locations
+            .into_iter()
+            .filter_map(|location| {
+                let file_name = Self::extract_file_name_from_full_path(location.clone());
+                let part_id = get_part_id_from_filename(&DefaultPartNaming, &file_name)?;
+                Some((part_id, location))
+            })
+            .collect();
+        parts.sort();
+        tracing::debug!(target: "state_sync_dump", shard_id, directory_path, num_parts = parts.len(), ?next_continuation_token, "Listed a page of state parts");
+        Ok((parts, next_continuation_token))
+    }
+
+    /// Returns the epochs having a directory of the shard in the
+    /// `chain_id=../epoch_height=../epoch_id=../shard_id=..` structure created
+    /// by the state dump, sorted by epoch height. The shard directory may
+    /// contain only a part of the state parts.
+    /// Directories that don't follow the structure are ignored, and so are
+    /// date-partitioned dumps.
+    pub async fn list_dumped_epochs(
+        &self,
+        chain_id: &str,
+        shard_id: ShardId,
+    ) -> Result<Vec<(EpochId, EpochHeight)>, anyhow::Error> {
+        let mut epochs = vec![];
+        let chain_directory = format!("chain_id={}", chain_id);
+        for epoch_height_name in self.list_directories(&chain_directory).await? {
+            let epoch_height = match parse_location_component(&epoch_height_name, "epoch_height") {
+                Some(epoch_height) => epoch_height,
+                None => continue,
+            };
+            let epoch_height_directory = format!("{}/{}", chain_directory, epoch_height_name);
+            for epoch_id_name in self.list_directories(&epoch_height_directory).await? {
+                let epoch_id = match parse_location_component(&epoch_id_name, "epoch_id") {
+                    Some(epoch_id) => EpochId(epoch_id),
+                    None => continue,
+                };
+                let epoch_id_directory = format!("{}/{}", epoch_height_directory, epoch_id_name);
+                let has_shard = self
+                    .list_directories(&epoch_id_directory)
+                    .await?
+                    .iter()
+                    .any(|name| parse_location_component(name, "shard_id") == Some(shard_id));
+                if has_shard {
+                    epochs.push((epoch_id, epoch_height));
+                }
+            }
+        }
+        epochs.sort_by_key(|(_, epoch_height)| *epoch_height);
+        Ok(epochs)
+    }
+
+    /// Checks that the dump of the epoch is complete: the manifest, which the
+    /// state dump writes after all parts, is present and all parts it claims
+    /// are listed. Returns the manifest, or the reason why the dump can't be
+    /// used to restore the state.
+    pub async fn verify_dumped_epoch(
+        &self,
+        chain_id: &str,
+        shard_id: ShardId,
+        epoch_id: &EpochId,
+        epoch_height: EpochHeight,
+    ) -> Result<StatePartsManifest, anyhow::Error> {
+        let location =
+            external_storage_manifest_location(chain_id, epoch_id, epoch_height, shard_id, None);
+        let manifest = match self.get_file(shard_id, &location).await? {
+            Some(manifest) => manifest,
+            None => anyhow::bail!("No manifest, the dump may be in progress"),
+        };
+        let manifest: StatePartsManifest = serde_json::from_slice(&manifest)?;
+        let directory_path = location_prefix(chain_id, epoch_height, epoch_id, shard_id, None);
+        let num_listed_parts = self
+            .list_state_parts(shard_id, &directory_path)
+            .await?
+            .iter()
+            .filter(|part| {
+                get_num_parts_from_filename(&DefaultPartNaming, &part.file_name)
+                    == Some(manifest.num_parts)
+            })
+            .count() as u64;
+        if num_listed_parts != manifest.num_parts {
+            anyhow::bail!(
+                "Only {} of {} parts claimed by the manifest are listed",
+                num_listed_parts,
+                manifest.num_parts
+            );
+        }
+        Ok(manifest)
+    }
+
+    /// Lists names of subdirectories of the given directory, for example
+    /// `epoch_height=123` in `chain_id=mainnet`.
+    pub async fn list_directories(
+        &self,
+        directory_path: &str,
+    ) -> Result<Vec<String>, anyhow::Error> {
+        match self {
+            ExternalConnection::S3 { bucket } => {
+                let prefix = format!("{}/", directory_path);
+                tracing::debug!(target: "state_sync_dump", ?directory_path, "List directories in s3");
+                // S3 has no directories, but grouping keys by the delimiter
+                // returns the same names as common prefixes.
+                list_all_pages(|continuation_token| {
+                    let prefix = prefix.clone();
+                    async move {
+                        let (page, status_code) = bucket
+                            .list_page(
+                                prefix,
+                                Some("/".to_string()),
+                                continuation_token,
+                                None,
+                                None,
+                            )
+                            .await?;
+                        if status_code != 200 {
+                            anyhow::bail!(
+                                "Listing directories failed with status code {status_code}"
+                            );
+                        }
+                        let directories = page
+                            .common_prefixes
+                            .unwrap_or_default()
+                            .into_iter()
+                            .map(|common_prefix| {
+                                Self::extract_file_name_from_full_path(common_prefix.prefix)
+                            })
+                            .collect();
+                        let next_continuation_token =
+                            if page.is_truncated { page.next_continuation_token } else { None };
+                        Ok((directories, next_continuation_token))
+                    }
+                })
+                .await
+            }
+            ExternalConnection::Pipe { .. } => Ok(vec![]),
+            ExternalConnection::Filesystem { root_dir, .. } => {
+                let path = root_dir.join(directory_path);
+                tracing::debug!(target: "state_sync_dump", ?path, "List directories in local directory");
+                if !path.exists() {
+                    return Ok(vec![]);
+                }
+                let mut directories = vec![];
+                for entry in std::fs::read_dir(&path)? {
+                    let entry = entry?;
+                    if entry.metadata()?.is_dir() {
+                        directories.push(Self::extract_file_name_from_path_buf(entry.path()));
+                    }
+                }
+                Ok(directories)
+            }
+            ExternalConnection::Azure { container } => {
+                let prefix = format!("{}/", directory_path);
+                tracing::debug!(target: "state_sync_dump", ?directory_path, "List directories in Azure");
+                // Similarly to S3, directories are common prefixes of blob names.
+                list_all_pages(|marker| {
+                    let prefix = prefix.clone();
+                    async move {
+                        let page = container
+                            .list_blobs_page(&prefix, Some("/"), marker.as_deref(), None)
+                            .await?;
+                        let directories = page
+                            .prefixes
+                            .into_iter()
+                            .map(Self::extract_file_name_from_full_path)
+                            .collect();
+                        Ok::<_, anyhow::Error>((directories, page.next_marker))
+                    }
+                })
+                .await
+            }
+        }
+    }
+
+    /// Deletes a file. Deleting a missing file is not an error.
+    pub async fn delete_file(
+        &self,
+        shard_id: ShardId,
+        location: &str,
+    ) -> Result<(), anyhow::Error> {
+        match self {
+            ExternalConnection::S3 { bucket } => {
+                let response = bucket.delete_object(location).await?;
+                // S3 responds with 204 No Content.
+                if !(200..300).contains(&response.status_code()) {
+                    anyhow::bail!("Bad response status code: {}", response.status_code());
                 }
-                Ok(file_names)
+                tracing::debug!(target: "state_sync_dump", shard_id, ?location, "Deleted a file from S3");
+                Ok(())
+            }
+            ExternalConnection::Filesystem { root_dir, .. } => {
+                let path = root_dir.join(location);
+                match std::fs::remove_file(&path) {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                        return Err(err.into())
+                    }
+                    _ => {}
+                }
+                tracing::debug!(target: "state_sync_dump", shard_id, ?location, "Deleted a file");
+                Ok(())
+            }
+            ExternalConnection::Azure { container } => {
+                container.delete_blob(location).await?;
+                tracing::debug!(target: "state_sync_dump", shard_id, ?location, "Deleted a blob from Azure");
+                Ok(())
+            }
+            // Nothing is stored.
+            ExternalConnection::Pipe { .. } => Ok(()),
+        }
+    }
+
+    /// Deletes the directory of the filesystem if it's empty. Object storages
+    /// have no directories, a prefix disappears with its last object.
+    pub async fn delete_directory_if_empty(
+        &self,
+        shard_id: ShardId,
+        directory_path: &str,
+    ) -> Result<(), anyhow::Error> {
+        match self {
+            ExternalConnection::Filesystem { root_dir, .. } => {
+                let path = root_dir.join(directory_path);
+                let is_empty = match std::fs::read_dir(&path) {
+                    Ok(mut entries) => entries.next().is_none(),
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+                    Err(err) => return Err(err.into()),
+                };
+                if is_empty {
+                    std::fs::remove_dir(&path)?;
+                    tracing::debug!(target: "state_sync_dump", shard_id, ?directory_path, "Deleted an empty directory");
+                }
+                Ok(())
+            }
+            ExternalConnection::S3 { .. }
+            | ExternalConnection::Azure { .. }
+            | ExternalConnection::Pipe { .. } => Ok(()),
+        }
+    }
+
+    /// Performs a cheap request that fails if the external storage is not
+    /// reachable or doesn't allow writes.
+    /// Meant to be called once before dumping any state parts, to detect
+    /// misconfigured locations and credentials early.
+    pub async fn check_access(&self) -> Result<(), anyhow::Error> {
+        match self {
+            ExternalConnection::S3 { bucket } => {
+                // Listing at most one object requires both the bucket to exist
+                // and the credentials to be valid.
+                let (_, status_code) = bucket
+                    .list_page("".to_string(), Some("/".to_string()), None, None, Some(1))
+                    .await?;
+                tracing::debug!(target: "state_sync_dump", status_code, "Checked access to S3");
+                if status_code == 200 {
+                    Ok(())
+                } else {
+                    Err(anyhow::anyhow!("Bad response status code: {}", status_code))
+                }
+            }
+            ExternalConnection::Filesystem { root_dir, .. } => {
+                std::fs::create_dir_all(root_dir)?;
+                let path = root_dir.join(ACCESS_PROBE_FILENAME);
+                std::fs::write(&path, b"")?;
+                std::fs::remove_file(&path)?;
+                tracing::debug!(target: "state_sync_dump", ?root_dir, "Checked access to a local directory");
+                Ok(())
+            }
+            ExternalConnection::Azure { container } => {
+                // Listing at most one blob requires both the container to
+                // exist and the credentials to be valid.
+                container.list_blobs_page("", Some("/"), None, Some(1)).await?;
+                tracing::debug!(target: "state_sync_dump", "Checked access to Azure");
+                Ok(())
+            }
+            ExternalConnection::Pipe { path, .. } => {
+                // Opening a named pipe for writing blocks until it gets opened
+                // for reading, so it's only checked to exist.
+                std::fs::metadata(path)?;
+                tracing::debug!(target: "state_sync_dump", ?path, "Checked access to a pipe");
+                Ok(())
             }
         }
     }
 }
 
+/// Writes a frame of `ExternalConnection::Pipe`, opening the file if needed.
+/// Both may block, so they run on a blocking thread.
+async fn write_to_pipe(
+    path: &Path,
+    file: &Arc<Mutex<PipeFile>>,
+    location: &str,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    let mut frame = Vec::with_capacity(4 + location.len() + 8 + data.len());
+    frame.extend_from_slice(&(location.len() as u32).to_le_bytes());
+    frame.extend_from_slice(location.as_bytes());
+    frame.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    frame.extend_from_slice(data);
+
+    let path = path.to_path_buf();
+    let file = file.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut file = file.lock().unwrap();
+        if let PipeFile::Closed = &*file {
+            *file = PipeFile::Open(open_pipe(&path)?);
+        }
+        let open_file = match &mut *file {
+            PipeFile::Open(open_file) => open_file,
+            PipeFile::Broken(err) => {
+                anyhow::bail!("Pipe {} is broken by a failed write: {}", path.display(), err)
+            }
+            PipeFile::Closed => unreachable!("the file is opened above"),
+        };
+        if let Err(err) = write_all_to_pipe(open_file, &frame) {
+            tracing::error!(target: "state_sync_dump", ?path, ?err, "Failed to write to a pipe, no more files can be written to it");
+            *file = PipeFile::Broken(err.to_string());
+            return Err(err.into());
+        }
+        Ok(())
+    })
+    .await?
+}
+
+/// Opens the file of `ExternalConnection::Pipe` for appending. Opening a named
+/// pipe blocks until a reader opens it, so it's opened without blocking, and
+/// opening is retried for up to `PIPE_OPEN_TIMEOUT`.
+#[cfg(unix)]
+fn open_pipe(path: &Path) -> anyhow::Result<std::fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let start = std::time::Instant::now();
+    loop {
+        match std::fs::OpenOptions::new().append(true).custom_flags(libc::O_NONBLOCK).open(path) {
+            Ok(file) => return Ok(file),
+            // A named pipe without a reader.
+            Err(err) if err.raw_os_error() == Some(libc::ENXIO) => {
+                if start.elapsed() >= PIPE_OPEN_TIMEOUT {
+                    anyhow::bail!(
+                        "Pipe {} wasn't opened for reading within {:?}",
+                        path.display(),
+                        PIPE_OPEN_TIMEOUT
+                    );
+                }
+                std::thread::sleep(PIPE_RETRY_DELAY);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn open_pipe(path: &Path) -> anyhow::Result<std::fs::File> {
+    Ok(std::fs::OpenOptions::new().append(true).open(path)?)
+}
+
+/// Same as `write_all()`, but waits for a full pipe opened without blocking
+/// to be drained by the reader.
+fn write_all_to_pipe(file: &mut std::fs::File, mut data: &[u8]) -> std::io::Result<()> {
+    while !data.is_empty() {
+        match file.write(data) {
+            Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+            Ok(written) => data = &data[written..],
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(PIPE_RETRY_DELAY)
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Name of a file that gets created and immediately deleted to check that the
+/// external storage is writable.
+const ACCESS_PROBE_FILENAME: &str = ".access_probe";
+
 /// Helper to track state sync.
 pub struct StateSync {
     /// How to retrieve the state data.
@@ -257,7 +1398,27 @@ impl StateSync {
         timeout: TimeDuration,
         chain_id: &str,
         sync_config: &SyncConfig,
-    ) -> Self {
+    ) -> Result<Self, near_chain::Error> {
+        Self::new_with_part_transform(
+            network_adapter,
+            timeout,
+            chain_id,
+            sync_config,
+            Arc::new(IdentityPartTransform),
+        )
+    }
+
+    /// Same as `new()`, but decodes the parts downloaded from external
+    /// storage with `part_transform`, which needs to match the transform of
+    /// the dump.
+    /// Fails if the external storage can't be accessed.
+    pub fn new_with_part_transform(
+        network_adapter: PeerManagerAdapter,
+        timeout: TimeDuration,
+        chain_id: &str,
+        sync_config: &SyncConfig,
+        part_transform: Arc<dyn PartTransform>,
+    ) -> Result<Self, near_chain::Error> {
         let inner = match sync_config {
             SyncConfig::Peers => StateSyncInner::Peers {
                 last_part_id_requested: Default::default(),
@@ -266,35 +1427,54 @@ impl StateSync {
             SyncConfig::ExternalStorage(ExternalStorageConfig {
                 location,
                 num_concurrent_requests,
+                date_partitioned,
+                path_chain_id,
             }) => {
                 let external = match location {
                     ExternalStorageLocation::S3 { bucket, region } => {
-                        let bucket = create_bucket(&bucket, &region, timeout);
-                        if let Err(err) = bucket {
-                            panic!("Failed to create an S3 bucket: {}", err);
-                        }
-                        ExternalConnection::S3 { bucket: Arc::new(bucket.unwrap()) }
+                        let bucket = create_bucket(&bucket, &region, timeout)?;
+                        ExternalConnection::S3 { bucket: Arc::new(bucket) }
                     }
                     ExternalStorageLocation::Filesystem { root_dir } => {
-                        ExternalConnection::Filesystem { root_dir: root_dir.clone() }
+                        ExternalConnection::Filesystem { root_dir: root_dir.clone(), fsync: false }
+                    }
+                    ExternalStorageLocation::Azure { container, account } => {
+                        let container = AzureContainer::anonymous(account, container, timeout)
+                            .map_err(|err| {
+                                near_chain::Error::Other(format!(
+                                    "Failed to create an Azure container: {}",
+                                    err
+                                ))
+                            })?;
+                        ExternalConnection::Azure { container: Arc::new(container) }
+                    }
+                    ExternalStorageLocation::Pipe { path } => {
+                        // Rejected by the config validation.
+                        return Err(near_chain::Error::Other(format!(
+                            "State parts can't be read from pipe {}",
+                            path.display()
+                        )));
                     }
                 };
                 StateSyncInner::PartsFromExternal {
-                    chain_id: chain_id.to_string(),
+                    chain_id: path_chain_id.clone().unwrap_or_else(|| chain_id.to_string()),
                     requests_remaining: Arc::new(AtomicI32::new(*num_concurrent_requests as i32)),
                     external,
+                    date_partitioned: *date_partitioned,
+                    dumped_epochs: Arc::new(Mutex::new(DumpedEpochsLookup::NotStarted)),
+                    part_transform,
                 }
             }
         };
         let timeout = Duration::from_std(timeout).unwrap();
-        StateSync {
+        Ok(StateSync {
             inner,
             network_adapter,
             last_time_block_requested: None,
             timeout,
             state_parts_apply_results: HashMap::new(),
             split_state_roots: HashMap::new(),
-        }
+        })
     }
 
     fn sync_block_status(
@@ -514,17 +1694,103 @@ impl StateSync {
         let mut hash = *header.hash();
         let mut prev_hash = *header.prev_hash();
         loop {
-            if prev_hash == CryptoHash::default() {
-                return Ok(hash);
+            if prev_hash == CryptoHash::default() {
+                return Ok(hash);
+            }
+            header = chain.get_block_header(&prev_hash)?;
+            if &epoch_id != header.epoch_id() {
+                return Ok(hash);
+            }
+            epoch_id = header.epoch_id().clone();
+            hash = *header.hash();
+            prev_hash = *header.prev_hash();
+        }
+    }
+
+    /// When the state parts come from an external storage, chooses the newest
+    /// epoch, not newer than the epoch of `sync_hash`, that has a complete
+    /// dump of every shard, and returns the sync hash of that epoch.
+    /// Syncing to an older epoch means more blocks to catch up, but the parts
+    /// of an incomplete dump may never become available.
+    /// Returns `None` while the dumped epochs are being looked up, and
+    /// `sync_hash` if no epoch has a complete dump.
+    pub fn select_sync_hash(
+        &mut self,
+        chain: &Chain,
+        sync_hash: CryptoHash,
+    ) -> Result<Option<CryptoHash>, near_chain::Error> {
+        let (chain_id, external, dumped_epochs) = match &self.inner {
+            StateSyncInner::Peers { .. } => return Ok(Some(sync_hash)),
+            // Dumps partitioned by date can't be listed by epoch.
+            StateSyncInner::PartsFromExternal { date_partitioned: true, .. } => {
+                return Ok(Some(sync_hash))
+            }
+            StateSyncInner::PartsFromExternal { chain_id, external, dumped_epochs, .. } => {
+                (chain_id, external, dumped_epochs)
+            }
+        };
+
+        let mut lookup = dumped_epochs.lock().unwrap();
+        let dumped = match std::mem::replace(&mut *lookup, DumpedEpochsLookup::NotStarted) {
+            DumpedEpochsLookup::NotStarted => {
+                *lookup = DumpedEpochsLookup::InProgress;
+                let epoch_id = chain.get_block_header(&sync_hash)?.epoch_id().clone();
+                let shard_ids: Vec<ShardId> =
+                    (0..chain.epoch_manager.num_shards(&epoch_id)?).collect();
+                let chain_id = chain_id.clone();
+                let external = external.clone();
+                let dumped_epochs = dumped_epochs.clone();
+                near_performance_metrics::actix::spawn("StateSync", async move {
+                    let result = lookup_dumped_epochs(&chain_id, &shard_ids, &external)
+                        .await
+                        .map_err(|err| err.to_string());
+                    *dumped_epochs.lock().unwrap() = DumpedEpochsLookup::Done(result);
+                });
+                return Ok(None);
+            }
+            DumpedEpochsLookup::InProgress => {
+                *lookup = DumpedEpochsLookup::InProgress;
+                return Ok(None);
+            }
+            DumpedEpochsLookup::Done(Err(err)) => {
+                tracing::warn!(target: "sync", %sync_hash, ?err, "Failed to look up the dumped epochs, syncing to the latest epoch");
+                return Ok(Some(sync_hash));
+            }
+            DumpedEpochsLookup::Done(Ok(dumped)) => dumped,
+        };
+        drop(lookup);
+
+        let oldest_dumped_epoch_height =
+            dumped.values().flatten().map(|dumped_epoch| dumped_epoch.epoch_height).min();
+        let mut candidate = sync_hash;
+        loop {
+            let header = chain.get_block_header(&candidate)?;
+            let epoch_id = header.epoch_id().clone();
+            let epoch_height = chain.epoch_manager.get_epoch_info(&epoch_id)?.epoch_height();
+            if oldest_dumped_epoch_height.map_or(true, |oldest| epoch_height < oldest) {
+                break;
             }
-            header = chain.get_block_header(&prev_hash)?;
-            if &epoch_id != header.epoch_id() {
-                return Ok(hash);
+            let shard_ids: Vec<ShardId> = (0..chain.epoch_manager.num_shards(&epoch_id)?).collect();
+            match dump_rejection(&dumped, &epoch_id, &shard_ids) {
+                None => {
+                    tracing::info!(target: "sync", epoch_height, ?epoch_id, sync_hash = %candidate, "Chose the newest epoch with a complete dump");
+                    return Ok(Some(candidate));
+                }
+                Some(reason) => {
+                    tracing::info!(target: "sync", epoch_height, ?epoch_id, %reason, "Rejected the epoch, its dump is incomplete");
+                }
+            }
+            let prev_hash = *header.prev_hash();
+            if &prev_hash == chain.genesis().hash() {
+                break;
+            }
+            candidate = Self::get_epoch_start_sync_hash(chain, &prev_hash)?;
+            if &candidate == chain.genesis().hash() {
+                break;
             }
-            epoch_id = header.epoch_id().clone();
-            hash = *header.hash();
-            prev_hash = *header.prev_hash();
         }
+        tracing::warn!(target: "sync", %sync_hash, "No epoch has a complete dump, syncing to the latest epoch");
+        Ok(Some(sync_hash))
     }
 
     // Function called when our node receives the network response with a part.
@@ -758,8 +2024,20 @@ impl StateSync {
                     );
                 }
             }
-            StateSyncInner::PartsFromExternal { chain_id, requests_remaining, external } => {
+            StateSyncInner::PartsFromExternal {
+                chain_id,
+                requests_remaining,
+                external,
+                date_partitioned,
+                part_transform,
+                ..
+            } => {
                 let sync_block_header = chain.get_block_header(&sync_hash).unwrap();
+                let date_partition = if *date_partitioned {
+                    Some(date_partition(sync_block_header.raw_timestamp()))
+                } else {
+                    None
+                };
                 let epoch_id = sync_block_header.epoch_id();
                 let epoch_info = chain.epoch_manager.get_epoch_info(epoch_id).unwrap();
                 let epoch_height = epoch_info.epoch_height();
@@ -777,8 +2055,10 @@ impl StateSync {
                         epoch_height,
                         state_num_parts,
                         &chain_id.clone(),
+                        date_partition.as_deref(),
                         requests_remaining.clone(),
                         external.clone(),
+                        part_transform.clone(),
                     );
                 }
             }
@@ -1215,6 +2495,61 @@ fn parts_to_fetch(
         .map(|(part_id, download)| (part_id as u64, download))
 }
 
+/// Lists the dumped epochs of the given shards and verifies the newest of them.
+async fn lookup_dumped_epochs(
+    chain_id: &str,
+    shard_ids: &[ShardId],
+    external: &ExternalConnection,
+) -> Result<HashMap<ShardId, Vec<DumpedEpoch>>, anyhow::Error> {
+    let mut dumped = HashMap::new();
+    for &shard_id in shard_ids {
+        let mut dumped_epochs = vec![];
+        for (epoch_id, epoch_height) in external
+            .list_dumped_epochs(chain_id, shard_id)
+            .await?
+            .into_iter()
+            .rev()
+            .take(MAX_DUMPED_EPOCHS_TO_VERIFY)
+        {
+            let verified = external
+                .verify_dumped_epoch(chain_id, shard_id, &epoch_id, epoch_height)
+                .await
+                .map(|_| ())
+                .map_err(|err| err.to_string());
+            dumped_epochs.push(DumpedEpoch { epoch_id, epoch_height, verified });
+        }
+        dumped.insert(shard_id, dumped_epochs);
+    }
+    Ok(dumped)
+}
+
+/// Returns why the dump of the epoch can't be used to sync the given shards,
+/// or `None` if the dump of every shard is complete.
+fn dump_rejection(
+    dumped: &HashMap<ShardId, Vec<DumpedEpoch>>,
+    epoch_id: &EpochId,
+    shard_ids: &[ShardId],
+) -> Option<String> {
+    let reasons: Vec<String> = shard_ids
+        .iter()
+        .filter_map(|shard_id| {
+            let dumped_epoch = dumped
+                .get(shard_id)
+                .and_then(|epochs| epochs.iter().find(|epoch| &epoch.epoch_id == epoch_id));
+            match dumped_epoch.map(|dumped_epoch| &dumped_epoch.verified) {
+                None => Some(format!("shard {}: no recent dump", shard_id)),
+                Some(Ok(())) => None,
+                Some(Err(err)) => Some(format!("shard {}: {}", shard_id, err)),
+            }
+        })
+        .collect();
+    if reasons.is_empty() {
+        None
+    } else {
+        Some(reasons.join("; "))
+    }
+}
+
 /// Starts an asynchronous network request to external storage to fetch the given state part.
 fn request_part_from_external_storage(
     part_id: u64,
@@ -1224,8 +2559,10 @@ fn request_part_from_external_storage(
     epoch_height: EpochHeight,
     num_parts: u64,
     chain_id: &str,
+    date_partition: Option<&str>,
     requests_remaining: Arc<AtomicI32>,
     external: ExternalConnection,
+    part_transform: Arc<dyn PartTransform>,
 ) {
     if !allow_request(&requests_remaining) {
         return;
@@ -1238,12 +2575,23 @@ fn request_part_from_external_storage(
     download.state_requests_count += 1;
     download.last_target = None;
 
-    let location =
-        external_storage_location(chain_id, epoch_id, epoch_height, shard_id, part_id, num_parts);
+    let location = external_storage_location(
+        &DefaultPartNaming,
+        chain_id,
+        epoch_id,
+        epoch_height,
+        shard_id,
+        part_id,
+        num_parts,
+        date_partition,
+    );
     let download_response = download.response.clone();
     near_performance_metrics::actix::spawn("StateSync", {
         async move {
-            let result = external.get_part(shard_id, &location).await;
+            let result = external
+                .get_part(shard_id, &location)
+                .await
+                .and_then(|part| part_transform.decode(&part));
             finished_request(&requests_remaining);
             let mut lock = download_response.lock().unwrap();
             *lock = Some(result.map_err(|err| err.to_string()));
@@ -1501,19 +2849,188 @@ impl<T: Clone> Iterator for SamplerLimited<T> {
     }
 }
 
+/// Counts bytes passing through an `AsyncRead`.
+struct CountingReader<'a, R> {
+    inner: &'a mut R,
+    num_bytes: u64,
+}
+
+impl<'a, R: tokio::io::AsyncRead + Unpin> tokio::io::AsyncRead for CountingReader<'a, R> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let filled_before = buf.filled().len();
+        let result = std::pin::Pin::new(&mut *self.inner).poll_read(cx, buf);
+        self.num_bytes += (buf.filled().len() - filled_before) as u64;
+        result
+    }
+}
+
+/// Size of the chunks in which files are read and written when the progress
+/// of the transfer is reported.
+const TRANSFER_PROGRESS_CHUNK_SIZE: usize = 1 << 20;
+
+/// Reports the bytes passing through an `AsyncRead` to `progress`, together
+/// with the expected total.
+struct ProgressReader<'a, 'p, R> {
+    inner: &'a mut R,
+    num_bytes: u64,
+    total: u64,
+    progress: &'p mut (dyn FnMut(u64, u64) + Send),
+}
+
+impl<'a, 'p, R: tokio::io::AsyncRead + Unpin> tokio::io::AsyncRead for ProgressReader<'a, 'p, R> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let filled_before = buf.filled().len();
+        let result = std::pin::Pin::new(&mut *self.inner).poll_read(cx, buf);
+        let num_read = (buf.filled().len() - filled_before) as u64;
+        if num_read > 0 {
+            self.num_bytes += num_read;
+            let (num_bytes, total) = (self.num_bytes, self.total);
+            (self.progress)(num_bytes, total);
+        }
+        result
+    }
+}
+
+/// Collects the bytes written to it, and reports their number to `progress`,
+/// together with the expected total.
+struct ProgressWriter<'p> {
+    data: Vec<u8>,
+    total: u64,
+    progress: &'p mut (dyn FnMut(u64, u64) + Send),
+}
+
+impl<'p> tokio::io::AsyncWrite for ProgressWriter<'p> {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.data.extend_from_slice(buf);
+        let (num_bytes, total) = (self.data.len() as u64, self.total);
+        (self.progress)(num_bytes, total);
+        std::task::Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+/// Syncs the contents of a written file to disk, and then its parent directory,
+/// so that the directory entry of a new file doesn't survive a power loss
+/// without the data it points to.
+fn sync_file_and_parent_dir(
+    file: &std::fs::File,
+    path: &std::path::Path,
+    shard_id: ShardId,
+) -> std::io::Result<()> {
+    let _timer = metrics::STATE_SYNC_DUMP_FSYNC_ELAPSED
+        .with_label_values(&[&shard_id.to_string()])
+        .start_timer();
+    file.sync_all()?;
+    if let Some(parent_dir) = path.parent() {
+        std::fs::File::open(parent_dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Collects the results of a paginated listing.
+/// `list_page` is called with the continuation token returned by the previous
+/// call, starting with `None`, until it doesn't return a continuation token.
+async fn list_all_pages<T, E, F, Fut>(mut list_page: F) -> Result<Vec<T>, E>
+where
+    F: FnMut(Option<String>) -> Fut,
+    Fut: std::future::Future<Output = Result<(Vec<T>, Option<String>), E>>,
+{
+    let mut result = vec![];
+    let mut continuation_token = None;
+    loop {
+        let (mut page, next_continuation_token) = list_page(continuation_token).await?;
+        result.append(&mut page);
+        match next_continuation_token {
+            Some(token) => continuation_token = Some(token),
+            None => return Ok(result),
+        }
+    }
+}
+
+/// Creates a connection to the external storage that state parts are dumped
+/// to. Unlike the connection used to sync state, it can write.
+pub fn create_dump_external_connection(
+    location: &ExternalStorageLocation,
+    fsync: bool,
+) -> anyhow::Result<ExternalConnection> {
+    Ok(match location {
+        ExternalStorageLocation::S3 { bucket, region } => {
+            // Credentials to establish a connection are taken from environment variables:
+            // * `AWS_ACCESS_KEY_ID`
+            // * `AWS_SECRET_ACCESS_KEY`
+            let creds = match s3::creds::Credentials::default() {
+                Ok(creds) => creds,
+                Err(err) => {
+                    tracing::error!(target: "state_sync_dump", "Failed to create a connection to S3. Did you provide environment variables AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY?");
+                    return Err(err.into());
+                }
+            };
+            let bucket = s3::Bucket::new(bucket, region.parse::<s3::Region>()?, creds)?;
+            ExternalConnection::S3 { bucket: Arc::new(bucket) }
+        }
+        ExternalStorageLocation::Filesystem { root_dir } => {
+            ExternalConnection::Filesystem { root_dir: root_dir.clone(), fsync }
+        }
+        ExternalStorageLocation::Azure { container, account } => {
+            // Credentials to establish a connection are taken from environment variables:
+            // * `AZURE_STORAGE_ACCESS_KEY`
+            // * `AZURE_STORAGE_CONNECTION_STRING`, if the access key is not set.
+            let container = match AzureContainer::from_env(account, container) {
+                Ok(container) => container,
+                Err(err) => {
+                    tracing::error!(target: "state_sync_dump", "Failed to create a connection to Azure Blob Storage. Did you provide environment variable AZURE_STORAGE_ACCESS_KEY with the access key of the storage account, or AZURE_STORAGE_CONNECTION_STRING?");
+                    return Err(err.into());
+                }
+            };
+            ExternalConnection::Azure { container: Arc::new(container) }
+        }
+        ExternalStorageLocation::Pipe { path } => ExternalConnection::Pipe {
+            path: path.clone(),
+            file: Arc::new(Mutex::new(PipeFile::Closed)),
+        },
+    })
+}
+
 /// Construct a location on the external storage.
 pub fn external_storage_location(
+    part_naming: &dyn PartNaming,
     chain_id: &str,
     epoch_id: &EpochId,
     epoch_height: u64,
     shard_id: u64,
     part_id: u64,
     num_parts: u64,
+    date_partition: Option<&str>,
 ) -> String {
     format!(
         "{}/{}",
-        location_prefix(chain_id, epoch_height, epoch_id, shard_id),
-        part_filename(part_id, num_parts)
+        location_prefix(chain_id, epoch_height, epoch_id, shard_id, date_partition),
+        part_naming.format(part_id, num_parts)
     )
 }
 
@@ -1522,55 +3039,201 @@ pub fn external_storage_location_directory(
     epoch_id: &EpochId,
     epoch_height: u64,
     shard_id: u64,
+    date_partition: Option<&str>,
 ) -> String {
-    location_prefix(chain_id, epoch_height, epoch_id, shard_id)
+    location_prefix(chain_id, epoch_height, epoch_id, shard_id, date_partition)
 }
 
-pub fn location_prefix(
+pub fn external_storage_lease_location(
+    part_naming: &dyn PartNaming,
+    chain_id: &str,
+    epoch_id: &EpochId,
+    epoch_height: u64,
+    shard_id: u64,
+    part_id: u64,
+    num_parts: u64,
+    date_partition: Option<&str>,
+) -> String {
+    format!(
+        "{}/{}",
+        location_prefix(chain_id, epoch_height, epoch_id, shard_id, date_partition),
+        part_lease_filename(part_naming, part_id, num_parts)
+    )
+}
+
+/// Location of the archive of all state parts of a shard.
+/// The archive is stored in a subdirectory to keep it out of listings of
+/// individual parts.
+pub fn external_storage_archive_location(
     chain_id: &str,
+    epoch_id: &EpochId,
     epoch_height: u64,
+    shard_id: u64,
+    num_parts: u64,
+    date_partition: Option<&str>,
+) -> String {
+    format!(
+        "{}/archive/state_parts_archive_of_{:06}",
+        location_prefix(chain_id, epoch_height, epoch_id, shard_id, date_partition),
+        num_parts
+    )
+}
+
+/// The manifest is stored in a subdirectory to keep it out of listings of
+/// individual parts.
+pub fn external_storage_manifest_location(
+    chain_id: &str,
     epoch_id: &EpochId,
+    epoch_height: u64,
     shard_id: u64,
+    date_partition: Option<&str>,
 ) -> String {
     format!(
+        "{}/manifest/state_parts_manifest",
+        location_prefix(chain_id, epoch_height, epoch_id, shard_id, date_partition)
+    )
+}
+
+pub fn location_prefix(
+    chain_id: &str,
+    epoch_height: u64,
+    epoch_id: &EpochId,
+    shard_id: u64,
+    date_partition: Option<&str>,
+) -> String {
+    let prefix = format!(
         "chain_id={}/epoch_height={}/epoch_id={}/shard_id={}",
         chain_id, epoch_height, epoch_id.0, shard_id
-    )
+    );
+    match date_partition {
+        Some(date_partition) => format!("{}/{}", date_partition, prefix),
+        None => prefix,
+    }
+}
+
+/// Parses the value of a component of a location, such as a directory named
+/// `epoch_height=123`. Returns `None` if the name doesn't start with `key=`.
+pub fn parse_location_component<T: std::str::FromStr>(name: &str, key: &str) -> Option<T> {
+    name.strip_prefix(key)?.strip_prefix('=')?.parse().ok()
+}
+
+/// Date of a block in UTC as `YYYY/MM/DD`, which prefixes the locations of
+/// date-partitioned dumps.
+pub fn date_partition(block_timestamp_nanosec: u64) -> String {
+    Utc.timestamp_nanos(block_timestamp_nanosec as i64).format("%Y/%m/%d").to_string()
+}
+
+/// Prefix of the objects stored instead of parts identical to a part of
+/// another epoch. A state part can't start with these bytes, because they
+/// would encode an unrealistic number of trie nodes.
+const STATE_PART_POINTER_PREFIX: &[u8] = b"NEAR_STATE_PART_POINTER:";
+
+/// Objects larger than this are not considered to be pointers.
+const MAX_STATE_PART_POINTER_LEN: u64 = 4096;
+
+/// Contents of an object that stands in for a part identical to the part
+/// stored at `target_location`. Nodes downloading the part follow the pointer.
+pub fn state_part_pointer(target_location: &str) -> Vec<u8> {
+    [STATE_PART_POINTER_PREFIX, target_location.as_bytes()].concat()
+}
+
+/// Returns the location of the part that `data` points to, if `data` is a
+/// pointer created by `state_part_pointer()`.
+pub fn parse_state_part_pointer(data: &[u8]) -> Option<&str> {
+    if data.len() as u64 > MAX_STATE_PART_POINTER_LEN {
+        return None;
+    }
+    std::str::from_utf8(data.strip_prefix(STATE_PART_POINTER_PREFIX)?).ok()
+}
+
+/// Naming scheme of state part files in external storage. Lets a dump be
+/// consumed by tools that expect a layout different from the one of nearcore.
+pub trait PartNaming: Send + Sync {
+    /// Name of the file of the given part.
+    fn format(&self, part_id: u64, num_parts: u64) -> String;
+    /// Returns the part id and the number of parts if `filename` is a name of
+    /// a part file. Must be the inverse of `format()`.
+    fn parse(&self, filename: &str) -> Option<(u64, u64)>;
+}
+
+/// Names part files as `state_part_000005_of_000015`.
+pub struct DefaultPartNaming;
+
+impl PartNaming for DefaultPartNaming {
+    fn format(&self, part_id: u64, num_parts: u64) -> String {
+        part_filename(part_id, num_parts)
+    }
+
+    fn parse(&self, filename: &str) -> Option<(u64, u64)> {
+        let captures = match_filename(filename)?;
+        let part_id = captures.get(1)?.as_str().parse::<u64>().ok()?;
+        let num_parts = captures.get(2)?.as_str().parse::<u64>().ok()?;
+        Some((part_id, num_parts))
+    }
+}
+
+/// Transformation of the bytes of the state parts stored in external storage,
+/// for example client-side encryption.
+/// Applies to full copies of parts and to archives of parts, but not to
+/// pointers, leases and manifests. Checksums and the Merkle root in the
+/// manifest are computed over the parts before `encode()`, so that they verify
+/// the decoded parts.
+/// Identical parts are found by comparing the encoded parts, so a transform
+/// that isn't deterministic disables `skip_existing` and incremental dumps.
+pub trait PartTransform: Send + Sync {
+    /// Transforms a part before it leaves the node.
+    fn encode(&self, part: &[u8]) -> Vec<u8>;
+    /// Restores a part from the bytes downloaded from external storage.
+    /// Must be the inverse of `encode()`.
+    fn decode(&self, data: &[u8]) -> anyhow::Result<Vec<u8>>;
 }
 
+/// Stores parts as they are.
+pub struct IdentityPartTransform;
+
+impl PartTransform for IdentityPartTransform {
+    fn encode(&self, part: &[u8]) -> Vec<u8> {
+        part.to_vec()
+    }
+
+    fn decode(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+}
+
+/// Name of a part file in the default naming scheme.
 pub fn part_filename(part_id: u64, num_parts: u64) -> String {
     format!("state_part_{:06}_of_{:06}", part_id, num_parts)
 }
 
+/// Name of a file that marks the part as being dumped by some node.
+pub fn part_lease_filename(part_naming: &dyn PartNaming, part_id: u64, num_parts: u64) -> String {
+    format!("{}{}", part_naming.format(part_id, num_parts), PART_LEASE_SUFFIX)
+}
+
+const PART_LEASE_SUFFIX: &str = ".inprogress";
+
+/// Returns the part id if `s` is a name of a lease file.
+pub fn get_part_id_from_lease_filename(part_naming: &dyn PartNaming, s: &str) -> Option<u64> {
+    get_part_id_from_filename(part_naming, s.strip_suffix(PART_LEASE_SUFFIX)?)
+}
+
+/// Matches part file names of the default naming scheme.
 pub fn match_filename(s: &str) -> Option<regex::Captures> {
-    let re = regex::Regex::new(r"^state_part_(\d{6})_of_(\d{6})$").unwrap();
+    let re = regex::Regex::new(r"^state_part_(\d{6,})_of_(\d{6,})$").unwrap();
     re.captures(s)
 }
 
-pub fn is_part_filename(s: &str) -> bool {
-    match_filename(s).is_some()
+pub fn is_part_filename(part_naming: &dyn PartNaming, s: &str) -> bool {
+    part_naming.parse(s).is_some()
 }
 
-pub fn get_num_parts_from_filename(s: &str) -> Option<u64> {
-    if let Some(captures) = match_filename(s) {
-        if let Some(num_parts) = captures.get(2) {
-            if let Ok(num_parts) = num_parts.as_str().parse::<u64>() {
-                return Some(num_parts);
-            }
-        }
-    }
-    None
+pub fn get_num_parts_from_filename(part_naming: &dyn PartNaming, s: &str) -> Option<u64> {
+    part_naming.parse(s).map(|(_, num_parts)| num_parts)
 }
 
-pub fn get_part_id_from_filename(s: &str) -> Option<u64> {
-    if let Some(captures) = match_filename(s) {
-        if let Some(part_id) = captures.get(1) {
-            if let Ok(part_id) = part_id.as_str().parse::<u64>() {
-                return Some(part_id);
-            }
-        }
-    }
-    None
+pub fn get_part_id_from_filename(part_naming: &dyn PartNaming, s: &str) -> Option<u64> {
+    part_naming.parse(s).map(|(part_id, _)| part_id)
 }
 
 #[cfg(test)]
@@ -1597,7 +3260,8 @@ mod test {
             TimeDuration::from_secs(1),
             "chain_id",
             &SyncConfig::Peers,
-        );
+        )
+        .unwrap();
         let mut new_shard_sync = HashMap::new();
 
         let (mut chain, kv, _, signer) = test_utils::setup();
@@ -1708,16 +3372,458 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_date_partition() {
+        let timestamp = 1_600_000_000 * 1_000_000_000;
+        assert_eq!(date_partition(timestamp), "2020/09/13");
+        let epoch_id = EpochId::default();
+        assert_eq!(
+            location_prefix("mainnet", 5, &epoch_id, 1, Some(date_partition(timestamp).as_str())),
+            format!("2020/09/13/{}", location_prefix("mainnet", 5, &epoch_id, 1, None))
+        );
+    }
+
     #[test]
     fn test_match_filename() {
+        let naming = &DefaultPartNaming;
         let filename = part_filename(5, 15);
-        assert!(is_part_filename(&filename));
-        assert!(!is_part_filename("123123"));
+        assert!(is_part_filename(naming, &filename));
+        assert!(!is_part_filename(naming, "123123"));
+
+        assert_eq!(get_num_parts_from_filename(naming, &filename), Some(15));
+        assert_eq!(get_num_parts_from_filename(naming, "123123"), None);
+
+        assert_eq!(get_part_id_from_filename(naming, &filename), Some(5));
+        assert_eq!(get_part_id_from_filename(naming, "123123"), None);
+
+        let lease_filename = part_lease_filename(naming, 5, 15);
+        assert!(!is_part_filename(naming, &lease_filename));
+        assert_eq!(get_part_id_from_lease_filename(naming, &lease_filename), Some(5));
+        assert_eq!(get_part_id_from_lease_filename(naming, &filename), None);
+    }
+
+    /// A scheme different from the default one, to check that nothing assumes
+    /// the default scheme.
+    struct FlatPartNaming;
+
+    impl PartNaming for FlatPartNaming {
+        fn format(&self, part_id: u64, num_parts: u64) -> String {
+            format!("{}-{}.part", part_id, num_parts)
+        }
+
+        fn parse(&self, filename: &str) -> Option<(u64, u64)> {
+            let (part_id, num_parts) = filename.strip_suffix(".part")?.split_once('-')?;
+            Some((part_id.parse().ok()?, num_parts.parse().ok()?))
+        }
+    }
+
+    #[test]
+    fn test_part_naming_round_trip() {
+        bolero::check!().with_type::<(u64, u64)>().for_each(|&(part_id, num_parts)| {
+            let naming: [&dyn PartNaming; 2] = [&DefaultPartNaming, &FlatPartNaming];
+            for naming in naming {
+                let filename = naming.format(part_id, num_parts);
+                assert_eq!(naming.parse(&filename), Some((part_id, num_parts)));
+                assert_eq!(get_part_id_from_filename(naming, &filename), Some(part_id));
+                let lease_filename = part_lease_filename(naming, part_id, num_parts);
+                assert!(!is_part_filename(naming, &lease_filename));
+                assert_eq!(get_part_id_from_lease_filename(naming, &lease_filename), Some(part_id));
+            }
+        });
+    }
+
+    #[test]
+    fn test_list_all_pages() {
+        let pages = [
+            (vec![part_filename(0, 3), part_filename(1, 3)], Some("token".to_string())),
+            (vec![part_filename(2, 3)], None),
+        ];
+        let mut requested_tokens = vec![];
+        let file_names = futures::executor::block_on(list_all_pages(|continuation_token| {
+            let page = pages[requested_tokens.len()].clone();
+            requested_tokens.push(continuation_token);
+            async move { Ok::<_, anyhow::Error>(page) }
+        }))
+        .unwrap();
+        assert_eq!(file_names, vec![part_filename(0, 3), part_filename(1, 3), part_filename(2, 3)]);
+        assert_eq!(requested_tokens, vec![None, Some("token".to_string())]);
+    }
+
+    #[test]
+    fn test_list_state_parts_error_kind() {
+        let error = |status_code| {
+            ListStatePartsError::from_status_code(status_code, anyhow::anyhow!("{status_code}"))
+        };
+        assert!(error(403).is_fatal());
+        assert!(error(404).is_fatal());
+        assert!(!error(429).is_fatal());
+        assert!(!error(500).is_fatal());
+        assert!(!error(503).is_fatal());
+        assert!(ListStatePartsError::from_io_error(std::io::ErrorKind::PermissionDenied.into())
+            .is_fatal());
+        assert!(!ListStatePartsError::from_io_error(std::io::ErrorKind::TimedOut.into()).is_fatal());
+    }
+
+    #[test]
+    fn test_filesystem_fsync() {
+        // A shard id that no other test uses, so that the metric counts only
+        // writes of this test.
+        let shard_id = 1_000_001;
+        let num_fsyncs = || {
+            metrics::STATE_SYNC_DUMP_FSYNC_ELAPSED
+                .with_label_values(&[&shard_id.to_string()])
+                .get_sample_count()
+        };
+        let root_dir = tempfile::Builder::new().prefix("fsync").tempdir().unwrap();
+        let location = "dir/part";
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+        let external = ExternalConnection::Filesystem {
+            root_dir: root_dir.path().to_path_buf(),
+            fsync: false,
+        };
+        runtime.block_on(external.put_state_part(&[1, 2, 3], shard_id, location)).unwrap();
+        assert_eq!(num_fsyncs(), 0);
+
+        let external =
+            ExternalConnection::Filesystem { root_dir: root_dir.path().to_path_buf(), fsync: true };
+        runtime.block_on(external.put_state_part(&[4, 5, 6], shard_id, location)).unwrap();
+        assert_eq!(num_fsyncs(), 1);
+        runtime
+            .block_on(external.put_state_part_streamed(&mut &[7, 8, 9][..], shard_id, location))
+            .unwrap();
+        assert_eq!(num_fsyncs(), 2);
+        assert_eq!(std::fs::read(root_dir.path().join(location)).unwrap(), vec![7, 8, 9]);
+    }
+
+    #[test]
+    fn test_list_dumped_epochs() {
+        let root_dir = tempfile::Builder::new().prefix("dumped_epochs").tempdir().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let external = ExternalConnection::Filesystem {
+            root_dir: root_dir.path().to_path_buf(),
+            fsync: false,
+        };
+        let epoch_ids: Vec<EpochId> =
+            (0..3u8).map(|i| EpochId(CryptoHash::hash_bytes(&[i]))).collect();
+        for (epoch_id, epoch_height, shard_id) in
+            [(&epoch_ids[0], 12, 0), (&epoch_ids[1], 3, 0), (&epoch_ids[2], 7, 1)]
+        {
+            let location = external_storage_location(
+                &DefaultPartNaming,
+                "test",
+                epoch_id,
+                epoch_height,
+                shard_id,
+                0,
+                1,
+                None,
+            );
+            runtime.block_on(external.put_state_part(&[1], shard_id, &location)).unwrap();
+        }
+        // Not a part of the directory structure.
+        std::fs::create_dir_all(root_dir.path().join("chain_id=test/tmp")).unwrap();
+
+        assert_eq!(
+            runtime.block_on(external.list_dumped_epochs("test", 0)).unwrap(),
+            vec![(epoch_ids[1].clone(), 3), (epoch_ids[0].clone(), 12)]
+        );
+        assert_eq!(
+            runtime.block_on(external.list_dumped_epochs("test", 1)).unwrap(),
+            vec![(epoch_ids[2].clone(), 7)]
+        );
+        assert_eq!(runtime.block_on(external.list_dumped_epochs("other", 0)).unwrap(), vec![]);
+    }
 
-        assert_eq!(get_num_parts_from_filename(&filename), Some(15));
-        assert_eq!(get_num_parts_from_filename("123123"), None);
+    #[test]
+    fn test_state_part_pointer() {
+        let root_dir = tempfile::Builder::new().prefix("state_part_pointer").tempdir().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let external = ExternalConnection::Filesystem {
+            root_dir: root_dir.path().to_path_buf(),
+            fsync: false,
+        };
+        let location = |epoch_height| {
+            external_storage_location(
+                &DefaultPartNaming,
+                "test",
+                &EpochId::default(),
+                epoch_height,
+                0,
+                0,
+                1,
+                None,
+            )
+        };
+        let state_part = vec![1, 2, 3];
+        runtime.block_on(external.put_state_part(&state_part, 0, &location(1))).unwrap();
+        runtime
+            .block_on(external.put_state_part(&state_part_pointer(&location(1)), 0, &location(2)))
+            .unwrap();
+
+        assert_eq!(runtime.block_on(external.get_state_part_pointer(0, &location(1))), None);
+        assert_eq!(
+            runtime.block_on(external.get_state_part_pointer(0, &location(2))),
+            Some(location(1))
+        );
+        assert_eq!(
+            runtime.block_on(external.clone().get_part(0, &location(2))).unwrap(),
+            state_part
+        );
+        assert_eq!(parse_state_part_pointer(&state_part), None);
+    }
+
+    #[test]
+    fn test_get_state_part_range() {
+        let root_dir = tempfile::Builder::new().prefix("state_part_range").tempdir().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let external = ExternalConnection::Filesystem {
+            root_dir: root_dir.path().to_path_buf(),
+            fsync: false,
+        };
+        let location = |epoch_height| {
+            external_storage_location(
+                &DefaultPartNaming,
+                "test",
+                &EpochId::default(),
+                epoch_height,
+                0,
+                0,
+                1,
+                None,
+            )
+        };
+        let state_part: Vec<u8> = (0..100).collect();
+        runtime.block_on(external.put_state_part(&state_part, 0, &location(1))).unwrap();
+        runtime
+            .block_on(external.put_state_part(&state_part_pointer(&location(1)), 0, &location(2)))
+            .unwrap();
+
+        let get_range = |epoch_height, range| {
+            runtime.block_on(external.get_state_part_range(0, &location(epoch_height), range))
+        };
+        assert_eq!(get_range(1, 0..10).unwrap(), state_part[0..10]);
+        assert_eq!(get_range(1, 99..100).unwrap(), state_part[99..100]);
+        // The range is read from the part that the pointer points to.
+        assert_eq!(get_range(2, 20..30).unwrap(), state_part[20..30]);
+        assert!(get_range(1, 90..101).is_err());
+        assert!(get_range(1, 200..300).is_err());
+        assert!(get_range(1, 10..10).is_err());
+    }
+
+    #[test]
+    fn test_stream_parts() {
+        let root_dir = tempfile::Builder::new().prefix("stream_parts").tempdir().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let external = ExternalConnection::Filesystem {
+            root_dir: root_dir.path().to_path_buf(),
+            fsync: false,
+        };
+        let directory =
+            external_storage_location_directory("test", &EpochId::default(), 1, 0, None);
+        for part_id in [2, 0, 1] {
+            let location = format!("{}/{}", directory, part_filename(part_id, 3));
+            runtime.block_on(external.put_state_part(&[part_id as u8], 0, &location)).unwrap();
+        }
+        let lease_location =
+            format!("{}/{}", directory, part_lease_filename(&DefaultPartNaming, 1, 3));
+        runtime.block_on(external.put_state_part(&[], 0, &lease_location)).unwrap();
+
+        let parts: Vec<(u64, Vec<u8>)> = runtime
+            .block_on(futures::StreamExt::collect::<Vec<_>>(external.stream_parts(0, &directory)))
+            .into_iter()
+            .map(|part| part.unwrap())
+            .collect();
+        assert_eq!(parts, vec![(0, vec![0]), (1, vec![1]), (2, vec![2])]);
+
+        // A missing directory fails the stream.
+        let parts = runtime
+            .block_on(futures::StreamExt::collect::<Vec<_>>(external.stream_parts(0, "missing")));
+        assert_eq!(parts.len(), 1);
+        assert!(parts[0].is_err());
+    }
+
+    #[test]
+    fn test_verify_dumped_epoch() {
+        let root_dir = tempfile::Builder::new().prefix("verify_dumped_epoch").tempdir().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let external = ExternalConnection::Filesystem {
+            root_dir: root_dir.path().to_path_buf(),
+            fsync: false,
+        };
+        let epoch_id = EpochId(CryptoHash::hash_bytes(&[1]));
+        let put_part = |part_id| {
+            let location = external_storage_location(
+                &DefaultPartNaming,
+                "test",
+                &epoch_id,
+                5,
+                0,
+                part_id,
+                2,
+                None,
+            );
+            runtime.block_on(external.put_state_part(&[1, 2], 0, &location)).unwrap();
+        };
+        let verify = || runtime.block_on(external.verify_dumped_epoch("test", 0, &epoch_id, 5));
+
+        put_part(0);
+        // No manifest yet.
+        assert!(verify().is_err());
+
+        let manifest = StatePartsManifest { num_parts: 2, total_bytes: 4, merkle_root: None };
+        let location = external_storage_manifest_location("test", &epoch_id, 5, 0, None);
+        runtime
+            .block_on(external.put_state_part(
+                &serde_json::to_vec(&manifest).unwrap(),
+                0,
+                &location,
+            ))
+            .unwrap();
+        // The manifest claims a part that is missing.
+        assert!(verify().is_err());
+
+        put_part(1);
+        assert_eq!(verify().unwrap(), manifest);
+    }
+
+    #[test]
+    fn test_transfer_progress() {
+        let root_dir = tempfile::Builder::new().prefix("progress").tempdir().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let external = ExternalConnection::Filesystem {
+            root_dir: root_dir.path().to_path_buf(),
+            fsync: false,
+        };
+        let chunk_size = TRANSFER_PROGRESS_CHUNK_SIZE as u64;
+        let total = 2 * chunk_size + 10;
+        let state_part: Vec<u8> = (0..total).map(|i| i as u8).collect();
+        let expected = vec![(chunk_size, total), (2 * chunk_size, total), (total, total)];
+
+        let mut calls = vec![];
+        runtime
+            .block_on(external.put_state_part_with_progress(&state_part, 0, "part", &mut |n, t| {
+                calls.push((n, t))
+            }))
+            .unwrap();
+        assert_eq!(calls, expected);
+
+        let mut calls = vec![];
+        let data = runtime
+            .block_on(
+                external.clone().get_part_with_progress(0, "part", &mut |n, t| calls.push((n, t))),
+            )
+            .unwrap();
+        assert_eq!(data, state_part);
+        assert_eq!(calls, expected);
+
+        // Only the number of bytes consumed from the reader is known.
+        let mut calls = vec![];
+        let written = runtime
+            .block_on(external.put_state_part_streamed_with_progress(
+                &mut &state_part[..],
+                total,
+                0,
+                "streamed_part",
+                &mut |n, t| calls.push((n, t)),
+            ))
+            .unwrap();
+        assert_eq!(written, total);
+        assert_eq!(calls.last(), Some(&(total, total)));
+        assert!(calls.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(std::fs::read(root_dir.path().join("streamed_part")).unwrap(), state_part);
+    }
+
+    #[test]
+    fn test_pipe_framing() {
+        let dir = tempfile::Builder::new().prefix("pipe").tempdir().unwrap();
+        let path = dir.path().join("pipe");
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let external = ExternalConnection::Pipe {
+            path: path.clone(),
+            file: Arc::new(Mutex::new(PipeFile::Closed)),
+        };
+        // Nothing to write to yet.
+        assert!(runtime.block_on(external.check_access()).is_err());
+        assert!(runtime.block_on(external.put_state_part(&[1, 2, 3], 0, "dir/part_0")).is_err());
+
+        std::fs::write(&path, []).unwrap();
+        runtime.block_on(external.check_access()).unwrap();
+        runtime.block_on(external.put_state_part(&[1, 2, 3], 0, "dir/part_0")).unwrap();
+        runtime
+            .block_on(external.put_state_part_streamed(&mut &[4, 5][..], 0, "dir/part_1"))
+            .unwrap();
+
+        let mut frames = vec![];
+        let data = std::fs::read(&path).unwrap();
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let location_len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+            let location = std::str::from_utf8(&rest[4..4 + location_len]).unwrap().to_string();
+            rest = &rest[4 + location_len..];
+            let data_len = u64::from_le_bytes(rest[..8].try_into().unwrap()) as usize;
+            frames.push((location, rest[8..8 + data_len].to_vec()));
+            rest = &rest[8 + data_len..];
+        }
+        assert_eq!(
+            frames,
+            vec![("dir/part_0".to_string(), vec![1, 2, 3]), ("dir/part_1".to_string(), vec![4, 5])]
+        );
+        // The dumped parts can't be read back.
+        assert!(runtime.block_on(external.list_state_parts(0, "dir")).unwrap().is_empty());
+        assert!(runtime.block_on(external.get_file(0, "dir/part_0")).unwrap().is_none());
+        assert!(runtime.block_on(external.clone().get_part(0, "dir/part_0")).is_err());
+
+        // Nothing gets written after a failed write.
+        if let ExternalConnection::Pipe { file, .. } = &external {
+            *file.lock().unwrap() = PipeFile::Broken("test".to_string());
+        }
+        assert!(runtime.block_on(external.put_state_part(&[6], 0, "dir/part_2")).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+    }
 
-        assert_eq!(get_part_id_from_filename(&filename), Some(5));
-        assert_eq!(get_part_id_from_filename("123123"), None);
+    #[test]
+    fn test_dump_rejection() {
+        let epoch_ids: Vec<EpochId> =
+            (0..2u8).map(|i| EpochId(CryptoHash::hash_bytes(&[i]))).collect();
+        let dumped: HashMap<ShardId, Vec<DumpedEpoch>> = [
+            (
+                0,
+                vec![
+                    DumpedEpoch {
+                        epoch_id: epoch_ids[1].clone(),
+                        epoch_height: 2,
+                        verified: Err("No manifest".to_string()),
+                    },
+                    DumpedEpoch {
+                        epoch_id: epoch_ids[0].clone(),
+                        epoch_height: 1,
+                        verified: Ok(()),
+                    },
+                ],
+            ),
+            (
+                1,
+                vec![DumpedEpoch {
+                    epoch_id: epoch_ids[0].clone(),
+                    epoch_height: 1,
+                    verified: Ok(()),
+                }],
+            ),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(dump_rejection(&dumped, &epoch_ids[0], &[0, 1]), None);
+        assert_eq!(
+            dump_rejection(&dumped, &epoch_ids[1], &[0, 1]).unwrap(),
+            "shard 0: No manifest; shard 1: no recent dump"
+        );
+        // Only the given shards are considered.
+        assert_eq!(
+            dump_rejection(&dumped, &epoch_ids[1], &[1]).unwrap(),
+            "shard 1: no recent dump"
+        );
+        assert!(dump_rejection(&dumped, &epoch_ids[0], &[2]).is_some());
     }
 }
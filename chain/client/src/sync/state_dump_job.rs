@@ -0,0 +1,181 @@
+//! One-off dumps of the state of the latest complete epoch, triggered by the
+//! `adv_dump_state` RPC method.
+//! The shards are dumped by the `ShardStateDumper` of the state dump of the
+//! node, so the parts are obtained, transformed and stored the same way the
+//! state dump loop does it, to the location configured in
+//! `config.state_sync.dump`.
+
+use crate::sync::state::StateSync;
+use futures::future::BoxFuture;
+use near_chain::Chain;
+use near_chain_configs::DumpConfig;
+use near_primitives::hash::CryptoHash;
+use near_primitives::syncing::get_num_state_parts;
+use near_primitives::types::{EpochHeight, EpochId, ShardId, StateRoot};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Progress of dumping the state of a single shard.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct ShardDumpProgress {
+    pub shard_id: ShardId,
+    pub num_parts: u64,
+    pub dumped_parts: u64,
+}
+
+/// Progress of a state dump job, returned when the job is polled.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct StateDumpJobProgress {
+    pub epoch_height: EpochHeight,
+    pub sync_hash: CryptoHash,
+    pub shards: Vec<ShardDumpProgress>,
+    pub done: bool,
+    /// Set if the job stopped because of an error.
+    pub error: Option<String>,
+}
+
+/// State of a shard as of the sync block of the epoch to dump.
+#[derive(Clone, Debug)]
+pub struct ShardStateToDump {
+    pub shard_id: ShardId,
+    pub epoch_id: EpochId,
+    pub epoch_height: EpochHeight,
+    pub sync_hash: CryptoHash,
+    pub sync_prev_hash: CryptoHash,
+    /// Timestamp of the sync block, in nanoseconds.
+    pub sync_block_timestamp: u64,
+    pub state_root: StateRoot,
+    pub num_parts: u64,
+}
+
+/// Dumps all parts of a shard at once. Implemented by the state dump of the
+/// node, see `nearcore::state_sync::StateSyncDumpHandle::shard_state_dumper()`.
+pub trait ShardStateDumper: Send + Sync {
+    /// Calls `on_parts_dumped` with the number of parts present in the
+    /// external storage every time it changes.
+    fn dump_shard(
+        &self,
+        shard: ShardStateToDump,
+        on_parts_dumped: Box<dyn Fn(u64) + Send + Sync>,
+    ) -> BoxFuture<'static, anyhow::Result<()>>;
+}
+
+/// The state dump jobs started since the node started.
+#[derive(Default)]
+pub struct StateDumpJobs {
+    next_job_id: AtomicU64,
+    jobs: Mutex<HashMap<u64, Arc<Mutex<StateDumpJobProgress>>>>,
+    /// Not set if the node doesn't run the state dump.
+    shard_dumper: Mutex<Option<Arc<dyn ShardStateDumper>>>,
+}
+
+impl StateDumpJobs {
+    pub fn set_shard_dumper(&self, shard_dumper: Arc<dyn ShardStateDumper>) {
+        *self.shard_dumper.lock().unwrap() = Some(shard_dumper);
+    }
+
+    /// Starts dumping the state of `shard_ids` as of the start of the latest
+    /// complete epoch, one shard after another. Shards in
+    /// `DumpConfig::skip_shards` are not dumped.
+    /// Returns the id of the job.
+    pub fn start(
+        &self,
+        chain: &Chain,
+        dump_config: &DumpConfig,
+        shard_ids: Vec<ShardId>,
+    ) -> anyhow::Result<u64> {
+        let shard_dumper = self
+            .shard_dumper
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("The state dump of the node is not running"))?;
+        let skip_shards = dump_config.skip_shards.clone().unwrap_or_default();
+        let (skipped_shard_ids, shard_ids): (Vec<ShardId>, Vec<ShardId>) =
+            shard_ids.into_iter().partition(|shard_id| skip_shards.contains(shard_id));
+        if !skipped_shard_ids.is_empty() {
+            tracing::info!(target: "state_sync_dump", ?skipped_shard_ids, "Not dumping the configured shards");
+        }
+        let head = chain.head()?;
+        let final_hash = *chain.get_block_header(&head.last_block_hash)?.last_final_block();
+        let sync_hash = StateSync::get_epoch_start_sync_hash(chain, &final_hash)?;
+        let sync_header = chain.get_block_header(&sync_hash)?;
+        let sync_prev_hash = *sync_header.prev_hash();
+        let epoch_id = sync_header.epoch_id().clone();
+        let epoch_height = chain.epoch_manager.get_epoch_info(&epoch_id)?.epoch_height();
+        let mut shards = vec![];
+        for shard_id in shard_ids {
+            let state_header = chain.get_state_response_header(shard_id, sync_hash)?;
+            shards.push(ShardStateToDump {
+                shard_id,
+                epoch_id: epoch_id.clone(),
+                epoch_height,
+                sync_hash,
+                sync_prev_hash,
+                sync_block_timestamp: sync_header.raw_timestamp(),
+                state_root: state_header.chunk_prev_state_root(),
+                num_parts: get_num_state_parts(state_header.state_root_node().memory_usage),
+            });
+        }
+
+        let job_id = self.next_job_id.fetch_add(1, Ordering::Relaxed);
+        let progress = Arc::new(Mutex::new(StateDumpJobProgress {
+            epoch_height,
+            sync_hash,
+            shards: shards
+                .iter()
+                .map(|shard| ShardDumpProgress {
+                    shard_id: shard.shard_id,
+                    num_parts: shard.num_parts,
+                    dumped_parts: 0,
+                })
+                .collect(),
+            done: false,
+            error: None,
+        }));
+        self.jobs.lock().unwrap().insert(job_id, progress.clone());
+        tracing::info!(target: "state_sync_dump", job_id, epoch_height, %sync_hash, "Starting a state dump job");
+
+        // The parts are obtained on blocking threads, so the job doesn't hold
+        // up the actor it is started from.
+        actix_rt::spawn(async move {
+            let result = dump_shards(shard_dumper.as_ref(), shards, &progress).await;
+            let mut progress = progress.lock().unwrap();
+            progress.done = true;
+            if let Err(err) = result {
+                tracing::warn!(target: "state_sync_dump", job_id, ?err, "State dump job failed");
+                progress.error = Some(err.to_string());
+            } else {
+                tracing::info!(target: "state_sync_dump", job_id, "State dump job finished");
+            }
+        });
+        Ok(job_id)
+    }
+
+    /// Returns `None` if no job with the given id was started.
+    pub fn progress(&self, job_id: u64) -> Option<StateDumpJobProgress> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(&job_id).map(|progress| progress.lock().unwrap().clone())
+    }
+}
+
+/// Dumps `shards` with `shard_dumper`, one shard after another.
+async fn dump_shards(
+    shard_dumper: &dyn ShardStateDumper,
+    shards: Vec<ShardStateToDump>,
+    progress: &Arc<Mutex<StateDumpJobProgress>>,
+) -> anyhow::Result<()> {
+    for (shard_index, shard) in shards.into_iter().enumerate() {
+        let progress = progress.clone();
+        shard_dumper
+            .dump_shard(
+                shard,
+                Box::new(move |dumped_parts| {
+                    progress.lock().unwrap().shards[shard_index].dumped_parts = dumped_parts;
+                }),
+            )
+            .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,112 @@
+//! A single object containing all state parts of a shard of an epoch.
+//!
+//! Restoring nodes can download it with one request instead of downloading
+//! every part separately.
+//!
+//! Format of the archive, all integers are little-endian `u64`:
+//!
+//! ```text
+//! [part 0][part 1]...[part N-1]
+//! [offset of part 0][length of part 0]...[offset of part N-1][length of part N-1]
+//! [N][magic]
+//! ```
+//!
+//! * Parts are concatenated in the order of their ids, offsets are counted
+//!   from the beginning of the archive.
+//! * The index follows the parts, because sizes of the parts are known only
+//!   after all parts are written.
+//! * The archive ends with the number of parts and the 8 bytes
+//!   `STATE_PARTS_ARCHIVE_MAGIC`.
+//!
+//! To extract a single part, read the last 16 bytes to get `N`, then the
+//! `16 * N` bytes of the index before them, and then the part itself.
+
+use std::io::Write;
+
+/// Identifies the archive format and its version.
+pub const STATE_PARTS_ARCHIVE_MAGIC: &[u8; 8] = b"NEARSPA1";
+
+const FOOTER_LEN: usize = 16;
+const INDEX_ENTRY_LEN: usize = 16;
+
+/// Writes parts one by one, keeping only the index in memory.
+pub struct StatePartsArchiveWriter<W: Write> {
+    writer: W,
+    index: Vec<(u64, u64)>,
+    offset: u64,
+}
+
+impl<W: Write> StatePartsArchiveWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer, index: vec![], offset: 0 }
+    }
+
+    /// Parts need to be added in the order of their ids.
+    pub fn add_part(&mut self, part: &[u8]) -> std::io::Result<()> {
+        self.writer.write_all(part)?;
+        self.index.push((self.offset, part.len() as u64));
+        self.offset += part.len() as u64;
+        Ok(())
+    }
+
+    /// Writes the index and the footer. Returns the underlying writer.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        for (offset, length) in &self.index {
+            self.writer.write_all(&offset.to_le_bytes())?;
+            self.writer.write_all(&length.to_le_bytes())?;
+        }
+        self.writer.write_all(&(self.index.len() as u64).to_le_bytes())?;
+        self.writer.write_all(STATE_PARTS_ARCHIVE_MAGIC)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Returns the number of parts in the archive, or `None` if the archive is malformed.
+pub fn get_num_parts_from_archive(archive: &[u8]) -> Option<u64> {
+    let footer = archive.get(archive.len().checked_sub(FOOTER_LEN)?..)?;
+    if &footer[8..] != STATE_PARTS_ARCHIVE_MAGIC {
+        return None;
+    }
+    Some(u64::from_le_bytes(footer[..8].try_into().unwrap()))
+}
+
+/// Returns the part with the given id, or `None` if the archive is malformed or
+/// doesn't contain such part.
+pub fn get_part_from_archive(archive: &[u8], part_id: u64) -> Option<&[u8]> {
+    let num_parts = get_num_parts_from_archive(archive)?;
+    if part_id >= num_parts {
+        return None;
+    }
+    let index_start = archive
+        .len()
+        .checked_sub(FOOTER_LEN)?
+        .checked_sub(INDEX_ENTRY_LEN.checked_mul(num_parts as usize)?)?;
+    let entry_start = index_start + INDEX_ENTRY_LEN * part_id as usize;
+    let entry = &archive[entry_start..entry_start + INDEX_ENTRY_LEN];
+    let offset = u64::from_le_bytes(entry[..8].try_into().unwrap()) as usize;
+    let length = u64::from_le_bytes(entry[8..].try_into().unwrap()) as usize;
+    archive.get(offset..offset.checked_add(length)?).filter(|_| offset + length <= index_start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_archive_round_trip() {
+        let parts = [vec![1, 2, 3], vec![], vec![4; 100]];
+        let mut writer = StatePartsArchiveWriter::new(vec![]);
+        for part in &parts {
+            writer.add_part(part).unwrap();
+        }
+        let archive = writer.finish().unwrap();
+
+        assert_eq!(get_num_parts_from_archive(&archive), Some(3));
+        for (part_id, part) in parts.iter().enumerate() {
+            assert_eq!(get_part_from_archive(&archive, part_id as u64), Some(part.as_slice()));
+        }
+        assert_eq!(get_part_from_archive(&archive, 3), None);
+        assert_eq!(get_num_parts_from_archive(&archive[..archive.len() - 1]), None);
+    }
+}
@@ -2,6 +2,7 @@ use std::cmp::max;
 use std::collections::{HashMap, HashSet};
 use std::mem::swap;
 use std::ops::DerefMut;
+use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
 
@@ -283,6 +284,7 @@ pub fn setup(
         Some(signer.clone()),
         enable_doomslug,
         TEST_SEED,
+        Arc::new(AtomicBool::new(false)),
     )
     .unwrap();
     let client_actor = ClientActor::new(
@@ -1158,6 +1160,7 @@ pub fn setup_client_with_runtime(
         validator_signer,
         enable_doomslug,
         rng_seed,
+        Arc::new(AtomicBool::new(false)),
     )
     .unwrap();
     client.sync_status = SyncStatus::NoSync;
@@ -8,6 +8,7 @@ use near_primitives::sharding::ShardChunkHeaderV3;
 use near_primitives::test_utils::create_test_signer;
 use near_primitives::types::validator_stake::ValidatorStake;
 use near_primitives::utils::MaybeValidated;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 /// Only process one block per height
@@ -77,3 +78,24 @@ fn test_bad_shard_id() {
         .unwrap_err();
     assert_matches!(err, near_chain::Error::InvalidShardId(1));
 }
+
+/// Background jobs that give way to block production also give way to the
+/// processing of a block, until the block is postprocessed.
+#[test]
+fn test_block_production_in_progress_until_postprocessed() {
+    let mut env = TestEnv::builder(ChainGenesis::test()).build();
+    let block = env.clients[0].produce_block(1).unwrap().unwrap();
+    let client = &mut env.clients[0];
+    let block_production_in_progress = client.block_production_in_progress.clone();
+    assert!(!block_production_in_progress.load(Ordering::Relaxed));
+
+    client
+        .start_process_block(MaybeValidated::from(block), Provenance::PRODUCED, Arc::new(|_| {}))
+        .unwrap();
+    assert!(block_production_in_progress.load(Ordering::Relaxed));
+    test_utils::wait_for_all_blocks_in_processing(&mut client.chain);
+    assert!(block_production_in_progress.load(Ordering::Relaxed));
+    let (accepted_blocks, _) = client.postprocess_ready_blocks(Arc::new(|_| {}), true);
+    assert_eq!(accepted_blocks.len(), 1);
+    assert!(!block_production_in_progress.load(Ordering::Relaxed));
+}
@@ -391,6 +391,8 @@ impl JsonRpcHandler {
             "adv_switch_to_height" => self.adv_switch_to_height(request.params).await,
             "adv_get_saved_blocks" => self.adv_get_saved_blocks(request.params).await,
             "adv_check_store" => self.adv_check_store(request.params).await,
+            "adv_dump_state" => self.adv_dump_state(request.params).await,
+            "adv_dump_state_progress" => self.adv_dump_state_progress(request.params).await,
             _ => return Err(request),
         })
     }
@@ -1325,6 +1327,34 @@ impl JsonRpcHandler {
             _ => Err(RpcError::server_error::<String>(None)),
         }
     }
+
+    /// Starts dumping the state of the given shards and returns the id of the
+    /// job, to be polled with `adv_dump_state_progress`.
+    async fn adv_dump_state(&self, params: Value) -> Result<Value, RpcError> {
+        let (shard_ids,) = crate::api::Params::parse(params)?;
+        match self
+            .client_addr
+            .send(
+                near_client::NetworkAdversarialMessage::AdvDumpState(shard_ids).with_span_context(),
+            )
+            .await
+        {
+            Ok(Some(job_id)) => serialize_response(job_id),
+            _ => Err(RpcError::server_error::<String>(None)),
+        }
+    }
+
+    async fn adv_dump_state_progress(&self, params: Value) -> Result<Value, RpcError> {
+        let (job_id,) = crate::api::Params::parse(params)?;
+        match self
+            .client_addr
+            .send(near_client::AdvDumpStateProgress(job_id).with_span_context())
+            .await
+        {
+            Ok(Some(progress)) => serialize_response(progress),
+            _ => Err(RpcError::server_error::<String>(None)),
+        }
+    }
 }
 
 fn rpc_handler(
@@ -85,6 +85,15 @@ pub struct ExternalStorageConfig {
     /// to this many concurrent requests per shard.
     #[serde(default = "default_num_concurrent_requests")]
     pub num_concurrent_requests: u32,
+    /// Whether the state parts are stored under date-partitioned paths, see
+    /// `DumpConfig::date_partitioned`. Needs to match the config of the dump.
+    #[serde(default)]
+    pub date_partitioned: bool,
+    /// Chain id in the paths of the state parts, if it differs from the chain
+    /// id of the node, see `DumpConfig::path_chain_id`. Needs to match the
+    /// config of the dump.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_chain_id: Option<String>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
@@ -98,6 +107,69 @@ pub enum ExternalStorageLocation {
     Filesystem {
         root_dir: PathBuf,
     },
+    /// A container of Azure Blob Storage.
+    Azure {
+        /// Name of the container.
+        container: String,
+        /// Name of the storage account owning the container.
+        account: String,
+    },
+    /// A named pipe, or another file that gets appended to, which a separate
+    /// process reads the dumped files from, see `ExternalConnection::Pipe`.
+    /// Only suitable for dumping, nodes can't sync state from it.
+    Pipe {
+        path: PathBuf,
+    },
+}
+
+/// Shards whose dump progress is dropped when the node starts.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(from = "RestartShardsRepr", into = "RestartShardsRepr")]
+pub enum RestartShards {
+    /// All shards, regardless of their number.
+    All,
+    Shards(Vec<ShardId>),
+}
+
+impl RestartShards {
+    pub fn contains(&self, shard_id: ShardId) -> bool {
+        match self {
+            RestartShards::All => true,
+            RestartShards::Shards(shard_ids) => shard_ids.contains(&shard_id),
+        }
+    }
+}
+
+/// Representation of `RestartShards` in the config, `"all"` or a list.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+enum RestartShardsRepr {
+    Keyword(RestartShardsKeyword),
+    Shards(Vec<ShardId>),
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RestartShardsKeyword {
+    All,
+}
+
+impl From<RestartShardsRepr> for RestartShards {
+    fn from(repr: RestartShardsRepr) -> Self {
+        match repr {
+            RestartShardsRepr::Keyword(RestartShardsKeyword::All) => RestartShards::All,
+            RestartShardsRepr::Shards(shard_ids) => RestartShards::Shards(shard_ids),
+        }
+    }
+}
+
+impl From<RestartShards> for RestartShardsRepr {
+    fn from(restart_shards: RestartShards) -> Self {
+        match restart_shards {
+            RestartShards::All => RestartShardsRepr::Keyword(RestartShardsKeyword::All),
+            RestartShards::Shards(shard_ids) => RestartShardsRepr::Shards(shard_ids),
+        }
+    }
 }
 
 /// Configures how to dump state to external storage.
@@ -106,13 +178,194 @@ pub struct DumpConfig {
     /// Specifies where to write the obtained state parts.
     pub location: ExternalStorageLocation,
     /// Use in case a node that dumps state to the external storage
-    /// gets in trouble.
+    /// gets in trouble. Either a list of shard ids, or `"all"`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restart_dump_for_shards: Option<RestartShards>,
+    /// Shards that are never dumped, even if the node tracks them. Useful
+    /// when some shards are dumped by a dedicated node.
+    /// A shard can't be both skipped and restarted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_shards: Option<Vec<ShardId>>,
+    /// How often to check if a new epoch has started, i.e. the delay after
+    /// an iteration of the dump loop that had nothing to do.
+    /// Defaults to 10 seconds. Called `iteration_delay` previously.
+    #[serde(alias = "iteration_delay", skip_serializing_if = "Option::is_none")]
+    pub idle_delay: Option<Duration>,
+    /// If set, the delay between idle iterations starts at this value after
+    /// an iteration that did some work, and doubles after every idle
+    /// iteration up to `idle_delay`. Lets the dump of a new epoch start
+    /// sooner without checking often during long idle periods.
+    /// If not set, idle iterations are always `idle_delay` apart.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_iteration_delay: Option<Duration>,
+    /// Delay after an iteration of the dump loop that dumped some parts.
+    /// Defaults to zero, i.e. the next iteration starts right away.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub work_delay: Option<Duration>,
+    /// Dump only epochs with height divisible by this number.
+    /// Other epochs are skipped. If not set, every epoch is dumped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dump_every_n_epochs: Option<u64>,
+    /// Pause obtaining state parts while the node produces or processes a block.
+    /// Defaults to `true` on validator nodes and to `false` otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub defer_to_block_production: Option<bool>,
+    /// Directory of a separate database to keep the obtained state parts in,
+    /// instead of the main database. Must be an absolute path.
+    /// Parts are deleted from that database once all parts of an epoch are dumped.
+    /// If not set, state parts are written to the main database.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_parts_scratch_dir: Option<PathBuf>,
+    /// Once all parts of an epoch are dumped, additionally upload a single
+    /// archive containing all the parts. Disabled by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload_archive: Option<bool>,
+    /// If set, a node marks a part as being dumped before obtaining it, so that
+    /// other nodes dumping to the same location can avoid duplicate work.
+    /// Marks older than this are considered abandoned.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub restart_dump_for_shards: Option<Vec<ShardId>>,
-    /// How often to check if a new epoch has started.
-    /// Feel free to set to `None`, defaults are sensible.
+    pub part_lease_ttl: Option<Duration>,
+    /// Only dump parts with ids in this half-open range. Other parts are not
+    /// touched, and an epoch is considered dumped once all parts in the range
+    /// are dumped. Meant for debugging, defaults to all parts.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub iteration_delay: Option<Duration>,
+    pub part_range: Option<(u64, u64)>,
+    /// If enabled, a part is not uploaded if an identical part is already
+    /// stored at its location, for example when re-dumping an epoch after a
+    /// restart. Costs an extra request per part, disabled by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_existing: Option<bool>,
+    /// If enabled, the dump of an epoch gets interrupted when a newer epoch
+    /// becomes available. The newer epoch gets dumped first, and then the
+    /// dump of the interrupted epoch continues. Disabled by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefer_latest_epoch: Option<bool>,
+    /// If enabled, files written to a `Filesystem` location are synced to disk
+    /// together with their parent directory, so that dumped parts survive a
+    /// power loss. Nodes serving as archival mirrors of state parts should
+    /// enable it. Disabled by default, because it slows down dumping.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fsync: Option<bool>,
+    /// At most this many shards are dumped at the same time. Other shards
+    /// wait for their turn, and get it in the order in which they started
+    /// waiting. Every shard is dumped in turns limited in time, so that no
+    /// shard waits for an entire epoch of another shard to be dumped.
+    /// If not set, all tracked shards are dumped at the same time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_shard_dumps: Option<usize>,
+    /// If enabled, paths of all files of an epoch start with the date of the
+    /// first block of the epoch as `YYYY/MM/DD/`, in UTC. Lets storage
+    /// lifecycle policies target old dumps by their date.
+    /// Nodes syncing from such a dump need to enable
+    /// `ExternalStorageConfig::date_partitioned`. Disabled by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_partitioned: Option<bool>,
+    /// If enabled, once all parts of an epoch are dumped, the Merkle root of
+    /// the hashes of the parts ordered by part id is logged and written to
+    /// the manifest of the epoch. It lets anyone who downloads the parts check
+    /// that they are the parts this node has dumped. Parts missing in the
+    /// database need to be obtained again to compute it. Disabled by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compute_merkle_root: Option<bool>,
+    /// If enabled, a part identical to the same part of the previous epoch
+    /// is stored as a small pointer to that part instead of a full copy.
+    /// Parts are compared the same way as for `skip_existing`, and a part
+    /// that can't be compared, for example an S3 object uploaded in chunks,
+    /// is stored as a full copy. Requires restoring nodes that follow the
+    /// pointers, and dumps of earlier epochs must be kept as long as later
+    /// epochs are. Disabled by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub incremental: Option<bool>,
+    /// If set, once all parts of an epoch whose height is a multiple of this
+    /// number are dumped, the parts are downloaded and applied to an empty
+    /// temporary database, the same way a syncing node applies them, and the
+    /// resulting trie is checked against the state root. If the check fails,
+    /// the parts get deleted and the epoch gets dumped again.
+    /// Takes as long as a state sync and needs as much space in the
+    /// temporary directory as the state of a shard. Disabled by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify_reconstruction_every_n_epochs: Option<u64>,
+    /// If set, an iteration of the dump loop ends after dumping this many
+    /// parts, even if the iteration time limit isn't reached. Together with
+    /// `work_delay` this limits the rate of uploads.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_parts_per_iteration: Option<usize>,
+    /// Chain id to use in the paths of the state parts instead of the chain
+    /// id of the node. Only meant for testing, for example to serve parts of
+    /// mainnet to nodes of a test network. Defaults to the chain id of the
+    /// node.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path_chain_id: Option<String>,
+    /// If enabled, only the latest complete epoch is dumped, and once all of
+    /// its parts are dumped, the dumps of earlier epochs of the shard get
+    /// deleted. Epochs missed while the node was behind or stopped are never
+    /// dumped, and a newer epoch interrupts the dump of an older one for good.
+    /// Keeps about one epoch of state parts in the external storage.
+    /// Not compatible with `incremental` and `date_partitioned`.
+    /// Disabled by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latest_only: Option<bool>,
+    /// With `latest_only`, how long the dump of an earlier epoch is kept after
+    /// the latest epoch is dumped, so that nodes that are downloading it can
+    /// finish. Defaults to one hour.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latest_only_grace_period: Option<Duration>,
+    /// If set, before the dump of an epoch starts, this many parts, the first
+    /// and the last part among them, are obtained and checked to be valid
+    /// parts of the state. An epoch whose parts can't be obtained, because
+    /// its state is gone, or whose parts are invalid gets skipped instead of
+    /// being dumped. Defaults to 0, i.e. no parts are probed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe_parts: Option<usize>,
+    /// If set, epochs missed by the dump loop and epochs whose dump was
+    /// interrupted by a newer epoch are only dumped during this time since the
+    /// dump loop started. After it, the epoch being dumped is finished, and
+    /// then only the latest epochs get dumped. Not limited by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backfill_deadline: Option<Duration>,
+    /// The dumps of all shards run on at most this many threads. Shards are
+    /// assigned to the threads in a round-robin way, and the dumps of the
+    /// shards of a thread take turns. Unlike `max_concurrent_shard_dumps`, it
+    /// limits the threads, not the shards dumping parts at the same time.
+    /// If not set, the dump of every shard runs on its own thread.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_dump_threads: Option<usize>,
+}
+
+impl Default for DumpConfig {
+    /// All options unset. There is no meaningful default location, callers
+    /// need to set `location`.
+    fn default() -> Self {
+        Self {
+            location: ExternalStorageLocation::Filesystem { root_dir: PathBuf::new() },
+            restart_dump_for_shards: None,
+            skip_shards: None,
+            idle_delay: None,
+            min_iteration_delay: None,
+            work_delay: None,
+            dump_every_n_epochs: None,
+            defer_to_block_production: None,
+            state_parts_scratch_dir: None,
+            upload_archive: None,
+            part_lease_ttl: None,
+            part_range: None,
+            skip_existing: None,
+            prefer_latest_epoch: None,
+            fsync: None,
+            max_concurrent_shard_dumps: None,
+            date_partitioned: None,
+            compute_merkle_root: None,
+            incremental: None,
+            verify_reconstruction_every_n_epochs: None,
+            max_parts_per_iteration: None,
+            path_chain_id: None,
+            latest_only: None,
+            latest_only_grace_period: None,
+            probe_parts: None,
+            backfill_deadline: None,
+            max_dump_threads: None,
+        }
+    }
 }
 
 /// Configures how to fetch state parts during state sync.
@@ -330,3 +583,31 @@ impl ClientConfig {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{DumpConfig, RestartShards};
+    use std::time::Duration;
+
+    #[test]
+    fn test_restart_shards_serde() {
+        let all: RestartShards = serde_json::from_str("\"all\"").unwrap();
+        assert_eq!(all, RestartShards::All);
+        assert_eq!(serde_json::to_string(&all).unwrap(), "\"all\"");
+        let shards: RestartShards = serde_json::from_str("[0, 2]").unwrap();
+        assert_eq!(shards, RestartShards::Shards(vec![0, 2]));
+        assert_eq!(serde_json::to_string(&shards).unwrap(), "[0,2]");
+        assert!(serde_json::from_str::<RestartShards>("\"some\"").is_err());
+    }
+
+    /// Configs that set `iteration_delay` keep working.
+    #[test]
+    fn test_dump_config_iteration_delay_alias() {
+        let config: DumpConfig = serde_json::from_str(
+            r#"{"location": {"Filesystem": {"root_dir": "/tmp"}}, "iteration_delay": {"secs": 5, "nanos": 0}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.idle_delay, Some(Duration::from_secs(5)));
+        assert_eq!(config.work_delay, None);
+    }
+}
@@ -242,6 +242,8 @@ pub enum StateSyncDumpProgress {
         num_parts: Option<u64>,
     },
     /// Represents the case of an epoch being partially dumped.
+    /// Stored only by older versions, which get it replaced with
+    /// `InProgressV2`.
     InProgress {
         /// The dumped state corresponds to the state at the beginning of the specified epoch.
         epoch_id: EpochId,
@@ -250,4 +252,48 @@ pub enum StateSyncDumpProgress {
         /// The dumped state corresponds to the state before applying this block.
         sync_hash: CryptoHash,
     },
+    /// The epoch was not dumped, because it doesn't match the configured dump
+    /// cadence, or because its state is no longer available.
+    Skipped { epoch_id: EpochId, epoch_height: EpochHeight },
+    /// Same as `AllDumped`, but also records the combined size of the parts.
+    /// A separate variant keeps the progress stored by older versions readable.
+    AllDumpedV2 {
+        epoch_id: EpochId,
+        epoch_height: EpochHeight,
+        num_parts: Option<u64>,
+        /// Combined size of the parts in bytes, as known to the dumping node.
+        total_bytes: u64,
+    },
+    /// Same as `InProgress`, but also records the number of parts computed
+    /// when the dump of the epoch started. Parts are stored under locations
+    /// containing the number of parts, which must not change during the dump.
+    InProgressV2 {
+        epoch_id: EpochId,
+        epoch_height: EpochHeight,
+        sync_hash: CryptoHash,
+        num_parts: u64,
+    },
+}
+
+impl StateSyncDumpProgress {
+    pub fn epoch_height(&self) -> EpochHeight {
+        match self {
+            StateSyncDumpProgress::AllDumped { epoch_height, .. }
+            | StateSyncDumpProgress::InProgress { epoch_height, .. }
+            | StateSyncDumpProgress::Skipped { epoch_height, .. }
+            | StateSyncDumpProgress::AllDumpedV2 { epoch_height, .. }
+            | StateSyncDumpProgress::InProgressV2 { epoch_height, .. } => *epoch_height,
+        }
+    }
+}
+
+/// Epochs whose dump got interrupted to dump a newer epoch first.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Default)]
+pub struct StateSyncDumpBackfill {
+    /// `InProgress` states of the interrupted epochs, the most recently
+    /// interrupted epoch is the last.
+    pub preempted: Vec<StateSyncDumpProgress>,
+    /// Progress to restore once the dump of an interrupted epoch is complete.
+    /// Set only while an interrupted epoch is being dumped.
+    pub resume_to: Option<StateSyncDumpProgress>,
 }
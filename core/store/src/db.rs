@@ -154,6 +154,36 @@ pub trait Database: Sync + Send {
     /// properly handle reference-counted columns.
     fn get_raw_bytes(&self, col: DBCol, key: &[u8]) -> io::Result<Option<DBSlice<'_>>>;
 
+    /// Returns raw bytes for each of the given `keys`, in the same order as
+    /// the keys.  See [`Self::get_raw_bytes`] for how reference-counted
+    /// columns are handled.
+    ///
+    /// The default implementation looks up the keys one by one.  Databases
+    /// which can look up multiple keys at once should override it.
+    fn multi_get_raw_bytes(
+        &self,
+        col: DBCol,
+        keys: &[&[u8]],
+    ) -> io::Result<Vec<Option<DBSlice<'_>>>> {
+        keys.iter().map(|key| self.get_raw_bytes(col, key)).collect()
+    }
+
+    /// Returns values for given `keys` forcing a reference count decoding.
+    ///
+    /// **Panics** if the column is not reference counted.
+    fn multi_get_with_rc_stripped(
+        &self,
+        col: DBCol,
+        keys: &[&[u8]],
+    ) -> io::Result<Vec<Option<DBSlice<'_>>>> {
+        assert!(col.is_rc());
+        Ok(self
+            .multi_get_raw_bytes(col, keys)?
+            .into_iter()
+            .map(|value| value.and_then(DBSlice::strip_refcount))
+            .collect())
+    }
+
     /// Returns value for given `key` forcing a reference count decoding.
     ///
     /// **Panics** if the column is not reference counted.
@@ -53,6 +53,26 @@ impl Database for ColdDB {
         self.cold.get_with_rc_stripped(col, key)
     }
 
+    /// Returns raw bytes for given `keys` ignoring any reference count decoding if any.
+    fn multi_get_raw_bytes(
+        &self,
+        col: DBCol,
+        keys: &[&[u8]],
+    ) -> std::io::Result<Vec<Option<DBSlice<'_>>>> {
+        Self::check_is_in_colddb(col)?;
+        self.cold.multi_get_raw_bytes(col, keys)
+    }
+
+    /// Returns values for given `keys` forcing a reference count decoding.
+    fn multi_get_with_rc_stripped(
+        &self,
+        col: DBCol,
+        keys: &[&[u8]],
+    ) -> std::io::Result<Vec<Option<DBSlice<'_>>>> {
+        Self::check_is_in_colddb(col)?;
+        self.cold.multi_get_with_rc_stripped(col, keys)
+    }
+
     /// Iterates over all values in a column.
     fn iter<'a>(&'a self, col: DBCol) -> DBIterator<'a> {
         Self::log_assert_is_in_colddb(col);
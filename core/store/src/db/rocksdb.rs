@@ -290,6 +290,26 @@ impl Database for RocksDB {
         Ok(result)
     }
 
+    fn multi_get_raw_bytes(
+        &self,
+        col: DBCol,
+        keys: &[&[u8]],
+    ) -> io::Result<Vec<Option<DBSlice<'_>>>> {
+        let timer = metrics::DATABASE_OP_LATENCY_HIST
+            .with_label_values(&["multi_get", col.into()])
+            .start_timer();
+        let read_options = rocksdb_read_options();
+        let result: io::Result<Vec<_>> = self
+            .db
+            .batched_multi_get_cf_opt(self.cf_handle(col)?, keys, false, &read_options)
+            .into_iter()
+            .map(|value| value.map(|value| value.map(DBSlice::from_rocksdb_slice)))
+            .collect::<Result<_, _>>()
+            .map_err(into_other);
+        timer.observe_duration();
+        result
+    }
+
     fn iter_raw_bytes(&self, col: DBCol) -> DBIterator {
         Box::new(self.iter_raw_bytes_internal(col, None, None, None))
     }
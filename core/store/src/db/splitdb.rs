@@ -104,6 +104,35 @@ impl Database for SplitDB {
         Ok(None)
     }
 
+    /// Returns values for given `keys` forcing a reference count decoding.
+    ///
+    /// **Panics** if the column is not reference counted.
+    ///
+    /// First reads all the keys from the hot db. Then reads the keys that
+    /// were not found there from the cold db, same as `get_with_rc_stripped`.
+    fn multi_get_with_rc_stripped(
+        &self,
+        col: DBCol,
+        keys: &[&[u8]],
+    ) -> io::Result<Vec<Option<DBSlice<'_>>>> {
+        assert!(col.is_rc());
+
+        let mut values = self.hot.multi_get_with_rc_stripped(col, keys)?;
+        if !col.is_cold() {
+            return Ok(values);
+        }
+        let missing: Vec<usize> = (0..values.len()).filter(|&i| values[i].is_none()).collect();
+        if missing.is_empty() {
+            return Ok(values);
+        }
+        let missing_keys: Vec<&[u8]> = missing.iter().map(|&i| keys[i]).collect();
+        let cold_values = self.cold.multi_get_with_rc_stripped(col, &missing_keys)?;
+        for (i, cold_value) in missing.into_iter().zip(cold_values) {
+            values[i] = cold_value;
+        }
+        Ok(values)
+    }
+
     /// Iterate over all items in given column in lexicographical order sorted
     /// by the key.
     ///
@@ -314,6 +343,27 @@ mod test {
         // Test 3: nothing, there aren't any non-cold reference counted columns.
     }
 
+    #[test]
+    fn test_multi_get_with_rc_stripped() {
+        let hot = create_hot();
+        let cold = create_cold();
+        let split = SplitDB::new(hot.clone(), cold.clone());
+
+        let col = DBCol::Transactions;
+
+        // FOO is in both dbs, BAR only in the cold db, and BAZ only in the
+        // hot db but with zero reference count.
+        set_rc(&hot, col, FOO, FOO);
+        set_rc(&cold, col, FOO, NOT_FOO);
+        set_rc(&cold, col, BAR, BAR);
+        set(&hot, col, BAZ, &[BAZ, &0i64.to_le_bytes()].concat());
+        set_rc(&cold, col, BAZ, BAZ);
+
+        let values = split.multi_get_with_rc_stripped(col, &[FOO, BAR, BAZ, NOT_FOO]).unwrap();
+        let values = values.iter().map(|value| value.as_deref()).collect_vec();
+        assert_eq!(values, vec![Some(FOO), Some(BAR), Some(BAZ), None]);
+    }
+
     #[test]
     fn test_iter() {
         let hot = create_hot();
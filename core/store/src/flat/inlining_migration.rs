@@ -1,22 +1,173 @@
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 use borsh::{BorshDeserialize, BorshSerialize};
 use crossbeam::channel;
 use itertools::Itertools;
 use near_primitives::hash::CryptoHash;
 use near_primitives::shard_layout::ShardUId;
-use tracing::{debug, info};
+use near_primitives::state::ValueRef;
+use tracing::{debug, error, info, trace, warn};
 
 use crate::metrics::flat_state_metrics::inlining_migration::{
-    FLAT_STATE_PAUSED_DURATION, INLINED_COUNT, INLINED_TOTAL_VALUES_SIZE, PROCESSED_COUNT,
-    PROCESSED_TOTAL_VALUES_SIZE, SKIPPED_COUNT,
+    FLAT_STATE_INLINING_MIGRATION_RUNNING_SECONDS, FLAT_STATE_INLINING_MIGRATION_SECONDS,
+    FLAT_STATE_INLINING_READERS_BUSY, FLAT_STATE_PAUSED_DURATION, FLAT_STATE_PAUSED_SECONDS_TOTAL,
+    FLAT_STATE_VALUE_SIZE_BYTES, INLINED_COUNT, INLINED_TOTAL_SERIALIZED_SIZE,
+    INLINED_TOTAL_VALUES_SIZE, PROCESSED_COUNT, PROCESSED_TOTAL_VALUES_SIZE, SKIPPED_COUNT,
 };
-use crate::{DBCol, Store, TrieDBStorage, TrieStorage};
+use crate::{DBCol, Store, StoreUpdate, TrieCachingStorage, TrieDBStorage};
 
 use super::store_helper::decode_flat_state_db_key;
 use super::types::INLINE_DISK_VALUE_THRESHOLD;
 use super::{FlatStateValue, FlatStorageManager};
 
+/// Maximum number of values that a reader thread reads in a single database
+/// request.
+const READ_VALUE_BATCH_SIZE: usize = 16;
+
+/// Same as `READ_VALUE_BATCH_SIZE`, but for a migration trusting FlatState
+/// value references. RocksDB sorts the keys of a single request, so the
+/// larger the batch, the more values are read from every visited block.
+/// Values are still read by their keys rather than by a sequential scan of
+/// `DBCol::State`: the values referenced by a range of FlatState are spread
+/// over the whole column, mixed with trie nodes and large values, so a scan
+/// would read the whole column for every range.
+const TRUSTED_READ_VALUE_BATCH_SIZE: usize = 256;
+
+/// Maximum time that reader threads get to finish once the migration is done
+/// or stopped, unless the timeout of the whole migration is shorter.
+const READER_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often `StateValueReader::close` checks whether the reader threads have
+/// finished.
+const READER_SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Counts of the whole migration, shared by the scan threads and reported by
+/// the progress logger.
+#[derive(Default)]
+struct InliningProgress {
+    processed_count: AtomicUsize,
+    inlined_count: AtomicUsize,
+    /// See `InliningMigrationStats`.
+    processed_values_size: AtomicU64,
+    inlined_values_size: AtomicU64,
+    too_large_count: AtomicUsize,
+    /// Number of processed values in every bucket of
+    /// `VALUE_SIZE_BUCKET_BOUNDS`, and of larger values.
+    value_size_counts: [AtomicU64; VALUE_SIZE_BUCKET_BOUNDS.len() + 1],
+}
+
+impl InliningProgress {
+    fn observe_value_size(&self, value_size: u64) {
+        self.processed_values_size.fetch_add(value_size, Ordering::Relaxed);
+        let bucket = VALUE_SIZE_BUCKET_BOUNDS
+            .iter()
+            .position(|bound| value_size <= *bound)
+            .unwrap_or(VALUE_SIZE_BUCKET_BOUNDS.len());
+        self.value_size_counts[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Logs the cumulative counts every `interval` until the sender of `done`
+    /// is dropped. The rate is the number of processed rows per second since
+    /// the previous log.
+    fn log_periodically(
+        &self,
+        interval: Duration,
+        done: channel::Receiver<()>,
+        migration_start: Instant,
+    ) {
+        let mut last_log = migration_start;
+        let mut last_processed_count = 0;
+        while let Err(channel::RecvTimeoutError::Timeout) = done.recv_timeout(interval) {
+            let now = Instant::now();
+            let processed_count = self.processed_count.load(Ordering::Relaxed);
+            let inlined_count = self.inlined_count.load(Ordering::Relaxed);
+            let processed_per_second = ((processed_count - last_processed_count) as f64
+                / now.duration_since(last_log).as_secs_f64())
+                as u64;
+            let migration_elapsed = now.duration_since(migration_start);
+            info!(target: "store", %processed_count, %inlined_count, %processed_per_second, ?migration_elapsed, "FlatState value inlining migration progress");
+            last_log = now;
+            last_processed_count = processed_count;
+        }
+    }
+}
+
+/// Inclusive upper bounds of the value sizes in bytes of the buckets of
+/// `InliningMigrationStats::value_size_histogram`, the same as of the
+/// `FLAT_STATE_VALUE_SIZE_BYTES` metric.
+const VALUE_SIZE_BUCKET_BOUNDS: [u64; 10] = [16, 32, 64, 128, 256, 512, 1024, 2048, 4096, 8192];
+
+/// Version of the serialized `InliningMigrationStats`. Increased whenever a
+/// field gets removed or renamed, or changes its meaning, so that scripts
+/// parsing the stats can detect it. Adding a field doesn't change it.
+pub const INLINING_MIGRATION_STATS_SCHEMA_VERSION: u32 = 1;
+
+/// Counts of a single run of `inline_flat_state_values`, not including the
+/// values processed before the run resumed from its checkpoints.
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct InliningMigrationStats {
+    /// Always `INLINING_MIGRATION_STATS_SCHEMA_VERSION`.
+    pub schema_version: u32,
+    /// Number of scanned FlatState entries.
+    pub processed_count: u64,
+    /// Number of values inlined by this run.
+    pub inlined_count: u64,
+    /// Number of references to values larger than
+    /// `INLINE_DISK_VALUE_THRESHOLD`, which are never inlined.
+    pub too_large_count: u64,
+    /// Total size of the scanned values, inlined or referenced.
+    pub processed_values_size: u64,
+    /// Total size of the values inlined by this run.
+    pub inlined_values_size: u64,
+    /// Numbers of scanned values, inlined or referenced, by their size. The
+    /// buckets are ordered by size and don't overlap.
+    pub value_size_histogram: Vec<ValueSizeBucket>,
+    /// Duration of this run in seconds.
+    pub elapsed_secs: f64,
+}
+
+/// A bucket of `InliningMigrationStats::value_size_histogram`.
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct ValueSizeBucket {
+    /// Inclusive upper bound of the sizes in bytes of the values of the
+    /// bucket, `None` for the bucket of the largest values. The lower bound
+    /// is the upper bound of the previous bucket.
+    pub max_size: Option<u64>,
+    pub count: u64,
+}
+
+/// Error returned by `inline_flat_state_values`.
+#[derive(thiserror::Error, Debug)]
+pub enum InliningMigrationError {
+    /// The migration didn't finish in time, or its reader threads didn't stop
+    /// in time and were detached. Progress is checkpointed, so running the
+    /// migration again resumes where it stopped.
+    #[error("FlatState inlining migration didn't finish within {0:?}")]
+    Timeout(Duration),
+    /// The key to start the migration from isn't a FlatState key of the
+    /// given shard.
+    #[error("FlatState key {key:?} to start inlining from doesn't belong to shard {shard_uid}")]
+    InvalidStartKey { shard_uid: ShardUId, key: Vec<u8> },
+    /// A migration trusting value references found a reference to a value
+    /// missing in `State`. The migration resumes from its checkpoints once
+    /// the database is fixed, or when it runs without trusting references.
+    #[error("FlatState references value {value_hash} missing in State, the migration must not trust the references")]
+    MissingValue { value_hash: CryptoHash },
+    /// A migration trusting value references failed to read values from
+    /// `State`. Without trusting references, such values are skipped.
+    #[error("failed to read values from State: {0}")]
+    ReadValues(String),
+}
+
+/// Why the migration of a range stopped before the range was done.
+enum RangeStopped {
+    /// The deadline of the migration has passed.
+    DeadlineExceeded,
+    Failed(InliningMigrationError),
+}
+
 struct ReadValueRequest {
     shard_uid: ShardUId,
     value_hash: CryptoHash,
@@ -24,20 +175,33 @@ struct ReadValueRequest {
 
 struct ReadValueResponse {
     value_hash: CryptoHash,
-    value_bytes: Option<Vec<u8>>,
+    /// `None` if the value is missing or can't be read. A failed read is an
+    /// error only for a reader trusting the references.
+    value_bytes: Result<Option<Vec<u8>>, String>,
 }
 
 /// An abstraction that enables reading values from State in parallel using
 /// multiple threads.
+///
+/// Values are read from the live DB rather than from a snapshot taken at
+/// migration start. `State` is content-addressed, so the bytes read for a given
+/// hash can't change during the migration, and values that get removed in the
+/// meantime are no longer referenced by FlatState entries, which get re-read
+/// while FlatState updates are paused.
+///
+/// If `trust_refs` is set, every requested value must be present in `State`,
+/// otherwise receiving the values fails, see
+/// `InliningMigrationOptions::trust_refs`.
 struct StateValueReader {
     pending_requests: usize,
+    trust_refs: bool,
     value_request_send: channel::Sender<ReadValueRequest>,
     value_response_recv: channel::Receiver<ReadValueResponse>,
     join_handles: Vec<std::thread::JoinHandle<()>>,
 }
 
 impl StateValueReader {
-    fn new(store: Store, num_threads: usize) -> Self {
+    fn new(store: Store, num_threads: usize, trust_refs: bool) -> Self {
         let (value_request_send, value_request_recv) = channel::unbounded();
         let (value_response_send, value_response_recv) = channel::unbounded();
         let mut join_handles = Vec::new();
@@ -46,9 +210,16 @@ impl StateValueReader {
                 store.clone(),
                 value_request_recv.clone(),
                 value_response_send.clone(),
+                trust_refs,
             ));
         }
-        Self { pending_requests: 0, value_request_send, value_response_recv, join_handles }
+        Self {
+            pending_requests: 0,
+            trust_refs,
+            value_request_send,
+            value_response_recv,
+            join_handles,
+        }
     }
 
     fn submit(&mut self, shard_uid: ShardUId, value_hash: CryptoHash) {
@@ -57,45 +228,408 @@ impl StateValueReader {
         self.pending_requests += 1;
     }
 
-    fn receive_all(&mut self) -> HashMap<CryptoHash, Vec<u8>> {
+    /// Returns `Err` if some values are still not received at `deadline`,
+    /// e.g. because a reader thread is wedged on a database read, or if a
+    /// value can't be read while the references are trusted.
+    fn receive_all(
+        &mut self,
+        deadline: Option<Instant>,
+    ) -> Result<HashMap<CryptoHash, Vec<u8>>, RangeStopped> {
         let mut ret = HashMap::new();
         while self.pending_requests > 0 {
-            let resp = self.value_response_recv.recv().expect("recv should not fail here");
-            if let Some(value) = resp.value_bytes {
-                ret.insert(resp.value_hash, value);
+            let resp = match deadline {
+                Some(deadline) => match self.value_response_recv.recv_deadline(deadline) {
+                    Ok(resp) => resp,
+                    Err(channel::RecvTimeoutError::Timeout) => {
+                        warn!(target: "store", pending_requests = %self.pending_requests, "Timed out waiting for values read from State");
+                        return Err(RangeStopped::DeadlineExceeded);
+                    }
+                    Err(channel::RecvTimeoutError::Disconnected) => {
+                        panic!("recv should not fail here")
+                    }
+                },
+                None => self.value_response_recv.recv().expect("recv should not fail here"),
+            };
+            match resp.value_bytes {
+                Ok(Some(value)) => {
+                    ret.insert(resp.value_hash, value);
+                }
+                Ok(None) if self.trust_refs => {
+                    return Err(RangeStopped::Failed(InliningMigrationError::MissingValue {
+                        value_hash: resp.value_hash,
+                    }));
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    return Err(RangeStopped::Failed(InliningMigrationError::ReadValues(err)));
+                }
             }
             self.pending_requests -= 1;
         }
-        ret
+        Ok(ret)
     }
 
     fn spawn_read_value_thread(
         store: Store,
         recv: channel::Receiver<ReadValueRequest>,
         send: channel::Sender<ReadValueResponse>,
+        trust_refs: bool,
     ) -> std::thread::JoinHandle<()> {
+        let batch_size =
+            if trust_refs { TRUSTED_READ_VALUE_BATCH_SIZE } else { READ_VALUE_BATCH_SIZE };
         std::thread::spawn(move || {
+            let mut trie_storages: HashMap<ShardUId, TrieDBStorage> = HashMap::new();
             while let Ok(req) = recv.recv() {
-                let trie_storage = TrieDBStorage::new(store.clone(), req.shard_uid);
-                let bytes = match trie_storage.retrieve_raw_bytes(&req.value_hash) {
-                    Ok(bytes) => Some(bytes.to_vec()),
-                    Err(err) => {
-                        log_skipped("failed to read value from State", err);
-                        None
+                FLAT_STATE_INLINING_READERS_BUSY.inc();
+                // Requests that are already waiting get read together with
+                // this one, which saves per-request overhead of the database.
+                let mut value_hashes_by_shard: HashMap<ShardUId, Vec<CryptoHash>> =
+                    HashMap::from([(req.shard_uid, vec![req.value_hash])]);
+                for req in recv.try_iter().take(batch_size - 1) {
+                    value_hashes_by_shard.entry(req.shard_uid).or_default().push(req.value_hash);
+                }
+                for (shard_uid, value_hashes) in value_hashes_by_shard {
+                    let values: Vec<Result<Option<Vec<u8>>, String>> = if trust_refs {
+                        match read_trusted_values(&store, shard_uid, &value_hashes) {
+                            Ok(values) => values.into_iter().map(Ok).collect(),
+                            Err(err) => vec![Err(err.to_string()); value_hashes.len()],
+                        }
+                    } else {
+                        let trie_storage = trie_storages
+                            .entry(shard_uid)
+                            .or_insert_with(|| TrieDBStorage::new(store.clone(), shard_uid));
+                        trie_storage
+                            .retrieve_raw_bytes_multi(&value_hashes)
+                            .into_iter()
+                            .map(|value| match value {
+                                Ok(bytes) => Ok(Some(bytes.to_vec())),
+                                Err(err) => {
+                                    log_skipped("read_value", err);
+                                    Ok(None)
+                                }
+                            })
+                            .collect()
+                    };
+                    for (value_hash, bytes) in value_hashes.into_iter().zip(values) {
+                        send.send(ReadValueResponse { value_hash, value_bytes: bytes })
+                            .expect("send should not fail here");
                     }
-                };
-                send.send(ReadValueResponse { value_hash: req.value_hash, value_bytes: bytes })
-                    .expect("send should not fail here");
+                }
+                FLAT_STATE_INLINING_READERS_BUSY.dec();
             }
         })
     }
 
+    /// Waits for the reader threads to finish. Threads still running after
+    /// `timeout` are detached, and `false` is returned.
+    ///
     /// Note that we cannot use standard `drop` because it takes `&mut self`
     /// as an argument which prevents manual drop of `self.value_request_send`
-    fn close(self) {
+    fn close(self, timeout: Option<Duration>) -> bool {
         std::mem::drop(self.value_request_send);
-        for join_handle in self.join_handles {
-            join_handle.join().expect("join should not fail here");
+        let deadline = match timeout {
+            Some(timeout) => Instant::now() + timeout,
+            None => {
+                for join_handle in self.join_handles {
+                    join_handle.join().expect("join should not fail here");
+                }
+                return true;
+            }
+        };
+        let mut join_handles = self.join_handles;
+        loop {
+            let (finished, running): (Vec<_>, Vec<_>) =
+                join_handles.into_iter().partition(|join_handle| join_handle.is_finished());
+            for join_handle in finished {
+                join_handle.join().expect("join should not fail here");
+            }
+            join_handles = running;
+            if join_handles.is_empty() {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                // Dropping the handles detaches the threads.
+                warn!(target: "store", stuck_threads = %join_handles.len(), pending_requests = %self.pending_requests, "State reader threads didn't stop in time, detaching them");
+                return false;
+            }
+            std::thread::sleep(READER_SHUTDOWN_POLL_INTERVAL);
+        }
+    }
+}
+
+/// Reads the values of `value_hashes` from `State` in a single request.
+/// Unlike `TrieDBStorage::retrieve_raw_bytes_multi()`, a missing value isn't
+/// turned into an error, because the caller doesn't expect one.
+/// Values are returned in the same order as the hashes.
+fn read_trusted_values(
+    store: &Store,
+    shard_uid: ShardUId,
+    value_hashes: &[CryptoHash],
+) -> std::io::Result<Vec<Option<Vec<u8>>>> {
+    let keys: Vec<[u8; 40]> = value_hashes
+        .iter()
+        .map(|hash| TrieCachingStorage::get_key_from_shard_uid_and_hash(shard_uid, hash))
+        .collect();
+    let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_ref()).collect();
+    Ok(store
+        .multi_get(DBCol::State, &keys)?
+        .into_iter()
+        .map(|value| value.map(|value| value.to_vec()))
+        .collect())
+}
+
+/// Keeps FlatState updates disabled while at least one scan worker is
+/// committing inlined values. Updates get re-enabled only when the last worker
+/// leaves its critical section.
+/// Does nothing if there is no `FlatStorageManager`, i.e. when the migration
+/// runs offline.
+struct FlatStateUpdatesPause<'a> {
+    flat_storage_manager: Option<&'a FlatStorageManager>,
+    num_paused: std::sync::Mutex<usize>,
+}
+
+impl<'a> FlatStateUpdatesPause<'a> {
+    fn new(flat_storage_manager: Option<&'a FlatStorageManager>) -> Self {
+        Self { flat_storage_manager, num_paused: std::sync::Mutex::new(0) }
+    }
+
+    /// Updates stay paused until the returned guard is dropped.
+    fn pause(&self) -> FlatStateUpdatesPaused<'_, 'a> {
+        if let Some(flat_storage_manager) = self.flat_storage_manager {
+            let mut num_paused = self.num_paused.lock().expect("lock should not be poisoned");
+            if *num_paused == 0 {
+                flat_storage_manager.set_flat_state_updates_mode(false);
+            }
+            *num_paused += 1;
+        }
+        FlatStateUpdatesPaused { pause: self }
+    }
+
+    fn resume(&self) {
+        if let Some(flat_storage_manager) = self.flat_storage_manager {
+            let mut num_paused = self.num_paused.lock().expect("lock should not be poisoned");
+            *num_paused -= 1;
+            if *num_paused == 0 {
+                flat_storage_manager.set_flat_state_updates_mode(true);
+            }
+        }
+    }
+
+    fn is_offline(&self) -> bool {
+        self.flat_storage_manager.is_none()
+    }
+}
+
+/// Critical section of a worker, during which FlatState updates are paused.
+/// Resumes the updates when dropped, so that a panic or an early return while
+/// committing doesn't leave the node without FlatState updates, which would
+/// wedge block processing.
+#[must_use = "updates are resumed as soon as the guard is dropped"]
+struct FlatStateUpdatesPaused<'p, 'a> {
+    pause: &'p FlatStateUpdatesPause<'a>,
+}
+
+impl Drop for FlatStateUpdatesPaused<'_, '_> {
+    fn drop(&mut self) {
+        self.pause.resume();
+    }
+}
+
+/// A range of FlatState keys that is scanned and inlined independently of
+/// other ranges. Currently every range covers the keys of a single shard.
+struct FlatStateKeyRange {
+    /// Inclusive lower bound.
+    start: Vec<u8>,
+    /// Exclusive upper bound, `None` means unbounded.
+    end: Option<Vec<u8>>,
+}
+
+impl FlatStateKeyRange {
+    /// Checkpoints of migrations with different ids don't affect each other.
+    fn checkpoint_key(&self, migration_id: &str) -> Vec<u8> {
+        let mut key = INLINING_CHECKPOINT_KEY_PREFIX.to_vec();
+        key.extend_from_slice(migration_id.as_bytes());
+        key.push(b':');
+        key.extend_from_slice(&self.start);
+        key
+    }
+}
+
+/// Prefix of `DBCol::BlockMisc` keys storing `InliningCheckpoint` of every
+/// key range.
+const INLINING_CHECKPOINT_KEY_PREFIX: &[u8] = b"FLAT_STATE_INLINING_CHECKPOINT:";
+
+/// Id of the regular inlining migration, see `inline_flat_state_values`.
+pub const DEFAULT_INLINING_MIGRATION_ID: &str = "default";
+
+/// Id of a migration that inlines the values written since an earlier
+/// migration finished, because block processing keeps adding references to
+/// small values. FlatState keys are ordered by shard and trie key, not by the
+/// time of the write, so there is no key or timestamp to resume from, and a
+/// top-up scans the whole column again. Only references get decoded and read
+/// from `State` though, so its cost is dominated by the scan, not by the
+/// values inlined before. A separate id keeps a top-up from resuming an
+/// unfinished regular migration, and the other way around.
+pub const TOP_UP_INLINING_MIGRATION_ID: &str = "top_up";
+
+/// Progress of the migration of a single `FlatStateKeyRange`, which allows an
+/// interrupted migration to resume where it stopped.
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+enum InliningCheckpoint {
+    /// All keys up to and including `last_key` were processed.
+    InProgress { last_key: Vec<u8> },
+    /// All keys of the range were processed.
+    Done,
+}
+
+/// Splits FlatState into ranges, one per shard present in the column.
+fn flat_state_key_ranges(store: &Store) -> Vec<FlatStateKeyRange> {
+    let mut ranges = vec![];
+    let mut lower_bound: Option<Vec<u8>> = None;
+    // Every iteration seeks to the first key of the next shard.
+    while let Some(Ok((key, _))) =
+        store.iter_range(DBCol::FlatState, lower_bound.as_deref(), None).next()
+    {
+        let shard_uid = match decode_flat_state_db_key(&key) {
+            Ok((shard_uid, _)) => shard_uid,
+            Err(err) => {
+                log_skipped("decode_key", err);
+                break;
+            }
+        };
+        let start = shard_uid.to_bytes().to_vec();
+        let end = next_prefix(&start);
+        ranges.push(FlatStateKeyRange { start, end: end.clone() });
+        match end {
+            Some(end) => lower_bound = Some(end),
+            None => break,
+        }
+    }
+    ranges
+}
+
+/// Narrows every range of `flat_state_key_ranges` to the keys whose trie key
+/// starts with one of `trie_key_prefixes`. A prefix extending another prefix
+/// is dropped, so that no key is scanned twice.
+fn restrict_to_trie_key_prefixes(
+    ranges: Vec<FlatStateKeyRange>,
+    trie_key_prefixes: &[Vec<u8>],
+) -> Vec<FlatStateKeyRange> {
+    let mut sorted_prefixes = trie_key_prefixes.to_vec();
+    sorted_prefixes.sort();
+    // Once sorted, the prefixes extending a prefix directly follow it.
+    let mut prefixes: Vec<Vec<u8>> = vec![];
+    for prefix in sorted_prefixes {
+        if prefixes.last().map_or(true, |last| !prefix.starts_with(last)) {
+            prefixes.push(prefix);
+        }
+    }
+    let mut restricted = vec![];
+    for range in ranges {
+        // Every range starts with the `ShardUId` of its keys.
+        for prefix in &prefixes {
+            let start = [range.start.as_slice(), prefix.as_slice()].concat();
+            let end = next_prefix(&start);
+            restricted.push(FlatStateKeyRange { start, end });
+        }
+    }
+    restricted
+}
+
+/// Returns the smallest key that is greater than all keys starting with
+/// `prefix`, or `None` if no such key exists.
+fn next_prefix(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut next = prefix.to_vec();
+    while let Some(last) = next.pop() {
+        if last < u8::MAX {
+            next.push(last + 1);
+            return Some(next);
+        }
+    }
+    None
+}
+
+/// Options of `inline_flat_state_values`.
+#[derive(Clone, Debug)]
+pub struct InliningMigrationOptions {
+    /// Namespaces the checkpoints, so that migrations with different ids
+    /// don't resume from each other's checkpoints. Normally
+    /// `DEFAULT_INLINING_MIGRATION_ID`.
+    pub migration_id: String,
+    /// Number of threads for reading values from `State` in parallel, split
+    /// evenly between the scan threads.
+    pub read_state_threads: usize,
+    /// Number of FlatState entries to be read in one batch.
+    pub scan_batch_size: usize,
+    /// Maximum number of values inlined in one commit. A batch having more
+    /// values to inline gets committed in several parts, which bounds the
+    /// pauses of FlatState updates and the size of the writes. `None` means
+    /// `scan_batch_size`.
+    pub commit_batch_size: Option<usize>,
+    /// Maximum number of values read from `State` that are kept in memory at
+    /// once. A batch having more values to inline gets committed in several
+    /// sub-batches, each of them within its own pause of FlatState updates.
+    /// `None` means that the whole batch is buffered.
+    pub max_buffered_values: Option<usize>,
+    /// Number of key ranges to be scanned in parallel.
+    pub scan_threads: usize,
+    /// Commit batches without pausing FlatState updates. The caller must
+    /// guarantee that nothing else writes to the database during the
+    /// migration, e.g. by running it on a copy of the database without a node.
+    pub offline: bool,
+    /// Stop the migration if it doesn't finish in time. Reader threads that
+    /// are stuck, e.g. on a database read, get detached, FlatState updates get
+    /// re-enabled and an error is returned. `None` means no timeout.
+    pub timeout: Option<Duration>,
+    /// How often the numbers of processed and inlined values of the whole
+    /// migration are logged, regardless of the batch size. `None` disables
+    /// the progress logs.
+    pub progress_log_interval: Option<Duration>,
+    /// How long every scan thread sleeps after a batch, to leave CPU to the
+    /// node, e.g. block processing, when the migration runs on a live node.
+    /// Slows the migration down accordingly. Zero disables it.
+    pub inter_batch_sleep: Duration,
+    /// Inline only the values of the given shard, starting from the given
+    /// FlatState key, e.g. to re-run the migration of a part of a shard. The
+    /// key must belong to the shard. The run is checkpointed separately from
+    /// a run over the whole shard. Can't be combined with `trie_key_prefixes`.
+    pub start_from: Option<(ShardUId, Vec<u8>)>,
+    /// Assume that every FlatState reference to a value to be inlined
+    /// resolves in `State`, and read the values in larger batches. This holds
+    /// e.g. on a node freshly synced from state parts, or if
+    /// `verify_flat_state_value_refs` found no unresolved references, as long
+    /// as nothing modifies the database meanwhile. On a live node, garbage
+    /// collection removes values whose references are about to be
+    /// overwritten, so trusting the references requires `offline`. A reference
+    /// that doesn't resolve after all, or a failed read, stops the migration
+    /// with an error instead of being skipped, and the migration resumes from
+    /// the last checkpoint once the database is fixed.
+    pub trust_refs: bool,
+    /// Inline only the values whose trie key, i.e. the FlatState key without
+    /// the `ShardUId`, starts with one of the given prefixes, e.g. to inline
+    /// the values of a single account. Only the keys with these prefixes are
+    /// scanned, every prefix of every shard as a separate range, which is
+    /// checkpointed separately from a run over the whole shard. `None` means
+    /// all values.
+    pub trie_key_prefixes: Option<Vec<Vec<u8>>>,
+}
+
+impl Default for InliningMigrationOptions {
+    fn default() -> Self {
+        Self {
+            migration_id: DEFAULT_INLINING_MIGRATION_ID.to_string(),
+            read_state_threads: 16,
+            scan_batch_size: 50_000,
+            commit_batch_size: None,
+            max_buffered_values: None,
+            scan_threads: 1,
+            offline: false,
+            timeout: None,
+            progress_log_interval: None,
+            inter_batch_sleep: Duration::ZERO,
+            start_from: None,
+            trust_refs: false,
+            trie_key_prefixes: None,
         }
     }
 }
@@ -105,42 +639,350 @@ impl StateValueReader {
 /// is achieved by temporary preventing FlatState updates with
 /// `FlatStorageManager::set_flat_state_updates_mode`.
 ///
-/// * `read_state_threads` - number of threads for reading values from `State` in parallel.
-/// * `batch_size` - number of values to be processed for inlining in one batch.
+/// FlatState is split into key ranges which are scanned in parallel.
+/// Progress of every range is checkpointed after every batch, and an
+/// interrupted migration resumes from the checkpoints.
+///
+/// Returns the counts of this run.
 pub fn inline_flat_state_values(
     store: Store,
     flat_storage_manager: &FlatStorageManager,
+    options: &InliningMigrationOptions,
+) -> Result<InliningMigrationStats, InliningMigrationError> {
+    let &InliningMigrationOptions {
+        ref migration_id,
+        read_state_threads,
+        scan_batch_size,
+        commit_batch_size,
+        max_buffered_values,
+        scan_threads,
+        offline,
+        timeout,
+        progress_log_interval,
+        inter_batch_sleep,
+        ref start_from,
+        trust_refs,
+        ref trie_key_prefixes,
+    } = options;
+    assert!(
+        !offline || !flat_storage_manager.has_flat_storages(),
+        "Offline FlatState inlining requires exclusive access to the database, but flat storage is in use"
+    );
+    assert!(
+        !trust_refs || offline,
+        "FlatState inlining can trust value references only when running offline"
+    );
+    assert!(
+        start_from.is_none() || trie_key_prefixes.is_none(),
+        "FlatState inlining can't both start from a key and be restricted to trie key prefixes"
+    );
+    let scan_threads = std::cmp::max(1, scan_threads);
+    let commit_batch_size = std::cmp::max(1, commit_batch_size.unwrap_or(scan_batch_size));
+    let max_buffered_values = std::cmp::max(1, max_buffered_values.unwrap_or(scan_batch_size));
+    info!(target: "store", %migration_id, %read_state_threads, %scan_batch_size, %commit_batch_size, %max_buffered_values, %scan_threads, %offline, ?timeout, ?progress_log_interval, ?inter_batch_sleep, ?start_from, %trust_refs, ?trie_key_prefixes, "Starting FlatState value inlining migration");
+    let migration_start = std::time::Instant::now();
+    let deadline = timeout.map(|timeout| migration_start + timeout);
+    let reader_shutdown_timeout =
+        timeout.map(|timeout| std::cmp::min(timeout, READER_SHUTDOWN_TIMEOUT));
+    let ranges = match start_from {
+        None => match trie_key_prefixes {
+            Some(trie_key_prefixes) => {
+                restrict_to_trie_key_prefixes(flat_state_key_ranges(&store), trie_key_prefixes)
+            }
+            None => flat_state_key_ranges(&store),
+        },
+        &Some((shard_uid, ref key)) => {
+            let key = key.clone().into_boxed_slice();
+            match decode_flat_state_db_key(&key) {
+                Ok((key_shard_uid, _)) if key_shard_uid == shard_uid => {}
+                _ => {
+                    return Err(InliningMigrationError::InvalidStartKey {
+                        shard_uid,
+                        key: key.into_vec(),
+                    })
+                }
+            }
+            vec![FlatStateKeyRange {
+                start: key.into_vec(),
+                end: next_prefix(&shard_uid.to_bytes()),
+            }]
+        }
+    };
+    let pause = FlatStateUpdatesPause::new(if offline { None } else { Some(flat_storage_manager) });
+    let next_range_index = AtomicUsize::new(0);
+    let inlined_total_count = AtomicUsize::new(0);
+    let timed_out = AtomicBool::new(false);
+    // The first error of a scan thread, which stops the other scan threads
+    // once their current ranges are done.
+    let failure = std::sync::Mutex::new(None);
+    let progress = InliningProgress::default();
+    let read_state_threads_per_range = std::cmp::max(1, read_state_threads / scan_threads);
+    std::thread::scope(|scope| {
+        // Dropped once all scan threads are finished, which stops the
+        // progress logger.
+        let (progress_done_send, progress_done_recv) = channel::bounded::<()>(0);
+        if let Some(interval) = progress_log_interval {
+            let progress = &progress;
+            scope.spawn(move || {
+                progress.log_periodically(interval, progress_done_recv, migration_start)
+            });
+        }
+        let mut scan_handles = vec![];
+        for _ in 0..std::cmp::min(scan_threads, ranges.len()) {
+            scan_handles.push(scope.spawn(|| {
+                let mut value_reader =
+                    StateValueReader::new(store.clone(), read_state_threads_per_range, trust_refs);
+                while failure.lock().expect("lock should not be poisoned").is_none() {
+                    let range = match ranges.get(next_range_index.fetch_add(1, Ordering::Relaxed)) {
+                        Some(range) => range,
+                        None => break,
+                    };
+                    match inline_range(
+                        &store,
+                        &pause,
+                        &mut value_reader,
+                        range,
+                        migration_id,
+                        scan_batch_size,
+                        commit_batch_size,
+                        max_buffered_values,
+                        inter_batch_sleep,
+                        migration_start,
+                        deadline,
+                        &progress,
+                    ) {
+                        Ok(inlined_range_count) => {
+                            inlined_total_count.fetch_add(inlined_range_count, Ordering::Relaxed);
+                        }
+                        Err(RangeStopped::DeadlineExceeded) => {
+                            timed_out.store(true, Ordering::Relaxed);
+                            break;
+                        }
+                        Err(RangeStopped::Failed(err)) => {
+                            failure.lock().expect("lock should not be poisoned").get_or_insert(err);
+                            break;
+                        }
+                    }
+                }
+                if !value_reader.close(reader_shutdown_timeout) {
+                    timed_out.store(true, Ordering::Relaxed);
+                }
+            }));
+        }
+        for scan_handle in scan_handles {
+            scan_handle.join().expect("join should not fail here");
+        }
+        std::mem::drop(progress_done_send);
+    });
+    let inlined_total_count = inlined_total_count.into_inner();
+    let migration_elapsed = migration_start.elapsed();
+    if let Some(err) = failure.into_inner().expect("lock should not be poisoned") {
+        if !offline {
+            flat_storage_manager.set_flat_state_updates_mode(true);
+        }
+        error!(target: "store", %inlined_total_count, ?migration_elapsed, %err, "FlatState value inlining migration failed");
+        FLAT_STATE_INLINING_MIGRATION_RUNNING_SECONDS.set(migration_elapsed.as_secs_f64());
+        return Err(err);
+    }
+    if timed_out.into_inner() {
+        // Every pause is matched by a resume, but a node must not be left
+        // without FlatState updates in any case.
+        if !offline {
+            flat_storage_manager.set_flat_state_updates_mode(true);
+        }
+        let num_started_ranges = std::cmp::min(next_range_index.into_inner(), ranges.len());
+        error!(target: "store", %inlined_total_count, %num_started_ranges, num_ranges = %ranges.len(), ?migration_elapsed, "FlatState value inlining migration timed out");
+        FLAT_STATE_INLINING_MIGRATION_RUNNING_SECONDS.set(migration_elapsed.as_secs_f64());
+        return Err(InliningMigrationError::Timeout(
+            timeout.expect("only a migration with a timeout can time out"),
+        ));
+    }
+    // All ranges are done, a future migration needs to start from scratch.
+    let mut store_update = store.store_update();
+    for range in &ranges {
+        store_update.delete(DBCol::BlockMisc, &range.checkpoint_key(migration_id));
+    }
+    store_update.commit().expect("failed to delete inlining checkpoints");
+    FLAT_STATE_INLINING_MIGRATION_RUNNING_SECONDS.set(migration_elapsed.as_secs_f64());
+    FLAT_STATE_INLINING_MIGRATION_SECONDS.set(migration_elapsed.as_secs_f64());
+    let value_size_histogram = VALUE_SIZE_BUCKET_BOUNDS
+        .iter()
+        .map(|bound| Some(*bound))
+        .chain(std::iter::once(None))
+        .zip(progress.value_size_counts)
+        .map(|(max_size, count)| ValueSizeBucket { max_size, count: count.into_inner() })
+        .collect();
+    let stats = InliningMigrationStats {
+        schema_version: INLINING_MIGRATION_STATS_SCHEMA_VERSION,
+        processed_count: progress.processed_count.into_inner() as u64,
+        inlined_count: inlined_total_count as u64,
+        too_large_count: progress.too_large_count.into_inner() as u64,
+        processed_values_size: progress.processed_values_size.into_inner(),
+        inlined_values_size: progress.inlined_values_size.into_inner(),
+        value_size_histogram,
+        elapsed_secs: migration_elapsed.as_secs_f64(),
+    };
+    info!(target: "store", ?stats, "Finished FlatState value inlining migration");
+    Ok(stats)
+}
+
+/// Result of `verify_flat_state_value_refs`.
+#[derive(Debug, Default, PartialEq, Eq, serde::Serialize)]
+pub struct ValueRefsVerification {
+    /// Number of references to values that would be inlined, and which are
+    /// present in `State`.
+    pub resolved: usize,
+    /// Number of references to values that would be inlined, but which are
+    /// missing in `State` or have a different length.
+    pub unresolved: usize,
+}
+
+/// Checks that every FlatState value that would be inlined by
+/// `inline_flat_state_values` can be read from `State`, without writing
+/// anything. Meant to be run before the migration, to find dangling
+/// references before they get skipped by the migration.
+///
+/// * `read_state_threads` - number of threads for reading values from `State` in parallel.
+/// * `batch_size` - number of values to be read in one batch.
+pub fn verify_flat_state_value_refs(
+    store: &Store,
     read_state_threads: usize,
     batch_size: usize,
-) {
-    info!(target: "store", %read_state_threads, %batch_size, "Starting FlatState value inlining migration");
-    let migration_start = std::time::Instant::now();
-    let mut value_reader = StateValueReader::new(store.clone(), read_state_threads);
+) -> ValueRefsVerification {
+    info!(target: "store", %read_state_threads, %batch_size, "Starting verification of FlatState value references");
+    let verification_start = std::time::Instant::now();
+    let mut value_reader = StateValueReader::new(store.clone(), read_state_threads, false);
+    let mut verification = ValueRefsVerification::default();
+    for batch in store.iter(DBCol::FlatState).chunks(batch_size).into_iter() {
+        let mut value_refs = vec![];
+        for entry in batch {
+            let (key, value) = match entry {
+                Ok(v) => v,
+                Err(err) => {
+                    log_skipped("rocksdb_iter", err);
+                    continue;
+                }
+            };
+            let shard_uid = match decode_flat_state_db_key(&key) {
+                Ok((shard_uid, _)) => shard_uid,
+                Err(err) => {
+                    log_skipped("decode_key", err);
+                    continue;
+                }
+            };
+            match FlatStateValue::try_from_slice(&value) {
+                Ok(FlatStateValue::Ref(value_ref))
+                    if value_ref.length as usize <= INLINE_DISK_VALUE_THRESHOLD =>
+                {
+                    value_reader.submit(shard_uid, value_ref.hash);
+                    value_refs.push((key, value_ref));
+                }
+                Ok(_) => {}
+                Err(err) => log_skipped("decode_value", err),
+            }
+        }
+        let hash_to_value = match value_reader.receive_all(None) {
+            Ok(hash_to_value) => hash_to_value,
+            Err(_) => unreachable!("there is no deadline, and references aren't trusted"),
+        };
+        for (key, value_ref) in value_refs {
+            match hash_to_value.get(&value_ref.hash) {
+                Some(value) if value.len() == value_ref.length as usize => {
+                    verification.resolved += 1;
+                }
+                _ => {
+                    debug!(target: "store", ?key, ?value_ref, "FlatState value reference doesn't resolve in State");
+                    verification.unresolved += 1;
+                }
+            }
+        }
+    }
+    value_reader.close(None);
+    let verification_elapsed = verification_start.elapsed();
+    info!(target: "store", ?verification, ?verification_elapsed, "Finished verification of FlatState value references");
+    verification
+}
+
+/// Inlines values of a single key range starting from its checkpoint.
+/// Returns the number of inlined values, or `Err` if the range isn't done at
+/// `deadline` or values can't be read. Then the range resumes from its
+/// checkpoint next time.
+/// `migration_start` is used to report the elapsed time of the whole migration
+/// after every batch. Processed and inlined values are added to `progress` as
+/// they happen, so that the progress logs don't depend on the batch size.
+fn inline_range(
+    store: &Store,
+    pause: &FlatStateUpdatesPause,
+    value_reader: &mut StateValueReader,
+    range: &FlatStateKeyRange,
+    migration_id: &str,
+    scan_batch_size: usize,
+    commit_batch_size: usize,
+    max_buffered_values: usize,
+    inter_batch_sleep: Duration,
+    migration_start: std::time::Instant,
+    deadline: Option<Instant>,
+    progress: &InliningProgress,
+) -> Result<usize, RangeStopped> {
+    let checkpoint_key = range.checkpoint_key(migration_id);
+    let lower_bound = match store
+        .get_ser::<InliningCheckpoint>(DBCol::BlockMisc, &checkpoint_key)
+        .expect("failed to read inlining checkpoint")
+    {
+        None => range.start.clone(),
+        Some(InliningCheckpoint::InProgress { mut last_key }) => {
+            // The smallest key after `last_key`.
+            last_key.push(0u8);
+            last_key
+        }
+        Some(InliningCheckpoint::Done) => {
+            debug!(target: "store", range_start = ?range.start, "Skipping already inlined FlatState range");
+            return Ok(0);
+        }
+    };
     let mut inlined_total_count = 0;
-    for (batch_index, batch) in
-        store.iter(DBCol::FlatState).chunks(batch_size).into_iter().enumerate()
+    for (batch_index, batch) in store
+        .iter_range(DBCol::FlatState, Some(&lower_bound), range.end.as_deref())
+        .chunks(scan_batch_size)
+        .into_iter()
+        .enumerate()
     {
-        let (mut min_key, mut max_key) = (None, None);
+        if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+            return Err(RangeStopped::DeadlineExceeded);
+        }
+        // Keys and hashes of the values to be inlined, in key order.
+        let mut value_refs = vec![];
+        let mut last_key = None;
         for entry in batch {
             PROCESSED_COUNT.inc();
+            progress.processed_count.fetch_add(1, Ordering::Relaxed);
             let (key, value) = match entry {
                 Ok(v) => v,
                 Err(err) => {
-                    log_skipped("rocksdb iterator error", err);
+                    log_skipped("rocksdb_iter", err);
                     continue;
                 }
             };
+            last_key = Some(key.to_vec());
             let shard_uid = match decode_flat_state_db_key(&key) {
                 Ok((shard_uid, _)) => shard_uid,
                 Err(err) => {
-                    log_skipped("failed to decode FlatState key", err);
+                    log_skipped("decode_key", err);
                     continue;
                 }
             };
+            // Inlined values are skipped without being copied, which keeps
+            // scans of an already migrated column cheap.
+            if let Some(value_size) = inlined_value_size(&value) {
+                PROCESSED_TOTAL_VALUES_SIZE.inc_by(value_size);
+                progress.observe_value_size(value_size);
+                FLAT_STATE_VALUE_SIZE_BYTES.observe(value_size as f64);
+                continue;
+            }
             let fs_value = match FlatStateValue::try_from_slice(&value) {
                 Ok(fs_value) => fs_value,
                 Err(err) => {
-                    log_skipped("failed to deserialise FlatState value", err);
+                    log_skipped("decode_value", err);
                     continue;
                 }
             };
@@ -149,81 +991,322 @@ pub fn inline_flat_state_values(
                 FlatStateValue::Inlined(bytes) => bytes.len() as u64,
             };
             PROCESSED_TOTAL_VALUES_SIZE.inc_by(value_size);
+            progress.observe_value_size(value_size);
+            FLAT_STATE_VALUE_SIZE_BYTES.observe(value_size as f64);
             if let FlatStateValue::Ref(value_ref) = fs_value {
                 if value_ref.length as usize <= INLINE_DISK_VALUE_THRESHOLD {
-                    if min_key.is_none() {
-                        min_key = Some(key.to_vec());
-                    }
-                    max_key = Some(key.to_vec());
-                    INLINED_TOTAL_VALUES_SIZE.inc_by(value_size);
-                    value_reader.submit(shard_uid, value_ref.hash);
+                    value_refs.push((key.to_vec(), shard_uid, value_ref.hash));
+                } else {
+                    progress.too_large_count.fetch_add(1, Ordering::Relaxed);
                 }
             }
         }
-        let hash_to_value = value_reader.receive_all();
         let mut inlined_batch_count = 0;
         let mut batch_duration = std::time::Duration::ZERO;
-        if !hash_to_value.is_empty() {
-            // Here we need to re-read the latest FlatState values in `min_key..=max_key` range
-            // while updates are disabled. This way we prevent updating the values that
-            // were updated since migration start.
-            let batch_inlining_start = std::time::Instant::now();
-            flat_storage_manager.set_flat_state_updates_mode(false);
-            let mut store_update = store.store_update();
-            // rockdb API accepts the exclusive end of the range, so we append
-            // `0u8` here to make sure `max_key` is included in the range
-            let upper_bound_key = max_key.map(|mut v| {
-                v.push(0u8);
-                v
-            });
-            for (key, value) in store
-                .iter_range(DBCol::FlatState, min_key.as_deref(), upper_bound_key.as_deref())
-                .flat_map(|v| v)
-            {
-                if let Ok(FlatStateValue::Ref(value_ref)) = FlatStateValue::try_from_slice(&value) {
-                    if let Some(value) = hash_to_value.get(&value_ref.hash) {
-                        store_update.set(
-                            DBCol::FlatState,
-                            &key,
-                            &FlatStateValue::inlined(value)
-                                .try_to_vec()
-                                .expect("borsh should not fail here"),
-                        );
-                        inlined_batch_count += 1;
-                        INLINED_COUNT.inc();
+        // The checkpoint is committed together with the last commit of the
+        // last sub-batch, so that an interrupted batch gets processed again
+        // from its start. Values inlined by the earlier commits are not
+        // references anymore and get skipped then.
+        let mut checkpoint_update = store.store_update();
+        if let Some(last_key) = last_key {
+            checkpoint_update
+                .set_ser(
+                    DBCol::BlockMisc,
+                    &checkpoint_key,
+                    &InliningCheckpoint::InProgress { last_key },
+                )
+                .expect("borsh should not fail here");
+        }
+        let mut checkpoint_update = Some(checkpoint_update);
+        let mut sub_batches = value_refs.chunks(max_buffered_values).peekable();
+        while let Some(sub_batch) = sub_batches.next() {
+            for (_, shard_uid, value_hash) in sub_batch {
+                value_reader.submit(*shard_uid, *value_hash);
+            }
+            let hash_to_value = value_reader.receive_all(deadline)?;
+            let mut commit_batches = sub_batch.chunks(commit_batch_size).peekable();
+            while let Some(commit_batch) = commit_batches.next() {
+                let store_update =
+                    if sub_batches.peek().is_none() && commit_batches.peek().is_none() {
+                        checkpoint_update.take().expect("checkpoint is committed only once")
+                    } else {
+                        store.store_update()
+                    };
+                // Keys are scanned in order, so the commit covers exactly the
+                // keys between its first and its last key.
+                let min_key = &commit_batch.first().expect("chunks are not empty").0;
+                let max_key = &commit_batch.last().expect("chunks are not empty").0;
+                let (inlined_count, inlined_size, duration) = commit_inlined_values(
+                    store,
+                    pause,
+                    store_update,
+                    &hash_to_value,
+                    min_key,
+                    max_key,
+                );
+                inlined_batch_count += inlined_count;
+                batch_duration += duration;
+                progress.inlined_count.fetch_add(inlined_count, Ordering::Relaxed);
+                progress.inlined_values_size.fetch_add(inlined_size, Ordering::Relaxed);
+            }
+        }
+        if let Some(checkpoint_update) = checkpoint_update {
+            checkpoint_update.commit().expect("failed to commit inlining checkpoint");
+        }
+        inlined_total_count += inlined_batch_count;
+        FLAT_STATE_INLINING_MIGRATION_RUNNING_SECONDS.set(migration_start.elapsed().as_secs_f64());
+        trace!(target: "store", range_start = ?range.start, %batch_index, %inlined_batch_count, %inlined_total_count, ?batch_duration, "Processed flat state value inlining batch");
+        if !inter_batch_sleep.is_zero() {
+            // Don't sleep past the deadline.
+            let sleep = match deadline {
+                Some(deadline) => std::cmp::min(
+                    inter_batch_sleep,
+                    deadline.saturating_duration_since(Instant::now()),
+                ),
+                None => inter_batch_sleep,
+            };
+            std::thread::sleep(sleep);
+        }
+    }
+    let mut store_update = store.store_update();
+    store_update
+        .set_ser(DBCol::BlockMisc, &checkpoint_key, &InliningCheckpoint::Done)
+        .expect("borsh should not fail here");
+    store_update.commit().expect("failed to commit inlining checkpoint");
+    Ok(inlined_total_count)
+}
+
+/// Inlines the values of `hash_to_value` referenced by FlatState entries in
+/// `min_key..=max_key` and commits them with `store_update`.
+/// Returns the number and the total size of inlined values, and the time
+/// spent with FlatState updates paused.
+fn commit_inlined_values(
+    store: &Store,
+    pause: &FlatStateUpdatesPause,
+    mut store_update: StoreUpdate,
+    hash_to_value: &HashMap<CryptoHash, Vec<u8>>,
+    min_key: &[u8],
+    max_key: &[u8],
+) -> (usize, u64, std::time::Duration) {
+    if hash_to_value.is_empty() {
+        store_update.commit().expect("failed to commit inlining checkpoint");
+        return (0, 0, std::time::Duration::ZERO);
+    }
+    let mut inlined_count = 0;
+    let mut inlined_size = 0;
+    // Here we need to re-read the latest FlatState values in `min_key..=max_key` range
+    // while updates are disabled. This way we prevent updating the values that
+    // were updated since migration start.
+    let inlining_start = std::time::Instant::now();
+    let paused = pause.pause();
+    // rockdb API accepts the exclusive end of the range, so we append
+    // `0u8` here to make sure `max_key` is included in the range
+    let mut upper_bound_key = max_key.to_vec();
+    upper_bound_key.push(0u8);
+    for (key, value) in
+        store.iter_range(DBCol::FlatState, Some(min_key), Some(&upper_bound_key)).flat_map(|v| v)
+    {
+        if let Ok(FlatStateValue::Ref(value_ref)) = FlatStateValue::try_from_slice(&value) {
+            if let Some(value) = hash_to_value.get(&value_ref.hash) {
+                let (serialized_value, serialized_size) = match inline_value_ref(&value_ref, value)
+                {
+                    Ok(inlined) => inlined,
+                    Err(err) => {
+                        log_skipped("length_mismatch", err);
+                        continue;
                     }
-                }
+                };
+                store_update.set(DBCol::FlatState, &key, &serialized_value);
+                inlined_count += 1;
+                inlined_size += value.len() as u64;
+                INLINED_COUNT.inc();
+                INLINED_TOTAL_VALUES_SIZE.inc_by(value.len() as u64);
+                INLINED_TOTAL_SERIALIZED_SIZE.inc_by(serialized_size);
             }
-            store_update.commit().expect("failed to commit inlined values");
-            flat_storage_manager.set_flat_state_updates_mode(true);
-            inlined_total_count += inlined_batch_count;
-            batch_duration = batch_inlining_start.elapsed();
-            FLAT_STATE_PAUSED_DURATION.observe(batch_duration.as_secs_f64());
         }
-        debug!(target: "store", %batch_index, %inlined_batch_count, %inlined_total_count, ?batch_duration, "Processed flat state value inlining batch");
     }
-    value_reader.close();
-    let migration_elapsed = migration_start.elapsed();
-    info!(target: "store", %inlined_total_count, ?migration_elapsed, "Finished FlatState value inlining migration");
+    store_update.commit().expect("failed to commit inlined values");
+    drop(paused);
+    let duration = inlining_start.elapsed();
+    if !pause.is_offline() {
+        FLAT_STATE_PAUSED_DURATION.observe(duration.as_secs_f64());
+        FLAT_STATE_PAUSED_SECONDS_TOTAL.inc_by(duration.as_secs_f64());
+    }
+    (inlined_count, inlined_size, duration)
+}
+
+/// Converts the value referenced by `value_ref` into an inlined FlatState
+/// value, serialized as it is stored in FlatState.
+/// Returns the serialized value and its size, or an error if `value` doesn't
+/// have the length of the referenced value.
+fn inline_value_ref(value_ref: &ValueRef, value: &[u8]) -> Result<(Vec<u8>, u64), String> {
+    if value.len() != value_ref.length as usize {
+        return Err(format!("expected {} bytes, read {} bytes", value_ref.length, value.len()));
+    }
+    let serialized_value =
+        FlatStateValue::inlined(value).try_to_vec().expect("borsh should not fail here");
+    let serialized_size = serialized_value.len() as u64;
+    Ok((serialized_value, serialized_size))
 }
 
-fn log_skipped(reason: &str, err: impl std::error::Error) {
+/// Returns the size of the value if `serialized_value` is a serialized
+/// `FlatStateValue::Inlined`, without deserializing it.
+fn inlined_value_size(serialized_value: &[u8]) -> Option<u64> {
+    // Borsh serializes the variant index as a byte, followed by the length of
+    // the inlined bytes as a little-endian u32.
+    if serialized_value.len() < 5 || serialized_value[0] != 1 {
+        return None;
+    }
+    let len = u32::from_le_bytes(serialized_value[1..5].try_into().unwrap()) as usize;
+    (serialized_value.len() == 5 + len).then(|| len as u64)
+}
+
+/// `reason` is used as a metric label and must be one of `decode_key`,
+/// `decode_value`, `rocksdb_iter`, `read_value` or `length_mismatch`.
+fn log_skipped(reason: &'static str, err: impl std::fmt::Display) {
     debug!(target: "store", %reason, %err, "Skipped value during FlatState inlining");
-    SKIPPED_COUNT.inc();
+    SKIPPED_COUNT.with_label_values(&[reason]).inc();
 }
 
 #[cfg(test)]
 mod tests {
+    use std::io;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use assert_matches::assert_matches;
     use borsh::{BorshDeserialize, BorshSerialize};
-    use near_primitives::hash::hash;
-    use near_primitives::shard_layout::ShardLayout;
+    use crossbeam::channel;
+    use itertools::Itertools;
+    use near_primitives::hash::{hash, CryptoHash};
+    use near_primitives::shard_layout::{ShardLayout, ShardUId};
 
+    use crate::db::{DBIterator, DBSlice, DBTransaction, Database, StoreStatistics, TestDB};
     use crate::flat::store_helper::encode_flat_state_db_key;
     use crate::flat::types::INLINE_DISK_VALUE_THRESHOLD;
     use crate::flat::{FlatStateValue, FlatStorageManager};
-    use crate::{DBCol, NodeStorage, TrieCachingStorage};
+    use crate::test_utils::create_test_store;
+    use crate::{DBCol, NodeStorage, Store, TrieCachingStorage};
 
-    use super::inline_flat_state_values;
+    use super::{
+        inline_flat_state_values, inline_value_ref, inlined_value_size, read_trusted_values,
+        restrict_to_trie_key_prefixes, verify_flat_state_value_refs, FlatStateKeyRange,
+        FlatStateUpdatesPause, InliningCheckpoint, InliningMigrationError,
+        InliningMigrationOptions, InliningMigrationStats, ValueRefsVerification, ValueSizeBucket,
+        DEFAULT_INLINING_MIGRATION_ID, INLINING_MIGRATION_STATS_SCHEMA_VERSION,
+        TOP_UP_INLINING_MIGRATION_ID, VALUE_SIZE_BUCKET_BOUNDS,
+    };
+
+    /// Reads of `State` block until the sender of `unblock` is dropped, like
+    /// a reader wedged on a RocksDB read. Everything else is served by `db`.
+    struct BlockingStateDB {
+        db: Arc<TestDB>,
+        unblock: channel::Receiver<()>,
+    }
+
+    impl Database for BlockingStateDB {
+        fn get_raw_bytes(&self, col: DBCol, key: &[u8]) -> io::Result<Option<DBSlice<'_>>> {
+            if col == DBCol::State {
+                let _ = self.unblock.recv();
+            }
+            self.db.get_raw_bytes(col, key)
+        }
+
+        fn iter<'a>(&'a self, col: DBCol) -> DBIterator<'a> {
+            self.db.iter(col)
+        }
+
+        fn iter_prefix<'a>(&'a self, col: DBCol, key_prefix: &'a [u8]) -> DBIterator<'a> {
+            self.db.iter_prefix(col, key_prefix)
+        }
+
+        fn iter_range<'a>(
+            &'a self,
+            col: DBCol,
+            lower_bound: Option<&[u8]>,
+            upper_bound: Option<&[u8]>,
+        ) -> DBIterator<'a> {
+            self.db.iter_range(col, lower_bound, upper_bound)
+        }
+
+        fn iter_raw_bytes<'a>(&'a self, col: DBCol) -> DBIterator<'a> {
+            self.db.iter_raw_bytes(col)
+        }
+
+        fn write(&self, batch: DBTransaction) -> io::Result<()> {
+            self.db.write(batch)
+        }
+
+        fn flush(&self) -> io::Result<()> {
+            self.db.flush()
+        }
+
+        fn compact(&self) -> io::Result<()> {
+            self.db.compact()
+        }
+
+        fn get_store_statistics(&self) -> Option<StoreStatistics> {
+            self.db.get_store_statistics()
+        }
+
+        fn create_checkpoint(&self, path: &std::path::Path) -> anyhow::Result<()> {
+            self.db.create_checkpoint(path)
+        }
+    }
+
+    fn write_values(store: &Store, shard_uid: ShardUId, values: &[Vec<u8>]) {
+        let mut store_update = store.store_update();
+        for (i, value) in values.iter().enumerate() {
+            let trie_key =
+                TrieCachingStorage::get_key_from_shard_uid_and_hash(shard_uid, &hash(&value));
+            store_update.increment_refcount(DBCol::State, &trie_key, &value);
+            let fs_key = encode_flat_state_db_key(shard_uid, &[i as u8]);
+            let fs_value = FlatStateValue::value_ref(&value).try_to_vec().unwrap();
+            store_update.set(DBCol::FlatState, &fs_key, &fs_value);
+        }
+        store_update.commit().unwrap();
+    }
+
+    fn read_values(store: &Store) -> Vec<FlatStateValue> {
+        store
+            .iter(DBCol::FlatState)
+            .flat_map(|r| r.map(|(_, v)| FlatStateValue::try_from_slice(&v).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn inline_value_ref_serialized_size() {
+        let value = vec![7u8; 10];
+        let value_ref = FlatStateValue::value_ref(&value).to_value_ref();
+        let (serialized_value, serialized_size) = inline_value_ref(&value_ref, &value).unwrap();
+        assert_eq!(serialized_size, serialized_value.len() as u64);
+        assert_eq!(
+            FlatStateValue::try_from_slice(&serialized_value).unwrap(),
+            FlatStateValue::inlined(&value)
+        );
+        assert_eq!(serialized_value, FlatStateValue::inlined(&value).try_to_vec().unwrap());
+        assert!(inline_value_ref(&value_ref, &value[..9]).is_err());
+    }
+
+    #[test]
+    fn inlined_value_size_without_deserializing() {
+        let value = vec![7u8; 10];
+        assert_eq!(
+            inlined_value_size(&FlatStateValue::inlined(&value).try_to_vec().unwrap()),
+            Some(10)
+        );
+        assert_eq!(
+            inlined_value_size(&FlatStateValue::inlined(&[]).try_to_vec().unwrap()),
+            Some(0)
+        );
+        assert_eq!(
+            inlined_value_size(&FlatStateValue::value_ref(&value).try_to_vec().unwrap()),
+            None
+        );
+        let mut truncated = FlatStateValue::inlined(&value).try_to_vec().unwrap();
+        truncated.pop();
+        assert_eq!(inlined_value_size(&truncated), None);
+    }
 
     #[test]
     fn full_migration() {
@@ -231,24 +1314,260 @@ mod tests {
         let shard_uid = ShardLayout::v0_single_shard().get_shard_uids()[0];
         let values =
             [vec![0], vec![1], vec![2; INLINE_DISK_VALUE_THRESHOLD + 1], vec![3], vec![4], vec![5]];
-        {
-            let mut store_update = store.store_update();
-            for (i, value) in values.iter().enumerate() {
-                let trie_key =
-                    TrieCachingStorage::get_key_from_shard_uid_and_hash(shard_uid, &hash(&value));
-                store_update.increment_refcount(DBCol::State, &trie_key, &value);
-                let fs_key = encode_flat_state_db_key(shard_uid, &[i as u8]);
-                let fs_value = FlatStateValue::value_ref(&value).try_to_vec().unwrap();
-                store_update.set(DBCol::FlatState, &fs_key, &fs_value);
+        write_values(&store, shard_uid, &values);
+        let stats = inline_flat_state_values(
+            store.clone(),
+            &FlatStorageManager::new(store.clone()),
+            &InliningMigrationOptions {
+                read_state_threads: 2,
+                scan_batch_size: 4,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            read_values(&store),
+            vec![
+                FlatStateValue::inlined(&values[0]),
+                FlatStateValue::inlined(&values[1]),
+                FlatStateValue::value_ref(&values[2]),
+                FlatStateValue::inlined(&values[3]),
+                FlatStateValue::inlined(&values[4]),
+                FlatStateValue::inlined(&values[5]),
+            ]
+        );
+        assert_eq!(
+            stats,
+            InliningMigrationStats {
+                schema_version: INLINING_MIGRATION_STATS_SCHEMA_VERSION,
+                processed_count: 6,
+                inlined_count: 5,
+                too_large_count: 1,
+                processed_values_size: 5 + INLINE_DISK_VALUE_THRESHOLD as u64 + 1,
+                inlined_values_size: 5,
+                // Five values of a single byte and one of 4001 bytes.
+                value_size_histogram: VALUE_SIZE_BUCKET_BOUNDS
+                    .iter()
+                    .map(|bound| Some(*bound))
+                    .chain(std::iter::once(None))
+                    .zip([5, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0])
+                    .map(|(max_size, count)| ValueSizeBucket { max_size, count })
+                    .collect(),
+                elapsed_secs: stats.elapsed_secs,
             }
-            store_update.commit().unwrap();
-        }
-        inline_flat_state_values(store.clone(), &FlatStorageManager::new(store.clone()), 2, 4);
+        );
+    }
+
+    /// Values referenced by FlatState are inlined the same way when the
+    /// references are trusted.
+    #[test]
+    fn migration_trusting_refs() {
+        let store = NodeStorage::test_opener().1.open().unwrap().get_hot_store();
+        let shard_uid = ShardLayout::v0_single_shard().get_shard_uids()[0];
+        let values = [vec![0], vec![1], vec![2; INLINE_DISK_VALUE_THRESHOLD + 1], vec![3]];
+        write_values(&store, shard_uid, &values);
+        inline_flat_state_values(
+            store.clone(),
+            &FlatStorageManager::new(store.clone()),
+            &InliningMigrationOptions {
+                read_state_threads: 2,
+                scan_batch_size: 4,
+                offline: true,
+                trust_refs: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            read_values(&store),
+            vec![
+                FlatStateValue::inlined(&values[0]),
+                FlatStateValue::inlined(&values[1]),
+                FlatStateValue::value_ref(&values[2]),
+                FlatStateValue::inlined(&values[3]),
+            ]
+        );
+    }
+
+    /// A reference that doesn't resolve stops a migration trusting the
+    /// references with an error instead of being skipped.
+    #[test]
+    fn migration_trusting_missing_ref() {
+        let store = NodeStorage::test_opener().1.open().unwrap().get_hot_store();
+        let shard_uid = ShardLayout::v0_single_shard().get_shard_uids()[0];
+        write_values(&store, shard_uid, &[vec![0], vec![1]]);
+        let missing_value = vec![2];
+        let mut store_update = store.store_update();
+        store_update.set(
+            DBCol::FlatState,
+            &encode_flat_state_db_key(shard_uid, &[2]),
+            &FlatStateValue::value_ref(&missing_value).try_to_vec().unwrap(),
+        );
+        store_update.commit().unwrap();
+        let result = inline_flat_state_values(
+            store.clone(),
+            &FlatStorageManager::new(store.clone()),
+            &InliningMigrationOptions {
+                read_state_threads: 2,
+                scan_batch_size: 4,
+                offline: true,
+                trust_refs: true,
+                ..Default::default()
+            },
+        );
+        assert_matches!(
+            result,
+            Err(InliningMigrationError::MissingValue { value_hash }) if value_hash == hash(&missing_value)
+        );
+    }
+
+    #[test]
+    fn read_trusted_values_keeps_order() {
+        let store = create_test_store();
+        let shard_uid = ShardLayout::v0_single_shard().get_shard_uids()[0];
+        let values = [vec![1], vec![2]];
+        write_values(&store, shard_uid, &values);
+        let missing_hash = hash(&[3]);
+        assert_eq!(
+            read_trusted_values(
+                &store,
+                shard_uid,
+                &[hash(&values[1]), missing_hash, hash(&values[0])]
+            )
+            .unwrap(),
+            vec![Some(values[1].clone()), None, Some(values[0].clone())]
+        );
+    }
+
+    /// Only the values whose trie keys start with one of the prefixes get
+    /// inlined, and a later run over all keys inlines the rest.
+    #[test]
+    fn migration_of_trie_key_prefixes() {
+        let store = NodeStorage::test_opener().1.open().unwrap().get_hot_store();
+        let shard_uid = ShardLayout::v0_single_shard().get_shard_uids()[0];
+        let flat_storage_manager = FlatStorageManager::new(store.clone());
+        let values = [vec![0], vec![1], vec![2], vec![3], vec![4]];
+        write_values(&store, shard_uid, &values);
+        let migrate = |trie_key_prefixes| {
+            inline_flat_state_values(
+                store.clone(),
+                &flat_storage_manager,
+                &InliningMigrationOptions {
+                    read_state_threads: 2,
+                    scan_batch_size: 4,
+                    scan_threads: 2,
+                    trie_key_prefixes,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        // The keys are `[0]`..`[4]`, and `[3, 0]` extends `[3]`.
+        migrate(Some(vec![vec![3], vec![1], vec![3, 0], vec![7]]));
+        assert_eq!(
+            read_values(&store),
+            vec![
+                FlatStateValue::value_ref(&values[0]),
+                FlatStateValue::inlined(&values[1]),
+                FlatStateValue::value_ref(&values[2]),
+                FlatStateValue::inlined(&values[3]),
+                FlatStateValue::value_ref(&values[4]),
+            ]
+        );
+        migrate(None);
+        assert_eq!(
+            read_values(&store),
+            values.iter().map(|value| FlatStateValue::inlined(value)).collect_vec()
+        );
+    }
+
+    #[test]
+    fn restrict_key_ranges_to_trie_key_prefixes() {
+        let ranges = vec![
+            FlatStateKeyRange { start: vec![0; 8], end: Some(vec![0, 0, 0, 0, 0, 0, 0, 1]) },
+            FlatStateKeyRange { start: vec![1; 8], end: Some(vec![1, 1, 1, 1, 1, 1, 1, 2]) },
+        ];
+        let restricted = restrict_to_trie_key_prefixes(ranges, &[vec![5, 1], vec![5], vec![2]]);
+        assert_eq!(
+            restricted.iter().map(|range| (range.start.clone(), range.end.clone())).collect_vec(),
+            vec![
+                ([vec![0; 8], vec![2]].concat(), Some([vec![0; 8], vec![3]].concat())),
+                ([vec![0; 8], vec![5]].concat(), Some([vec![0; 8], vec![6]].concat())),
+                ([vec![1; 8], vec![2]].concat(), Some([vec![1; 8], vec![3]].concat())),
+                ([vec![1; 8], vec![5]].concat(), Some([vec![1; 8], vec![6]].concat())),
+            ]
+        );
+    }
+
+    /// A top-up inlines the references written after the regular migration.
+    #[test]
+    fn top_up_after_migration() {
+        let store = NodeStorage::test_opener().1.open().unwrap().get_hot_store();
+        let shard_uid = ShardLayout::v0_single_shard().get_shard_uids()[0];
+        let flat_storage_manager = FlatStorageManager::new(store.clone());
+        let migrate = |migration_id| {
+            inline_flat_state_values(
+                store.clone(),
+                &flat_storage_manager,
+                &InliningMigrationOptions {
+                    migration_id: migration_id.to_string(),
+                    read_state_threads: 2,
+                    scan_batch_size: 4,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        write_values(&store, shard_uid, &[vec![0], vec![1], vec![2]]);
+        migrate(DEFAULT_INLINING_MIGRATION_ID);
+
+        // Written by block processing after the migration.
+        let new_value = vec![3];
+        let mut store_update = store.store_update();
+        let trie_key =
+            TrieCachingStorage::get_key_from_shard_uid_and_hash(shard_uid, &hash(&new_value));
+        store_update.increment_refcount(DBCol::State, &trie_key, &new_value);
+        store_update.set(
+            DBCol::FlatState,
+            &encode_flat_state_db_key(shard_uid, &[3]),
+            &FlatStateValue::value_ref(&new_value).try_to_vec().unwrap(),
+        );
+        store_update.commit().unwrap();
+
+        migrate(TOP_UP_INLINING_MIGRATION_ID);
+        assert_eq!(
+            read_values(&store),
+            vec![
+                FlatStateValue::inlined(&[0]),
+                FlatStateValue::inlined(&[1]),
+                FlatStateValue::inlined(&[2]),
+                FlatStateValue::inlined(&new_value),
+            ]
+        );
+    }
+
+    /// Committing fewer values than are read in a batch doesn't change the
+    /// result.
+    #[test]
+    fn migration_with_small_commit_batch_size() {
+        let store = NodeStorage::test_opener().1.open().unwrap().get_hot_store();
+        let shard_uid = ShardLayout::v0_single_shard().get_shard_uids()[0];
+        let values =
+            [vec![0], vec![1], vec![2; INLINE_DISK_VALUE_THRESHOLD + 1], vec![3], vec![4], vec![5]];
+        write_values(&store, shard_uid, &values);
+        inline_flat_state_values(
+            store.clone(),
+            &FlatStorageManager::new(store.clone()),
+            &InliningMigrationOptions {
+                read_state_threads: 2,
+                scan_batch_size: 4,
+                commit_batch_size: Some(1),
+                ..Default::default()
+            },
+        )
+        .unwrap();
         assert_eq!(
-            store
-                .iter(DBCol::FlatState)
-                .flat_map(|r| r.map(|(_, v)| FlatStateValue::try_from_slice(&v).unwrap()))
-                .collect::<Vec<_>>(),
+            read_values(&store),
             vec![
                 FlatStateValue::inlined(&values[0]),
                 FlatStateValue::inlined(&values[1]),
@@ -259,4 +1578,336 @@ mod tests {
             ]
         );
     }
+
+    /// Sleeping between batches slows the migration down, but doesn't change
+    /// its result.
+    #[test]
+    fn migration_with_inter_batch_sleep() {
+        let store = NodeStorage::test_opener().1.open().unwrap().get_hot_store();
+        let shard_uid = ShardLayout::v0_single_shard().get_shard_uids()[0];
+        let values = [vec![0], vec![1], vec![2; INLINE_DISK_VALUE_THRESHOLD + 1], vec![3]];
+        write_values(&store, shard_uid, &values);
+        let start = std::time::Instant::now();
+        inline_flat_state_values(
+            store.clone(),
+            &FlatStorageManager::new(store.clone()),
+            &InliningMigrationOptions {
+                read_state_threads: 2,
+                scan_batch_size: 1,
+                inter_batch_sleep: Duration::from_millis(50),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        // One batch per value.
+        assert!(start.elapsed() >= Duration::from_millis(4 * 50));
+        assert_eq!(
+            read_values(&store),
+            vec![
+                FlatStateValue::inlined(&values[0]),
+                FlatStateValue::inlined(&values[1]),
+                FlatStateValue::value_ref(&values[2]),
+                FlatStateValue::inlined(&values[3]),
+            ]
+        );
+    }
+
+    /// The progress logger stops together with the migration.
+    #[test]
+    fn migration_with_progress_logs() {
+        let store = NodeStorage::test_opener().1.open().unwrap().get_hot_store();
+        let shard_uid = ShardLayout::v0_single_shard().get_shard_uids()[0];
+        let values = [vec![0], vec![1], vec![2; INLINE_DISK_VALUE_THRESHOLD + 1]];
+        write_values(&store, shard_uid, &values);
+        inline_flat_state_values(
+            store.clone(),
+            &FlatStorageManager::new(store.clone()),
+            &InliningMigrationOptions {
+                read_state_threads: 2,
+                scan_batch_size: 1,
+                progress_log_interval: Some(Duration::from_millis(1)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            read_values(&store),
+            vec![
+                FlatStateValue::inlined(&values[0]),
+                FlatStateValue::inlined(&values[1]),
+                FlatStateValue::value_ref(&values[2]),
+            ]
+        );
+    }
+
+    #[test]
+    fn migration_with_sub_batches() {
+        let store = NodeStorage::test_opener().1.open().unwrap().get_hot_store();
+        let shard_uid = ShardLayout::v0_single_shard().get_shard_uids()[0];
+        let values =
+            [vec![0], vec![1], vec![2; INLINE_DISK_VALUE_THRESHOLD + 1], vec![3], vec![4], vec![5]];
+        write_values(&store, shard_uid, &values);
+        // Every batch of 4 values is committed in sub-batches of 2 values.
+        inline_flat_state_values(
+            store.clone(),
+            &FlatStorageManager::new(store.clone()),
+            &InliningMigrationOptions {
+                read_state_threads: 2,
+                scan_batch_size: 4,
+                max_buffered_values: Some(2),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            read_values(&store),
+            vec![
+                FlatStateValue::inlined(&values[0]),
+                FlatStateValue::inlined(&values[1]),
+                FlatStateValue::value_ref(&values[2]),
+                FlatStateValue::inlined(&values[3]),
+                FlatStateValue::inlined(&values[4]),
+                FlatStateValue::inlined(&values[5]),
+            ]
+        );
+        assert_eq!(store.iter(DBCol::BlockMisc).count(), 0);
+    }
+
+    #[test]
+    fn parallel_migration_multiple_shards() {
+        let store = NodeStorage::test_opener().1.open().unwrap().get_hot_store();
+        let shard_uids = ShardLayout::get_simple_nightshade_layout().get_shard_uids();
+        let values = [vec![0], vec![1; INLINE_DISK_VALUE_THRESHOLD + 1], vec![2], vec![3]];
+        for &shard_uid in &shard_uids {
+            write_values(&store, shard_uid, &values);
+        }
+        inline_flat_state_values(
+            store.clone(),
+            &FlatStorageManager::new(store.clone()),
+            &InliningMigrationOptions {
+                read_state_threads: 4,
+                scan_batch_size: 3,
+                scan_threads: 2,
+                offline: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let expected = [
+            FlatStateValue::inlined(&values[0]),
+            FlatStateValue::value_ref(&values[1]),
+            FlatStateValue::inlined(&values[2]),
+            FlatStateValue::inlined(&values[3]),
+        ];
+        assert_eq!(
+            read_values(&store),
+            expected.iter().cycle().take(expected.len() * shard_uids.len()).cloned().collect_vec()
+        );
+        // Checkpoints are removed once the migration is finished.
+        assert_eq!(store.iter(DBCol::BlockMisc).count(), 0);
+    }
+
+    #[test]
+    fn verify_value_refs() {
+        let store = NodeStorage::test_opener().1.open().unwrap().get_hot_store();
+        let shard_uid = ShardLayout::v0_single_shard().get_shard_uids()[0];
+        let values = [vec![0], vec![1; INLINE_DISK_VALUE_THRESHOLD + 1], vec![2], vec![3]];
+        write_values(&store, shard_uid, &values);
+        // Make the reference to the last value dangling.
+        let mut store_update = store.store_update();
+        store_update.decrement_refcount(
+            DBCol::State,
+            &TrieCachingStorage::get_key_from_shard_uid_and_hash(shard_uid, &hash(&values[3])),
+        );
+        store_update.commit().unwrap();
+        let flat_state = read_values(&store);
+
+        assert_eq!(
+            verify_flat_state_value_refs(&store, 2, 3),
+            ValueRefsVerification { resolved: 2, unresolved: 1 }
+        );
+        // Nothing gets written.
+        assert_eq!(read_values(&store), flat_state);
+    }
+
+    #[test]
+    fn resume_from_checkpoint() {
+        let store = NodeStorage::test_opener().1.open().unwrap().get_hot_store();
+        let shard_uid = ShardLayout::v0_single_shard().get_shard_uids()[0];
+        let values = [vec![0], vec![1], vec![2], vec![3]];
+        write_values(&store, shard_uid, &values);
+        // Pretend that a previous run processed the first two keys and was
+        // interrupted.
+        let range = FlatStateKeyRange { start: shard_uid.to_bytes().to_vec(), end: None };
+        let mut store_update = store.store_update();
+        store_update
+            .set_ser(
+                DBCol::BlockMisc,
+                &range.checkpoint_key(DEFAULT_INLINING_MIGRATION_ID),
+                &InliningCheckpoint::InProgress {
+                    last_key: encode_flat_state_db_key(shard_uid, &[1]),
+                },
+            )
+            .unwrap();
+        store_update.commit().unwrap();
+        inline_flat_state_values(
+            store.clone(),
+            &FlatStorageManager::new(store.clone()),
+            &InliningMigrationOptions {
+                read_state_threads: 2,
+                scan_batch_size: 4,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            read_values(&store),
+            vec![
+                FlatStateValue::value_ref(&values[0]),
+                FlatStateValue::value_ref(&values[1]),
+                FlatStateValue::inlined(&values[2]),
+                FlatStateValue::inlined(&values[3]),
+            ]
+        );
+    }
+
+    #[test]
+    fn checkpoint_of_another_migration() {
+        let store = NodeStorage::test_opener().1.open().unwrap().get_hot_store();
+        let shard_uid = ShardLayout::v0_single_shard().get_shard_uids()[0];
+        let values = [vec![0], vec![1], vec![2], vec![3]];
+        write_values(&store, shard_uid, &values);
+        // Another migration processed the first two keys and was interrupted.
+        let range = FlatStateKeyRange { start: shard_uid.to_bytes().to_vec(), end: None };
+        let other_checkpoint_key = range.checkpoint_key("other");
+        let other_checkpoint =
+            InliningCheckpoint::InProgress { last_key: encode_flat_state_db_key(shard_uid, &[1]) };
+        let mut store_update = store.store_update();
+        store_update.set_ser(DBCol::BlockMisc, &other_checkpoint_key, &other_checkpoint).unwrap();
+        store_update.commit().unwrap();
+        inline_flat_state_values(
+            store.clone(),
+            &FlatStorageManager::new(store.clone()),
+            &InliningMigrationOptions {
+                read_state_threads: 2,
+                scan_batch_size: 4,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            read_values(&store),
+            values.iter().map(|value| FlatStateValue::inlined(value)).collect::<Vec<_>>()
+        );
+        // The checkpoint of the other migration is left untouched.
+        assert_eq!(
+            store.get_ser::<InliningCheckpoint>(DBCol::BlockMisc, &other_checkpoint_key).unwrap(),
+            Some(other_checkpoint)
+        );
+    }
+
+    /// Only the values of the given shard starting from the given key are
+    /// inlined.
+    #[test]
+    fn migration_from_start_key() {
+        let store = NodeStorage::test_opener().1.open().unwrap().get_hot_store();
+        let shard_uid = ShardUId { version: 1, shard_id: 0 };
+        let other_shard_uid = ShardUId { version: 1, shard_id: 1 };
+        let values = [vec![0], vec![1], vec![2], vec![3]];
+        let other_values = [vec![4], vec![5]];
+        write_values(&store, shard_uid, &values);
+        write_values(&store, other_shard_uid, &other_values);
+        let run = |start_from| {
+            inline_flat_state_values(
+                store.clone(),
+                &FlatStorageManager::new(store.clone()),
+                &InliningMigrationOptions {
+                    read_state_threads: 2,
+                    scan_batch_size: 4,
+                    start_from: Some(start_from),
+                    ..Default::default()
+                },
+            )
+        };
+        let flat_state = read_values(&store);
+        // The key must belong to the shard.
+        assert_matches!(
+            run((shard_uid, encode_flat_state_db_key(other_shard_uid, &[0]))),
+            Err(InliningMigrationError::InvalidStartKey { .. })
+        );
+        assert_matches!(
+            run((shard_uid, vec![1, 2, 3])),
+            Err(InliningMigrationError::InvalidStartKey { .. })
+        );
+        assert_eq!(read_values(&store), flat_state);
+
+        run((shard_uid, encode_flat_state_db_key(shard_uid, &[2]))).unwrap();
+        assert_eq!(
+            read_values(&store),
+            vec![
+                FlatStateValue::value_ref(&values[0]),
+                FlatStateValue::value_ref(&values[1]),
+                FlatStateValue::inlined(&values[2]),
+                FlatStateValue::inlined(&values[3]),
+                FlatStateValue::value_ref(&other_values[0]),
+                FlatStateValue::value_ref(&other_values[1]),
+            ]
+        );
+    }
+
+    #[test]
+    fn timeout_with_stuck_reader() {
+        let (unblock_send, unblock_recv) = channel::bounded::<()>(0);
+        let store = NodeStorage::new(Arc::new(BlockingStateDB {
+            db: TestDB::new(),
+            unblock: unblock_recv,
+        }))
+        .get_hot_store();
+        let shard_uid = ShardLayout::v0_single_shard().get_shard_uids()[0];
+        let values = [vec![0], vec![1]];
+        write_values(&store, shard_uid, &values);
+        let flat_state = read_values(&store);
+
+        // Returns even though the reader threads never finish.
+        assert_matches!(
+            inline_flat_state_values(
+                store.clone(),
+                &FlatStorageManager::new(store.clone()),
+                &InliningMigrationOptions {
+                    read_state_threads: 2,
+                    scan_batch_size: 4,
+                    timeout: Some(Duration::from_millis(100)),
+                    ..Default::default()
+                }
+            ),
+            Err(InliningMigrationError::Timeout(_))
+        );
+        // Nothing is inlined.
+        assert_eq!(read_values(&store), flat_state);
+        // Lets the detached reader threads finish.
+        drop(unblock_send);
+    }
+
+    /// A worker that panics while FlatState updates are paused, e.g. on a
+    /// failed commit, doesn't leave them disabled.
+    #[test]
+    fn updates_resumed_after_panic() {
+        let shard_uid = ShardUId::single_shard();
+        let flat_storage_manager =
+            FlatStorageManager::test(create_test_store(), &[shard_uid], CryptoHash::default());
+        let flat_storage = flat_storage_manager.get_flat_storage_for_shard(shard_uid).unwrap();
+        let pause = FlatStateUpdatesPause::new(Some(&flat_storage_manager));
+
+        let outer = pause.pause();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _paused = pause.pause();
+            assert!(!flat_storage.is_flat_head_update_enabled());
+            panic!("injected failure to commit");
+        }));
+        assert!(result.is_err());
+        // Another worker is still in its critical section.
+        assert!(!flat_storage.is_flat_head_update_enabled());
+        drop(outer);
+        assert!(flat_storage.is_flat_head_update_enabled());
+    }
 }
@@ -157,6 +157,12 @@ impl FlatStorageManager {
         Ok(())
     }
 
+    /// Whether flat storage is loaded for any shard, which is the case when the
+    /// node processes blocks.
+    pub(crate) fn has_flat_storages(&self) -> bool {
+        !self.0.flat_storages.lock().expect(POISONED_LOCK_ERR).is_empty()
+    }
+
     pub fn set_flat_state_updates_mode(&self, enabled: bool) {
         let flat_storages = self.0.flat_storages.lock().expect(POISONED_LOCK_ERR);
         for flat_storage in flat_storages.values() {
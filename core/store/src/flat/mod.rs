@@ -36,7 +36,12 @@ mod types;
 
 pub use chunk_view::FlatStorageChunkView;
 pub use delta::{FlatStateChanges, FlatStateDelta, FlatStateDeltaMetadata};
-pub use inlining_migration::inline_flat_state_values;
+pub use inlining_migration::{
+    inline_flat_state_values, verify_flat_state_value_refs, InliningMigrationError,
+    InliningMigrationOptions, InliningMigrationStats, ValueRefsVerification, ValueSizeBucket,
+    DEFAULT_INLINING_MIGRATION_ID, INLINING_MIGRATION_STATS_SCHEMA_VERSION,
+    TOP_UP_INLINING_MIGRATION_ID,
+};
 pub use manager::FlatStorageManager;
 pub use metrics::FlatStorageCreationMetrics;
 pub use storage::FlatStorage;
@@ -340,6 +340,11 @@ impl FlatStorage {
         let mut guard = self.0.write().expect(crate::flat::POISONED_LOCK_ERR);
         guard.move_head_enabled = enabled;
     }
+
+    #[cfg(test)]
+    pub(crate) fn is_flat_head_update_enabled(&self) -> bool {
+        self.0.read().expect(crate::flat::POISONED_LOCK_ERR).move_head_enabled
+    }
 }
 
 #[cfg(test)]
@@ -22,8 +22,11 @@ pub use near_primitives::errors::StorageError;
 use near_primitives::hash::CryptoHash;
 use near_primitives::receipt::{DelayedReceiptIndices, Receipt, ReceivedData};
 pub use near_primitives::shard_layout::ShardUId;
+use near_primitives::syncing::StatePartKey;
 use near_primitives::trie_key::{trie_key_parsers, TrieKey};
-use near_primitives::types::{AccountId, CompiledContract, CompiledContractCache, StateRoot};
+use near_primitives::types::{
+    AccountId, CompiledContract, CompiledContractCache, ShardId, StateRoot,
+};
 
 use crate::db::{
     refcount, DBIterator, DBOp, DBSlice, DBTransaction, Database, StoreStatistics,
@@ -285,6 +288,18 @@ impl Store {
         Ok(value)
     }
 
+    /// Same as [`Self::get`], but looks up multiple keys at once.  Values are
+    /// returned in the same order as the keys.
+    pub fn multi_get(&self, column: DBCol, keys: &[&[u8]]) -> io::Result<Vec<Option<DBSlice<'_>>>> {
+        let values = if column.is_rc() {
+            self.storage.multi_get_with_rc_stripped(column, keys)
+        } else {
+            self.storage.multi_get_raw_bytes(column, keys)
+        }?;
+        tracing::trace!(target: "store", db_op = "multi_get", col = %column, num_keys = keys.len());
+        Ok(values)
+    }
+
     pub fn get_ser<T: BorshDeserialize>(&self, column: DBCol, key: &[u8]) -> io::Result<Option<T>> {
         self.get(column, key)?.as_deref().map(T::try_from_slice).transpose()
     }
@@ -893,6 +908,24 @@ pub fn set_genesis_state_roots(store_update: &mut StoreUpdate, genesis_roots: &[
         .expect("Borsh cannot fail");
 }
 
+/// Deletes all parts of the state of `shard_id` as of `sync_hash` from
+/// `DBCol::StateParts` with a single range delete, without having to know the
+/// number of parts. Parts of other shards and of other sync blocks are kept.
+pub fn delete_state_parts(
+    store_update: &mut StoreUpdate,
+    sync_hash: CryptoHash,
+    shard_id: ShardId,
+) {
+    // Keys of the parts share a prefix followed by the part id, serialized as
+    // 8 bytes, so they lie between the keys of parts 0 and `u64::MAX`.
+    let from = StatePartKey(sync_hash, shard_id, 0).try_to_vec().expect("Borsh cannot fail");
+    let mut to =
+        StatePartKey(sync_hash, shard_id, u64::MAX).try_to_vec().expect("Borsh cannot fail");
+    // The end of the range is exclusive.
+    to.push(0);
+    store_update.delete_range(DBCol::StateParts, &from, &to);
+}
+
 pub struct StoreCompiledContractCache {
     db: Arc<dyn Database>,
 }
@@ -933,9 +966,11 @@ impl CompiledContractCache for StoreCompiledContractCache {
 
 #[cfg(test)]
 mod tests {
+    use borsh::BorshSerialize;
     use near_primitives::hash::CryptoHash;
+    use near_primitives::syncing::StatePartKey;
 
-    use super::{DBCol, NodeStorage, Store};
+    use super::{delete_state_parts, DBCol, NodeStorage, Store};
 
     #[test]
     fn test_no_cache_disabled() {
@@ -972,6 +1007,43 @@ mod tests {
         test_clear_column(crate::test_utils::create_test_store());
     }
 
+    fn test_delete_state_parts(store: Store) {
+        let sync_hashes = [CryptoHash::hash_bytes(&[1]), CryptoHash::hash_bytes(&[2])];
+        // Serialized shard ids 1 and 256 differ only after the first byte.
+        let shard_ids = [1, 256];
+        let mut keys = vec![];
+        let mut store_update = store.store_update();
+        for sync_hash in sync_hashes {
+            for shard_id in shard_ids {
+                for part_id in [0, 1, 255, 256, u64::MAX] {
+                    let key = StatePartKey(sync_hash, shard_id, part_id).try_to_vec().unwrap();
+                    store_update.set(DBCol::StateParts, &key, &[1]);
+                    keys.push((sync_hash, shard_id, key));
+                }
+            }
+        }
+        store_update.commit().unwrap();
+
+        let mut store_update = store.store_update();
+        delete_state_parts(&mut store_update, sync_hashes[0], shard_ids[0]);
+        store_update.commit().unwrap();
+        for (sync_hash, shard_id, key) in keys {
+            let deleted = sync_hash == sync_hashes[0] && shard_id == shard_ids[0];
+            assert_eq!(store.exists(DBCol::StateParts, &key).unwrap(), !deleted);
+        }
+    }
+
+    #[test]
+    fn delete_state_parts_rocksdb() {
+        let (_tmp_dir, opener) = NodeStorage::test_opener();
+        test_delete_state_parts(opener.open().unwrap().get_hot_store());
+    }
+
+    #[test]
+    fn delete_state_parts_testdb() {
+        test_delete_state_parts(crate::test_utils::create_test_store());
+    }
+
     /// Asserts that elements in the vector are sorted.
     #[track_caller]
     fn assert_sorted(want_count: usize, keys: Vec<Box<[u8]>>) {
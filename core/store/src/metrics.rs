@@ -322,7 +322,9 @@ pub mod flat_state_metrics {
 
     pub mod inlining_migration {
         use near_o11y::metrics::{
-            try_create_histogram, try_create_int_counter, Histogram, IntCounter,
+            exponential_buckets, try_create_counter, try_create_gauge, try_create_histogram,
+            try_create_histogram_with_buckets, try_create_int_counter, try_create_int_counter_vec,
+            try_create_int_gauge, Counter, Gauge, Histogram, IntCounter, IntCounterVec, IntGauge,
         };
         use once_cell::sync::Lazy;
 
@@ -354,10 +356,18 @@ pub mod flat_state_metrics {
             )
             .unwrap()
         });
-        pub static SKIPPED_COUNT: Lazy<IntCounter> = Lazy::new(|| {
+        pub static INLINED_TOTAL_SERIALIZED_SIZE: Lazy<IntCounter> = Lazy::new(|| {
             try_create_int_counter(
+                "near_flat_state_inlining_migration_inlined_total_serialized_size",
+                "Total size of inlined FlatState values as written to FlatState, including the serialization overhead, since the migration start.",
+            )
+            .unwrap()
+        });
+        pub static SKIPPED_COUNT: Lazy<IntCounterVec> = Lazy::new(|| {
+            try_create_int_counter_vec(
                 "near_flat_state_inlining_migration_skipped_count",
                 "Total number of FlatState values skipped since the migration start due to some kind of an issue while trying to read the value.",
+                &["reason"],
             )
             .unwrap()
         });
@@ -368,6 +378,42 @@ pub mod flat_state_metrics {
             )
             .unwrap()
         });
+        pub static FLAT_STATE_PAUSED_SECONDS_TOTAL: Lazy<Counter> = Lazy::new(|| {
+            try_create_counter(
+                "near_flat_state_inlining_migration_flat_state_paused_seconds_total",
+                "Total time FlatState updates were paused by the FlatState inlining migration.",
+            )
+            .unwrap()
+        });
+        pub static FLAT_STATE_VALUE_SIZE_BYTES: Lazy<Histogram> = Lazy::new(|| {
+            try_create_histogram_with_buckets(
+                "near_flat_state_inlining_migration_value_size_bytes",
+                "Sizes of processed FlatState values, both inlined and referenced.",
+                exponential_buckets(16.0, 2.0, 10).unwrap(),
+            )
+            .unwrap()
+        });
+        pub static FLAT_STATE_INLINING_MIGRATION_SECONDS: Lazy<Gauge> = Lazy::new(|| {
+            try_create_gauge(
+                "near_flat_state_inlining_migration_seconds",
+                "Total duration of the last finished FlatState inlining migration.",
+            )
+            .unwrap()
+        });
+        pub static FLAT_STATE_INLINING_MIGRATION_RUNNING_SECONDS: Lazy<Gauge> = Lazy::new(|| {
+            try_create_gauge(
+                "near_flat_state_inlining_migration_running_seconds",
+                "Time elapsed since the start of the FlatState inlining migration, updated after every batch.",
+            )
+            .unwrap()
+        });
+        pub static FLAT_STATE_INLINING_READERS_BUSY: Lazy<IntGauge> = Lazy::new(|| {
+            try_create_int_gauge(
+                "near_flat_state_inlining_migration_readers_busy",
+                "Number of threads reading values from State that are processing a request rather than waiting for one.",
+            )
+            .unwrap()
+        });
     }
 }
 pub static COLD_STORE_MIGRATION_BATCH_WRITE_COUNT: Lazy<IntCounterVec> = Lazy::new(|| {
@@ -1,7 +1,7 @@
 use std::collections::{HashSet, VecDeque};
 use std::path::Path;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
 
 use actix::System;
@@ -2189,6 +2189,7 @@ fn test_incorrect_validator_key_produce_block() {
         Some(signer),
         false,
         TEST_SEED,
+        Arc::new(AtomicBool::new(false)),
     )
     .unwrap();
     let res = client.produce_block(1);
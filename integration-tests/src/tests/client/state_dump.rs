@@ -0,0 +1,381 @@
+//! Dumps state of several epochs to external storage and restores it on nodes
+//! that don't have the state, reading the state parts only from the dump.
+
+use crate::tests::client::utils::TestEnvNightshadeSetupExt;
+use borsh::BorshSerialize;
+use near_chain::chain::ApplyStatePartsRequest;
+use near_chain::{ChainGenesis, Provenance};
+use near_chain_configs::{DumpConfig, ExternalStorageLocation, Genesis};
+use near_client::sync::state::{
+    external_storage_location, external_storage_manifest_location, DefaultPartNaming, StateSync,
+};
+use near_client::test_utils::TestEnv;
+use near_epoch_manager::EpochManagerAdapter;
+use near_network::test_utils::wait_or_timeout;
+use near_o11y::testonly::init_test_logger;
+use near_primitives::shard_layout::ShardUId;
+use near_primitives::state_part::PartId;
+use near_primitives::syncing::{get_num_state_parts, StatePartKey};
+use near_primitives::types::{AccountId, BlockHeight};
+use near_store::DBCol;
+use nearcore::config::GenesisExt;
+use nearcore::state_sync::{
+    spawn_state_sync_dump, RuntimeStatePartSource, StatePartSource, TrieStatePartSource,
+};
+use std::ops::ControlFlow;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+const EPOCH_LENGTH: BlockHeight = 5;
+/// Every epoch gets restored by a separate node.
+const NUM_EPOCHS: usize = 3;
+
+/// Produces `NUM_EPOCHS` epochs while the dump loop of the first node dumps
+/// them to `location`. After every epoch, a node that doesn't track the shard
+/// gets the state of that epoch from the parts returned by `read_part`, and
+/// the resulting state root is compared with the state root of the dumping
+/// node.
+/// `read_part` returns `None` if the part is not dumped yet.
+fn run_dump_and_restore(
+    location: ExternalStorageLocation,
+    read_part: impl Fn(&str) -> Option<Vec<u8>>,
+) {
+    init_test_logger();
+
+    let accounts: Vec<AccountId> =
+        (0..=NUM_EPOCHS).map(|i| format!("test{}", i).parse().unwrap()).collect();
+    let mut genesis = Genesis::test(accounts, 1);
+    genesis.config.epoch_length = EPOCH_LENGTH;
+    let chain_genesis = ChainGenesis::new(&genesis);
+    let mut env = TestEnv::builder(chain_genesis.clone())
+        .clients_count(NUM_EPOCHS + 1)
+        .real_epoch_managers(&genesis.config)
+        .nightshade_runtimes(&genesis)
+        .build();
+    let chain = &env.clients[0].chain;
+    let epoch_manager = chain.epoch_manager.clone();
+    let shard_tracker = chain.shard_tracker.clone();
+    let runtime = chain.runtime_adapter.clone();
+    let mut config = env.clients[0].config.clone();
+    config.state_sync.dump = Some(DumpConfig {
+        location,
+        idle_delay: Some(Duration::from_millis(100)),
+        ..Default::default()
+    });
+    let chain_id = config.chain_id.clone();
+
+    near_actix_test_utils::run_actix(async move {
+        let _state_sync_dump_handle = spawn_state_sync_dump(
+            &config,
+            chain_genesis,
+            epoch_manager.clone(),
+            shard_tracker,
+            runtime,
+            Some("test0".parse().unwrap()),
+            Arc::new(AtomicBool::new(false)),
+        )
+        .unwrap();
+
+        let mut next_height = 1;
+        for restoring_client in 1..=NUM_EPOCHS {
+            // Produce a few blocks past the epoch boundary, so that the last
+            // final block is in the new epoch, which makes the epoch eligible
+            // for dumping.
+            let last_height = restoring_client as BlockHeight * EPOCH_LENGTH + 4;
+            for height in next_height..=last_height {
+                let block = env.clients[0].produce_block(height).unwrap().unwrap();
+                env.process_block(0, block.clone(), Provenance::PRODUCED);
+                for i in 1..env.clients.len() {
+                    env.process_block(i, block.clone(), Provenance::NONE);
+                }
+            }
+            next_height = last_height + 1;
+
+            let chain = &env.clients[0].chain;
+            let head = chain.head().unwrap();
+            let epoch_id = head.epoch_id.clone();
+            let epoch_height = epoch_manager.get_epoch_info(&epoch_id).unwrap().epoch_height();
+            let sync_hash =
+                StateSync::get_epoch_start_sync_hash(chain, &head.last_block_hash).unwrap();
+            let state_header = chain.get_state_response_header(0, sync_hash).unwrap();
+            let state_root = state_header.chunk_prev_state_root();
+            let num_parts = get_num_state_parts(state_header.state_root_node().memory_usage);
+            let sync_prev_hash = *chain.get_block_header(&sync_hash).unwrap().prev_hash();
+
+            // A part that is still being written is incomplete and fails validation.
+            let restoring_runtime = env.clients[restoring_client].runtime_adapter.clone();
+            let parts = wait_or_timeout(100, 10000, || async {
+                let mut parts = vec![];
+                for part_id in 0..num_parts {
+                    let location = external_storage_location(
+                        &DefaultPartNaming,
+                        &chain_id,
+                        &epoch_id,
+                        epoch_height,
+                        0,
+                        part_id,
+                        num_parts,
+                        None,
+                    );
+                    match read_part(&location) {
+                        Some(part)
+                            if restoring_runtime.validate_state_part(
+                                &state_root,
+                                PartId::new(part_id, num_parts),
+                                &part,
+                            ) =>
+                        {
+                            parts.push(part)
+                        }
+                        _ => return ControlFlow::Continue(()),
+                    }
+                }
+                ControlFlow::Break(parts)
+            })
+            .await
+            .unwrap();
+
+            let chain = &mut env.clients[restoring_client].chain;
+            chain.set_state_header(0, sync_hash, state_header).unwrap();
+            for (part_id, part) in parts.iter().enumerate() {
+                chain
+                    .set_state_part(0, sync_hash, PartId::new(part_id as u64, num_parts), part)
+                    .unwrap();
+            }
+            let apply_state_parts = move |msg: ApplyStatePartsRequest| {
+                let store = restoring_runtime.store();
+                for part_id in 0..msg.num_parts {
+                    let key =
+                        StatePartKey(msg.sync_hash, msg.shard_id, part_id).try_to_vec().unwrap();
+                    let part = store.get(DBCol::StateParts, &key).unwrap().unwrap();
+                    restoring_runtime
+                        .apply_state_part(
+                            msg.shard_id,
+                            &msg.state_root,
+                            PartId::new(part_id, msg.num_parts),
+                            &part,
+                            &msg.epoch_id,
+                        )
+                        .unwrap();
+                }
+            };
+            chain.schedule_apply_state_parts(0, sync_hash, num_parts, &apply_state_parts).unwrap();
+            chain.set_state_finalize(0, sync_hash, Ok(())).unwrap();
+
+            let restored_chunk_extra =
+                chain.get_chunk_extra(&sync_prev_hash, &ShardUId::single_shard()).unwrap();
+            let expected_chunk_extra = env.clients[0]
+                .chain
+                .get_chunk_extra(&sync_prev_hash, &ShardUId::single_shard())
+                .unwrap();
+            assert_eq!(restored_chunk_extra.state_root(), &state_root);
+            assert_eq!(restored_chunk_extra, expected_chunk_extra);
+        }
+        actix_rt::System::current().stop();
+    });
+}
+
+#[test]
+fn test_dump_and_restore_filesystem() {
+    let root_dir = tempfile::Builder::new().prefix("state_dump").tempdir().unwrap();
+    let root_path = root_dir.path().to_path_buf();
+    run_dump_and_restore(
+        ExternalStorageLocation::Filesystem { root_dir: root_path.clone() },
+        |location| std::fs::read(root_path.join(location)).ok(),
+    );
+}
+
+/// Parts obtained from the tries of the node are the same as the parts
+/// obtained by the runtime.
+#[test]
+fn test_trie_state_part_source() {
+    init_test_logger();
+
+    let accounts: Vec<AccountId> = vec!["test0".parse().unwrap()];
+    let mut genesis = Genesis::test(accounts, 1);
+    genesis.config.epoch_length = EPOCH_LENGTH;
+    let chain_genesis = ChainGenesis::new(&genesis);
+    let mut env = TestEnv::builder(chain_genesis)
+        .real_epoch_managers(&genesis.config)
+        .nightshade_runtimes(&genesis)
+        .build();
+    for height in 1..=EPOCH_LENGTH + 4 {
+        let block = env.clients[0].produce_block(height).unwrap().unwrap();
+        env.process_block(0, block, Provenance::PRODUCED);
+    }
+
+    let chain = &env.clients[0].chain;
+    let head = chain.head().unwrap();
+    let sync_hash = StateSync::get_epoch_start_sync_hash(chain, &head.last_block_hash).unwrap();
+    let sync_prev_hash = *chain.get_block_header(&sync_hash).unwrap().prev_hash();
+    let state_header = chain.get_state_response_header(0, sync_hash).unwrap();
+    let state_root = state_header.chunk_prev_state_root();
+    let num_parts = get_num_state_parts(state_header.state_root_node().memory_usage);
+
+    let runtime = chain.runtime_adapter.clone();
+    let trie_source = TrieStatePartSource {
+        epoch_manager: chain.epoch_manager.clone(),
+        tries: runtime.get_tries(),
+    };
+    let runtime_source = RuntimeStatePartSource(runtime);
+    for part_id in 0..num_parts {
+        let part_id = PartId::new(part_id, num_parts);
+        assert_eq!(
+            trie_source.obtain_state_part(0, &sync_prev_hash, &state_root, part_id).unwrap(),
+            runtime_source.obtain_state_part(0, &sync_prev_hash, &state_root, part_id).unwrap()
+        );
+    }
+}
+
+/// The manifest of an epoch is written only once the dumped parts are
+/// verified to reconstruct the state.
+#[test]
+fn test_dump_with_reconstruction_verification() {
+    init_test_logger();
+
+    let accounts: Vec<AccountId> = vec!["test0".parse().unwrap()];
+    let mut genesis = Genesis::test(accounts, 1);
+    genesis.config.epoch_length = EPOCH_LENGTH;
+    let chain_genesis = ChainGenesis::new(&genesis);
+    let mut env = TestEnv::builder(chain_genesis.clone())
+        .real_epoch_managers(&genesis.config)
+        .nightshade_runtimes(&genesis)
+        .build();
+    let chain = &env.clients[0].chain;
+    let epoch_manager = chain.epoch_manager.clone();
+    let shard_tracker = chain.shard_tracker.clone();
+    let runtime = chain.runtime_adapter.clone();
+    let root_dir = tempfile::Builder::new().prefix("state_dump").tempdir().unwrap();
+    let mut config = env.clients[0].config.clone();
+    config.state_sync.dump = Some(DumpConfig {
+        location: ExternalStorageLocation::Filesystem { root_dir: root_dir.path().to_path_buf() },
+        idle_delay: Some(Duration::from_millis(100)),
+        verify_reconstruction_every_n_epochs: Some(1),
+        ..Default::default()
+    });
+    let chain_id = config.chain_id.clone();
+
+    near_actix_test_utils::run_actix(async move {
+        let _state_sync_dump_handle = spawn_state_sync_dump(
+            &config,
+            chain_genesis,
+            epoch_manager.clone(),
+            shard_tracker,
+            runtime,
+            Some("test0".parse().unwrap()),
+            Arc::new(AtomicBool::new(false)),
+        )
+        .unwrap();
+        for height in 1..=EPOCH_LENGTH + 4 {
+            let block = env.clients[0].produce_block(height).unwrap().unwrap();
+            env.process_block(0, block, Provenance::PRODUCED);
+        }
+        let epoch_id = env.clients[0].chain.head().unwrap().epoch_id;
+        let epoch_height = epoch_manager.get_epoch_info(&epoch_id).unwrap().epoch_height();
+        let manifest_path = root_dir.path().join(external_storage_manifest_location(
+            &chain_id,
+            &epoch_id,
+            epoch_height,
+            0,
+            None,
+        ));
+        wait_or_timeout(100, 10000, || async {
+            if manifest_path.exists() {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+        .await
+        .unwrap();
+        actix_rt::System::current().stop();
+    });
+}
+
+/// A state dump job, as started by the `adv_dump_state` RPC method, dumps all
+/// parts of the latest complete epoch, and the manifest of the epoch.
+#[cfg(feature = "test_features")]
+#[test]
+fn test_state_dump_job() {
+    use near_client::sync::state_dump_job::StateDumpJobs;
+
+    init_test_logger();
+
+    let accounts: Vec<AccountId> = vec!["test0".parse().unwrap()];
+    let mut genesis = Genesis::test(accounts, 1);
+    genesis.config.epoch_length = EPOCH_LENGTH;
+    let chain_genesis = ChainGenesis::new(&genesis);
+    let mut env = TestEnv::builder(chain_genesis.clone())
+        .real_epoch_managers(&genesis.config)
+        .nightshade_runtimes(&genesis)
+        .build();
+    for height in 1..=EPOCH_LENGTH + 4 {
+        let block = env.clients[0].produce_block(height).unwrap().unwrap();
+        env.process_block(0, block, Provenance::PRODUCED);
+    }
+    let root_dir = tempfile::Builder::new().prefix("state_dump").tempdir().unwrap();
+    let dump_config = DumpConfig {
+        location: ExternalStorageLocation::Filesystem { root_dir: root_dir.path().to_path_buf() },
+        // The dump loop skips the epochs, so the parts can only be dumped by
+        // the job.
+        dump_every_n_epochs: Some(1000),
+        ..Default::default()
+    };
+    let mut config = env.clients[0].config.clone();
+    config.state_sync.dump = Some(dump_config.clone());
+    let chain_id = config.chain_id.clone();
+
+    near_actix_test_utils::run_actix(async move {
+        let chain = &env.clients[0].chain;
+        let state_sync_dump_handle = spawn_state_sync_dump(
+            &config,
+            chain_genesis,
+            chain.epoch_manager.clone(),
+            chain.shard_tracker.clone(),
+            chain.runtime_adapter.clone(),
+            Some("test0".parse().unwrap()),
+            Arc::new(AtomicBool::new(false)),
+        )
+        .unwrap()
+        .unwrap();
+        let jobs = StateDumpJobs::default();
+        assert!(jobs.start(chain, &dump_config, vec![0]).is_err());
+        jobs.set_shard_dumper(state_sync_dump_handle.shard_state_dumper());
+        let job_id = jobs.start(chain, &dump_config, vec![0]).unwrap();
+        let progress = wait_or_timeout(100, 10000, || async {
+            match jobs.progress(job_id) {
+                Some(progress) if progress.done => ControlFlow::Break(progress),
+                _ => ControlFlow::Continue(()),
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(progress.error, None);
+        let shard = &progress.shards[0];
+        assert_eq!(shard.dumped_parts, shard.num_parts);
+        let epoch_id = chain.get_block_header(&progress.sync_hash).unwrap().epoch_id().clone();
+        for part_id in 0..shard.num_parts {
+            let location = external_storage_location(
+                &DefaultPartNaming,
+                &chain_id,
+                &epoch_id,
+                progress.epoch_height,
+                0,
+                part_id,
+                shard.num_parts,
+                None,
+            );
+            assert!(root_dir.path().join(location).exists());
+        }
+        let manifest_location = external_storage_manifest_location(
+            &chain_id,
+            &epoch_id,
+            progress.epoch_height,
+            0,
+            None,
+        );
+        assert!(root_dir.path().join(manifest_location).exists());
+        actix_rt::System::current().stop();
+    });
+}
@@ -432,8 +432,8 @@ fn sync_state_dump() {
             let dump_dir = tempfile::Builder::new().prefix("state_dump_1").tempdir().unwrap();
             near1.client_config.state_sync.dump = Some(DumpConfig {
                 location: Filesystem { root_dir: dump_dir.path().to_path_buf() },
-                restart_dump_for_shards: None,
-                iteration_delay: Some(Duration::from_millis(100)),
+                idle_delay: Some(Duration::from_millis(100)),
+                ..Default::default()
             });
 
             let dir1 = tempfile::Builder::new().prefix("sync_nodes_1").tempdir().unwrap();
@@ -482,6 +482,8 @@ fn sync_state_dump() {
                                             root_dir: dump_dir.path().to_path_buf(),
                                         },
                                         num_concurrent_requests: 10,
+                                        date_partitioned: false,
+                                        path_chain_id: None,
                                     });
 
                                 let nearcore::NearNode {
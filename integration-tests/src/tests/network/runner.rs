@@ -32,6 +32,7 @@ use std::future::Future;
 use std::iter::Iterator;
 use std::net::{Ipv6Addr, SocketAddr};
 use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use tracing::debug;
 
@@ -86,6 +87,7 @@ fn setup_network_node(
         None,
         adv.clone(),
         None,
+        Arc::new(AtomicBool::new(false)),
     )
     .0;
     let view_client_actor = start_view_client(
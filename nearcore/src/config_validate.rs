@@ -1,9 +1,10 @@
-use near_chain_configs::{ExternalStorageLocation, SyncConfig};
+use near_chain_configs::{ExternalStorageLocation, RestartShards, SyncConfig};
 use near_config_utils::{ValidationError, ValidationErrors};
 use std::collections::HashSet;
 use std::path::Path;
 
 use crate::config::Config;
+use crate::state_sync::DEFAULT_IDLE_DELAY;
 
 /// Validate Config extracted from config.json.
 /// This function does not panic. It returns the error if any validation fails.
@@ -81,7 +82,9 @@ impl<'a> ConfigValidator<'a> {
 
         if let Some(state_sync) = &self.config.state_sync {
             if let Some(dump_config) = &state_sync.dump {
-                if let Some(restart_dump_for_shards) = &dump_config.restart_dump_for_shards {
+                if let Some(RestartShards::Shards(restart_dump_for_shards)) =
+                    &dump_config.restart_dump_for_shards
+                {
                     let unique_values: HashSet<_> = restart_dump_for_shards.iter().collect();
                     if unique_values.len() != restart_dump_for_shards.len() {
                         let error_message = format!("'config.state_sync.dump.restart_dump_for_shards' contains duplicate values.");
@@ -89,6 +92,61 @@ impl<'a> ConfigValidator<'a> {
                     }
                 }
 
+                // Skipped shards are not dumped at all, so restarting all
+                // shards doesn't conflict with skipping some.
+                if let (Some(skip_shards), Some(RestartShards::Shards(restart_dump_for_shards))) =
+                    (&dump_config.skip_shards, &dump_config.restart_dump_for_shards)
+                {
+                    let both: Vec<_> = skip_shards
+                        .iter()
+                        .filter(|shard_id| restart_dump_for_shards.contains(shard_id))
+                        .collect();
+                    if !both.is_empty() {
+                        let error_message = format!("'config.state_sync.dump.skip_shards' and 'config.state_sync.dump.restart_dump_for_shards' both contain shards {:?}.", both);
+                        self.validation_errors.push_config_semantics_error(error_message);
+                    }
+                }
+
+                if let Some(min_iteration_delay) = dump_config.min_iteration_delay {
+                    let idle_delay = dump_config.idle_delay.unwrap_or(DEFAULT_IDLE_DELAY);
+                    if min_iteration_delay.is_zero() || min_iteration_delay > idle_delay {
+                        let error_message = format!("'config.state_sync.dump.min_iteration_delay' needs to be greater than 0 and at most 'config.state_sync.dump.idle_delay', which is {:?}", idle_delay);
+                        self.validation_errors.push_config_semantics_error(error_message);
+                    }
+                }
+
+                if dump_config.max_concurrent_shard_dumps == Some(0) {
+                    let error_message = format!("'config.state_sync.dump.max_concurrent_shard_dumps' needs to be greater than 0");
+                    self.validation_errors.push_config_semantics_error(error_message);
+                }
+
+                if dump_config.max_dump_threads == Some(0) {
+                    let error_message = format!(
+                        "'config.state_sync.dump.max_dump_threads' needs to be greater than 0"
+                    );
+                    self.validation_errors.push_config_semantics_error(error_message);
+                }
+
+                if dump_config.verify_reconstruction_every_n_epochs == Some(0) {
+                    let error_message = format!("'config.state_sync.dump.verify_reconstruction_every_n_epochs' needs to be greater than 0");
+                    self.validation_errors.push_config_semantics_error(error_message);
+                }
+
+                if dump_config.max_parts_per_iteration == Some(0) {
+                    let error_message = format!("'config.state_sync.dump.max_parts_per_iteration' needs to be greater than 0");
+                    self.validation_errors.push_config_semantics_error(error_message);
+                }
+
+                // Incremental dumps point to the parts of earlier epochs, and
+                // earlier epochs under other dates wouldn't be found.
+                if dump_config.latest_only.unwrap_or(false)
+                    && (dump_config.incremental.unwrap_or(false)
+                        || dump_config.date_partitioned.unwrap_or(false))
+                {
+                    let error_message = format!("'config.state_sync.dump.latest_only' can't be enabled together with 'config.state_sync.dump.incremental' or 'config.state_sync.dump.date_partitioned'");
+                    self.validation_errors.push_config_semantics_error(error_message);
+                }
+
                 match &dump_config.location {
                     ExternalStorageLocation::S3 { bucket, region } => {
                         if bucket.is_empty() || region.is_empty() {
@@ -102,6 +160,25 @@ impl<'a> ConfigValidator<'a> {
                             self.validation_errors.push_config_semantics_error(error_message);
                         }
                     }
+                    ExternalStorageLocation::Azure { container, account } => {
+                        if container.is_empty() || account.is_empty() {
+                            let error_message = format!("'config.state_sync.dump.location.Azure.container' and 'config.state_sync.dump.location.Azure.account' need to be specified when 'config.state_sync.dump.location.Azure' is present.");
+                            self.validation_errors.push_config_semantics_error(error_message);
+                        }
+                    }
+                    ExternalStorageLocation::Pipe { path } => {
+                        if path.as_path() == Path::new("") {
+                            let error_message = format!("'config.state_sync.dump.location.Pipe.path' needs to be specified when 'config.state_sync.dump.location.Pipe' is present.");
+                            self.validation_errors.push_config_semantics_error(error_message);
+                        }
+                        // Both need to read back what was dumped.
+                        if dump_config.part_lease_ttl.is_some()
+                            || dump_config.verify_reconstruction_every_n_epochs.is_some()
+                        {
+                            let error_message = format!("'config.state_sync.dump.part_lease_ttl' and 'config.state_sync.dump.verify_reconstruction_every_n_epochs' can't be set when 'config.state_sync.dump.location.Pipe' is present.");
+                            self.validation_errors.push_config_semantics_error(error_message);
+                        }
+                    }
                 }
             }
             match &state_sync.sync {
@@ -120,6 +197,16 @@ impl<'a> ConfigValidator<'a> {
                                 self.validation_errors.push_config_semantics_error(error_message);
                             }
                         }
+                        ExternalStorageLocation::Azure { container, account } => {
+                            if container.is_empty() || account.is_empty() {
+                                let error_message = format!("'config.state_sync.sync.ExternalStorage.location.Azure.container' and 'config.state_sync.sync.ExternalStorage.location.Azure.account' need to be specified when 'config.state_sync.sync.ExternalStorage.location.Azure' is present.");
+                                self.validation_errors.push_config_semantics_error(error_message);
+                            }
+                        }
+                        ExternalStorageLocation::Pipe { .. } => {
+                            let error_message = format!("'config.state_sync.sync.ExternalStorage.location.Pipe' is not supported, state parts can't be read from a pipe.");
+                            self.validation_errors.push_config_semantics_error(error_message);
+                        }
                     }
                     if config.num_concurrent_requests == 0 {
                         let error_message = format!("'config.state_sync.sync.ExternalStorage.num_concurrent_requests' needs to be greater than 0");
@@ -143,6 +230,7 @@ impl<'a> ConfigValidator<'a> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use near_chain_configs::{DumpConfig, StateSyncConfig};
 
     #[test]
     #[should_panic(expected = "gc config values should all be greater than 0")]
@@ -201,4 +289,22 @@ mod test {
         config.save_trie_changes = Some(false);
         validate_config(&config).unwrap();
     }
+
+    #[test]
+    #[should_panic(
+        expected = "\\nconfig.json semantic issue: 'config.state_sync.dump.skip_shards' and 'config.state_sync.dump.restart_dump_for_shards' both contain shards [1]."
+    )]
+    fn test_state_dump_shard_skipped_and_restarted() {
+        let mut config = Config::default();
+        config.state_sync = Some(StateSyncConfig {
+            dump: Some(DumpConfig {
+                location: ExternalStorageLocation::Filesystem { root_dir: "/tmp/dump".into() },
+                restart_dump_for_shards: Some(RestartShards::Shards(vec![0, 1])),
+                skip_shards: Some(vec![1, 2]),
+                ..Default::default()
+            }),
+            sync: SyncConfig::Peers,
+        });
+        validate_config(&config).unwrap();
+    }
 }
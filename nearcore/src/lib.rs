@@ -22,6 +22,7 @@ use near_store::metrics::spawn_db_metrics_loop;
 use near_store::{DBCol, Mode, NodeStorage, Store, StoreOpenerError};
 use near_telemetry::TelemetryActor;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use tokio::sync::broadcast;
 use tracing::info;
@@ -265,6 +266,9 @@ pub fn start_with_config_and_synchronization(
     let shards_manager_adapter = Arc::new(LateBoundSender::default());
     let client_adapter_for_shards_manager = Arc::new(LateBoundSender::default());
     let adv = near_client::adversarial::Controls::new(config.client_config.archive);
+    #[cfg(feature = "test_features")]
+    let state_dump_jobs_adv = adv.clone();
+    let block_production_in_progress = Arc::new(AtomicBool::new(false));
 
     let view_client = start_view_client(
         config.validator_signer.as_ref().map(|signer| signer.validator_id().clone()),
@@ -290,6 +294,7 @@ pub fn start_with_config_and_synchronization(
         shutdown_signal,
         adv,
         config_updater,
+        block_production_in_progress.clone(),
     );
     client_adapter_for_shards_manager.bind(client_actor.clone().with_auto_span_context());
     let (shards_manager_actor, shards_manager_arbiter_handle) = start_shards_manager(
@@ -310,7 +315,14 @@ pub fn start_with_config_and_synchronization(
         shard_tracker,
         runtime,
         config.validator_signer.as_ref().map(|signer| signer.validator_id().clone()),
+        block_production_in_progress,
     )?;
+    #[cfg(feature = "test_features")]
+    if let Some(state_sync_dump_handle) = &state_sync_dump_handle {
+        state_dump_jobs_adv
+            .state_dump_jobs()
+            .set_shard_dumper(state_sync_dump_handle.shard_state_dumper());
+    }
 
     #[allow(unused_mut)]
     let mut rpc_servers = Vec::new();
@@ -1,7 +1,7 @@
 use near_o11y::metrics::{
-    exponential_buckets, linear_buckets, try_create_histogram_vec, try_create_int_counter_vec,
-    try_create_int_gauge, try_create_int_gauge_vec, HistogramVec, IntCounterVec, IntGauge,
-    IntGaugeVec,
+    exponential_buckets, linear_buckets, try_create_gauge_vec, try_create_histogram_vec,
+    try_create_int_counter_vec, try_create_int_gauge, try_create_int_gauge_vec, GaugeVec,
+    HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
 };
 use once_cell::sync::Lazy;
 
@@ -69,6 +69,33 @@ pub(crate) static STATE_SYNC_DUMP_SIZE_TOTAL: Lazy<IntCounterVec> = Lazy::new(||
     .unwrap()
 });
 
+pub(crate) static STATE_SYNC_DUMP_THROUGHPUT_BYTES_PER_SEC: Lazy<GaugeVec> = Lazy::new(|| {
+    try_create_gauge_vec(
+        "near_state_sync_dump_throughput_bytes_per_sec",
+        "Moving average of the rate of dumping state parts over recent iterations",
+        &["shard_id"],
+    )
+    .unwrap()
+});
+
+pub(crate) static STATE_SYNC_DUMP_EPOCH_SIZE_BYTES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    try_create_int_gauge_vec(
+        "near_state_sync_dump_epoch_size_bytes",
+        "Combined size of the state parts of the latest fully dumped epoch",
+        &["shard_id"],
+    )
+    .unwrap()
+});
+
+pub(crate) static STATE_SYNC_DUMP_ESTIMATED_EPOCH_BYTES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    try_create_int_gauge_vec(
+        "near_state_sync_dump_estimated_epoch_bytes",
+        "Estimate of the total size of the state parts of the epoch being dumped, extrapolated from a few sampled parts",
+        &["shard_id"],
+    )
+    .unwrap()
+});
+
 pub(crate) static STATE_SYNC_DUMP_EPOCH_HEIGHT: Lazy<IntGaugeVec> = Lazy::new(|| {
     try_create_int_gauge_vec(
         "near_state_sync_dump_epoch_height",
@@ -78,6 +105,198 @@ pub(crate) static STATE_SYNC_DUMP_EPOCH_HEIGHT: Lazy<IntGaugeVec> = Lazy::new(||
     .unwrap()
 });
 
+pub(crate) static STATE_SYNC_DUMP_EPOCH_DURATION_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    try_create_histogram_vec(
+        "near_state_sync_dump_epoch_duration_seconds",
+        "Time from the start of dumping an epoch until all its parts are dumped",
+        &["shard_id"],
+        Some(exponential_buckets(1.0, 2.0, 20).unwrap()),
+    )
+    .unwrap()
+});
+
+pub(crate) static STATE_SYNC_DUMP_WAIT_FOR_TURN_ELAPSED: Lazy<HistogramVec> = Lazy::new(|| {
+    try_create_histogram_vec(
+        "near_state_sync_dump_wait_for_turn_elapsed_sec",
+        "Time a shard waits for its turn to dump parts, if the number of concurrently dumped shards is limited",
+        &["shard_id"],
+        Some(exponential_buckets(0.001, 2.0, 20).unwrap()),
+    )
+    .unwrap()
+});
+
+pub(crate) static STATE_SYNC_DUMP_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    try_create_int_counter_vec(
+        "near_state_sync_dump_errors_total",
+        "Number of failures of the state dump loop by category",
+        &["shard_id", "error"],
+    )
+    .unwrap()
+});
+
+pub(crate) static STATE_SYNC_DUMP_LIST_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    try_create_int_counter_vec(
+        "near_state_sync_dump_list_failures_total",
+        "Number of failures to list state parts in external storage, by whether they can be retried",
+        &["shard_id", "kind"],
+    )
+    .unwrap()
+});
+
+pub(crate) static STATE_SYNC_DUMP_SECONDS_SINCE_LAST_LIST: Lazy<GaugeVec> = Lazy::new(|| {
+    try_create_gauge_vec(
+        "near_state_sync_dump_seconds_since_last_list",
+        "Time since the state parts of the epoch being dumped were last listed successfully, as of the latest attempt to list them",
+        &["shard_id"],
+    )
+    .unwrap()
+});
+
+pub(crate) static STATE_SYNC_DUMP_SKIPPED_GAP_EPOCHS: Lazy<IntCounterVec> = Lazy::new(|| {
+    try_create_int_counter_vec(
+        "near_state_sync_dump_skipped_gap_epochs_total",
+        "Number of epochs that were not dumped because their state was no longer available when the dump loop caught up",
+        &["shard_id"],
+    )
+    .unwrap()
+});
+
+pub(crate) static STATE_SYNC_DUMP_IDLE_REASON: Lazy<IntCounterVec> = Lazy::new(|| {
+    try_create_int_counter_vec(
+        "near_state_sync_dump_idle_reason_total",
+        "Number of times the dump loop found no new epoch to dump, by reason",
+        &["shard_id", "reason"],
+    )
+    .unwrap()
+});
+
+pub(crate) static STATE_SYNC_DUMP_DEFERRED_ITERATIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    try_create_int_counter_vec(
+        "near_state_sync_dump_deferred_iterations_total",
+        "Number of times obtaining state parts was deferred because the node was busy with a block",
+        &["shard_id"],
+    )
+    .unwrap()
+});
+
+pub(crate) static STATE_SYNC_DUMP_EPOCHS_SKIPPED_UNAVAILABLE: Lazy<IntCounterVec> =
+    Lazy::new(|| {
+        try_create_int_counter_vec(
+            "near_state_sync_dump_epochs_skipped_unavailable_total",
+            "Number of epochs that were not dumped because their state was garbage collected",
+            &["shard_id"],
+        )
+        .unwrap()
+    });
+
+pub(crate) static STATE_SYNC_DUMP_EPOCHS_SKIPPED_UNTRACKED: Lazy<IntCounterVec> = Lazy::new(|| {
+    try_create_int_counter_vec(
+        "near_state_sync_dump_epochs_skipped_untracked_total",
+        "Number of epochs whose dump was stopped because the node stopped tracking the shard",
+        &["shard_id"],
+    )
+    .unwrap()
+});
+
+pub(crate) static STATE_SYNC_DUMP_SKIPPED_IDENTICAL_PARTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    try_create_int_counter_vec(
+        "near_state_sync_dump_skipped_identical_parts_total",
+        "Number of state parts not uploaded because an identical part was already stored",
+        &["shard_id"],
+    )
+    .unwrap()
+});
+
+pub(crate) static STATE_SYNC_DUMP_BACKEND: Lazy<IntGaugeVec> = Lazy::new(|| {
+    try_create_int_gauge_vec(
+        "near_state_sync_dump_backend",
+        "Set to 1 for the kind of external storage the state is dumped to",
+        &["backend"],
+    )
+    .unwrap()
+});
+
+pub(crate) static STATE_SYNC_DUMP_RECONSTRUCTION_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    try_create_int_counter_vec(
+        "near_state_sync_dump_reconstruction_failures_total",
+        "Number of dumped epochs whose parts didn't reconstruct the state, and which are dumped again",
+        &["shard_id"],
+    )
+    .unwrap()
+});
+
+pub(crate) static STATE_SYNC_DUMP_POINTER_PARTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    try_create_int_counter_vec(
+        "near_state_sync_dump_pointer_parts_total",
+        "Number of parts stored as pointers to identical parts of the previous epoch",
+        &["shard_id"],
+    )
+    .unwrap()
+});
+
+pub(crate) static STATE_SYNC_DUMP_NUM_PARTS_MISMATCHES: Lazy<IntCounterVec> = Lazy::new(|| {
+    try_create_int_counter_vec(
+        "near_state_sync_dump_num_parts_mismatches",
+        "Number of epoch dumps abandoned, because the number of parts differed from the one recorded when the dump started",
+        &["shard_id"],
+    )
+    .unwrap()
+});
+
+pub(crate) static STATE_SYNC_DUMP_ZERO_PART_EPOCHS: Lazy<IntCounterVec> = Lazy::new(|| {
+    try_create_int_counter_vec(
+        "near_state_sync_dump_zero_part_epochs",
+        "Number of epochs of tracked shards skipped, because the state of the shard has no parts",
+        &["shard_id"],
+    )
+    .unwrap()
+});
+
+pub(crate) static STATE_SYNC_DUMP_EPOCHS_SKIPPED_INVALID_PROBE: Lazy<IntCounterVec> =
+    Lazy::new(|| {
+        try_create_int_counter_vec(
+            "near_state_sync_dump_epochs_skipped_invalid_probe",
+            "Number of epochs skipped, because a part probed before the dump was invalid",
+            &["shard_id"],
+        )
+        .unwrap()
+    });
+
+pub(crate) static STATE_SYNC_DUMP_DELETED_EPOCHS: Lazy<IntCounterVec> = Lazy::new(|| {
+    try_create_int_counter_vec(
+        "near_state_sync_dump_deleted_epochs",
+        "Number of dumps of earlier epochs deleted, because only the latest epoch is kept",
+        &["shard_id"],
+    )
+    .unwrap()
+});
+
+pub(crate) static STATE_SYNC_DUMP_BACKFILL_EPOCHS_REMAINING: Lazy<IntGaugeVec> = Lazy::new(|| {
+    try_create_int_gauge_vec(
+        "near_state_sync_dump_backfill_epochs_remaining",
+        "Number of missed and interrupted epochs left to dump before the latest epoch. Zero if there are none, or if they are not dumped",
+        &["shard_id"],
+    )
+    .unwrap()
+});
+
+pub(crate) static STATE_SYNC_DUMP_BACKFILL_EPOCHS_DONE: Lazy<IntCounterVec> = Lazy::new(|| {
+    try_create_int_counter_vec(
+        "near_state_sync_dump_backfill_epochs_done",
+        "Number of epochs whose dump was completed while a newer epoch was available",
+        &["shard_id"],
+    )
+    .unwrap()
+});
+
+pub(crate) static STATE_SYNC_DUMP_COMPLETED_SHARDS: Lazy<IntGauge> = Lazy::new(|| {
+    try_create_int_gauge(
+        "near_state_sync_dump_completed_shards",
+        "Number of shards whose dump of the current epoch is complete according to the progress of this node",
+    )
+    .unwrap()
+});
+
 pub(crate) static STATE_SYNC_APPLY_PART_DELAY: Lazy<near_o11y::metrics::HistogramVec> =
     Lazy::new(|| {
         try_create_histogram_vec(
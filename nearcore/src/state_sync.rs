@@ -1,24 +1,41 @@
 use crate::metrics;
-use borsh::BorshSerialize;
+use borsh::{BorshDeserialize, BorshSerialize};
 use near_chain::types::RuntimeAdapter;
-use near_chain::{Chain, ChainGenesis, ChainStoreAccess, DoomslugThresholdMode, Error};
-use near_chain_configs::{ClientConfig, ExternalStorageLocation};
+use near_chain::{Chain, ChainGenesis, ChainStore, ChainStoreAccess, DoomslugThresholdMode, Error};
+use near_chain_configs::{ClientConfig, RestartShards};
 use near_client::sync::state::{
-    external_storage_location, external_storage_location_directory, get_part_id_from_filename,
-    is_part_filename, ExternalConnection, StateSync, STATE_DUMP_ITERATION_TIME_LIMIT_SECS,
+    create_dump_external_connection, date_partition, external_storage_archive_location,
+    external_storage_lease_location, external_storage_location,
+    external_storage_location_directory, external_storage_manifest_location,
+    get_part_id_from_filename, get_part_id_from_lease_filename, state_part_pointer,
+    DefaultPartNaming, ExternalConnection, IdentityPartTransform, ListStatePartsError,
+    ListedStatePart, PartNaming, PartTransform, StatePartsManifest, StateSync,
+    STATE_DUMP_ITERATION_TIME_LIMIT_SECS,
 };
+#[cfg(feature = "test_features")]
+use near_client::sync::state_dump_job::{ShardStateDumper, ShardStateToDump};
+use near_client::sync::state_parts_archive::StatePartsArchiveWriter;
 use near_epoch_manager::shard_tracker::ShardTracker;
 use near_epoch_manager::EpochManagerAdapter;
-use near_primitives::hash::CryptoHash;
+use near_primitives::challenge::PartialState;
+use near_primitives::errors::StorageError;
+use near_primitives::hash::{hash, CryptoHash};
+use near_primitives::merkle::merklize;
+use near_primitives::shard_layout::ShardUId;
 use near_primitives::state_part::PartId;
-use near_primitives::syncing::{get_num_state_parts, StatePartKey, StateSyncDumpProgress};
-use near_primitives::types::{AccountId, EpochHeight, EpochId, ShardId, StateRoot};
-use near_store::DBCol;
+use near_primitives::syncing::{
+    get_num_state_parts, StatePartKey, StateSyncDumpBackfill, StateSyncDumpProgress,
+};
+use near_primitives::types::{AccountId, BlockHeight, EpochHeight, EpochId, ShardId, StateRoot};
+use near_store::flat::FlatStorageManager;
+use near_store::{DBCol, NodeStorage, ShardTries, Store, StoreConfig, Trie, TrieConfig};
 use rand::{thread_rng, Rng};
 use std::collections::HashSet;
+use std::ops::{ControlFlow, Range};
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Semaphore;
 
 /// Starts one a thread per tracked shard.
 /// Each started thread will be dumping state parts of a single epoch to external storage.
@@ -29,6 +46,54 @@ pub fn spawn_state_sync_dump(
     shard_tracker: ShardTracker,
     runtime: Arc<dyn RuntimeAdapter>,
     account_id: Option<AccountId>,
+    block_production_in_progress: Arc<AtomicBool>,
+) -> anyhow::Result<Option<StateSyncDumpHandle>> {
+    spawn_state_sync_dump_with_options(
+        client_config,
+        chain_genesis,
+        epoch_manager,
+        shard_tracker,
+        runtime,
+        account_id,
+        block_production_in_progress,
+        StateSyncDumpOptions::default(),
+    )
+}
+
+/// Customizations of the state dump that can't be expressed in the config.
+pub struct StateSyncDumpOptions {
+    /// Names the part files, for example to produce a dump that a different
+    /// client can consume.
+    pub part_naming: Arc<dyn PartNaming>,
+    /// Obtains the state parts instead of the runtime of the node, for example
+    /// to let a dedicated dumper read the state from a cold store.
+    pub part_source: Option<Arc<dyn StatePartSource>>,
+    /// Transforms the parts before they are uploaded, for example to encrypt
+    /// them. Nodes syncing from the dump need to decode the parts with the
+    /// same transform, see `StateSync::new_with_part_transform()`.
+    pub part_transform: Arc<dyn PartTransform>,
+}
+
+impl Default for StateSyncDumpOptions {
+    fn default() -> Self {
+        Self {
+            part_naming: Arc::new(DefaultPartNaming),
+            part_source: None,
+            part_transform: Arc::new(IdentityPartTransform),
+        }
+    }
+}
+
+/// Same as `spawn_state_sync_dump()`, but customized with `options`.
+pub fn spawn_state_sync_dump_with_options(
+    client_config: &ClientConfig,
+    chain_genesis: ChainGenesis,
+    epoch_manager: Arc<dyn EpochManagerAdapter>,
+    shard_tracker: ShardTracker,
+    runtime: Arc<dyn RuntimeAdapter>,
+    account_id: Option<AccountId>,
+    block_production_in_progress: Arc<AtomicBool>,
+    options: StateSyncDumpOptions,
 ) -> anyhow::Result<Option<StateSyncDumpHandle>> {
     let dump_config = if let Some(dump_config) = client_config.state_sync.dump.clone() {
         dump_config
@@ -39,24 +104,22 @@ pub fn spawn_state_sync_dump(
     };
     tracing::info!(target: "state_sync_dump", "Spawning the state sync dump loop");
 
-    let external = match dump_config.location {
-        ExternalStorageLocation::S3 { bucket, region } => {
-            // Credentials to establish a connection are taken from environment variables:
-            // * `AWS_ACCESS_KEY_ID`
-            // * `AWS_SECRET_ACCESS_KEY`
-            let creds = match s3::creds::Credentials::default() {
-                Ok(creds) => creds,
-                Err(err) => {
-                    tracing::error!(target: "state_sync_dump", "Failed to create a connection to S3. Did you provide environment variables AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY?");
-                    return Err(err.into());
-                }
-            };
-            let bucket = s3::Bucket::new(&bucket, region.parse::<s3::Region>()?, creds)?;
-            ExternalConnection::S3 { bucket: Arc::new(bucket) }
-        }
-        ExternalStorageLocation::Filesystem { root_dir } => {
-            ExternalConnection::Filesystem { root_dir }
+    let external =
+        create_dump_external_connection(&dump_config.location, dump_config.fsync.unwrap_or(false))?;
+    if let Err(err) = check_external_storage_access(&external) {
+        tracing::error!(target: "state_sync_dump", ?err, "Failed to access the external storage. Check the configured location, and if using S3 or Azure, the bucket or the container, the region or the account, and the credentials.");
+        return Err(err);
+    }
+    metrics::STATE_SYNC_DUMP_BACKEND.with_label_values(&[external.kind()]).set(1);
+
+    // State parts are kept in the main database unless a separate database is configured.
+    let state_parts_scratch_store = match &dump_config.state_parts_scratch_dir {
+        Some(scratch_dir) => {
+            let store_config = StoreConfig::default();
+            let storage = NodeStorage::opener(scratch_dir, false, &store_config, None).open()?;
+            Some(storage.get_hot_store())
         }
+        None => None,
     };
 
     // Determine how many threads to start.
@@ -75,13 +138,70 @@ pub fn spawn_state_sync_dump(
         epoch_manager.num_shards(&epoch_id)
     }?;
 
-    let chain_id = client_config.chain_id.clone();
     let keep_running = Arc::new(AtomicBool::new(true));
-    // Start a thread for each shard.
-    let handles = (0..num_shards as usize)
-        .map(|shard_id| {
-            let runtime = runtime.clone();
-            let chain_genesis = chain_genesis.clone();
+    // Validators can't afford to delay their blocks.
+    let defer_to_block_production =
+        dump_config.defer_to_block_production.unwrap_or(account_id.is_some());
+    let latest_only = dump_config.latest_only.unwrap_or(false);
+    let config = ShardDumpConfig {
+        chain_id: dump_config
+            .path_chain_id
+            .clone()
+            .unwrap_or_else(|| client_config.chain_id.clone()),
+        external,
+        state_parts_scratch_store,
+        part_source: options
+            .part_source
+            .unwrap_or_else(|| Arc::new(RuntimeStatePartSource(runtime.clone()))),
+        part_naming: options.part_naming,
+        part_transform: options.part_transform,
+        restart_dump_for_shards: dump_config.restart_dump_for_shards.clone(),
+        upload_archive: dump_config.upload_archive.unwrap_or(false),
+        skip_existing: dump_config.skip_existing.unwrap_or(false),
+        part_lease_ttl: dump_config.part_lease_ttl,
+        part_range: dump_config.part_range.map(|(start, end)| start..end),
+        idle_delay: dump_config.idle_delay.unwrap_or(DEFAULT_IDLE_DELAY),
+        min_iteration_delay: dump_config.min_iteration_delay,
+        work_delay: dump_config.work_delay.unwrap_or(Duration::ZERO),
+        dump_every_n_epochs: dump_config.dump_every_n_epochs,
+        // With `latest_only`, an interrupted epoch is abandoned, never resumed.
+        prefer_latest_epoch: dump_config.prefer_latest_epoch.unwrap_or(false) && !latest_only,
+        latest_only,
+        latest_only_grace_period: dump_config
+            .latest_only_grace_period
+            .unwrap_or(DEFAULT_LATEST_ONLY_GRACE_PERIOD),
+        probe_parts: dump_config.probe_parts.unwrap_or(0),
+        backfill_deadline: dump_config.backfill_deadline,
+        account_id,
+        block_production_in_progress: if defer_to_block_production {
+            Some(block_production_in_progress)
+        } else {
+            None
+        },
+        // Shared by all shards. A semaphore hands out permits in the order of
+        // requests, which lets the waiting shards take turns.
+        shard_dump_permits: dump_config
+            .max_concurrent_shard_dumps
+            .map(|max_concurrent_shard_dumps| Arc::new(Semaphore::new(max_concurrent_shard_dumps))),
+        date_partitioned: dump_config.date_partitioned.unwrap_or(false),
+        compute_merkle_root: dump_config.compute_merkle_root.unwrap_or(false),
+        incremental: dump_config.incremental.unwrap_or(false),
+        verify_reconstruction_every_n_epochs: dump_config.verify_reconstruction_every_n_epochs,
+        max_parts_per_iteration: dump_config.max_parts_per_iteration,
+        keep_running: keep_running.clone(),
+    };
+    let skip_shards = dump_config.skip_shards.clone().unwrap_or_default();
+    if !skip_shards.is_empty() {
+        tracing::info!(target: "state_sync_dump", ?skip_shards, "Not dumping the configured shards");
+    }
+    let shard_ids: Vec<ShardId> =
+        (0..num_shards).filter(|shard_id| !skip_shards.contains(shard_id)).collect();
+    // Start the dumps of the shards that aren't skipped.
+    let handles = spawn_shard_dump_threads(
+        &shard_ids,
+        dump_config.max_dump_threads,
+        &keep_running,
+        |shard_id, arbiter_handle| {
             let chain = Chain::new_for_view_client(
                 epoch_manager.clone(),
                 shard_tracker.clone(),
@@ -89,33 +209,65 @@ pub fn spawn_state_sync_dump(
                 &chain_genesis,
                 DoomslugThresholdMode::TwoThirds,
                 false,
-            )
-            .unwrap();
-            let arbiter_handle = actix_rt::Arbiter::new().handle();
-            assert!(arbiter_handle.spawn(state_sync_dump(
-                shard_id as ShardId,
-                chain,
-                epoch_manager.clone(),
-                shard_tracker.clone(),
-                runtime.clone(),
-                chain_id.clone(),
-                dump_config.restart_dump_for_shards.clone().unwrap_or_default(),
-                external.clone(),
-                dump_config.iteration_delay.unwrap_or(Duration::from_secs(10)),
-                account_id.clone(),
-                keep_running.clone(),
-            )));
-            arbiter_handle
-        })
-        .collect();
+            )?;
+            reconcile_dump_progress(shard_id, &chain);
+            assert!(arbiter_handle.spawn(state_sync_dump(shard_id, chain, config.clone())));
+            Ok(())
+        },
+    )?;
+    tracing::info!(target: "state_sync_dump", num_shards = shard_ids.len(), num_threads = handles.len(), "Started the state dump");
+
+    Ok(Some(StateSyncDumpHandle {
+        handles,
+        keep_running,
+        store: runtime.store().clone(),
+        genesis_height: chain_genesis.height,
+        shard_ids,
+        #[cfg(feature = "test_features")]
+        config,
+    }))
+}
 
-    Ok(Some(StateSyncDumpHandle { handles, keep_running }))
+/// Starts a thread for every shard, or `max_threads` threads if there are
+/// more shards, and calls `spawn_shard` for every shard with the arbiter of
+/// the thread to start the dump of the shard on. Shards are assigned to the
+/// threads in a round-robin way. If the dump of a shard fails to start, all
+/// threads get stopped, and the error is returned.
+fn spawn_shard_dump_threads(
+    shard_ids: &[ShardId],
+    max_threads: Option<usize>,
+    keep_running: &AtomicBool,
+    mut spawn_shard: impl FnMut(ShardId, &actix_rt::ArbiterHandle) -> anyhow::Result<()>,
+) -> anyhow::Result<Vec<actix_rt::ArbiterHandle>> {
+    let num_threads = match max_threads {
+        Some(max_threads) => std::cmp::min(std::cmp::max(max_threads, 1), shard_ids.len()),
+        None => shard_ids.len(),
+    };
+    let handles: Vec<_> = (0..num_threads).map(|_| actix_rt::Arbiter::new().handle()).collect();
+    for (index, &shard_id) in shard_ids.iter().enumerate() {
+        if let Err(err) = spawn_shard(shard_id, &handles[index % num_threads]) {
+            tracing::error!(target: "state_sync_dump", shard_id, ?err, "Failed to start the dump of a shard, stopping the dumps of the other shards");
+            keep_running.store(false, std::sync::atomic::Ordering::Relaxed);
+            for handle in handles {
+                handle.stop();
+            }
+            return Err(err);
+        }
+    }
+    Ok(handles)
 }
 
 /// Holds arbiter handles controlling the lifetime of the spawned threads.
 pub struct StateSyncDumpHandle {
     pub handles: Vec<actix_rt::ArbiterHandle>,
     keep_running: Arc<AtomicBool>,
+    /// Store with the persisted progress of the dump.
+    store: Store,
+    genesis_height: BlockHeight,
+    /// Shards dumped by this node.
+    shard_ids: Vec<ShardId>,
+    #[cfg(feature = "test_features")]
+    config: ShardDumpConfig,
 }
 
 impl Drop for StateSyncDumpHandle {
@@ -131,39 +283,382 @@ impl StateSyncDumpHandle {
             handle.stop();
         });
     }
+
+    /// Returns the shards whose dump of the given epoch is complete according
+    /// to the progress persisted by this node. Dumps by other nodes sharing
+    /// the external storage are not taken into account.
+    pub fn completed_shards(&self, epoch_id: &EpochId) -> Vec<ShardId> {
+        let chain_store = ChainStore::new(self.store.clone(), self.genesis_height, false);
+        completed_shards(&chain_store, self.shard_ids.iter().copied(), epoch_id)
+    }
+
+    /// Number of shards dumped by this node.
+    pub fn shard_count(&self) -> usize {
+        self.shard_ids.len()
+    }
+
+    /// Number of threads the dumps of the shards run on, see
+    /// `DumpConfig::max_dump_threads`.
+    pub fn thread_count(&self) -> usize {
+        self.handles.len()
+    }
+
+    /// Dumps shards on demand with the same options as the dump loop, for the
+    /// `adv_dump_state` RPC method.
+    #[cfg(feature = "test_features")]
+    pub fn shard_state_dumper(&self) -> Arc<dyn ShardStateDumper> {
+        Arc::new(OneShotShardDumper { config: self.config.clone(), store: self.store.clone() })
+    }
+}
+
+/// Dumps all parts of a shard of an epoch at once, regardless of the progress
+/// of the dump loop, which is left as is.
+#[cfg(feature = "test_features")]
+struct OneShotShardDumper {
+    config: ShardDumpConfig,
+    /// Stores the state parts unless a scratch database is configured.
+    store: Store,
+}
+
+#[cfg(feature = "test_features")]
+impl ShardStateDumper for OneShotShardDumper {
+    fn dump_shard(
+        &self,
+        shard: ShardStateToDump,
+        on_parts_dumped: Box<dyn Fn(u64) + Send + Sync>,
+    ) -> futures::future::BoxFuture<'static, anyhow::Result<()>> {
+        let config = self.config.clone();
+        let state_parts_store =
+            config.state_parts_scratch_store.clone().unwrap_or_else(|| self.store.clone());
+        Box::pin(async move {
+            dump_shard_once(&config, &state_parts_store, &shard, on_parts_dumped.as_ref())
+                .await
+                .map_err(anyhow::Error::from)
+        })
+    }
+}
+
+/// Dumps the parts missing from the external storage the same way as an
+/// iteration of the dump loop, and then finishes the dump of the epoch.
+#[cfg(feature = "test_features")]
+async fn dump_shard_once(
+    config: &ShardDumpConfig,
+    state_parts_store: &Store,
+    shard: &ShardStateToDump,
+    on_parts_dumped: &(dyn Fn(u64) + Send + Sync),
+) -> Result<(), StateSyncDumpError> {
+    let ShardStateToDump {
+        shard_id,
+        ref epoch_id,
+        epoch_height,
+        sync_hash,
+        sync_prev_hash,
+        sync_block_timestamp,
+        state_root,
+        num_parts,
+    } = *shard;
+    let date_partition =
+        if config.date_partitioned { Some(date_partition(sync_block_timestamp)) } else { None };
+    let report = if config.external.is_write_only() {
+        MissingPartsReport::new(
+            config.part_naming.as_ref(),
+            num_parts,
+            &[],
+            None,
+            SystemTime::now(),
+        )
+    } else {
+        get_missing_part_ids_for_epoch(
+            config.part_naming.as_ref(),
+            shard_id,
+            &config.chain_id,
+            date_partition.as_deref(),
+            epoch_id,
+            epoch_height,
+            num_parts,
+            None,
+            None,
+            &config.external,
+        )
+        .await
+        .map_err(StateSyncDumpError::ListFailed)?
+    };
+    let mut dumped_parts = report.present.len() as u64;
+    let mut total_bytes = report.present_bytes;
+    on_parts_dumped(dumped_parts);
+    for part_id in report.missing {
+        let state_part = match obtain_and_store_state_part_cancellable(
+            config.part_source.clone(),
+            shard_id,
+            sync_hash,
+            sync_prev_hash,
+            state_root,
+            part_id,
+            num_parts,
+            state_parts_store.clone(),
+            &config.keep_running,
+        )
+        .await
+        {
+            None => return Err(Error::Other("The node is stopping".to_string()).into()),
+            Some(result) => result.map_err(StateSyncDumpError::ObtainFailed)?,
+        };
+        let location = external_storage_location(
+            config.part_naming.as_ref(),
+            &config.chain_id,
+            epoch_id,
+            epoch_height,
+            shard_id,
+            part_id,
+            num_parts,
+            date_partition.as_deref(),
+        );
+        upload_state_part(
+            &config.part_transform.encode(&state_part),
+            None,
+            shard_id,
+            &location,
+            &config.external,
+        )
+        .await
+        .map_err(StateSyncDumpError::UploadFailed)?;
+        update_dumped_size_and_cnt_metrics(&shard_id, epoch_height, state_part.len());
+        dumped_parts += 1;
+        total_bytes += state_part.len() as u64;
+        on_parts_dumped(dumped_parts);
+    }
+    let epoch = EpochDump {
+        shard_id,
+        epoch_id: epoch_id.clone(),
+        epoch_height,
+        sync_hash,
+        sync_prev_hash,
+        state_root,
+        num_parts,
+        date_partition,
+    };
+    finish_epoch_dump(config, state_parts_store, &epoch, total_bytes, false, true).await
+}
+
+/// Shards among `shard_ids` whose progress is `AllDumped` for the epoch.
+fn completed_shards(
+    chain_store: &ChainStore,
+    shard_ids: impl Iterator<Item = ShardId>,
+    epoch_id: &EpochId,
+) -> Vec<ShardId> {
+    shard_ids
+        .filter(|&shard_id| match chain_store.get_state_sync_dump_progress(shard_id) {
+            Ok(Some(StateSyncDumpProgress::AllDumped { epoch_id: dumped_epoch_id, .. }))
+            | Ok(Some(StateSyncDumpProgress::AllDumpedV2 { epoch_id: dumped_epoch_id, .. })) => {
+                &dumped_epoch_id == epoch_id
+            }
+            _ => false,
+        })
+        .collect()
+}
+
+/// Sets the number of shards whose dump of the epoch of the head is complete.
+fn update_completed_shards_metric(chain: &Chain, epoch_manager: &dyn EpochManagerAdapter) {
+    let epoch_id = match chain.head() {
+        Ok(head) => head.epoch_id,
+        Err(_) => return,
+    };
+    let num_shards = match epoch_manager.num_shards(&epoch_id) {
+        Ok(num_shards) => num_shards,
+        Err(_) => return,
+    };
+    let completed_shards = completed_shards(chain.store(), 0..num_shards, &epoch_id);
+    metrics::STATE_SYNC_DUMP_COMPLETED_SHARDS.set(completed_shards.len() as i64);
+}
+
+/// Sets the number of epochs of the shard left to backfill, which is zero if
+/// missed and interrupted epochs are not dumped.
+fn update_backfill_epochs_remaining_metric(
+    shard_id: ShardId,
+    chain: &Chain,
+    epoch_manager: &dyn EpochManagerAdapter,
+    backfill: bool,
+) {
+    let num_epochs = if backfill {
+        match count_epochs_to_backfill(shard_id, chain, epoch_manager) {
+            Ok(num_epochs) => num_epochs,
+            Err(err) => {
+                tracing::debug!(target: "state_sync_dump", shard_id, ?err, "Failed to count the epochs left to backfill");
+                return;
+            }
+        }
+    } else {
+        0
+    };
+    metrics::STATE_SYNC_DUMP_BACKFILL_EPOCHS_REMAINING
+        .with_label_values(&[&shard_id.to_string()])
+        .set(num_epochs as i64);
+}
+
+/// Reasons why the state dump loop failed to make progress.
+#[derive(thiserror::Error, Debug)]
+pub enum StateSyncDumpError {
+    #[error("Failed to list state parts in external storage")]
+    ListFailed(#[source] ListStatePartsError),
+    #[error("Failed to obtain a state part")]
+    ObtainFailed(#[source] Error),
+    #[error("Failed to upload a state part to external storage")]
+    UploadFailed(#[source] anyhow::Error),
+    #[error("Failed to get a state header")]
+    HeaderFailed(#[source] Error),
+    #[error("Failed to write an archive of state parts")]
+    ArchiveFailed(#[source] std::io::Error),
+    #[error("Failed to download a dumped state part")]
+    DownloadFailed(#[source] anyhow::Error),
+    #[error("Dumped state parts don't reconstruct the state")]
+    ReconstructionFailed(#[source] anyhow::Error),
+    #[error("A state part probed before the dump is invalid")]
+    ProbeFailed(#[source] anyhow::Error),
+    #[error(transparent)]
+    Chain(#[from] Error),
+}
+
+impl StateSyncDumpError {
+    /// Value of the `error` label of the `STATE_SYNC_DUMP_ERRORS` metric.
+    pub fn label(&self) -> &'static str {
+        match self {
+            StateSyncDumpError::ListFailed(_) => "list",
+            StateSyncDumpError::ObtainFailed(_) => "obtain",
+            StateSyncDumpError::UploadFailed(_) => "upload",
+            StateSyncDumpError::HeaderFailed(_) => "header",
+            StateSyncDumpError::ArchiveFailed(_) => "archive",
+            StateSyncDumpError::DownloadFailed(_) => "download",
+            StateSyncDumpError::ReconstructionFailed(_) => "reconstruction",
+            StateSyncDumpError::ProbeFailed(_) => "probe",
+            StateSyncDumpError::Chain(_) => "chain",
+        }
+    }
+    /// Whether retrying will not help, and the dump loop needs to stop until
+    /// the configuration is fixed.
+    fn is_fatal(&self) -> bool {
+        match self {
+            StateSyncDumpError::ListFailed(err) => err.is_fatal(),
+            _ => false,
+        }
+    }
+}
+
+/// Blocks until `ExternalConnection::check_access()` completes.
+/// `spawn_state_sync_dump()` gets called from within an actix system, where
+/// blocking on a future is not allowed, therefore the check runs in a separate
+/// thread with its own runtime.
+fn check_external_storage_access(external: &ExternalConnection) -> anyhow::Result<()> {
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?
+                    .block_on(external.check_access())
+            })
+            .join()
+            .unwrap_or_else(|_| {
+                Err(anyhow::anyhow!("Checking access to the external storage panicked"))
+            })
+    })
+}
+
+/// Which parts of an epoch dump are already present in the external storage.
+#[derive(Debug, PartialEq, Eq)]
+struct MissingPartsReport {
+    total: u64,
+    present: Vec<u64>,
+    missing: Vec<u64>,
+    /// Missing parts that are currently being dumped by another node.
+    leased: Vec<u64>,
+    /// Combined size of the present parts. Parts with unknown size are not
+    /// accounted for.
+    present_bytes: u64,
 }
 
-fn extract_part_id_from_part_file_name(file_name: &String) -> u64 {
-    assert!(is_part_filename(file_name));
-    return get_part_id_from_filename(file_name).unwrap();
+impl MissingPartsReport {
+    /// Leases older than `lease_ttl` are considered abandoned. Leases of
+    /// unknown age are considered abandoned too.
+    fn new(
+        part_naming: &dyn PartNaming,
+        total: u64,
+        listed_parts: &[ListedStatePart],
+        lease_ttl: Option<Duration>,
+        now: SystemTime,
+    ) -> Self {
+        let mut present_bytes = 0;
+        let mut existing_nums = HashSet::new();
+        let mut leased_nums = HashSet::new();
+        for part in listed_parts {
+            if let Some(part_id) = get_part_id_from_filename(part_naming, &part.file_name) {
+                if existing_nums.insert(part_id) {
+                    present_bytes += part.size.unwrap_or(0);
+                }
+            } else if let Some(part_id) =
+                get_part_id_from_lease_filename(part_naming, &part.file_name)
+            {
+                let is_fresh = match (lease_ttl, part.last_modified) {
+                    (Some(lease_ttl), Some(last_modified)) => last_modified + lease_ttl > now,
+                    _ => false,
+                };
+                if is_fresh {
+                    leased_nums.insert(part_id);
+                }
+            }
+        }
+        let (present, missing): (Vec<u64>, Vec<u64>) =
+            (0..total).partition(|i| existing_nums.contains(i));
+        let leased = missing.iter().copied().filter(|i| leased_nums.contains(i)).collect();
+        Self { total, present, missing, leased, present_bytes }
+    }
+
+    /// Ignores missing parts outside of the range.
+    fn restrict_to(&mut self, part_range: Range<u64>) {
+        self.missing.retain(|part_id| part_range.contains(part_id));
+        self.leased.retain(|part_id| part_range.contains(part_id));
+    }
 }
 
 async fn get_missing_part_ids_for_epoch(
+    part_naming: &dyn PartNaming,
     shard_id: ShardId,
     chain_id: &String,
+    date_partition: Option<&str>,
     epoch_id: &EpochId,
     epoch_height: u64,
     total_parts: u64,
+    part_lease_ttl: Option<Duration>,
+    part_range: Option<Range<u64>>,
     external: &ExternalConnection,
-) -> Result<Vec<u64>, anyhow::Error> {
-    let directory_path =
-        external_storage_location_directory(chain_id, epoch_id, epoch_height, shard_id);
-    let file_names = external.list_state_parts(shard_id, &directory_path).await?;
-    if !file_names.is_empty() {
-        let existing_nums: HashSet<_> = file_names
-            .iter()
-            .map(|file_name| extract_part_id_from_part_file_name(file_name))
-            .collect();
-        let missing_nums: Vec<u64> =
-            (0..total_parts).filter(|i| !existing_nums.contains(i)).collect();
-        let num_missing = missing_nums.len();
-        tracing::debug!(target: "state_sync_dump", ?num_missing, ?directory_path, "Some parts have already been dumped.");
-        Ok(missing_nums)
+) -> Result<MissingPartsReport, ListStatePartsError> {
+    let directory_path = external_storage_location_directory(
+        chain_id,
+        epoch_id,
+        epoch_height,
+        shard_id,
+        date_partition,
+    );
+    let listed_parts = external.list_state_parts(shard_id, &directory_path).await?;
+    let mut report = MissingPartsReport::new(
+        part_naming,
+        total_parts,
+        &listed_parts,
+        part_lease_ttl,
+        SystemTime::now(),
+    );
+    if let Some(part_range) = part_range {
+        report.restrict_to(part_range);
+    }
+    if !report.present.is_empty() {
+        let num_missing = report.missing.len();
+        let num_leased = report.leased.len();
+        let present_bytes = report.present_bytes;
+        tracing::debug!(target: "state_sync_dump", ?num_missing, num_leased, present_bytes, ?directory_path, "Some parts have already been dumped.");
     } else {
+        let total_parts = report.total;
         tracing::debug!(target: "state_sync_dump", ?total_parts, ?directory_path, "No part has been dumped.");
-        let missing_nums = (0..total_parts).collect::<Vec<_>>();
-        Ok(missing_nums)
     }
+    Ok(report)
 }
 
 fn select_random_part_id_with_index(parts_to_be_dumped: &Vec<u64>) -> (u64, usize) {
@@ -174,216 +669,1048 @@ fn select_random_part_id_with_index(parts_to_be_dumped: &Vec<u64>) -> (u64, usiz
     (selected_element, selected_idx)
 }
 
-async fn state_sync_dump(
-    shard_id: ShardId,
-    chain: Chain,
-    epoch_manager: Arc<dyn EpochManagerAdapter>,
-    shard_tracker: ShardTracker,
-    runtime: Arc<dyn RuntimeAdapter>,
+async fn state_sync_dump(shard_id: ShardId, chain: Chain, config: ShardDumpConfig) {
+    tracing::info!(target: "state_sync_dump", shard_id, "Running StateSyncDump loop");
+
+    let mut dumper = ShardDumper::new(shard_id, chain, &config);
+    let idle_delay = config.idle_delay;
+    let min_iteration_delay = config.min_iteration_delay;
+    let work_delay = config.work_delay;
+    // Delay before the next iteration if the current one is idle.
+    let mut current_idle_delay = min_iteration_delay.unwrap_or(idle_delay);
+    // Stop if the node is stopped.
+    // Note that without this check the state dumping thread is unstoppable, i.e. non-interruptable.
+    while config.keep_running.load(std::sync::atomic::Ordering::Relaxed) {
+        let has_progress = match dumper.run_iteration(&config).await {
+            ControlFlow::Continue(has_progress) => has_progress,
+            ControlFlow::Break(()) => break,
+        };
+
+        if has_progress {
+            current_idle_delay = min_iteration_delay.unwrap_or(idle_delay);
+            if !work_delay.is_zero() {
+                actix_rt::time::sleep(tokio::time::Duration::from(work_delay)).await;
+            }
+        } else {
+            // Avoid a busy-loop when there is nothing to do.
+            actix_rt::time::sleep(tokio::time::Duration::from(current_idle_delay)).await;
+            current_idle_delay = next_idle_delay(current_idle_delay, idle_delay);
+        }
+    }
+    tracing::debug!(target: "state_sync_dump", shard_id, "Stopped state dump thread");
+}
+
+/// Options of the dump of a shard, resolved from `DumpConfig` once and shared
+/// by the dumps of all shards. See `DumpConfig` for the meaning of the options.
+#[derive(Clone)]
+struct ShardDumpConfig {
     chain_id: String,
-    restart_dump_for_shards: Vec<ShardId>,
     external: ExternalConnection,
-    iteration_delay: Duration,
+    state_parts_scratch_store: Option<Store>,
+    part_source: Arc<dyn StatePartSource>,
+    part_naming: Arc<dyn PartNaming>,
+    part_transform: Arc<dyn PartTransform>,
+    restart_dump_for_shards: Option<RestartShards>,
+    upload_archive: bool,
+    skip_existing: bool,
+    part_lease_ttl: Option<Duration>,
+    part_range: Option<Range<u64>>,
+    idle_delay: Duration,
+    min_iteration_delay: Option<Duration>,
+    work_delay: Duration,
+    dump_every_n_epochs: Option<u64>,
+    /// Always `false` if `latest_only` is set.
+    prefer_latest_epoch: bool,
+    latest_only: bool,
+    latest_only_grace_period: Duration,
+    probe_parts: usize,
+    backfill_deadline: Option<Duration>,
     account_id: Option<AccountId>,
+    /// `None` if the dump doesn't defer to block production.
+    block_production_in_progress: Option<Arc<AtomicBool>>,
+    shard_dump_permits: Option<Arc<Semaphore>>,
+    date_partitioned: bool,
+    compute_merkle_root: bool,
+    incremental: bool,
+    verify_reconstruction_every_n_epochs: Option<u64>,
+    max_parts_per_iteration: Option<usize>,
     keep_running: Arc<AtomicBool>,
-) {
-    tracing::info!(target: "state_sync_dump", shard_id, "Running StateSyncDump loop");
+}
+
+/// The state machine of the dump of a single shard, whose state is the
+/// `StateSyncDumpProgress` of the shard in the database.
+/// Every iteration of the dump loop is a single step of it, and the loop only
+/// decides how long to wait between the steps, and keeps the options of the
+/// dump, which every phase of a step takes.
+struct ShardDumper {
+    shard_id: ShardId,
+    chain: Chain,
+    epoch_manager: Arc<dyn EpochManagerAdapter>,
+    shard_tracker: ShardTracker,
+    state_parts_store: Store,
+    /// When to stop dumping missed and interrupted epochs, see
+    /// `DumpConfig::backfill_deadline`.
+    backfill_deadline: Option<Instant>,
+    /// Whether the backfill deadline has passed.
+    backfill_stopped: bool,
+    /// When dumping of the current epoch started.
+    /// Known only if the epoch dump was started by this process.
+    epoch_dump_start: Option<Instant>,
+    /// Moving average of the dump throughput in bytes per second.
+    dump_throughput: Option<f64>,
+    /// Sync hash of the latest epoch which was checked for a manifest.
+    manifest_checked_sync_hash: Option<CryptoHash>,
+    /// Sync hash of the latest epoch which was found not to need a dump when
+    /// checked for preempting the epoch being dumped, see
+    /// `get_latest_epoch_to_preempt()`.
+    preemption_rejected_sync_hash: Option<CryptoHash>,
+    /// When the state parts were last listed successfully.
+    last_successful_list: Option<Instant>,
+    /// When to delete the dumps of the epochs earlier than the given one,
+    /// see `DumpConfig::latest_only`.
+    delete_earlier_epochs_at: Option<(Instant, EpochHeight)>,
+    /// Parts pushed to a write-only external storage, which can't be listed.
+    pushed_parts: Option<PushedParts>,
+}
+
+/// Parts of an epoch pushed to a write-only external storage, such as
+/// `ExternalConnection::Pipe`, by this process. They stand in for the listing
+/// of the dumped parts, so every other part gets pushed, without checking the
+/// external storage. Only the epoch being dumped is remembered, parts of an
+/// interrupted epoch and parts pushed before a restart get pushed again.
+struct PushedParts {
+    sync_hash: CryptoHash,
+    part_ids: HashSet<u64>,
+    num_bytes: u64,
+}
+
+/// An epoch of a shard whose state is available for the dump, with everything
+/// needed to obtain its parts and to store them in the external storage.
+struct EpochDump {
+    shard_id: ShardId,
+    epoch_id: EpochId,
+    epoch_height: EpochHeight,
+    sync_hash: CryptoHash,
+    sync_prev_hash: CryptoHash,
+    state_root: StateRoot,
+    num_parts: u64,
+    /// All files of the epoch are stored under the date of its first block,
+    /// see `DumpConfig::date_partitioned`.
+    date_partition: Option<String>,
+}
 
-    if restart_dump_for_shards.contains(&shard_id) {
-        tracing::debug!(target: "state_sync_dump", shard_id, "Dropped existing progress");
-        chain.store().set_state_sync_dump_progress(shard_id, None).unwrap();
+impl EpochDump {
+    fn part_location(&self, config: &ShardDumpConfig, part_id: u64) -> String {
+        external_storage_location(
+            config.part_naming.as_ref(),
+            &config.chain_id,
+            &self.epoch_id,
+            self.epoch_height,
+            self.shard_id,
+            part_id,
+            self.num_parts,
+            self.date_partition.as_deref(),
+        )
     }
+}
+
+impl ShardDumper {
+    fn new(shard_id: ShardId, chain: Chain, config: &ShardDumpConfig) -> Self {
+        if config
+            .restart_dump_for_shards
+            .as_ref()
+            .map_or(false, |restart_dump_for_shards| restart_dump_for_shards.contains(shard_id))
+        {
+            tracing::debug!(target: "state_sync_dump", shard_id, "Dropped existing progress");
+            chain.store().set_state_sync_dump_progress(shard_id, None).unwrap();
+            chain
+                .store()
+                .set_state_sync_dump_backfill(shard_id, &StateSyncDumpBackfill::default())
+                .unwrap();
+        }
+
+        let state_parts_store = config
+            .state_parts_scratch_store
+            .clone()
+            .unwrap_or_else(|| chain.store().store().clone());
+        // When to delete the dumps of the epochs earlier than the given one.
+        // A dumped epoch found on startup may have been dumped just before a
+        // restart, so its grace period starts over.
+        let delete_earlier_epochs_at: Option<(Instant, EpochHeight)> = if config.latest_only {
+            match chain.store().get_state_sync_dump_progress(shard_id) {
+                Ok(Some(
+                    StateSyncDumpProgress::AllDumped { epoch_height, .. }
+                    | StateSyncDumpProgress::AllDumpedV2 { epoch_height, .. },
+                )) => Some((Instant::now() + config.latest_only_grace_period, epoch_height)),
+                _ => None,
+            }
+        } else {
+            None
+        };
+
+        Self {
+            shard_id,
+            epoch_manager: chain.epoch_manager.clone(),
+            shard_tracker: chain.shard_tracker.clone(),
+            chain,
+            state_parts_store,
+            backfill_deadline: config.backfill_deadline.map(|deadline| Instant::now() + deadline),
+            backfill_stopped: false,
+            epoch_dump_start: None,
+            dump_throughput: None,
+            manifest_checked_sync_hash: None,
+            preemption_rejected_sync_hash: None,
+            last_successful_list: None,
+            delete_earlier_epochs_at,
+            pushed_parts: None,
+        }
+    }
+
+    /// Advances the state machine by one step and records the new progress.
+    /// Returns whether the step made any progress, or `Break` if the dump of
+    /// the shard can't continue.
+    async fn run_iteration(&mut self, config: &ShardDumpConfig) -> ControlFlow<(), bool> {
+        let shard_id = self.shard_id;
+        let backfill = self.check_backfill_deadline(config);
 
-    // Stop if the node is stopped.
-    // Note that without this check the state dumping thread is unstoppable, i.e. non-interruptable.
-    while keep_running.load(std::sync::atomic::Ordering::Relaxed) {
         // TODO (ND-437): Start every iteration of the state dumping loop with checking if a new epoch is available.
-        let progress = chain.store().get_state_sync_dump_progress(shard_id);
+        let progress = self.chain.store().get_state_sync_dump_progress(shard_id);
         tracing::debug!(target: "state_sync_dump", shard_id, ?progress, "Running StateSyncDump loop iteration");
+        let was_in_progress = matches!(
+            progress,
+            Ok(Some(
+                StateSyncDumpProgress::InProgress { .. }
+                    | StateSyncDumpProgress::InProgressV2 { .. }
+            ))
+        );
+        let in_progress_epoch_height = match &progress {
+            Ok(Some(
+                progress @ (StateSyncDumpProgress::InProgress { .. }
+                | StateSyncDumpProgress::InProgressV2 { .. }),
+            )) => Some(progress.epoch_height()),
+            _ => None,
+        };
         // The `match` returns the next state of the state machine.
-        let next_state: Result<Option<StateSyncDumpProgress>, Error> = match progress {
+        let next_state: Result<Option<StateSyncDumpProgress>, StateSyncDumpError> = match progress {
             Ok(Some(StateSyncDumpProgress::AllDumped { epoch_id, epoch_height, num_parts })) => {
                 // The latest epoch was dumped. Check if a newer epoch is available.
-                check_new_epoch(
+                self.start_next_epoch_dump(
+                    config,
                     Some(epoch_id),
                     Some(epoch_height),
                     num_parts,
-                    shard_id,
-                    &chain,
-                    epoch_manager.as_ref(),
-                    &shard_tracker,
-                    &account_id,
+                    None,
+                    backfill,
                 )
+                .await
             }
-            Err(Error::DBNotFoundErr(_)) | Ok(None) => {
-                // First invocation of this state-machine. See if at least one epoch is available for dumping.
-                check_new_epoch(
-                    None,
+            Ok(Some(StateSyncDumpProgress::AllDumpedV2 {
+                epoch_id,
+                epoch_height,
+                num_parts,
+                total_bytes,
+            })) => {
+                // The latest epoch was dumped. Check if a newer epoch is available.
+                self.start_next_epoch_dump(
+                    config,
+                    Some(epoch_id),
+                    Some(epoch_height),
+                    num_parts,
+                    Some(total_bytes),
+                    backfill,
+                )
+                .await
+            }
+            Ok(Some(StateSyncDumpProgress::Skipped { epoch_id, epoch_height })) => {
+                // The latest epoch was skipped. Check if a newer epoch is available.
+                self.start_next_epoch_dump(
+                    config,
+                    Some(epoch_id),
+                    Some(epoch_height),
                     None,
                     None,
-                    shard_id,
-                    &chain,
-                    epoch_manager.as_ref(),
-                    &shard_tracker,
-                    &account_id,
+                    backfill,
                 )
+                .await
+            }
+            Err(Error::DBNotFoundErr(_)) | Ok(None) => {
+                // First invocation of this state-machine. See if at least one epoch is available for dumping.
+                self.start_next_epoch_dump(config, None, None, None, None, backfill).await
             }
             Err(err) => {
                 // Something went wrong, let's retry.
                 tracing::warn!(target: "state_sync_dump", shard_id, ?err, "Failed to read the progress, will now delete and retry");
-                if let Err(err) = chain.store().set_state_sync_dump_progress(shard_id, None) {
+                if let Err(err) = self.chain.store().set_state_sync_dump_progress(shard_id, None) {
                     tracing::warn!(target: "state_sync_dump", shard_id, ?err, "and failed to delete the progress. Will later retry.");
                 }
                 Ok(None)
             }
             Ok(Some(StateSyncDumpProgress::InProgress { epoch_id, epoch_height, sync_hash })) => {
-                let in_progress_data = get_in_progress_data(shard_id, sync_hash, &chain);
-                match in_progress_data {
-                    Err(error) => Err(error),
-                    Ok((state_root, num_parts, sync_prev_hash)) => {
-                        let missing_parts = get_missing_part_ids_for_epoch(
-                            shard_id,
-                            &chain_id,
-                            &epoch_id,
-                            epoch_height,
-                            num_parts,
-                            &external,
-                        )
-                        .await;
-
-                        match missing_parts {
-                            Err(err) => {
-                                tracing::debug!(target: "state_sync_dump", shard_id, ?err, "get_missing_state_parts_for_epoch error");
-                                Err(Error::Other(format!(
-                                    "get_missing_state_parts_for_epoch failed"
-                                )))
-                            }
-                            Ok(parts_not_dumped) if parts_not_dumped.is_empty() => {
-                                Ok(Some(StateSyncDumpProgress::AllDumped {
-                                    epoch_id,
-                                    epoch_height,
-                                    num_parts: Some(num_parts),
-                                }))
-                            }
-                            Ok(parts_not_dumped) => {
-                                let mut parts_to_dump = parts_not_dumped.clone();
-                                let timer = Instant::now();
-                                // Stop if the node is stopped.
-                                // Note that without this check the state dumping thread is unstoppable, i.e. non-interruptable.
-                                while keep_running.load(std::sync::atomic::Ordering::Relaxed)
-                                    && timer.elapsed().as_secs()
-                                        <= STATE_DUMP_ITERATION_TIME_LIMIT_SECS
-                                    && !parts_to_dump.is_empty()
-                                {
-                                    let _timer = metrics::STATE_SYNC_DUMP_ITERATION_ELAPSED
-                                        .with_label_values(&[&shard_id.to_string()])
-                                        .start_timer();
-
-                                    let (part_id, selected_idx) =
-                                        select_random_part_id_with_index(&parts_to_dump);
-
-                                    let state_part = match obtain_and_store_state_part(
-                                        runtime.as_ref(),
-                                        shard_id,
-                                        sync_hash,
-                                        &sync_prev_hash,
-                                        &state_root,
-                                        part_id,
-                                        num_parts,
-                                        &chain,
-                                    ) {
-                                        Ok(state_part) => state_part,
-                                        Err(err) => {
-                                            tracing::warn!(target: "state_sync_dump", shard_id, epoch_height, part_id, ?err, "Failed to obtain and store part. Will skip this part.");
-                                            break;
-                                        }
-                                    };
-                                    let location = external_storage_location(
-                                        &chain_id,
-                                        &epoch_id,
-                                        epoch_height,
-                                        shard_id,
-                                        part_id,
-                                        num_parts,
-                                    );
-                                    if let Err(_) = external
-                                        .put_state_part(&state_part, shard_id, &location)
-                                        .await
-                                    {
-                                        // no need to break if there's an error, we should keep dumping other parts.
-                                        // reason is we are dumping random selected parts, so it's fine if we are not able to finish all of them
-                                        continue;
-                                    }
-
-                                    // remove the dumped part from parts_to_dump so that we draw without replacement
-                                    parts_to_dump.swap_remove(selected_idx);
-                                    update_dumped_size_and_cnt_metrics(
-                                        &shard_id,
-                                        epoch_height,
-                                        state_part.len(),
-                                    );
-                                }
-
-                                if parts_to_dump.is_empty() {
-                                    Ok(Some(StateSyncDumpProgress::AllDumped {
-                                        epoch_id,
-                                        epoch_height,
-                                        num_parts: Some(num_parts),
-                                    }))
-                                } else {
-                                    Ok(Some(StateSyncDumpProgress::InProgress {
-                                        epoch_id,
-                                        epoch_height,
-                                        sync_hash,
-                                    }))
-                                }
-                            }
-                        }
-                    }
+                // Stored by an older version, which didn't record the number
+                // of parts.
+                match get_in_progress_data(shard_id, sync_hash, &self.chain) {
+                    Ok((_, num_parts, _)) => Ok(Some(StateSyncDumpProgress::InProgressV2 {
+                        epoch_id,
+                        epoch_height,
+                        sync_hash,
+                        num_parts,
+                    })),
+                    Err(error) => skip_unavailable_epoch(
+                        error,
+                        shard_id,
+                        &self.chain,
+                        epoch_id,
+                        epoch_height,
+                        sync_hash,
+                    ),
                 }
             }
+            Ok(Some(StateSyncDumpProgress::InProgressV2 {
+                epoch_id,
+                epoch_height,
+                sync_hash,
+                num_parts,
+            })) => {
+                self.continue_epoch_dump(config, epoch_id, epoch_height, sync_hash, num_parts).await
+            }
         };
+        let next_state =
+            self.switch_preempted_epoch_dump(config, next_state, was_in_progress, backfill);
 
-        // Record the next state of the state machine.
-        let has_progress = match next_state {
-            Ok(Some(next_state)) => {
-                tracing::debug!(target: "state_sync_dump", shard_id, ?next_state);
-                match chain.store().set_state_sync_dump_progress(shard_id, Some(next_state)) {
-                    Ok(_) => true,
-                    Err(err) => {
-                        // This will be retried.
-                        tracing::debug!(target: "state_sync_dump", shard_id, ?err, "Failed to set progress");
-                        false
-                    }
+        let has_progress = match self.record_next_state(
+            config,
+            next_state,
+            was_in_progress,
+            in_progress_epoch_height,
+        ) {
+            ControlFlow::Continue(has_progress) => has_progress,
+            ControlFlow::Break(()) => return ControlFlow::Break(()),
+        };
+
+        update_completed_shards_metric(&self.chain, self.epoch_manager.as_ref());
+        update_backfill_epochs_remaining_metric(
+            shard_id,
+            &self.chain,
+            self.epoch_manager.as_ref(),
+            backfill,
+        );
+        self.delete_earlier_epochs(config).await;
+
+        ControlFlow::Continue(has_progress)
+    }
+
+    /// Stops the backfill once its deadline passes. Returns whether missed and
+    /// interrupted epochs get dumped.
+    fn check_backfill_deadline(&mut self, config: &ShardDumpConfig) -> bool {
+        let shard_id = self.shard_id;
+        if !self.backfill_stopped
+            && self.backfill_deadline.map_or(false, |deadline| Instant::now() >= deadline)
+        {
+            self.backfill_stopped = true;
+            let num_epochs_left =
+                count_epochs_to_backfill(shard_id, &self.chain, self.epoch_manager.as_ref())
+                    .map_err(|err| {
+                        tracing::debug!(target: "state_sync_dump", shard_id, ?err, "Failed to count the epochs left to backfill");
+                    })
+                    .ok();
+            tracing::info!(target: "state_sync_dump", shard_id, ?num_epochs_left, "Backfill deadline passed, finishing the current epoch and dumping only the latest epochs from now on");
+        }
+        !config.latest_only && !self.backfill_stopped
+    }
+
+    /// Starts the dump of the next epoch after the given dumped or skipped
+    /// epoch, or of the first epoch to dump if there is none.
+    /// Returns `InProgressV2` if an epoch needs to be dumped, and `None` if
+    /// there is nothing new to dump.
+    async fn start_next_epoch_dump(
+        &self,
+        config: &ShardDumpConfig,
+        epoch_id: Option<EpochId>,
+        epoch_height: Option<EpochHeight>,
+        num_parts: Option<u64>,
+        total_bytes: Option<u64>,
+        backfill: bool,
+    ) -> Result<Option<StateSyncDumpProgress>, StateSyncDumpError> {
+        check_new_epoch(
+            epoch_id,
+            epoch_height,
+            num_parts,
+            total_bytes,
+            self.shard_id,
+            &self.chain,
+            self.epoch_manager.as_ref(),
+            &self.shard_tracker,
+            &config.part_source,
+            &config.account_id,
+            config.dump_every_n_epochs,
+            config.probe_parts,
+            backfill,
+        )
+        .await
+    }
+
+    /// Continues the dump of the epoch in progress, unless it's already dumped
+    /// according to its manifest, its state is going away, the dump defers to
+    /// the block production, or a newer epoch preempts it.
+    async fn continue_epoch_dump(
+        &mut self,
+        config: &ShardDumpConfig,
+        epoch_id: EpochId,
+        epoch_height: EpochHeight,
+        sync_hash: CryptoHash,
+        recorded_num_parts: u64,
+    ) -> Result<Option<StateSyncDumpProgress>, StateSyncDumpError> {
+        let shard_id = self.shard_id;
+        let latest_epoch_progress = if config.prefer_latest_epoch || config.latest_only {
+            match get_latest_epoch_to_preempt(
+                epoch_height,
+                sync_hash,
+                shard_id,
+                &self.chain,
+                self.epoch_manager.as_ref(),
+                &self.shard_tracker,
+                &config.part_source,
+                &config.account_id,
+                config.dump_every_n_epochs,
+                config.probe_parts,
+                &mut self.preemption_rejected_sync_hash,
+            )
+            .await
+            {
+                Ok(latest_epoch_progress) => latest_epoch_progress,
+                Err(err) => {
+                    tracing::debug!(target: "state_sync_dump", shard_id, ?err, "Failed to check for a newer epoch");
+                    None
                 }
             }
-            Ok(None) => {
-                // Will retry.
-                tracing::debug!(target: "state_sync_dump", shard_id, "Idle");
-                false
+        } else {
+            None
+        };
+        // All files of the epoch are stored under the date of its first block.
+        let date_partition = if config.date_partitioned {
+            match self.chain.get_block_header(&sync_hash) {
+                Ok(sync_header) => Some(date_partition(sync_header.raw_timestamp())),
+                // The epoch gets skipped, because its state is not available.
+                Err(err) => {
+                    tracing::debug!(target: "state_sync_dump", shard_id, ?err, "Failed to get the sync block header");
+                    None
+                }
             }
+        } else {
+            None
+        };
+        // The progress may be behind the external storage, for example if the
+        // database was restored from a backup. Check once per epoch whether all
+        // parts are already dumped, before obtaining any parts.
+        let dumped_epoch_manifest =
+            if config.part_range.is_none() && self.manifest_checked_sync_hash != Some(sync_hash) {
+                self.manifest_checked_sync_hash = Some(sync_hash);
+                get_epoch_manifest(
+                    shard_id,
+                    &config.chain_id,
+                    date_partition.as_deref(),
+                    &epoch_id,
+                    epoch_height,
+                    &config.external,
+                )
+                .await
+            } else {
+                None
+            };
+        // Tracked shards of the node may change while an epoch is being dumped,
+        // and the state of a shard that is no longer tracked doesn't stay for long.
+        let stopped_tracking_shard = match is_shard_state_going_away(
+            shard_id,
+            &self.chain,
+            &self.shard_tracker,
+            &config.account_id,
+        ) {
+            Ok(stopped_tracking_shard) => stopped_tracking_shard,
             Err(err) => {
-                // Will retry.
-                tracing::debug!(target: "state_sync_dump", shard_id, ?err, "Failed to determine what to do");
+                tracing::debug!(target: "state_sync_dump", shard_id, ?err, "Failed to check whether the shard is still tracked");
                 false
             }
         };
 
-        if !has_progress {
-            // Avoid a busy-loop when there is nothing to do.
-            actix_rt::time::sleep(tokio::time::Duration::from(iteration_delay)).await;
-        }
-    }
-    tracing::debug!(target: "state_sync_dump", shard_id, "Stopped state dump thread");
-}
-
-// Extracts extra data needed for obtaining state parts.
+        if let Some(manifest) = dumped_epoch_manifest {
+            tracing::info!(target: "state_sync_dump", shard_id, epoch_height, ?manifest, "All parts are already dumped according to the manifest");
+            if let Some(state_parts_scratch_store) = &config.state_parts_scratch_store {
+                clear_scratch_state_parts(state_parts_scratch_store, sync_hash, shard_id);
+            }
+            Ok(Some(StateSyncDumpProgress::AllDumpedV2 {
+                epoch_id,
+                epoch_height,
+                num_parts: Some(manifest.num_parts),
+                total_bytes: manifest.total_bytes,
+            }))
+        } else if stopped_tracking_shard {
+            // Parts would fail to be obtained for the rest of the epoch.
+            tracing::warn!(target: "state_sync_dump", shard_id, ?epoch_id, epoch_height, account_id = ?config.account_id, "Stop dumping the epoch, because the node neither tracks the shard nor will track it in the next epoch, and therefore doesn't keep its state. This happens if the shards assigned to the validator have changed");
+            metrics::STATE_SYNC_DUMP_EPOCHS_SKIPPED_UNTRACKED
+                .with_label_values(&[&shard_id.to_string()])
+                .inc();
+            Ok(Some(StateSyncDumpProgress::Skipped { epoch_id, epoch_height }))
+        } else if is_block_production_in_progress(&config.block_production_in_progress) {
+            // Give way to the block production and try again later.
+            metrics::STATE_SYNC_DUMP_DEFERRED_ITERATIONS
+                .with_label_values(&[&shard_id.to_string()])
+                .inc();
+            Ok(None)
+        } else if latest_epoch_progress.is_some() {
+            let progress = StateSyncDumpProgress::InProgressV2 {
+                epoch_id,
+                epoch_height,
+                sync_hash,
+                num_parts: recorded_num_parts,
+            };
+            if config.latest_only {
+                // Parts that are already dumped get deleted together with
+                // the other earlier epochs.
+                tracing::info!(target: "state_sync_dump", shard_id, ?progress, "Abandoning the dump of an epoch, because a newer epoch is available");
+                Ok(latest_epoch_progress)
+            } else {
+                // Parts that are already dumped stay in the external storage,
+                // and get listed when the dump of this epoch continues.
+                preempt_epoch_dump(shard_id, &self.chain, progress).map(|()| latest_epoch_progress)
+            }
+        } else {
+            match get_in_progress_data(shard_id, sync_hash, &self.chain) {
+                Err(error) => skip_unavailable_epoch(
+                    error,
+                    shard_id,
+                    &self.chain,
+                    epoch_id,
+                    epoch_height,
+                    sync_hash,
+                ),
+                Ok((_, 0, _)) if recorded_num_parts == 0 => Ok(Some(
+                    skip_zero_part_epoch(
+                        shard_id,
+                        &config.chain_id,
+                        date_partition.as_deref(),
+                        epoch_id,
+                        epoch_height,
+                        &config.external,
+                    )
+                    .await,
+                )),
+                Ok((_, num_parts, _)) if num_parts != recorded_num_parts => {
+                    // Parts would be stored under locations that disagree
+                    // with the parts already dumped.
+                    tracing::error!(target: "state_sync_dump", shard_id, ?epoch_id, epoch_height, recorded_num_parts, num_parts, "The number of parts differs from the one recorded when the dump of the epoch started, abandoning the dump of the epoch");
+                    metrics::STATE_SYNC_DUMP_NUM_PARTS_MISMATCHES
+                        .with_label_values(&[&shard_id.to_string()])
+                        .inc();
+                    Ok(Some(StateSyncDumpProgress::Skipped { epoch_id, epoch_height }))
+                }
+                Ok((state_root, num_parts, sync_prev_hash)) => {
+                    let epoch = EpochDump {
+                        shard_id,
+                        epoch_id,
+                        epoch_height,
+                        sync_hash,
+                        sync_prev_hash,
+                        state_root,
+                        num_parts,
+                        date_partition,
+                    };
+                    self.dump_epoch_parts(config, &epoch).await
+                }
+            }
+        }
+    }
+
+    /// Dumps the parts of the epoch missing from the external storage, and
+    /// finishes the dump of the epoch once all parts are dumped.
+    /// Returns `AllDumpedV2` if the dump of the epoch is done, and
+    /// `InProgressV2` if parts are left for the next iterations.
+    async fn dump_epoch_parts(
+        &mut self,
+        config: &ShardDumpConfig,
+        epoch: &EpochDump,
+    ) -> Result<Option<StateSyncDumpProgress>, StateSyncDumpError> {
+        let EpochDump { shard_id, ref epoch_id, epoch_height, sync_hash, num_parts, .. } = *epoch;
+        // Only complete dumps can be verified.
+        let verify_reconstruction = config.part_range.is_none()
+            && config
+                .verify_reconstruction_every_n_epochs
+                .map_or(false, |n| is_epoch_on_dump_cadence(epoch_height, Some(n)));
+        let mut missing_parts = get_missing_part_ids_for_epoch(
+            config.part_naming.as_ref(),
+            shard_id,
+            &config.chain_id,
+            epoch.date_partition.as_deref(),
+            epoch_id,
+            epoch_height,
+            num_parts,
+            config.part_lease_ttl,
+            config.part_range.clone(),
+            &config.external,
+        )
+        .await;
+        if config.external.is_write_only() {
+            if self.pushed_parts.as_ref().map(|pushed| pushed.sync_hash) != Some(sync_hash) {
+                self.pushed_parts =
+                    Some(PushedParts { sync_hash, part_ids: HashSet::new(), num_bytes: 0 });
+            }
+            if let (Ok(report), Some(pushed)) = (&mut missing_parts, self.pushed_parts.as_ref()) {
+                report.missing.retain(|part_id| !pushed.part_ids.contains(part_id));
+                report.present_bytes += pushed.num_bytes;
+            }
+        }
+        // Uploads may succeed while listing fails, and then the
+        // loop can't tell which parts are dumped.
+        if missing_parts.is_ok() {
+            self.last_successful_list = Some(Instant::now());
+        }
+        if let Some(last_successful_list) = self.last_successful_list {
+            metrics::STATE_SYNC_DUMP_SECONDS_SINCE_LAST_LIST
+                .with_label_values(&[&shard_id.to_string()])
+                .set(last_successful_list.elapsed().as_secs_f64());
+        }
+        let report = missing_parts.map_err(StateSyncDumpError::ListFailed)?;
+
+        // Parts leased by other nodes are dumped only after all other parts.
+        let (mut leased_parts, mut parts_to_dump): (Vec<u64>, Vec<u64>) =
+            report.missing.iter().partition(|part_id| report.leased.contains(*part_id));
+        let dumped_bytes = if report.missing.is_empty() {
+            0
+        } else {
+            self.dump_parts(config, epoch, &mut parts_to_dump, &mut leased_parts).await
+        };
+
+        if parts_to_dump.is_empty() && leased_parts.is_empty() {
+            let total_bytes = report.present_bytes + dumped_bytes;
+            finish_epoch_dump(
+                config,
+                &self.state_parts_store,
+                epoch,
+                total_bytes,
+                verify_reconstruction,
+                config.part_range.is_none(),
+            )
+            .await
+            .map(|()| {
+                Some(StateSyncDumpProgress::AllDumpedV2 {
+                    epoch_id: epoch_id.clone(),
+                    epoch_height,
+                    num_parts: Some(num_parts),
+                    total_bytes,
+                })
+            })
+        } else {
+            Ok(Some(StateSyncDumpProgress::InProgressV2 {
+                epoch_id: epoch_id.clone(),
+                epoch_height,
+                sync_hash,
+                num_parts,
+            }))
+        }
+    }
+
+    /// Dumps randomly selected parts among `parts_to_dump`, and then among
+    /// `leased_parts`, until the iteration runs out of time or parts, and
+    /// removes the dumped parts. Returns the size of the dumped parts.
+    async fn dump_parts(
+        &mut self,
+        config: &ShardDumpConfig,
+        epoch: &EpochDump,
+        parts_to_dump: &mut Vec<u64>,
+        leased_parts: &mut Vec<u64>,
+    ) -> u64 {
+        let EpochDump { shard_id, epoch_height, .. } = *epoch;
+        // Other shards continue once this iteration ends and the permit is dropped.
+        let _shard_dump_permit = match &config.shard_dump_permits {
+            Some(shard_dump_permits) => {
+                let _timer = metrics::STATE_SYNC_DUMP_WAIT_FOR_TURN_ELAPSED
+                    .with_label_values(&[&shard_id.to_string()])
+                    .start_timer();
+                Some(shard_dump_permits.acquire().await.expect("the semaphore is never closed"))
+            }
+            None => None,
+        };
+        // Parts identical to the parts of the previous epoch are stored as
+        // pointers to them.
+        let previous_epoch = if config.incremental {
+            match get_previous_epoch(
+                &epoch.sync_prev_hash,
+                &self.chain,
+                self.epoch_manager.as_ref(),
+                config.date_partitioned,
+            ) {
+                Ok(previous_epoch) => Some(previous_epoch),
+                Err(err) => {
+                    tracing::debug!(target: "state_sync_dump", shard_id, epoch_height, ?err, "Failed to get the previous epoch, parts will be stored as full copies");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        // Size of the parts dumped in this iteration.
+        let mut dumped_bytes = 0;
+        // Number of parts dumped in this iteration.
+        let mut dumped_parts = 0;
+        let timer = Instant::now();
+        // Stop if the node is stopped.
+        // Note that without this check the state dumping thread is unstoppable, i.e. non-interruptable.
+        while config.keep_running.load(std::sync::atomic::Ordering::Relaxed)
+            && timer.elapsed().as_secs() <= STATE_DUMP_ITERATION_TIME_LIMIT_SECS
+            && config.max_parts_per_iteration.map_or(true, |max_parts| dumped_parts < max_parts)
+            && !(parts_to_dump.is_empty() && leased_parts.is_empty())
+            && !is_block_production_in_progress(&config.block_production_in_progress)
+        {
+            let _timer = metrics::STATE_SYNC_DUMP_ITERATION_ELAPSED
+                .with_label_values(&[&shard_id.to_string()])
+                .start_timer();
+
+            if parts_to_dump.is_empty() {
+                *parts_to_dump = std::mem::take(leased_parts);
+            }
+            let (part_id, selected_idx) = select_random_part_id_with_index(parts_to_dump);
+            let part_size = match self.dump_part(config, epoch, part_id, &previous_epoch).await {
+                ControlFlow::Continue(Some(part_size)) => part_size,
+                // no need to break if there's an error, we should keep dumping other parts.
+                // reason is we are dumping random selected parts, so it's fine if we are not able to finish all of them
+                ControlFlow::Continue(None) => continue,
+                ControlFlow::Break(()) => break,
+            };
+
+            // remove the dumped part from parts_to_dump so that we draw without replacement
+            parts_to_dump.swap_remove(selected_idx);
+            if let Some(pushed) = self.pushed_parts.as_mut() {
+                pushed.part_ids.insert(part_id);
+                pushed.num_bytes += part_size as u64;
+            }
+            dumped_bytes += part_size as u64;
+            dumped_parts += 1;
+            update_dumped_size_and_cnt_metrics(&shard_id, epoch_height, part_size);
+        }
+        let elapsed = timer.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            let throughput =
+                update_moving_average(self.dump_throughput, dumped_bytes as f64 / elapsed);
+            self.dump_throughput = Some(throughput);
+            metrics::STATE_SYNC_DUMP_THROUGHPUT_BYTES_PER_SEC
+                .with_label_values(&[&shard_id.to_string()])
+                .set(throughput);
+        }
+        dumped_bytes
+    }
+
+    /// Leases, obtains and uploads a single part. Returns the size of the part
+    /// once it's dumped, `None` if it fails to be uploaded and gets retried
+    /// later, or `Break` if no more parts can be obtained in this iteration.
+    async fn dump_part(
+        &self,
+        config: &ShardDumpConfig,
+        epoch: &EpochDump,
+        part_id: u64,
+        previous_epoch: &Option<(EpochId, EpochHeight, Option<String>)>,
+    ) -> ControlFlow<(), Option<usize>> {
+        let EpochDump {
+            shard_id,
+            ref epoch_id,
+            epoch_height,
+            sync_hash,
+            sync_prev_hash,
+            state_root,
+            num_parts,
+            ref date_partition,
+        } = *epoch;
+        let lease_location = config.part_lease_ttl.map(|_| {
+            external_storage_lease_location(
+                config.part_naming.as_ref(),
+                &config.chain_id,
+                epoch_id,
+                epoch_height,
+                shard_id,
+                part_id,
+                num_parts,
+                date_partition.as_deref(),
+            )
+        });
+        if let Some(lease_location) = &lease_location {
+            // Best effort, a failure only risks duplicate work.
+            if let Err(err) = config.external.put_state_part(&[], shard_id, lease_location).await {
+                tracing::debug!(target: "state_sync_dump", shard_id, part_id, ?err, "Failed to lease a part");
+            }
+        }
+
+        let state_part = match obtain_and_store_state_part_cancellable(
+            config.part_source.clone(),
+            shard_id,
+            sync_hash,
+            sync_prev_hash,
+            state_root,
+            part_id,
+            num_parts,
+            self.state_parts_store.clone(),
+            &config.keep_running,
+        )
+        .await
+        {
+            None => {
+                tracing::debug!(target: "state_sync_dump", shard_id, epoch_height, part_id, "Abandoned obtaining a part, because the node is stopping");
+                release_part_lease(&config.external, shard_id, part_id, lease_location.as_deref())
+                    .await;
+                return ControlFlow::Break(());
+            }
+            Some(Ok(state_part)) => state_part,
+            Some(Err(err)) => {
+                let err = StateSyncDumpError::ObtainFailed(err);
+                inc_error_metric(shard_id, &err);
+                tracing::warn!(target: "state_sync_dump", shard_id, epoch_height, part_id, ?err, "Failed to obtain and store part. Will skip this part.");
+                release_part_lease(&config.external, shard_id, part_id, lease_location.as_deref())
+                    .await;
+                return ControlFlow::Break(());
+            }
+        };
+        let encoded_part = config.part_transform.encode(&state_part);
+        let location = epoch.part_location(config, part_id);
+        let identical_previous_part = match previous_epoch {
+            Some((previous_epoch_id, previous_epoch_height, previous_date_partition)) => {
+                let previous_location = external_storage_location(
+                    config.part_naming.as_ref(),
+                    &config.chain_id,
+                    previous_epoch_id,
+                    *previous_epoch_height,
+                    shard_id,
+                    part_id,
+                    num_parts,
+                    previous_date_partition.as_deref(),
+                );
+                find_identical_part(&encoded_part, shard_id, previous_location, &config.external)
+                    .await
+            }
+            None => None,
+        };
+        let dumped = if config.skip_existing
+            && config.external.has_identical_state_part(&encoded_part, shard_id, &location).await
+        {
+            tracing::debug!(target: "state_sync_dump", shard_id, epoch_height, part_id, "Skipped uploading a part, because an identical part is already stored");
+            metrics::STATE_SYNC_DUMP_SKIPPED_IDENTICAL_PARTS
+                .with_label_values(&[&shard_id.to_string()])
+                .inc();
+            true
+        } else if let Err(err) = upload_state_part(
+            &encoded_part,
+            identical_previous_part.as_deref(),
+            shard_id,
+            &location,
+            &config.external,
+        )
+        .await
+        .map_err(StateSyncDumpError::UploadFailed)
+        {
+            inc_error_metric(shard_id, &err);
+            tracing::debug!(target: "state_sync_dump", shard_id, epoch_height, part_id, ?err, "Failed to upload part. Will retry later.");
+            false
+        } else {
+            true
+        };
+        release_part_lease(&config.external, shard_id, part_id, lease_location.as_deref()).await;
+        ControlFlow::Continue(dumped.then_some(state_part.len()))
+    }
+
+    /// Switches between the latest epoch and the interrupted epochs, see
+    /// `DumpConfig::prefer_latest_epoch`.
+    fn switch_preempted_epoch_dump(
+        &self,
+        config: &ShardDumpConfig,
+        next_state: Result<Option<StateSyncDumpProgress>, StateSyncDumpError>,
+        was_in_progress: bool,
+        backfill: bool,
+    ) -> Result<Option<StateSyncDumpProgress>, StateSyncDumpError> {
+        match next_state {
+            // Nothing new to dump, continue dumping an interrupted epoch.
+            Ok(None) if config.prefer_latest_epoch && backfill && !was_in_progress => {
+                resume_preempted_epoch_dump(self.shard_id, &self.chain)
+            }
+            // The dump of an interrupted epoch is done, return to the latest epoch.
+            Ok(Some(next_state))
+                if config.prefer_latest_epoch
+                    && was_in_progress
+                    && !matches!(next_state, StateSyncDumpProgress::InProgressV2 { .. }) =>
+            {
+                finish_preempted_epoch_dump(self.shard_id, &self.chain, next_state).map(Some)
+            }
+            next_state => next_state,
+        }
+    }
+
+    /// Records the next state of the state machine. Returns whether it differs
+    /// from the current state, or `Break` if the dump of the shard can't
+    /// continue.
+    fn record_next_state(
+        &mut self,
+        config: &ShardDumpConfig,
+        next_state: Result<Option<StateSyncDumpProgress>, StateSyncDumpError>,
+        was_in_progress: bool,
+        in_progress_epoch_height: Option<EpochHeight>,
+    ) -> ControlFlow<(), bool> {
+        let shard_id = self.shard_id;
+        let has_progress = match next_state {
+            Ok(Some(next_state)) => {
+                tracing::debug!(target: "state_sync_dump", shard_id, ?next_state);
+                match &next_state {
+                    StateSyncDumpProgress::InProgressV2 { .. } if !was_in_progress => {
+                        self.epoch_dump_start = Some(Instant::now());
+                    }
+                    StateSyncDumpProgress::AllDumped { .. }
+                    | StateSyncDumpProgress::AllDumpedV2 { .. }
+                        if was_in_progress =>
+                    {
+                        // Shards that are not tracked go straight to `AllDumped` and are not observed.
+                        if let Some(epoch_dump_start) = self.epoch_dump_start.take() {
+                            metrics::STATE_SYNC_DUMP_EPOCH_DURATION_SECONDS
+                                .with_label_values(&[&shard_id.to_string()])
+                                .observe(epoch_dump_start.elapsed().as_secs_f64());
+                        }
+                        // The completed epoch is not `next_state` if the
+                        // dump returns to a newer epoch.
+                        if let Some(epoch_height) = in_progress_epoch_height {
+                            match get_latest_complete_epoch_height(
+                                &self.chain,
+                                self.epoch_manager.as_ref(),
+                            ) {
+                                Ok(latest_epoch_height) if epoch_height < latest_epoch_height => {
+                                    metrics::STATE_SYNC_DUMP_BACKFILL_EPOCHS_DONE
+                                        .with_label_values(&[&shard_id.to_string()])
+                                        .inc();
+                                }
+                                Ok(_) => {}
+                                Err(err) => {
+                                    tracing::debug!(target: "state_sync_dump", shard_id, ?err, "Failed to get the latest complete epoch");
+                                }
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+                if let StateSyncDumpProgress::AllDumpedV2 { epoch_height, .. } = &next_state {
+                    if config.latest_only && was_in_progress {
+                        tracing::debug!(target: "state_sync_dump", shard_id, epoch_height, latest_only_grace_period = ?config.latest_only_grace_period, "Earlier epochs will be deleted after the grace period");
+                        self.delete_earlier_epochs_at =
+                            Some((Instant::now() + config.latest_only_grace_period, *epoch_height));
+                    }
+                }
+                match self.chain.store().set_state_sync_dump_progress(shard_id, Some(next_state)) {
+                    Ok(_) => true,
+                    Err(err) => {
+                        // This will be retried.
+                        tracing::debug!(target: "state_sync_dump", shard_id, ?err, "Failed to set progress");
+                        false
+                    }
+                }
+            }
+            Ok(None) => {
+                // Will retry.
+                tracing::debug!(target: "state_sync_dump", shard_id, "Idle");
+                false
+            }
+            Err(err) if err.is_fatal() => {
+                inc_error_metric(shard_id, &err);
+                tracing::error!(target: "state_sync_dump", shard_id, ?err, "Stopping the state dump of the shard, because the external storage can't be accessed. Check the configured location and the credentials, and restart the node");
+                return ControlFlow::Break(());
+            }
+            Err(err) => {
+                // Will retry.
+                inc_error_metric(shard_id, &err);
+                tracing::debug!(target: "state_sync_dump", shard_id, ?err, "Failed to determine what to do");
+                false
+            }
+        };
+        ControlFlow::Continue(has_progress)
+    }
+
+    /// Deletes the dumps of the epochs earlier than the latest dumped epoch
+    /// once its grace period is over, see `DumpConfig::latest_only`.
+    async fn delete_earlier_epochs(&mut self, config: &ShardDumpConfig) {
+        let shard_id = self.shard_id;
+        if let Some((delete_at, epoch_height)) = self.delete_earlier_epochs_at {
+            if Instant::now() >= delete_at {
+                match delete_earlier_epoch_dumps(
+                    shard_id,
+                    &config.chain_id,
+                    epoch_height,
+                    &config.external,
+                )
+                .await
+                {
+                    Ok(()) => self.delete_earlier_epochs_at = None,
+                    Err(err) => {
+                        // Will retry.
+                        tracing::debug!(target: "state_sync_dump", shard_id, epoch_height, ?err, "Failed to delete the dumps of earlier epochs");
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl ShardDumpConfig {
+    /// Dumps all parts to `external`, with the defaults of `DumpConfig`.
+    fn for_test(
+        part_source: Arc<dyn StatePartSource>,
+        external: ExternalConnection,
+        account_id: Option<AccountId>,
+    ) -> Self {
+        Self {
+            chain_id: "test".to_string(),
+            external,
+            state_parts_scratch_store: None,
+            part_source,
+            part_naming: Arc::new(DefaultPartNaming),
+            part_transform: Arc::new(IdentityPartTransform),
+            restart_dump_for_shards: None,
+            upload_archive: false,
+            skip_existing: false,
+            part_lease_ttl: None,
+            part_range: None,
+            idle_delay: DEFAULT_IDLE_DELAY,
+            min_iteration_delay: None,
+            work_delay: Duration::ZERO,
+            dump_every_n_epochs: None,
+            prefer_latest_epoch: false,
+            latest_only: false,
+            latest_only_grace_period: DEFAULT_LATEST_ONLY_GRACE_PERIOD,
+            probe_parts: 0,
+            backfill_deadline: None,
+            account_id,
+            block_production_in_progress: None,
+            shard_dump_permits: None,
+            date_partitioned: false,
+            compute_merkle_root: false,
+            incremental: false,
+            verify_reconstruction_every_n_epochs: None,
+            max_parts_per_iteration: None,
+            keep_running: Arc::new(AtomicBool::new(true)),
+        }
+    }
+}
+
+#[cfg(test)]
+impl ShardDumper {
+    /// Runs a single iteration of the dump loop to completion and returns the
+    /// progress of the shard afterwards, `None` if there is none yet.
+    fn step(&mut self, config: &ShardDumpConfig) -> Option<StateSyncDumpProgress> {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        assert!(runtime.block_on(self.run_iteration(config)).is_continue());
+        self.chain.store().get_state_sync_dump_progress(self.shard_id).ok().flatten()
+    }
+}
+
+// Extracts extra data needed for obtaining state parts.
 fn get_in_progress_data(
     shard_id: ShardId,
     sync_hash: CryptoHash,
     chain: &Chain,
-) -> Result<(StateRoot, u64, CryptoHash), Error> {
-    let state_header = chain.get_state_response_header(shard_id, sync_hash)?;
+) -> Result<(StateRoot, u64, CryptoHash), StateSyncDumpError> {
+    let state_header = chain
+        .get_state_response_header(shard_id, sync_hash)
+        .map_err(StateSyncDumpError::HeaderFailed)?;
     let state_root = state_header.chunk_prev_state_root();
     let num_parts = get_num_state_parts(state_header.state_root_node().memory_usage);
 
@@ -392,6 +1719,116 @@ fn get_in_progress_data(
     Ok((state_root, num_parts, *sync_prev_hash))
 }
 
+/// `None` means that the dump doesn't need to defer to block production.
+fn is_block_production_in_progress(block_production_in_progress: &Option<Arc<AtomicBool>>) -> bool {
+    block_production_in_progress
+        .as_ref()
+        .map_or(false, |flag| flag.load(std::sync::atomic::Ordering::Relaxed))
+}
+
+/// Gives up on the epoch if its state is no longer available, otherwise
+/// returns the error to retry later.
+fn skip_unavailable_epoch(
+    err: StateSyncDumpError,
+    shard_id: ShardId,
+    chain: &Chain,
+    epoch_id: EpochId,
+    epoch_height: EpochHeight,
+    sync_hash: CryptoHash,
+) -> Result<Option<StateSyncDumpProgress>, StateSyncDumpError> {
+    match is_epoch_state_garbage_collected(chain, &sync_hash) {
+        Ok(true) => {}
+        Ok(false) => return Err(err),
+        Err(gc_err) => {
+            tracing::debug!(target: "state_sync_dump", shard_id, epoch_height, %sync_hash, ?gc_err, "Failed to check whether the state of the epoch is garbage collected");
+            return Err(err);
+        }
+    }
+    tracing::warn!(target: "state_sync_dump", shard_id, ?epoch_id, epoch_height, ?err, "State of the epoch is no longer available, skip the epoch");
+    metrics::STATE_SYNC_DUMP_EPOCHS_SKIPPED_UNAVAILABLE
+        .with_label_values(&[&shard_id.to_string()])
+        .inc();
+    Ok(Some(StateSyncDumpProgress::Skipped { epoch_id, epoch_height }))
+}
+
+/// Clears the progress persisted by a previous run of the node if it's a dump
+/// of an epoch whose state is no longer available, for example because the
+/// node crashed mid-dump and stayed down for long. The dump loop then checks
+/// for a new epoch to dump instead of failing to continue the old one.
+fn reconcile_dump_progress(shard_id: ShardId, chain: &Chain) {
+    let (epoch_height, sync_hash) = match chain.store().get_state_sync_dump_progress(shard_id) {
+        Ok(Some(
+            StateSyncDumpProgress::InProgress { epoch_height, sync_hash, .. }
+            | StateSyncDumpProgress::InProgressV2 { epoch_height, sync_hash, .. },
+        )) => (epoch_height, sync_hash),
+        _ => return,
+    };
+    match is_epoch_state_garbage_collected(chain, &sync_hash) {
+        Ok(false) => {
+            tracing::info!(target: "state_sync_dump", shard_id, epoch_height, %sync_hash, "Continuing the dump of the epoch in progress");
+        }
+        Ok(true) => {
+            tracing::warn!(target: "state_sync_dump", shard_id, epoch_height, %sync_hash, "State of the epoch in progress is no longer available, clearing the progress");
+            if let Err(err) = chain.store().set_state_sync_dump_progress(shard_id, None) {
+                tracing::warn!(target: "state_sync_dump", shard_id, ?err, "Failed to clear the progress");
+            }
+        }
+        Err(err) => {
+            tracing::debug!(target: "state_sync_dump", shard_id, epoch_height, %sync_hash, ?err, "Failed to check the state of the epoch in progress, keeping the progress");
+        }
+    }
+}
+
+/// Whether the state of the epoch that ends right before `sync_hash` was
+/// garbage collected, i.e. the last block of the epoch is below the tail of
+/// the chain. Retrying to dump such an epoch will not help, unlike after
+/// other failures.
+fn is_epoch_state_garbage_collected(chain: &Chain, sync_hash: &CryptoHash) -> Result<bool, Error> {
+    let sync_header = chain.get_block_header(sync_hash)?;
+    let sync_prev_header = chain.get_block_header(sync_header.prev_hash())?;
+    Ok(sync_prev_header.height() < chain.store().tail()?)
+}
+
+/// Checks whether the node neither tracks the shard in the current epoch nor
+/// will track it in the next epoch. The state of such a shard doesn't get
+/// updated, and is eventually garbage collected.
+fn is_shard_state_going_away(
+    shard_id: ShardId,
+    chain: &Chain,
+    shard_tracker: &ShardTracker,
+    account_id: &Option<AccountId>,
+) -> Result<bool, Error> {
+    let head = chain.head()?;
+    let account_id = account_id.as_ref();
+    Ok(!shard_tracker.care_about_shard(account_id, &head.last_block_hash, shard_id, true)
+        && !shard_tracker.will_care_about_shard(account_id, &head.last_block_hash, shard_id, true))
+}
+
+/// Doubles the delay between idle iterations, up to `max_delay`.
+fn next_idle_delay(delay: Duration, max_delay: Duration) -> Duration {
+    std::cmp::min(delay.saturating_mul(2), max_delay)
+}
+
+/// Weight of the latest value in the moving average of the dump throughput.
+const THROUGHPUT_SMOOTHING_FACTOR: f64 = 0.3;
+
+/// Exponential moving average, which starts from the first value.
+fn update_moving_average(average: Option<f64>, value: f64) -> f64 {
+    match average {
+        Some(average) => average + THROUGHPUT_SMOOTHING_FACTOR * (value - average),
+        None => value,
+    }
+}
+
+fn inc_error_metric(shard_id: ShardId, err: &StateSyncDumpError) {
+    metrics::STATE_SYNC_DUMP_ERRORS.with_label_values(&[&shard_id.to_string(), err.label()]).inc();
+    if let StateSyncDumpError::ListFailed(err) = err {
+        metrics::STATE_SYNC_DUMP_LIST_FAILURES
+            .with_label_values(&[&shard_id.to_string(), err.label()])
+            .inc();
+    }
+}
+
 fn update_dumped_size_and_cnt_metrics(
     shard_id: &ShardId,
     epoch_height: EpochHeight,
@@ -408,6 +1845,7 @@ fn set_metrics(
     shard_id: &ShardId,
     parts_dumped: Option<u64>,
     num_parts: Option<u64>,
+    total_bytes: Option<u64>,
     epoch_height: Option<EpochHeight>,
 ) {
     if let Some(parts_dumped) = parts_dumped {
@@ -420,33 +1858,80 @@ fn set_metrics(
             .with_label_values(&[&shard_id.to_string()])
             .set(num_parts as i64);
     }
+    if let Some(total_bytes) = total_bytes {
+        metrics::STATE_SYNC_DUMP_EPOCH_SIZE_BYTES
+            .with_label_values(&[&shard_id.to_string()])
+            .set(total_bytes as i64);
+    }
     if let Some(epoch_height) = epoch_height {
-        assert!(
-            epoch_height < 10000,
-            "Impossible: {:?} {:?} {:?} {:?}",
-            shard_id,
-            parts_dumped,
-            num_parts,
-            epoch_height
-        );
         metrics::STATE_SYNC_DUMP_EPOCH_HEIGHT
             .with_label_values(&[&shard_id.to_string()])
             .set(epoch_height as i64);
     }
 }
 
+/// Where the state dump obtains state parts from.
+pub trait StatePartSource: Send + Sync {
+    /// Same as `RuntimeAdapter::obtain_state_part()`: returns the part of the
+    /// state with root `state_root` of the shard as of block `prev_hash`.
+    fn obtain_state_part(
+        &self,
+        shard_id: ShardId,
+        prev_hash: &CryptoHash,
+        state_root: &StateRoot,
+        part_id: PartId,
+    ) -> Result<Vec<u8>, Error>;
+}
+
+/// Obtains state parts from the live state of the node. Used by default.
+pub struct RuntimeStatePartSource(pub Arc<dyn RuntimeAdapter>);
+
+impl StatePartSource for RuntimeStatePartSource {
+    fn obtain_state_part(
+        &self,
+        shard_id: ShardId,
+        prev_hash: &CryptoHash,
+        state_root: &StateRoot,
+        part_id: PartId,
+    ) -> Result<Vec<u8>, Error> {
+        self.0.obtain_state_part(shard_id, prev_hash, state_root, part_id)
+    }
+}
+
+/// Obtains state parts from the given tries, which may be backed by a store
+/// other than the one of the node, such as a cold store or a snapshot.
+pub struct TrieStatePartSource {
+    pub epoch_manager: Arc<dyn EpochManagerAdapter>,
+    pub tries: ShardTries,
+}
+
+impl StatePartSource for TrieStatePartSource {
+    fn obtain_state_part(
+        &self,
+        shard_id: ShardId,
+        prev_hash: &CryptoHash,
+        state_root: &StateRoot,
+        part_id: PartId,
+    ) -> Result<Vec<u8>, Error> {
+        let epoch_id = self.epoch_manager.get_epoch_id(prev_hash)?;
+        let shard_uid = self.epoch_manager.shard_id_to_uid(shard_id, &epoch_id)?;
+        let trie = self.tries.get_view_trie_for_shard(shard_uid, *state_root);
+        Ok(trie.get_trie_nodes_for_part(part_id)?.try_to_vec()?)
+    }
+}
+
 /// Obtains and then saves the part data.
 fn obtain_and_store_state_part(
-    runtime: &dyn RuntimeAdapter,
+    part_source: &dyn StatePartSource,
     shard_id: ShardId,
     sync_hash: CryptoHash,
     sync_prev_hash: &CryptoHash,
     state_root: &StateRoot,
     part_id: u64,
     num_parts: u64,
-    chain: &Chain,
+    state_parts_store: &Store,
 ) -> Result<Vec<u8>, Error> {
-    let state_part = runtime.obtain_state_part(
+    let state_part = part_source.obtain_state_part(
         shard_id,
         sync_prev_hash,
         state_root,
@@ -454,171 +1939,3047 @@ fn obtain_and_store_state_part(
     )?;
 
     let key = StatePartKey(sync_hash, shard_id, part_id).try_to_vec()?;
-    let mut store_update = chain.store().store().store_update();
+    let mut store_update = state_parts_store.store_update();
     store_update.set(DBCol::StateParts, &key, &state_part);
     store_update.commit()?;
     Ok(state_part)
 }
 
-/// Gets basic information about the epoch to be dumped.
-fn start_dumping(
-    epoch_id: EpochId,
-    sync_hash: CryptoHash,
+/// Same as `obtain_and_store_state_part()`, but runs on a blocking thread and
+/// returns `None` as soon as `keep_running` is unset, so that the dump loop can
+/// stop promptly.
+/// An abandoned blocking task keeps running until the part is obtained and
+/// stored in `DBCol::StateParts`, and then its result is discarded. The stored
+/// part is valid and is cleaned up like any other stored part.
+async fn obtain_and_store_state_part_cancellable(
+    part_source: Arc<dyn StatePartSource>,
     shard_id: ShardId,
-    chain: &Chain,
-    epoch_manager: &dyn EpochManagerAdapter,
-    shard_tracker: &ShardTracker,
-    account_id: &Option<AccountId>,
-) -> Result<Option<StateSyncDumpProgress>, Error> {
-    let epoch_info = epoch_manager.get_epoch_info(&epoch_id)?;
-    let epoch_height = epoch_info.epoch_height();
+    sync_hash: CryptoHash,
+    sync_prev_hash: CryptoHash,
+    state_root: StateRoot,
+    part_id: u64,
+    num_parts: u64,
+    state_parts_store: Store,
+    keep_running: &AtomicBool,
+) -> Option<Result<Vec<u8>, Error>> {
+    let mut task = tokio::task::spawn_blocking(move || {
+        obtain_and_store_state_part(
+            part_source.as_ref(),
+            shard_id,
+            sync_hash,
+            &sync_prev_hash,
+            &state_root,
+            part_id,
+            num_parts,
+            &state_parts_store,
+        )
+    });
+    loop {
+        tokio::select! {
+            result = &mut task => {
+                return Some(result.unwrap_or_else(|err| {
+                    Err(Error::Other(format!("Obtaining a state part panicked: {err}")))
+                }));
+            }
+            _ = tokio::time::sleep(CANCELLATION_CHECK_INTERVAL) => {
+                if !keep_running.load(std::sync::atomic::Ordering::Relaxed) {
+                    return None;
+                }
+            }
+        }
+    }
+}
 
-    let sync_header = chain.get_block_header(&sync_hash)?;
-    let sync_prev_hash = sync_header.prev_hash();
-    let sync_prev_header = chain.get_block_header(&sync_prev_hash)?;
-    // Need to check if the completed epoch had a shard this account cares about.
-    // sync_hash is the first block of the next epoch.
-    // `cares_about_shard()` accepts `parent_hash`, therefore we need prev-prev-hash,
-    // and its next-hash will be prev-hash. That is the last block of the completed epoch,
-    // which is what we wanted.
-    let sync_prev_prev_hash = sync_prev_header.prev_hash();
+/// Runs `f` on a blocking thread. Several shards may be dumped on the same
+/// thread, see `DumpConfig::max_dump_threads`, and obtaining or applying state
+/// parts on that thread would stall the dumps of the other shards.
+async fn spawn_blocking_dump_task<T, E>(
+    f: impl FnOnce() -> Result<T, E> + Send + 'static,
+) -> Result<T, E>
+where
+    T: Send + 'static,
+    E: From<Error> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await.unwrap_or_else(|err| {
+        Err(Error::Other(format!("A blocking task of the state dump panicked: {err}")).into())
+    })
+}
 
-    let state_header = chain.get_state_response_header(shard_id, sync_hash)?;
-    let num_parts = get_num_state_parts(state_header.state_root_node().memory_usage);
-    if shard_tracker.care_about_shard(account_id.as_ref(), sync_prev_prev_hash, shard_id, true) {
-        tracing::info!(target: "state_sync_dump", shard_id, ?epoch_id, %sync_prev_hash, %sync_hash, "Initialize dumping state of Epoch");
-        // Note that first the state of the state machines gets changes to
-        // `InProgress` and it starts dumping state after a short interval.
-        set_metrics(&shard_id, Some(0), Some(num_parts), Some(epoch_height));
-        Ok(Some(StateSyncDumpProgress::InProgress { epoch_id, epoch_height, sync_hash }))
+/// Delay between idle iterations of the dump loop, unless configured.
+pub(crate) const DEFAULT_IDLE_DELAY: Duration = Duration::from_secs(10);
+
+/// How long earlier epochs are kept with `latest_only`, unless configured.
+const DEFAULT_LATEST_ONLY_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// How often to check whether an ongoing part obtain needs to be abandoned.
+const CANCELLATION_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Called once all parts of an epoch are present in the external storage.
+/// Uploads the archive of all parts and computes the Merkle root of the parts
+/// if requested, and then deletes the parts from the scratch database if one
+/// is used.
+async fn finish_epoch_dump(
+    config: &ShardDumpConfig,
+    state_parts_store: &Store,
+    epoch: &EpochDump,
+    total_bytes: u64,
+    verify_reconstruction: bool,
+    write_manifest: bool,
+) -> Result<(), StateSyncDumpError> {
+    let EpochDump {
+        shard_id,
+        ref epoch_id,
+        epoch_height,
+        sync_hash,
+        sync_prev_hash,
+        state_root,
+        num_parts,
+        ref date_partition,
+    } = *epoch;
+    let ShardDumpConfig {
+        ref chain_id,
+        ref part_source,
+        ref part_naming,
+        ref part_transform,
+        ref external,
+        upload_archive,
+        compute_merkle_root,
+        ..
+    } = *config;
+    let date_partition = date_partition.as_deref();
+    if verify_reconstruction {
+        match verify_reconstructed_state(
+            shard_id,
+            chain_id,
+            date_partition,
+            epoch_id,
+            epoch_height,
+            &state_root,
+            num_parts,
+            part_naming.as_ref(),
+            part_transform,
+            external,
+        )
+        .await
+        {
+            Ok(()) => {}
+            Err(err @ StateSyncDumpError::ReconstructionFailed(_)) => {
+                tracing::error!(target: "state_sync_dump", shard_id, epoch_height, ?epoch_id, ?err, "Dumped state parts don't reconstruct the state, deleting them to dump the epoch again");
+                metrics::STATE_SYNC_DUMP_RECONSTRUCTION_FAILURES
+                    .with_label_values(&[&shard_id.to_string()])
+                    .inc();
+                // The parts are obtained again rather than read from the database.
+                clear_scratch_state_parts(state_parts_store, sync_hash, shard_id);
+                for part_id in 0..num_parts {
+                    let location = epoch.part_location(config, part_id);
+                    if let Err(err) = external.delete_file(shard_id, &location).await {
+                        tracing::warn!(target: "state_sync_dump", shard_id, epoch_height, part_id, ?err, "Failed to delete a dumped part");
+                    }
+                }
+                return Err(err);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    let archive = if upload_archive {
+        Some(tempfile::NamedTempFile::new().map_err(StateSyncDumpError::ArchiveFailed)?)
     } else {
-        tracing::info!(target: "state_sync_dump", shard_id, ?epoch_id, %sync_hash, "Shard is not tracked, skip the epoch");
-        Ok(Some(StateSyncDumpProgress::AllDumped { epoch_id, epoch_height, num_parts: Some(0) }))
+        None
+    };
+    // Checksums of the parts ordered by part id.
+    let mut part_checksums = vec![];
+    if upload_archive || compute_merkle_root {
+        let mut writer = archive.as_ref().map(|archive| {
+            StatePartsArchiveWriter::new(std::io::BufWriter::new(archive.as_file()))
+        });
+        for part_id in 0..num_parts {
+            let part_source = part_source.clone();
+            let state_parts_store = state_parts_store.clone();
+            let state_part = spawn_blocking_dump_task(move || {
+                get_or_obtain_state_part(
+                    part_source.as_ref(),
+                    shard_id,
+                    sync_hash,
+                    &sync_prev_hash,
+                    &state_root,
+                    part_id,
+                    num_parts,
+                    &state_parts_store,
+                )
+            })
+            .await?;
+            if let Some(writer) = &mut writer {
+                writer
+                    .add_part(&part_transform.encode(&state_part))
+                    .map_err(StateSyncDumpError::ArchiveFailed)?;
+            }
+            if compute_merkle_root {
+                part_checksums.push(hash(&state_part));
+            }
+        }
+        if let Some(writer) = writer {
+            writer.finish().map_err(StateSyncDumpError::ArchiveFailed)?;
+        }
+    }
+    if let Some(archive) = archive {
+        let location = external_storage_archive_location(
+            chain_id,
+            epoch_id,
+            epoch_height,
+            shard_id,
+            num_parts,
+            date_partition,
+        );
+        let archive_size = external
+            .put_archive(archive.path(), shard_id, &location)
+            .await
+            .map_err(StateSyncDumpError::UploadFailed)?;
+        tracing::info!(target: "state_sync_dump", shard_id, epoch_height, archive_size, ?location, "Uploaded an archive of state parts");
+    }
+    let merkle_root = if compute_merkle_root {
+        let (merkle_root, _) = merklize(&part_checksums);
+        tracing::info!(target: "state_sync_dump", shard_id, epoch_height, ?epoch_id, num_parts, %merkle_root, "Computed the Merkle root of the dumped state parts");
+        Some(merkle_root)
+    } else {
+        None
+    };
+    // The manifest is written last, because it claims that everything else is
+    // already uploaded.
+    if write_manifest {
+        put_epoch_manifest(
+            shard_id,
+            chain_id,
+            date_partition,
+            epoch_id,
+            epoch_height,
+            &StatePartsManifest { num_parts, total_bytes, merkle_root },
+            external,
+        )
+        .await;
+    }
+    if config.state_parts_scratch_store.is_some() {
+        clear_scratch_state_parts(state_parts_store, sync_hash, shard_id);
     }
+    Ok(())
 }
 
-/// Checks what is the latest complete epoch.
-/// `epoch_id` represents the last fully dumped epoch.
-fn check_new_epoch(
-    epoch_id: Option<EpochId>,
-    epoch_height: Option<EpochHeight>,
-    num_parts: Option<u64>,
+/// Outcome of checking the parts of an epoch dump of a shard.
+#[derive(Debug, Default, PartialEq, Eq, serde::Serialize)]
+pub struct DumpVerificationReport {
+    /// Parts absent from the external storage.
+    pub missing: Vec<u64>,
+    /// Parts that can't be decoded, or don't match the state root.
+    pub corrupt: Vec<u64>,
+    /// Number of valid parts.
+    pub ok: u64,
+    pub total: u64,
+}
+
+impl DumpVerificationReport {
+    /// Whether every part of the epoch is present and valid.
+    pub fn is_complete(&self) -> bool {
+        self.ok == self.total
+    }
+}
+
+/// Checks that every part of the epoch dump is present in the external
+/// storage and matches `state_root`. Unlike `verify_reconstructed_state`, it
+/// doesn't stop at the first invalid part, but reports all of them, so that
+/// only these parts can be dumped again.
+pub async fn verify_epoch_dump(
+    shard_id: ShardId,
+    chain_id: &str,
+    date_partition: Option<&str>,
+    epoch_id: &EpochId,
+    epoch_height: EpochHeight,
+    state_root: &StateRoot,
+    num_parts: u64,
+    part_naming: &dyn PartNaming,
+    part_transform: &dyn PartTransform,
+    external: &ExternalConnection,
+) -> Result<DumpVerificationReport, StateSyncDumpError> {
+    let parts = get_missing_part_ids_for_epoch(
+        part_naming,
+        shard_id,
+        &chain_id.to_string(),
+        date_partition,
+        epoch_id,
+        epoch_height,
+        num_parts,
+        None,
+        None,
+        external,
+    )
+    .await
+    .map_err(StateSyncDumpError::ListFailed)?;
+    let mut report =
+        DumpVerificationReport { missing: parts.missing, corrupt: vec![], ok: 0, total: num_parts };
+    for part_id in parts.present {
+        let location = external_storage_location(
+            part_naming,
+            chain_id,
+            epoch_id,
+            epoch_height,
+            shard_id,
+            part_id,
+            num_parts,
+            date_partition,
+        );
+        let state_part = external
+            .clone()
+            .get_part(shard_id, &location)
+            .await
+            .map_err(StateSyncDumpError::DownloadFailed)?;
+        match part_transform.decode(&state_part).and_then(|state_part| {
+            validate_dumped_part(state_root, PartId::new(part_id, num_parts), &state_part)
+        }) {
+            Ok(_) => report.ok += 1,
+            Err(err) => {
+                tracing::warn!(target: "state_sync_dump", shard_id, epoch_height, part_id, ?err, "Dumped part is corrupt");
+                report.corrupt.push(part_id);
+            }
+        }
+    }
+    report.missing.sort();
+    report.corrupt.sort();
+    Ok(report)
+}
+
+/// Outcome of repairing an epoch dump of a shard.
+#[derive(Debug, Default, PartialEq, Eq, serde::Serialize)]
+pub struct DumpRepairReport {
+    /// Parts that were missing or corrupt, and are dumped again.
+    pub repaired: Vec<u64>,
+    /// Parts that were missing or corrupt, and failed to be dumped again.
+    pub failed: Vec<u64>,
+    /// Number of parts that were valid already.
+    pub ok: u64,
+    pub total: u64,
+}
+
+impl DumpRepairReport {
+    /// Whether every part of the epoch is present and valid after the repair.
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Dumps again only the parts of a completely dumped epoch that are missing
+/// or corrupt according to `verify_epoch_dump()`, instead of dumping the whole
+/// epoch again. The dump progress of the node is left as is.
+/// The parts are obtained again rather than read from `DBCol::StateParts`,
+/// because the stored copy may be the corrupt one, and are validated before
+/// being uploaded.
+/// Fails if the epoch has no manifest, i.e. if its dump isn't complete, or
+/// if the manifest has a different number of parts.
+pub async fn repair_epoch_dump(
+    part_source: &dyn StatePartSource,
+    shard_id: ShardId,
+    chain_id: &str,
+    date_partition: Option<&str>,
+    epoch_id: &EpochId,
+    epoch_height: EpochHeight,
+    sync_prev_hash: &CryptoHash,
+    state_root: &StateRoot,
+    num_parts: u64,
+    part_naming: &dyn PartNaming,
+    part_transform: &dyn PartTransform,
+    external: &ExternalConnection,
+) -> Result<DumpRepairReport, StateSyncDumpError> {
+    match get_epoch_manifest(shard_id, chain_id, date_partition, epoch_id, epoch_height, external)
+        .await
+    {
+        Some(manifest) if manifest.num_parts == num_parts => {}
+        Some(manifest) => {
+            return Err(Error::Other(format!(
+                "The manifest of the epoch has {} parts instead of {}",
+                manifest.num_parts, num_parts
+            ))
+            .into())
+        }
+        None => {
+            return Err(Error::Other(
+                "The epoch has no manifest, its dump is not complete".to_string(),
+            )
+            .into())
+        }
+    }
+    let verification = verify_epoch_dump(
+        shard_id,
+        chain_id,
+        date_partition,
+        epoch_id,
+        epoch_height,
+        state_root,
+        num_parts,
+        part_naming,
+        part_transform,
+        external,
+    )
+    .await?;
+    let mut report =
+        DumpRepairReport { ok: verification.ok, total: verification.total, ..Default::default() };
+    let mut part_ids = [verification.missing, verification.corrupt].concat();
+    part_ids.sort();
+    for part_id in part_ids {
+        let state_part = part_source
+            .obtain_state_part(
+                shard_id,
+                sync_prev_hash,
+                state_root,
+                PartId::new(part_id, num_parts),
+            )
+            .map_err(StateSyncDumpError::ObtainFailed)
+            .and_then(|state_part| {
+                validate_dumped_part(state_root, PartId::new(part_id, num_parts), &state_part)
+                    .map_err(|err| StateSyncDumpError::ObtainFailed(Error::Other(err.to_string())))
+                    .map(|_| state_part)
+            });
+        let result = match state_part {
+            Ok(state_part) => {
+                let location = external_storage_location(
+                    part_naming,
+                    chain_id,
+                    epoch_id,
+                    epoch_height,
+                    shard_id,
+                    part_id,
+                    num_parts,
+                    date_partition,
+                );
+                external
+                    .put_state_part(&part_transform.encode(&state_part), shard_id, &location)
+                    .await
+                    .map_err(StateSyncDumpError::UploadFailed)
+            }
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => {
+                tracing::info!(target: "state_sync_dump", shard_id, epoch_height, part_id, "Repaired a dumped part");
+                report.repaired.push(part_id);
+            }
+            Err(err) => {
+                tracing::warn!(target: "state_sync_dump", shard_id, epoch_height, part_id, ?err, "Failed to repair a dumped part");
+                report.failed.push(part_id);
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Outcome of comparing the parts of a shard stored in the database with the
+/// parts dumped to the external storage.
+#[derive(Debug, Default, PartialEq, Eq, serde::Serialize)]
+pub struct LocalPartsComparison {
+    /// Parts stored in the database, but absent from the external storage.
+    pub missing: Vec<u64>,
+    /// Parts whose dumped copy differs from the one in the database, or can't
+    /// be decoded.
+    pub mismatched: Vec<u64>,
+    /// Number of parts whose dumped copy is identical.
+    pub matching: u64,
+    /// Number of parts stored in the database.
+    pub total: u64,
+}
+
+impl LocalPartsComparison {
+    /// Whether every part stored in the database is dumped as is.
+    pub fn is_consistent(&self) -> bool {
+        self.matching == self.total
+    }
+}
+
+/// Compares every part of the state of `shard_id` as of `sync_hash` stored in
+/// `DBCol::StateParts` of `store` with its dumped copy, by the hashes of their
+/// contents. Meant for debugging dumps that don't restore the expected state.
+/// Parts absent from the database are not checked, `verify_epoch_dump()`
+/// checks that all parts are dumped.
+pub async fn compare_local_state_parts(
+    store: &Store,
+    sync_hash: CryptoHash,
+    shard_id: ShardId,
+    chain_id: &str,
+    date_partition: Option<&str>,
+    epoch_id: &EpochId,
+    epoch_height: EpochHeight,
+    num_parts: u64,
+    part_naming: &dyn PartNaming,
+    part_transform: &dyn PartTransform,
+    external: &ExternalConnection,
+) -> Result<LocalPartsComparison, StateSyncDumpError> {
+    let dumped_parts = get_missing_part_ids_for_epoch(
+        part_naming,
+        shard_id,
+        &chain_id.to_string(),
+        date_partition,
+        epoch_id,
+        epoch_height,
+        num_parts,
+        None,
+        None,
+        external,
+    )
+    .await
+    .map_err(StateSyncDumpError::ListFailed)?;
+    let mut comparison = LocalPartsComparison::default();
+    // Keys of the parts of the shard start with the sync hash and the shard id.
+    let key_prefix = (sync_hash, shard_id).try_to_vec().map_err(Error::from)?;
+    for item in store.iter_prefix(DBCol::StateParts, &key_prefix) {
+        let (key, local_part) = item.map_err(Error::from)?;
+        let StatePartKey(_, _, part_id) =
+            StatePartKey::try_from_slice(&key).map_err(Error::from)?;
+        comparison.total += 1;
+        if !dumped_parts.present.contains(&part_id) {
+            comparison.missing.push(part_id);
+            continue;
+        }
+        let location = external_storage_location(
+            part_naming,
+            chain_id,
+            epoch_id,
+            epoch_height,
+            shard_id,
+            part_id,
+            num_parts,
+            date_partition,
+        );
+        let dumped_part = external
+            .clone()
+            .get_part(shard_id, &location)
+            .await
+            .map_err(StateSyncDumpError::DownloadFailed)?;
+        match part_transform.decode(&dumped_part) {
+            Ok(dumped_part) if hash(&dumped_part) == hash(&local_part) => comparison.matching += 1,
+            Ok(dumped_part) => {
+                tracing::warn!(target: "state_sync_dump", shard_id, part_id, local_hash = %hash(&local_part), dumped_hash = %hash(&dumped_part), "Dumped part differs from the part in the database");
+                comparison.mismatched.push(part_id);
+            }
+            Err(err) => {
+                tracing::warn!(target: "state_sync_dump", shard_id, part_id, ?err, "Dumped part can't be decoded");
+                comparison.mismatched.push(part_id);
+            }
+        }
+    }
+    comparison.missing.sort();
+    comparison.mismatched.sort();
+    Ok(comparison)
+}
+
+/// Decodes a dumped part and checks that it's the given part of the trie of
+/// `state_root`.
+fn validate_dumped_part(
+    state_root: &StateRoot,
+    part_id: PartId,
+    state_part: &[u8],
+) -> anyhow::Result<PartialState> {
+    let partial_state = PartialState::try_from_slice(state_part)
+        .map_err(|err| anyhow::anyhow!("Part {} can't be decoded: {err}", part_id.idx))?;
+    Trie::validate_trie_nodes_for_part(state_root, part_id, partial_state.clone())
+        .map_err(|err| anyhow::anyhow!("Part {} is invalid: {err}", part_id.idx))?;
+    Ok(partial_state)
+}
+
+/// Downloads all parts of the epoch and applies them to an empty temporary
+/// database, the same way a syncing node applies them. Fails with
+/// `ReconstructionFailed` if a part is invalid, or if the applied parts don't
+/// form the complete trie of `state_root`.
+async fn verify_reconstructed_state(
+    shard_id: ShardId,
+    chain_id: &str,
+    date_partition: Option<&str>,
+    epoch_id: &EpochId,
+    epoch_height: EpochHeight,
+    state_root: &StateRoot,
+    num_parts: u64,
+    part_naming: &dyn PartNaming,
+    part_transform: &Arc<dyn PartTransform>,
+    external: &ExternalConnection,
+) -> Result<(), StateSyncDumpError> {
+    tracing::info!(target: "state_sync_dump", shard_id, epoch_height, ?epoch_id, num_parts, "Verifying that the dumped parts reconstruct the state");
+    let temp_dir = tempfile::tempdir().map_err(Error::from)?;
+    let storage = NodeStorage::opener(temp_dir.path(), false, &StoreConfig::default(), None)
+        .open()
+        .map_err(|err| Error::Other(err.to_string()))?;
+    let store = storage.get_hot_store();
+    // The temporary database contains only the state of this shard.
+    let shard_uid = ShardUId::single_shard();
+    let tries = ShardTries::new(
+        store.clone(),
+        TrieConfig::default(),
+        &[shard_uid],
+        FlatStorageManager::new(store),
+    );
+    for part_id in 0..num_parts {
+        let location = external_storage_location(
+            part_naming,
+            chain_id,
+            epoch_id,
+            epoch_height,
+            shard_id,
+            part_id,
+            num_parts,
+            date_partition,
+        );
+        let state_part = external
+            .clone()
+            .get_part(shard_id, &location)
+            .await
+            .map_err(StateSyncDumpError::DownloadFailed)?;
+        let tries = tries.clone();
+        let part_transform = part_transform.clone();
+        let state_root = *state_root;
+        spawn_blocking_dump_task(move || {
+            let part_id = PartId::new(part_id, num_parts);
+            let partial_state = part_transform
+                .decode(&state_part)
+                .and_then(|state_part| validate_dumped_part(&state_root, part_id, &state_part))
+                .map_err(StateSyncDumpError::ReconstructionFailed)?;
+            let trie_changes =
+                Trie::apply_state_part(&state_root, part_id, partial_state).trie_changes;
+            let mut store_update = tries.store_update();
+            tries.apply_all(&trie_changes, shard_uid, &mut store_update);
+            store_update.commit().map_err(Error::from)?;
+            Ok(())
+        })
+        .await?;
+    }
+    // Every node and value of the trie is read, which fails if any is missing.
+    let state_root = *state_root;
+    let num_values = spawn_blocking_dump_task(move || {
+        let trie = tries.get_view_trie_for_shard(shard_uid, state_root);
+        let mut num_values = 0;
+        let reconstruction_error = |err: StorageError| {
+            StateSyncDumpError::ReconstructionFailed(anyhow::anyhow!(
+                "The trie of the applied parts is incomplete: {err}"
+            ))
+        };
+        for item in trie.iter().map_err(reconstruction_error)? {
+            item.map_err(reconstruction_error)?;
+            num_values += 1;
+        }
+        Ok(num_values)
+    })
+    .await?;
+    tracing::info!(target: "state_sync_dump", shard_id, epoch_height, ?epoch_id, num_values, "The dumped parts reconstruct the state");
+    Ok(())
+}
+
+/// Reads a part from `DBCol::StateParts`, and obtains it if it's not there.
+/// Parts obtained by a previous run of the node, or dumped by other nodes, may
+/// be missing.
+fn get_or_obtain_state_part(
+    part_source: &dyn StatePartSource,
+    shard_id: ShardId,
+    sync_hash: CryptoHash,
+    sync_prev_hash: &CryptoHash,
+    state_root: &StateRoot,
+    part_id: u64,
+    num_parts: u64,
+    state_parts_store: &Store,
+) -> Result<Vec<u8>, StateSyncDumpError> {
+    let key = StatePartKey(sync_hash, shard_id, part_id).try_to_vec().map_err(Error::from)?;
+    match state_parts_store.get(DBCol::StateParts, &key).map_err(Error::from)? {
+        Some(state_part) => Ok(state_part.to_vec()),
+        None => obtain_and_store_state_part(
+            part_source,
+            shard_id,
+            sync_hash,
+            sync_prev_hash,
+            state_root,
+            part_id,
+            num_parts,
+            state_parts_store,
+        )
+        .map_err(StateSyncDumpError::ObtainFailed),
+    }
+}
+
+/// Failures are not fatal, because without a manifest the parts get listed
+/// one by one.
+async fn put_epoch_manifest(
+    shard_id: ShardId,
+    chain_id: &str,
+    date_partition: Option<&str>,
+    epoch_id: &EpochId,
+    epoch_height: EpochHeight,
+    manifest: &StatePartsManifest,
+    external: &ExternalConnection,
+) {
+    let location = external_storage_manifest_location(
+        chain_id,
+        epoch_id,
+        epoch_height,
+        shard_id,
+        date_partition,
+    );
+    let manifest = serde_json::to_vec(manifest).expect("serialization should not fail here");
+    if let Err(err) = external.put_state_part(&manifest, shard_id, &location).await {
+        tracing::warn!(target: "state_sync_dump", shard_id, epoch_height, ?err, "Failed to write the manifest");
+    }
+}
+
+/// Writes a manifest claiming zero parts, which lets restoring nodes tell a
+/// shard without state from a dump that hasn't finished. The epoch is skipped
+/// rather than marked as dumped.
+async fn skip_zero_part_epoch(
+    shard_id: ShardId,
+    chain_id: &str,
+    date_partition: Option<&str>,
+    epoch_id: EpochId,
+    epoch_height: EpochHeight,
+    external: &ExternalConnection,
+) -> StateSyncDumpProgress {
+    put_epoch_manifest(
+        shard_id,
+        chain_id,
+        date_partition,
+        &epoch_id,
+        epoch_height,
+        &StatePartsManifest { num_parts: 0, total_bytes: 0, merkle_root: None },
+        external,
+    )
+    .await;
+    StateSyncDumpProgress::Skipped { epoch_id, epoch_height }
+}
+
+/// Returns `None` if the epoch has no manifest or it can't be read.
+async fn get_epoch_manifest(
+    shard_id: ShardId,
+    chain_id: &str,
+    date_partition: Option<&str>,
+    epoch_id: &EpochId,
+    epoch_height: EpochHeight,
+    external: &ExternalConnection,
+) -> Option<StatePartsManifest> {
+    let location = external_storage_manifest_location(
+        chain_id,
+        epoch_id,
+        epoch_height,
+        shard_id,
+        date_partition,
+    );
+    match external.get_file(shard_id, &location).await {
+        Ok(Some(manifest)) => match serde_json::from_slice(&manifest) {
+            Ok(manifest) => Some(manifest),
+            Err(err) => {
+                tracing::warn!(target: "state_sync_dump", shard_id, epoch_height, ?err, "Failed to parse the manifest");
+                None
+            }
+        },
+        Ok(None) => None,
+        Err(err) => {
+            tracing::debug!(target: "state_sync_dump", shard_id, epoch_height, ?err, "Failed to read the manifest");
+            None
+        }
+    }
+}
+
+/// Deletes the parts of a dumped epoch from the scratch database.
+/// Unlike the main database, the scratch database is not garbage collected.
+fn clear_scratch_state_parts(store: &Store, sync_hash: CryptoHash, shard_id: ShardId) {
+    let mut store_update = store.store_update();
+    near_store::delete_state_parts(&mut store_update, sync_hash, shard_id);
+    if let Err(err) = store_update.commit() {
+        tracing::warn!(target: "state_sync_dump", shard_id, ?err, "Failed to delete state parts from the scratch database");
+    }
+}
+
+/// Gets basic information about the epoch to be dumped.
+/// If `probe_parts` is set, checks that the epoch can be dumped by obtaining
+/// that many parts, see `DumpConfig::probe_parts`.
+async fn start_dumping(
+    epoch_id: EpochId,
+    sync_hash: CryptoHash,
     shard_id: ShardId,
     chain: &Chain,
     epoch_manager: &dyn EpochManagerAdapter,
     shard_tracker: &ShardTracker,
+    part_source: &Arc<dyn StatePartSource>,
     account_id: &Option<AccountId>,
-) -> Result<Option<StateSyncDumpProgress>, Error> {
-    let head = chain.head()?;
-    if Some(&head.epoch_id) == epoch_id.as_ref() {
-        set_metrics(&shard_id, num_parts, num_parts, epoch_height);
-        Ok(None)
-    } else {
-        // Check if the final block is now in the next epoch.
-        tracing::debug!(target: "state_sync_dump", shard_id, ?epoch_id, "Check if a new complete epoch is available");
-        let hash = head.last_block_hash;
-        let header = chain.get_block_header(&hash)?;
-        let final_hash = header.last_final_block();
-        let sync_hash = StateSync::get_epoch_start_sync_hash(chain, final_hash)?;
-        let header = chain.get_block_header(&sync_hash)?;
-        if Some(header.epoch_id()) == epoch_id.as_ref() {
-            // Still in the latest dumped epoch. Do nothing.
-            Ok(None)
-        } else {
-            start_dumping(
-                head.epoch_id,
-                sync_hash,
+    dump_every_n_epochs: Option<u64>,
+    probe_parts: usize,
+) -> Result<Option<StateSyncDumpProgress>, StateSyncDumpError> {
+    let epoch_info = epoch_manager.get_epoch_info(&epoch_id).map_err(Error::from)?;
+    let epoch_height = epoch_info.epoch_height();
+    if !is_epoch_on_dump_cadence(epoch_height, dump_every_n_epochs) {
+        tracing::info!(target: "state_sync_dump", shard_id, ?epoch_id, epoch_height, ?dump_every_n_epochs, "Epoch doesn't match the dump cadence, skip the epoch");
+        return Ok(Some(StateSyncDumpProgress::Skipped { epoch_id, epoch_height }));
+    }
+
+    let sync_header = chain.get_block_header(&sync_hash)?;
+    let sync_prev_hash = *sync_header.prev_hash();
+    let sync_prev_header = chain.get_block_header(&sync_prev_hash)?;
+    // Need to check if the completed epoch had a shard this account cares about.
+    // sync_hash is the first block of the next epoch.
+    // `cares_about_shard()` accepts `parent_hash`, therefore we need prev-prev-hash,
+    // and its next-hash will be prev-hash. That is the last block of the completed epoch,
+    // which is what we wanted.
+    let sync_prev_prev_hash = sync_prev_header.prev_hash();
+
+    let state_header = match chain.get_state_response_header(shard_id, sync_hash) {
+        Ok(state_header) => state_header,
+        Err(err) => {
+            return skip_unavailable_epoch(
+                StateSyncDumpError::HeaderFailed(err),
                 shard_id,
                 chain,
-                epoch_manager,
-                shard_tracker,
-                account_id,
+                epoch_id,
+                epoch_height,
+                sync_hash,
+            )
+        }
+    };
+    let num_parts = get_num_state_parts(state_header.state_root_node().memory_usage);
+    if shard_tracker.care_about_shard(account_id.as_ref(), sync_prev_prev_hash, shard_id, true) {
+        // Lets a failing restore be matched against exactly what was dumped.
+        let state_root = state_header.chunk_prev_state_root();
+        tracing::info!(target: "state_sync_dump", shard_id, ?epoch_id, epoch_height, %sync_hash, %sync_prev_hash, %state_root, num_parts, "Initialize dumping state of Epoch");
+        if num_parts == 0 {
+            // There is nothing to obtain. The dump loop writes a manifest and
+            // skips the epoch, see `skip_zero_part_epoch()`.
+            tracing::warn!(target: "state_sync_dump", shard_id, ?epoch_id, epoch_height, "The state of the tracked shard has no parts");
+            metrics::STATE_SYNC_DUMP_ZERO_PART_EPOCHS
+                .with_label_values(&[&shard_id.to_string()])
+                .inc();
+            return Ok(Some(StateSyncDumpProgress::InProgressV2 {
+                epoch_id,
+                epoch_height,
+                sync_hash,
+                num_parts,
+            }));
+        }
+        let probe_part_source = part_source.clone();
+        if let Err(err) = spawn_blocking_dump_task(move || {
+            probe_epoch_parts(
+                probe_part_source.as_ref(),
+                shard_id,
+                &sync_prev_hash,
+                &state_root,
+                num_parts,
+                probe_parts,
+            )
+        })
+        .await
+        {
+            return match err {
+                StateSyncDumpError::ProbeFailed(_) => {
+                    tracing::warn!(target: "state_sync_dump", shard_id, ?epoch_id, epoch_height, ?err, "A probed part is invalid, skip the epoch");
+                    metrics::STATE_SYNC_DUMP_EPOCHS_SKIPPED_INVALID_PROBE
+                        .with_label_values(&[&shard_id.to_string()])
+                        .inc();
+                    Ok(Some(StateSyncDumpProgress::Skipped { epoch_id, epoch_height }))
+                }
+                // Other failures to obtain a part may be temporary.
+                err => {
+                    skip_unavailable_epoch(err, shard_id, chain, epoch_id, epoch_height, sync_hash)
+                }
+            };
+        }
+        // Note that first the state of the state machines gets changes to
+        // `InProgress` and it starts dumping state after a short interval.
+        set_metrics(&shard_id, Some(0), Some(num_parts), None, Some(epoch_height));
+        let estimate_part_source = part_source.clone();
+        match spawn_blocking_dump_task(move || {
+            estimate_epoch_dump_bytes(
+                estimate_part_source.as_ref(),
+                shard_id,
+                &sync_prev_hash,
+                &state_root,
+                num_parts,
+            )
+        })
+        .await
+        {
+            Ok(estimated_bytes) => {
+                tracing::info!(target: "state_sync_dump", shard_id, ?epoch_id, num_parts, estimated_bytes, "Estimated the size of the epoch dump");
+                metrics::STATE_SYNC_DUMP_ESTIMATED_EPOCH_BYTES
+                    .with_label_values(&[&shard_id.to_string()])
+                    .set(estimated_bytes as i64);
+            }
+            Err(err) => {
+                tracing::debug!(target: "state_sync_dump", shard_id, ?err, "Failed to estimate the size of the epoch dump");
+            }
+        }
+        Ok(Some(StateSyncDumpProgress::InProgressV2 {
+            epoch_id,
+            epoch_height,
+            sync_hash,
+            num_parts,
+        }))
+    } else {
+        tracing::info!(target: "state_sync_dump", shard_id, ?epoch_id, %sync_hash, "Shard is not tracked, skip the epoch");
+        Ok(Some(StateSyncDumpProgress::AllDumpedV2 {
+            epoch_id,
+            epoch_height,
+            num_parts: Some(0),
+            total_bytes: 0,
+        }))
+    }
+}
+
+/// Obtains `probe_parts` parts of the epoch, see `probe_part_ids()`, and
+/// checks that they are valid parts of the trie of `state_root`.
+fn probe_epoch_parts(
+    part_source: &dyn StatePartSource,
+    shard_id: ShardId,
+    sync_prev_hash: &CryptoHash,
+    state_root: &StateRoot,
+    num_parts: u64,
+    probe_parts: usize,
+) -> Result<(), StateSyncDumpError> {
+    for part_id in probe_part_ids(num_parts, probe_parts as u64) {
+        let part_id = PartId::new(part_id, num_parts);
+        let state_part = part_source
+            .obtain_state_part(shard_id, sync_prev_hash, state_root, part_id)
+            .map_err(StateSyncDumpError::ObtainFailed)?;
+        validate_dumped_part(state_root, part_id, &state_part)
+            .map_err(StateSyncDumpError::ProbeFailed)?;
+    }
+    Ok(())
+}
+
+/// Returns up to `num_probes` part ids spread evenly over `0..num_parts`,
+/// starting with the first part and, if more than one part is probed, ending
+/// with the last part.
+fn probe_part_ids(num_parts: u64, num_probes: u64) -> Vec<u64> {
+    match num_probes.min(num_parts) {
+        0 => vec![],
+        1 => vec![0],
+        num_probes => (0..num_probes).map(|i| i * (num_parts - 1) / (num_probes - 1)).collect(),
+    }
+}
+
+/// Number of state parts obtained to estimate the size of an epoch dump.
+const NUM_PARTS_TO_ESTIMATE_DUMP_SIZE: u64 = 4;
+
+/// Estimates the total size of the state parts of an epoch, without
+/// obtaining all of them. Obtains a few parts spread evenly over the state
+/// and extrapolates their average size to `num_parts`.
+fn estimate_epoch_dump_bytes(
+    part_source: &dyn StatePartSource,
+    shard_id: ShardId,
+    sync_prev_hash: &CryptoHash,
+    state_root: &StateRoot,
+    num_parts: u64,
+) -> Result<u64, Error> {
+    let mut sampled_bytes = vec![];
+    for part_id in sample_part_ids(num_parts, NUM_PARTS_TO_ESTIMATE_DUMP_SIZE) {
+        let state_part = part_source.obtain_state_part(
+            shard_id,
+            sync_prev_hash,
+            state_root,
+            PartId::new(part_id, num_parts),
+        )?;
+        sampled_bytes.push(state_part.len() as u64);
+    }
+    Ok(extrapolate_dump_bytes(&sampled_bytes, num_parts))
+}
+
+/// Returns up to `num_samples` part ids spread evenly over `0..num_parts`.
+fn sample_part_ids(num_parts: u64, num_samples: u64) -> Vec<u64> {
+    let num_samples = num_samples.min(num_parts);
+    (0..num_samples).map(|i| i * num_parts / num_samples).collect()
+}
+
+/// Extrapolates the sizes of the sampled parts to `num_parts` parts.
+fn extrapolate_dump_bytes(sampled_bytes: &[u64], num_parts: u64) -> u64 {
+    if sampled_bytes.is_empty() {
+        return 0;
+    }
+    let sum: u128 = sampled_bytes.iter().map(|&bytes| bytes as u128).sum();
+    (sum * num_parts as u128 / sampled_bytes.len() as u128) as u64
+}
+
+/// Returns the progress of the latest complete epoch, if it is newer than
+/// the epoch being dumped, and newer than the epoch to return to after
+/// dumping an interrupted epoch.
+/// Returns `None` if the latest epoch doesn't need to be dumped.
+/// An epoch that `start_dumping()` found not to need a dump, for example
+/// because the shard is not tracked or a probed part is invalid, is remembered
+/// in `rejected_sync_hash` and not checked again, so that its parts don't get
+/// obtained on every iteration of the dump loop.
+async fn get_latest_epoch_to_preempt(
+    epoch_height: EpochHeight,
+    sync_hash: CryptoHash,
+    shard_id: ShardId,
+    chain: &Chain,
+    epoch_manager: &dyn EpochManagerAdapter,
+    shard_tracker: &ShardTracker,
+    part_source: &Arc<dyn StatePartSource>,
+    account_id: &Option<AccountId>,
+    dump_every_n_epochs: Option<u64>,
+    probe_parts: usize,
+    rejected_sync_hash: &mut Option<CryptoHash>,
+) -> Result<Option<StateSyncDumpProgress>, StateSyncDumpError> {
+    let head = chain.head()?;
+    let final_hash = *chain.get_block_header(&head.last_block_hash)?.last_final_block();
+    let latest_sync_hash = StateSync::get_epoch_start_sync_hash(chain, &final_hash)?;
+    if latest_sync_hash == sync_hash || *rejected_sync_hash == Some(latest_sync_hash) {
+        return Ok(None);
+    }
+    let latest_epoch_id = chain.get_block_header(&latest_sync_hash)?.epoch_id().clone();
+    let latest_epoch_height =
+        epoch_manager.get_epoch_info(&latest_epoch_id).map_err(Error::from)?.epoch_height();
+    let backfill = chain.store().get_state_sync_dump_backfill(shard_id)?;
+    let last_epoch_height = match &backfill.resume_to {
+        Some(resume_to) => resume_to.epoch_height().max(epoch_height),
+        None => epoch_height,
+    };
+    if latest_epoch_height <= last_epoch_height
+        || !is_epoch_on_dump_cadence(latest_epoch_height, dump_every_n_epochs)
+    {
+        return Ok(None);
+    }
+    match start_dumping(
+        latest_epoch_id,
+        latest_sync_hash,
+        shard_id,
+        chain,
+        epoch_manager,
+        shard_tracker,
+        part_source,
+        account_id,
+        dump_every_n_epochs,
+        probe_parts,
+    )
+    .await?
+    {
+        Some(progress @ StateSyncDumpProgress::InProgressV2 { .. }) => Ok(Some(progress)),
+        _ => {
+            *rejected_sync_hash = Some(latest_sync_hash);
+            Ok(None)
+        }
+    }
+}
+
+/// Deletes the dumps of the epochs of the shard earlier than `epoch_height`,
+/// including partial dumps of abandoned epochs.
+/// The manifest of an epoch is deleted first, so that the epoch stops being
+/// considered complete before any of its parts is gone.
+async fn delete_earlier_epoch_dumps(
+    shard_id: ShardId,
+    chain_id: &str,
+    epoch_height: EpochHeight,
+    external: &ExternalConnection,
+) -> anyhow::Result<()> {
+    for (earlier_epoch_id, earlier_epoch_height) in
+        external.list_dumped_epochs(chain_id, shard_id).await?
+    {
+        if earlier_epoch_height >= epoch_height {
+            continue;
+        }
+        let manifest_location = external_storage_manifest_location(
+            chain_id,
+            &earlier_epoch_id,
+            earlier_epoch_height,
+            shard_id,
+            None,
+        );
+        external.delete_file(shard_id, &manifest_location).await?;
+        let directory = external_storage_location_directory(
+            chain_id,
+            &earlier_epoch_id,
+            earlier_epoch_height,
+            shard_id,
+            None,
+        );
+        let mut num_deleted_files = 0;
+        for directory in [format!("{}/archive", directory), directory.clone()] {
+            for file in external.list_existing_state_parts(shard_id, &directory).await? {
+                external
+                    .delete_file(shard_id, &format!("{}/{}", directory, file.file_name))
+                    .await?;
+                num_deleted_files += 1;
+            }
+        }
+        // Directories of the filesystem would keep the epoch listed. The
+        // directories of the epoch are shared by all shards, and get deleted
+        // together with the last shard.
+        for subdirectory in ["archive", "manifest"] {
+            external
+                .delete_directory_if_empty(shard_id, &format!("{}/{}", directory, subdirectory))
+                .await?;
+        }
+        let mut directory = directory.as_str();
+        while let Some((parent_directory, _)) = directory.rsplit_once('/') {
+            external.delete_directory_if_empty(shard_id, directory).await?;
+            directory = parent_directory;
+        }
+        // The epoch may have been listed only because of its empty directories.
+        if num_deleted_files > 0 {
+            tracing::info!(target: "state_sync_dump", shard_id, ?earlier_epoch_id, earlier_epoch_height, num_deleted_files, "Deleted the dump of an earlier epoch");
+            metrics::STATE_SYNC_DUMP_DELETED_EPOCHS
+                .with_label_values(&[&shard_id.to_string()])
+                .inc();
+        }
+    }
+    Ok(())
+}
+
+/// Returns the id, the height and the date partition of the epoch preceding
+/// the epoch whose sync block's previous block is `sync_prev_hash`.
+fn get_previous_epoch(
+    sync_prev_hash: &CryptoHash,
+    chain: &Chain,
+    epoch_manager: &dyn EpochManagerAdapter,
+    date_partitioned: bool,
+) -> Result<(EpochId, EpochHeight, Option<String>), Error> {
+    let previous_epoch_id = epoch_manager.get_epoch_id(sync_prev_hash)?;
+    let previous_epoch_height = epoch_manager.get_epoch_info(&previous_epoch_id)?.epoch_height();
+    let previous_date_partition = if date_partitioned {
+        let previous_sync_hash = StateSync::get_epoch_start_sync_hash(chain, sync_prev_hash)?;
+        Some(date_partition(chain.get_block_header(&previous_sync_hash)?.raw_timestamp()))
+    } else {
+        None
+    };
+    Ok((previous_epoch_id, previous_epoch_height, previous_date_partition))
+}
+
+/// Deletes the lease of the part taken before obtaining it, so that other
+/// nodes don't have to wait for the lease to expire if the part didn't get
+/// uploaded. Best effort, a failure only delays other nodes.
+async fn release_part_lease(
+    external: &ExternalConnection,
+    shard_id: ShardId,
+    part_id: u64,
+    lease_location: Option<&str>,
+) {
+    if let Some(lease_location) = lease_location {
+        if let Err(err) = external.delete_file(shard_id, lease_location).await {
+            tracing::debug!(target: "state_sync_dump", shard_id, part_id, ?err, "Failed to release a part lease");
+        }
+    }
+}
+
+/// Returns the location of a full copy of a part identical to `state_part`
+/// stored at `location`, directly or behind a pointer.
+async fn find_identical_part(
+    state_part: &[u8],
+    shard_id: ShardId,
+    location: String,
+    external: &ExternalConnection,
+) -> Option<String> {
+    // Pointers always point to full copies.
+    let location = external.get_state_part_pointer(shard_id, &location).await.unwrap_or(location);
+    if external.has_identical_state_part(state_part, shard_id, &location).await {
+        Some(location)
+    } else {
+        None
+    }
+}
+
+/// Uploads the part, or a pointer to `identical_part_location` if an
+/// identical part is already stored there.
+async fn upload_state_part(
+    state_part: &[u8],
+    identical_part_location: Option<&str>,
+    shard_id: ShardId,
+    location: &str,
+    external: &ExternalConnection,
+) -> anyhow::Result<()> {
+    match identical_part_location {
+        Some(identical_part_location) => {
+            external
+                .put_state_part(&state_part_pointer(identical_part_location), shard_id, location)
+                .await?;
+            tracing::debug!(target: "state_sync_dump", shard_id, ?location, ?identical_part_location, "Stored a pointer to an identical part");
+            metrics::STATE_SYNC_DUMP_POINTER_PARTS
+                .with_label_values(&[&shard_id.to_string()])
+                .inc();
+        }
+        None => {
+            external.put_state_part_streamed(&mut &state_part[..], shard_id, location).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Remembers the interrupted epoch to continue dumping it later.
+fn preempt_epoch_dump(
+    shard_id: ShardId,
+    chain: &Chain,
+    progress: StateSyncDumpProgress,
+) -> Result<(), StateSyncDumpError> {
+    tracing::info!(target: "state_sync_dump", shard_id, ?progress, "Interrupting the dump of an epoch to dump a newer epoch first");
+    let mut backfill = chain.store().get_state_sync_dump_backfill(shard_id)?;
+    backfill.preempted.push(progress);
+    // If the interrupted epoch was itself interrupted before, the newer epoch
+    // will be the one to return to.
+    backfill.resume_to = None;
+    chain.store().set_state_sync_dump_backfill(shard_id, &backfill)?;
+    Ok(())
+}
+
+/// Continues dumping the most recently interrupted epoch, if any.
+fn resume_preempted_epoch_dump(
+    shard_id: ShardId,
+    chain: &Chain,
+) -> Result<Option<StateSyncDumpProgress>, StateSyncDumpError> {
+    let mut backfill = chain.store().get_state_sync_dump_backfill(shard_id)?;
+    let progress = match backfill.preempted.pop() {
+        Some(progress) => progress,
+        None => return Ok(None),
+    };
+    tracing::info!(target: "state_sync_dump", shard_id, ?progress, "Continuing the dump of an interrupted epoch");
+    backfill.resume_to = chain.store().get_state_sync_dump_progress(shard_id).ok().flatten();
+    chain.store().set_state_sync_dump_backfill(shard_id, &backfill)?;
+    Ok(Some(progress))
+}
+
+/// Returns the height of the latest epoch that can be dumped, i.e. of the
+/// epoch of the last final block.
+fn get_latest_complete_epoch_height(
+    chain: &Chain,
+    epoch_manager: &dyn EpochManagerAdapter,
+) -> Result<EpochHeight, StateSyncDumpError> {
+    let head = chain.head()?;
+    let final_hash = *chain.get_block_header(&head.last_block_hash)?.last_final_block();
+    let latest_sync_hash = StateSync::get_epoch_start_sync_hash(chain, &final_hash)?;
+    let latest_epoch_id = chain.get_block_header(&latest_sync_hash)?.epoch_id().clone();
+    Ok(epoch_manager.get_epoch_info(&latest_epoch_id).map_err(Error::from)?.epoch_height())
+}
+
+/// Returns the number of epochs that would be dumped before the latest
+/// complete epoch: the epoch being dumped unless it is the latest one, the
+/// epochs missed since the current epoch, and the interrupted epochs.
+fn count_epochs_to_backfill(
+    shard_id: ShardId,
+    chain: &Chain,
+    epoch_manager: &dyn EpochManagerAdapter,
+) -> Result<u64, StateSyncDumpError> {
+    let backfill = chain.store().get_state_sync_dump_backfill(shard_id)?;
+    let num_missed_epochs = match chain.store().get_state_sync_dump_progress(shard_id)? {
+        Some(progress) => {
+            let latest_epoch_height = get_latest_complete_epoch_height(chain, epoch_manager)?;
+            let num_epochs_after_current =
+                latest_epoch_height.saturating_sub(progress.epoch_height() + 1);
+            match progress {
+                StateSyncDumpProgress::InProgress { epoch_height, .. }
+                | StateSyncDumpProgress::InProgressV2 { epoch_height, .. }
+                    if epoch_height < latest_epoch_height =>
+                {
+                    num_epochs_after_current + 1
+                }
+                _ => num_epochs_after_current,
+            }
+        }
+        None => 0,
+    };
+    Ok(num_missed_epochs + backfill.preempted.len() as u64)
+}
+
+/// Returns the progress to restore after the dump of an epoch is complete.
+/// That is the latest dumped epoch if the complete epoch was an interrupted one.
+fn finish_preempted_epoch_dump(
+    shard_id: ShardId,
+    chain: &Chain,
+    next_state: StateSyncDumpProgress,
+) -> Result<StateSyncDumpProgress, StateSyncDumpError> {
+    let mut backfill = chain.store().get_state_sync_dump_backfill(shard_id)?;
+    match backfill.resume_to.take() {
+        Some(resume_to) => {
+            tracing::info!(target: "state_sync_dump", shard_id, ?next_state, ?resume_to, "Finished the dump of an interrupted epoch");
+            chain.store().set_state_sync_dump_backfill(shard_id, &backfill)?;
+            Ok(resume_to)
+        }
+        None => Ok(next_state),
+    }
+}
+
+/// Checks what is the latest complete epoch.
+/// `epoch_id` represents the last fully dumped epoch.
+/// If `fill_gaps` is set, epochs missed since the last dumped epoch get
+/// dumped before the latest one.
+async fn check_new_epoch(
+    epoch_id: Option<EpochId>,
+    epoch_height: Option<EpochHeight>,
+    num_parts: Option<u64>,
+    total_bytes: Option<u64>,
+    shard_id: ShardId,
+    chain: &Chain,
+    epoch_manager: &dyn EpochManagerAdapter,
+    shard_tracker: &ShardTracker,
+    part_source: &Arc<dyn StatePartSource>,
+    account_id: &Option<AccountId>,
+    dump_every_n_epochs: Option<u64>,
+    probe_parts: usize,
+    fill_gaps: bool,
+) -> Result<Option<StateSyncDumpProgress>, StateSyncDumpError> {
+    let head = chain.head()?;
+    if Some(&head.epoch_id) == epoch_id.as_ref() {
+        set_metrics(&shard_id, num_parts, num_parts, total_bytes, epoch_height);
+        metrics::STATE_SYNC_DUMP_IDLE_REASON
+            .with_label_values(&[&shard_id.to_string(), "same_epoch"])
+            .inc();
+        Ok(None)
+    } else {
+        // Check if the final block is now in the next epoch.
+        tracing::debug!(target: "state_sync_dump", shard_id, ?epoch_id, "Check if a new complete epoch is available");
+        let hash = head.last_block_hash;
+        let header = chain.get_block_header(&hash)?;
+        let final_hash = header.last_final_block();
+        let sync_hash = StateSync::get_epoch_start_sync_hash(chain, final_hash)?;
+        let header = chain.get_block_header(&sync_hash)?;
+        if Some(header.epoch_id()) == epoch_id.as_ref() {
+            // Still in the latest dumped epoch. Do nothing.
+            // If this keeps happening, the final block doesn't advance.
+            metrics::STATE_SYNC_DUMP_IDLE_REASON
+                .with_label_values(&[&shard_id.to_string(), "sync_hash_in_dumped_epoch"])
+                .inc();
+            return Ok(None);
+        }
+        let (epoch_id, sync_hash) = match epoch_height {
+            // If the loop fell behind by more than one epoch, dump the
+            // intermediate epochs first.
+            Some(last_epoch_height) if fill_gaps => match find_oldest_undumped_epoch(
+                chain,
+                epoch_manager,
+                header.epoch_id().clone(),
+                sync_hash,
+                last_epoch_height,
+            ) {
+                Ok((gap_epoch_id, gap_sync_hash)) if gap_sync_hash != sync_hash => {
+                    if chain.get_state_response_header(shard_id, gap_sync_hash).is_err() {
+                        let gap_epoch_height = epoch_manager
+                            .get_epoch_info(&gap_epoch_id)
+                            .map_err(Error::from)?
+                            .epoch_height();
+                        tracing::warn!(target: "state_sync_dump", shard_id, ?gap_epoch_id, gap_epoch_height, "State of a missed epoch is no longer available, skip the epoch");
+                        metrics::STATE_SYNC_DUMP_SKIPPED_GAP_EPOCHS
+                            .with_label_values(&[&shard_id.to_string()])
+                            .inc();
+                        return Ok(Some(StateSyncDumpProgress::Skipped {
+                            epoch_id: gap_epoch_id,
+                            epoch_height: gap_epoch_height,
+                        }));
+                    }
+                    tracing::info!(target: "state_sync_dump", shard_id, ?gap_epoch_id, last_epoch_height, "Catching up on a missed epoch");
+                    (gap_epoch_id, gap_sync_hash)
+                }
+                Ok(_) => (head.epoch_id, sync_hash),
+                Err(err) => {
+                    tracing::warn!(target: "state_sync_dump", shard_id, ?err, "Failed to look for missed epochs, dumping the latest epoch");
+                    (head.epoch_id, sync_hash)
+                }
+            },
+            _ => (head.epoch_id, sync_hash),
+        };
+        start_dumping(
+            epoch_id,
+            sync_hash,
+            shard_id,
+            chain,
+            epoch_manager,
+            shard_tracker,
+            part_source,
+            account_id,
+            dump_every_n_epochs,
+            probe_parts,
+        )
+        .await
+    }
+}
+
+/// Walks back from the latest complete epoch to the oldest epoch that follows
+/// the last dumped epoch.
+/// Returns the epoch id and the sync hash of that epoch.
+fn find_oldest_undumped_epoch(
+    chain: &Chain,
+    epoch_manager: &dyn EpochManagerAdapter,
+    mut epoch_id: EpochId,
+    mut sync_hash: CryptoHash,
+    last_epoch_height: EpochHeight,
+) -> Result<(EpochId, CryptoHash), Error> {
+    loop {
+        let epoch_height = epoch_manager.get_epoch_info(&epoch_id)?.epoch_height();
+        if epoch_height <= last_epoch_height + 1 {
+            return Ok((epoch_id, sync_hash));
+        }
+        let prev_hash = *chain.get_block_header(&sync_hash)?.prev_hash();
+        sync_hash = StateSync::get_epoch_start_sync_hash(chain, &prev_hash)?;
+        epoch_id = chain.get_block_header(&sync_hash)?.epoch_id().clone();
+    }
+}
+
+/// Whether an epoch of the given height needs to be dumped.
+/// Regardless of when the node started, the first dumped epoch is the first
+/// one that matches the cadence.
+fn is_epoch_on_dump_cadence(epoch_height: EpochHeight, dump_every_n_epochs: Option<u64>) -> bool {
+    match dump_every_n_epochs {
+        None | Some(0) => true,
+        Some(n) => epoch_height % n == 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::state_sync::{
+        compare_local_state_parts, completed_shards, delete_earlier_epoch_dumps,
+        extrapolate_dump_bytes, find_identical_part, finish_preempted_epoch_dump,
+        get_latest_epoch_to_preempt, is_epoch_on_dump_cadence, next_idle_delay, preempt_epoch_dump,
+        probe_epoch_parts, probe_part_ids, put_epoch_manifest, reconcile_dump_progress,
+        repair_epoch_dump, resume_preempted_epoch_dump, sample_part_ids, set_metrics,
+        skip_zero_part_epoch, spawn_shard_dump_threads, spawn_state_sync_dump,
+        update_moving_average, upload_state_part, verify_epoch_dump, DumpRepairReport,
+        DumpVerificationReport, LocalPartsComparison, MissingPartsReport, RuntimeStatePartSource,
+        ShardDumper, StatePartSource, StateSyncDumpError,
+    };
+    use borsh::BorshSerialize;
+    use near_chain::{Chain, ChainGenesis, ChainStore, DoomslugThresholdMode, Provenance};
+    use near_chain_configs::{DumpConfig, ExternalStorageLocation};
+    use near_client::sync::state::{
+        date_partition, external_storage_location, external_storage_location_directory,
+        part_filename, part_lease_filename, DefaultPartNaming, ExternalConnection,
+        IdentityPartTransform, ListedStatePart, PartTransform, StatePartsManifest, StateSync,
+    };
+    use near_client::test_utils::TestEnv;
+    use near_network::test_utils::wait_or_timeout;
+    use near_o11y::testonly::init_test_logger;
+    use near_primitives::hash::{hash, CryptoHash};
+    use near_primitives::merkle::merklize;
+    use near_primitives::shard_layout::ShardUId;
+    use near_primitives::state_part::PartId;
+    use near_primitives::syncing::{StatePartKey, StateSyncDumpProgress};
+    use near_primitives::types::{BlockHeight, EpochHeight, EpochId, ShardId, StateRoot};
+    use near_store::test_utils::{create_tries, test_populate_trie};
+    use near_store::{DBCol, ShardTries, Trie};
+    use std::collections::{BTreeMap, HashMap};
+    use std::ops::ControlFlow;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant, SystemTime};
+
+    #[test]
+    /// Produce several blocks, wait for the state dump thread to notice and
+    /// write files to a temp dir.
+    fn test_state_dump() {
+        init_test_logger();
+
+        let mut chain_genesis = ChainGenesis::test();
+        chain_genesis.epoch_length = 5;
+        let mut env = TestEnv::builder(chain_genesis.clone()).build();
+        let chain = &env.clients[0].chain;
+        let epoch_manager = chain.epoch_manager.clone();
+        let shard_tracker = chain.shard_tracker.clone();
+        let runtime = chain.runtime_adapter.clone();
+        let mut config = env.clients[0].config.clone();
+        let root_dir = tempfile::Builder::new().prefix("state_dump").tempdir().unwrap();
+        config.state_sync.dump = Some(DumpConfig {
+            location: ExternalStorageLocation::Filesystem {
+                root_dir: root_dir.path().to_path_buf(),
+            },
+            idle_delay: Some(Duration::from_millis(250)),
+            ..Default::default()
+        });
+
+        const MAX_HEIGHT: BlockHeight = 15;
+
+        near_actix_test_utils::run_actix(async move {
+            let _state_sync_dump_handle = spawn_state_sync_dump(
+                &config,
+                chain_genesis,
+                epoch_manager.clone(),
+                shard_tracker.clone(),
+                runtime.clone(),
+                Some("test0".parse().unwrap()),
+                Arc::new(AtomicBool::new(false)),
+            )
+            .unwrap();
+            for i in 1..=MAX_HEIGHT {
+                let block = env.clients[0].produce_block(i as u64).unwrap().unwrap();
+                env.process_block(0, block, Provenance::PRODUCED);
+            }
+            let head = &env.clients[0].chain.head().unwrap();
+            let epoch_id = head.clone().epoch_id;
+            let epoch_info = epoch_manager.get_epoch_info(&epoch_id).unwrap();
+            let epoch_height = epoch_info.epoch_height();
+
+            wait_or_timeout(100, 10000, || async {
+                let mut all_parts_present = true;
+
+                let num_shards = epoch_manager.num_shards(&epoch_id).unwrap();
+                assert_ne!(num_shards, 0);
+
+                for shard_id in 0..num_shards {
+                    let num_parts = 3;
+                    for part_id in 0..num_parts {
+                        let path = root_dir.path().join(external_storage_location(
+                            &DefaultPartNaming,
+                            "unittest",
+                            &epoch_id,
+                            epoch_height,
+                            shard_id,
+                            part_id,
+                            num_parts,
+                            None,
+                        ));
+                        if std::fs::read(&path).is_err() {
+                            println!("Missing {:?}", path);
+                            all_parts_present = false;
+                        }
+                    }
+                }
+                if all_parts_present {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            })
+            .await
+            .unwrap();
+            actix_rt::System::current().stop();
+        });
+    }
+
+    #[test]
+    /// Misconfigured external storage must be reported when the dump loop gets
+    /// spawned, and not when the first part gets uploaded.
+    fn test_state_dump_inaccessible_storage() {
+        init_test_logger();
+
+        let chain_genesis = ChainGenesis::test();
+        let env = TestEnv::builder(chain_genesis.clone()).build();
+        let chain = &env.clients[0].chain;
+        let epoch_manager = chain.epoch_manager.clone();
+        let shard_tracker = chain.shard_tracker.clone();
+        let runtime = chain.runtime_adapter.clone();
+        let mut config = env.clients[0].config.clone();
+        // A directory can't be created inside of a regular file.
+        let file = tempfile::NamedTempFile::new().unwrap();
+        config.state_sync.dump = Some(DumpConfig {
+            location: ExternalStorageLocation::Filesystem {
+                root_dir: file.path().join("state_dump"),
+            },
+            idle_delay: Some(Duration::from_millis(250)),
+            ..Default::default()
+        });
+
+        near_actix_test_utils::run_actix(async move {
+            let result = spawn_state_sync_dump(
+                &config,
+                chain_genesis,
+                epoch_manager,
+                shard_tracker,
+                runtime,
+                Some("test0".parse().unwrap()),
+                Arc::new(AtomicBool::new(false)),
+            );
+            assert!(result.is_err());
+            actix_rt::System::current().stop();
+        });
+    }
+
+    /// Waits until the progress of shard 0 says that the epoch is dumped.
+    async fn wait_epoch_dumped(chain: &Chain, epoch_height: EpochHeight) {
+        wait_or_timeout(100, 10000, || async {
+            match chain.store().get_state_sync_dump_progress(0) {
+                Ok(Some(StateSyncDumpProgress::AllDumpedV2 { epoch_height: height, .. }))
+                    if height == epoch_height =>
+                {
+                    ControlFlow::Break(())
+                }
+                _ => ControlFlow::Continue(()),
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    /// If the progress in the database is behind the external storage, for
+    /// example because the database was restored from a backup, an epoch with
+    /// a manifest is considered dumped without obtaining any parts.
+    fn test_state_dump_database_behind_external_storage() {
+        init_test_logger();
+
+        let mut chain_genesis = ChainGenesis::test();
+        chain_genesis.epoch_length = 5;
+        let mut env = TestEnv::builder(chain_genesis.clone()).build();
+        let chain = &env.clients[0].chain;
+        let epoch_manager = chain.epoch_manager.clone();
+        let shard_tracker = chain.shard_tracker.clone();
+        let runtime = chain.runtime_adapter.clone();
+        let mut config = env.clients[0].config.clone();
+        let root_dir = tempfile::Builder::new().prefix("state_dump").tempdir().unwrap();
+        config.state_sync.dump = Some(DumpConfig {
+            location: ExternalStorageLocation::Filesystem {
+                root_dir: root_dir.path().to_path_buf(),
+            },
+            idle_delay: Some(Duration::from_millis(100)),
+            ..Default::default()
+        });
+
+        const MAX_HEIGHT: BlockHeight = 15;
+
+        near_actix_test_utils::run_actix(async move {
+            let spawn_dump = || {
+                spawn_state_sync_dump(
+                    &config,
+                    chain_genesis.clone(),
+                    epoch_manager.clone(),
+                    shard_tracker.clone(),
+                    runtime.clone(),
+                    Some("test0".parse().unwrap()),
+                    Arc::new(AtomicBool::new(false)),
+                )
+                .unwrap()
+                .unwrap()
+            };
+            let state_sync_dump_handle = spawn_dump();
+            for i in 1..=MAX_HEIGHT {
+                let block = env.clients[0].produce_block(i as u64).unwrap().unwrap();
+                env.process_block(0, block, Provenance::PRODUCED);
+            }
+            let chain = &env.clients[0].chain;
+            let head = chain.head().unwrap();
+            let epoch_id = head.epoch_id.clone();
+            let epoch_height = epoch_manager.get_epoch_info(&epoch_id).unwrap().epoch_height();
+            let sync_hash =
+                StateSync::get_epoch_start_sync_hash(chain, &head.last_block_hash).unwrap();
+            wait_epoch_dumped(chain, epoch_height).await;
+            drop(state_sync_dump_handle);
+            // Let the stopped loop finish its iteration.
+            tokio::time::sleep(Duration::from_millis(500)).await;
+
+            // Roll the progress back, and delete a part to notice if it gets
+            // dumped again.
+            chain
+                .store()
+                .set_state_sync_dump_progress(
+                    0,
+                    Some(StateSyncDumpProgress::InProgress {
+                        epoch_id: epoch_id.clone(),
+                        epoch_height,
+                        sync_hash,
+                    }),
+                )
+                .unwrap();
+            let part_path = root_dir.path().join(external_storage_location(
+                &DefaultPartNaming,
+                "unittest",
+                &epoch_id,
+                epoch_height,
+                0,
+                0,
+                3,
+                None,
+            ));
+            std::fs::remove_file(&part_path).unwrap();
+
+            let _state_sync_dump_handle = spawn_dump();
+            wait_epoch_dumped(chain, epoch_height).await;
+            assert!(!part_path.exists());
+            actix_rt::System::current().stop();
+        });
+    }
+
+    /// Returns modification times and contents of all files under `dir`.
+    fn list_dumped_files(dir: &Path) -> BTreeMap<PathBuf, (SystemTime, Vec<u8>)> {
+        let mut files = BTreeMap::new();
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files.extend(list_dumped_files(&path));
+            } else {
+                let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+                files.insert(path.clone(), (modified, std::fs::read(&path).unwrap()));
+            }
+        }
+        files
+    }
+
+    #[test]
+    /// A restarted dump loop must recognize that the epoch is already dumped
+    /// and must not write any object again.
+    fn test_state_dump_idempotent_across_restarts() {
+        init_test_logger();
+
+        let mut chain_genesis = ChainGenesis::test();
+        chain_genesis.epoch_length = 5;
+        let mut env = TestEnv::builder(chain_genesis.clone()).build();
+        let chain = &env.clients[0].chain;
+        let epoch_manager = chain.epoch_manager.clone();
+        let shard_tracker = chain.shard_tracker.clone();
+        let runtime = chain.runtime_adapter.clone();
+        let mut config = env.clients[0].config.clone();
+        let root_dir = tempfile::Builder::new().prefix("state_dump").tempdir().unwrap();
+        config.state_sync.dump = Some(DumpConfig {
+            location: ExternalStorageLocation::Filesystem {
+                root_dir: root_dir.path().to_path_buf(),
+            },
+            idle_delay: Some(Duration::from_millis(100)),
+            ..Default::default()
+        });
+
+        const MAX_HEIGHT: BlockHeight = 15;
+
+        near_actix_test_utils::run_actix(async move {
+            let spawn_dump = || {
+                spawn_state_sync_dump(
+                    &config,
+                    chain_genesis.clone(),
+                    epoch_manager.clone(),
+                    shard_tracker.clone(),
+                    runtime.clone(),
+                    Some("test0".parse().unwrap()),
+                    Arc::new(AtomicBool::new(false)),
+                )
+                .unwrap()
+                .unwrap()
+            };
+            let state_sync_dump_handle = spawn_dump();
+            for i in 1..=MAX_HEIGHT {
+                let block = env.clients[0].produce_block(i as u64).unwrap().unwrap();
+                env.process_block(0, block, Provenance::PRODUCED);
+            }
+            let chain = &env.clients[0].chain;
+            let head = chain.head().unwrap();
+            let epoch_height = epoch_manager.get_epoch_info(&head.epoch_id).unwrap().epoch_height();
+            wait_epoch_dumped(chain, epoch_height).await;
+            drop(state_sync_dump_handle);
+            // Let the stopped loop finish its iteration.
+            tokio::time::sleep(Duration::from_millis(500)).await;
+
+            let dumped_files = list_dumped_files(root_dir.path());
+            assert!(!dumped_files.is_empty());
+
+            let _state_sync_dump_handle = spawn_dump();
+            // Give the restarted loop several iterations. The progress must
+            // not leave the dumped state even temporarily.
+            for _ in 0..10 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let progress = chain.store().get_state_sync_dump_progress(0).unwrap();
+                assert!(
+                    matches!(
+                        progress,
+                        Some(StateSyncDumpProgress::AllDumpedV2 { epoch_height: height, .. })
+                            if height == epoch_height
+                    ),
+                    "{:?}",
+                    progress
+                );
+            }
+            assert_eq!(list_dumped_files(root_dir.path()), dumped_files);
+            actix_rt::System::current().stop();
+        });
+    }
+
+    #[test]
+    fn test_state_parts_manifest() {
+        // Manifests written without a Merkle root are still readable.
+        let manifest: StatePartsManifest =
+            serde_json::from_str(r#"{"num_parts":3,"total_bytes":100}"#).unwrap();
+        assert_eq!(
+            manifest,
+            StatePartsManifest { num_parts: 3, total_bytes: 100, merkle_root: None }
+        );
+
+        let (merkle_root, _) = merklize(&[hash(b"part 0"), hash(b"part 1")]);
+        let manifest =
+            StatePartsManifest { num_parts: 2, total_bytes: 12, merkle_root: Some(merkle_root) };
+        let serialized = serde_json::to_vec(&manifest).unwrap();
+        assert_eq!(serde_json::from_slice::<StatePartsManifest>(&serialized).unwrap(), manifest);
+    }
+
+    #[test]
+    fn test_next_idle_delay() {
+        let max_delay = Duration::from_secs(10);
+        assert_eq!(next_idle_delay(Duration::from_secs(1), max_delay), Duration::from_secs(2));
+        assert_eq!(next_idle_delay(Duration::from_secs(8), max_delay), max_delay);
+        assert_eq!(next_idle_delay(max_delay, max_delay), max_delay);
+        // A zero delay doesn't grow, but is not a busy-loop either, because
+        // `min_iteration_delay` can't be zero.
+        assert_eq!(next_idle_delay(Duration::ZERO, max_delay), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_completed_shards() {
+        let chain_store = ChainStore::new(near_store::test_utils::create_test_store(), 0, false);
+        let epoch_id = EpochId(CryptoHash::hash_bytes(&[1]));
+        let previous_epoch_id = EpochId(CryptoHash::hash_bytes(&[0]));
+        let progress = [
+            StateSyncDumpProgress::AllDumped {
+                epoch_id: epoch_id.clone(),
+                epoch_height: 2,
+                num_parts: Some(1),
+            },
+            StateSyncDumpProgress::InProgressV2 {
+                epoch_id: epoch_id.clone(),
+                epoch_height: 2,
+                sync_hash: CryptoHash::default(),
+                num_parts: 1,
+            },
+            StateSyncDumpProgress::AllDumpedV2 {
+                epoch_id: previous_epoch_id.clone(),
+                epoch_height: 1,
+                num_parts: Some(1),
+                total_bytes: 10,
+            },
+            StateSyncDumpProgress::AllDumpedV2 {
+                epoch_id: epoch_id.clone(),
+                epoch_height: 2,
+                num_parts: Some(1),
+                total_bytes: 10,
+            },
+        ];
+        for (shard_id, progress) in progress.into_iter().enumerate() {
+            chain_store.set_state_sync_dump_progress(shard_id as u64, Some(progress)).unwrap();
+        }
+
+        // Shard 4 has no progress.
+        assert_eq!(completed_shards(&chain_store, 0..5, &epoch_id), vec![0, 3]);
+        assert_eq!(completed_shards(&chain_store, 1..5, &epoch_id), vec![3]);
+        assert_eq!(completed_shards(&chain_store, 0..5, &previous_epoch_id), vec![2]);
+    }
+
+    #[test]
+    fn test_update_moving_average() {
+        assert_eq!(update_moving_average(None, 100.0), 100.0);
+        let average = update_moving_average(Some(100.0), 200.0);
+        assert!(100.0 < average && average < 200.0);
+        assert_eq!(update_moving_average(Some(average), average), average);
+    }
+
+    #[test]
+    fn test_is_epoch_on_dump_cadence() {
+        assert!(is_epoch_on_dump_cadence(7, None));
+        assert!(is_epoch_on_dump_cadence(7, Some(0)));
+        assert!(is_epoch_on_dump_cadence(7, Some(1)));
+        assert!(!is_epoch_on_dump_cadence(7, Some(5)));
+        assert!(is_epoch_on_dump_cadence(10, Some(5)));
+    }
+
+    #[test]
+    fn test_estimate_epoch_dump_bytes() {
+        assert_eq!(sample_part_ids(100, 4), vec![0, 25, 50, 75]);
+        assert_eq!(sample_part_ids(3, 4), vec![0, 1, 2]);
+        assert_eq!(sample_part_ids(0, 4), Vec::<u64>::new());
+        assert_eq!(extrapolate_dump_bytes(&[10, 20, 30, 40], 100), 2500);
+        assert_eq!(extrapolate_dump_bytes(&[], 100), 0);
+    }
+
+    /// Serves the parts of the tries of the single shard, and garbage instead
+    /// of the part `garbage_part_id`.
+    struct GarbagePartSource {
+        tries: ShardTries,
+        garbage_part_id: Option<u64>,
+    }
+
+    impl StatePartSource for GarbagePartSource {
+        fn obtain_state_part(
+            &self,
+            _shard_id: ShardId,
+            _prev_hash: &CryptoHash,
+            state_root: &StateRoot,
+            part_id: PartId,
+        ) -> Result<Vec<u8>, near_chain::Error> {
+            if Some(part_id.idx) == self.garbage_part_id {
+                return Ok(vec![1, 2, 3]);
+            }
+            let trie = self.tries.get_trie_for_shard(ShardUId::single_shard(), *state_root);
+            Ok(trie.get_trie_nodes_for_part(part_id)?.try_to_vec()?)
+        }
+    }
+
+    #[test]
+    fn test_probe_epoch_parts() {
+        assert_eq!(probe_part_ids(100, 0), Vec::<u64>::new());
+        assert_eq!(probe_part_ids(100, 1), vec![0]);
+        assert_eq!(probe_part_ids(100, 2), vec![0, 99]);
+        assert_eq!(probe_part_ids(100, 4), vec![0, 33, 66, 99]);
+        assert_eq!(probe_part_ids(3, 5), vec![0, 1, 2]);
+
+        let tries = create_tries();
+        let changes = (0..20u8).map(|i| (vec![i, i], Some(vec![i; 10]))).collect();
+        let state_root =
+            test_populate_trie(&tries, &Trie::EMPTY_ROOT, ShardUId::single_shard(), changes);
+        let probe = |garbage_part_id, probe_parts| {
+            let part_source = GarbagePartSource { tries: tries.clone(), garbage_part_id };
+            probe_epoch_parts(&part_source, 0, &CryptoHash::default(), &state_root, 3, probe_parts)
+        };
+        assert!(probe(None, 0).is_ok());
+        assert!(probe(None, 2).is_ok());
+        // Only the first and the last part are probed.
+        assert!(probe(Some(1), 2).is_ok());
+        assert!(matches!(probe(Some(2), 2), Err(StateSyncDumpError::ProbeFailed(_))));
+        assert!(matches!(probe(Some(1), 3), Err(StateSyncDumpError::ProbeFailed(_))));
+    }
+
+    #[test]
+    fn test_missing_parts_report() {
+        let now = SystemTime::now();
+        let ttl = Duration::from_secs(60);
+        let listed_parts = [
+            ListedStatePart { file_name: part_filename(0, 5), size: Some(10), last_modified: None },
+            ListedStatePart { file_name: part_filename(2, 5), size: Some(20), last_modified: None },
+            ListedStatePart { file_name: part_filename(3, 5), size: None, last_modified: None },
+            // A fresh lease.
+            ListedStatePart {
+                file_name: part_lease_filename(&DefaultPartNaming, 1, 5),
+                size: Some(0),
+                last_modified: Some(now - Duration::from_secs(10)),
+            },
+            // A stale lease.
+            ListedStatePart {
+                file_name: part_lease_filename(&DefaultPartNaming, 4, 5),
+                size: Some(0),
+                last_modified: Some(now - Duration::from_secs(100)),
+            },
+        ];
+        assert_eq!(
+            MissingPartsReport::new(&DefaultPartNaming, 5, &listed_parts, Some(ttl), now),
+            MissingPartsReport {
+                total: 5,
+                present: vec![0, 2, 3],
+                missing: vec![1, 4],
+                leased: vec![1],
+                present_bytes: 30,
+            }
+        );
+        // Leases are ignored if not enabled.
+        assert_eq!(
+            MissingPartsReport::new(&DefaultPartNaming, 5, &listed_parts, None, now).leased,
+            Vec::<u64>::new()
+        );
+
+        let mut report =
+            MissingPartsReport::new(&DefaultPartNaming, 5, &listed_parts, Some(ttl), now);
+        report.restrict_to(2..5);
+        assert_eq!(report.missing, vec![4]);
+        assert_eq!(report.leased, Vec::<u64>::new());
+    }
+
+    #[test]
+    fn test_preempted_epoch_dump() {
+        let env = TestEnv::builder(ChainGenesis::test()).build();
+        let chain = &env.clients[0].chain;
+        let in_progress = |epoch_height| StateSyncDumpProgress::InProgressV2 {
+            epoch_id: EpochId::default(),
+            epoch_height,
+            sync_hash: CryptoHash::default(),
+            num_parts: 3,
+        };
+        let all_dumped = |epoch_height| StateSyncDumpProgress::AllDumpedV2 {
+            epoch_id: EpochId::default(),
+            epoch_height,
+            num_parts: Some(3),
+            total_bytes: 100,
+        };
+
+        // Epoch 1 gets interrupted, and epoch 2 gets dumped.
+        preempt_epoch_dump(0, chain, in_progress(1)).unwrap();
+        let next_state = finish_preempted_epoch_dump(0, chain, all_dumped(2)).unwrap();
+        assert_eq!(next_state.epoch_height(), 2);
+        chain.store().set_state_sync_dump_progress(0, Some(next_state)).unwrap();
+
+        // Epoch 1 continues, and afterwards the progress returns to epoch 2.
+        let next_state = resume_preempted_epoch_dump(0, chain).unwrap().unwrap();
+        assert!(matches!(next_state, StateSyncDumpProgress::InProgressV2 { epoch_height: 1, .. }));
+        let next_state = finish_preempted_epoch_dump(0, chain, all_dumped(1)).unwrap();
+        assert_eq!(next_state.epoch_height(), 2);
+        assert!(resume_preempted_epoch_dump(0, chain).unwrap().is_none());
+    }
+
+    /// Progress pointing at an epoch whose state is garbage collected is
+    /// cleared on startup, other progress is kept.
+    #[test]
+    fn test_reconcile_dump_progress() {
+        let mut chain_genesis = ChainGenesis::test();
+        chain_genesis.epoch_length = 5;
+        let mut env = TestEnv::builder(chain_genesis).build();
+        for i in 1..=15 {
+            let block = env.clients[0].produce_block(i).unwrap().unwrap();
+            env.process_block(0, block, Provenance::PRODUCED);
+        }
+        let chain = &mut env.clients[0].chain;
+        let all_dumped = StateSyncDumpProgress::AllDumpedV2 {
+            epoch_id: EpochId::default(),
+            epoch_height: 1,
+            num_parts: Some(3),
+            total_bytes: 100,
+        };
+        chain.store().set_state_sync_dump_progress(0, Some(all_dumped)).unwrap();
+        reconcile_dump_progress(0, chain);
+        assert!(matches!(
+            chain.store().get_state_sync_dump_progress(0),
+            Ok(Some(StateSyncDumpProgress::AllDumpedV2 { .. }))
+        ));
+
+        // The state can't be checked, which is not a reason to give up.
+        let unknown_epoch = StateSyncDumpProgress::InProgressV2 {
+            epoch_id: EpochId::default(),
+            epoch_height: 2,
+            sync_hash: hash(b"unknown"),
+            num_parts: 3,
+        };
+        chain.store().set_state_sync_dump_progress(0, Some(unknown_epoch)).unwrap();
+        reconcile_dump_progress(0, chain);
+        assert!(matches!(
+            chain.store().get_state_sync_dump_progress(0),
+            Ok(Some(StateSyncDumpProgress::InProgressV2 { epoch_height: 2, .. }))
+        ));
+
+        let head = chain.head().unwrap();
+        let sync_hash = StateSync::get_epoch_start_sync_hash(chain, &head.last_block_hash).unwrap();
+        let in_progress = StateSyncDumpProgress::InProgressV2 {
+            epoch_id: EpochId::default(),
+            epoch_height: 3,
+            sync_hash,
+            num_parts: 3,
+        };
+        chain.store().set_state_sync_dump_progress(0, Some(in_progress)).unwrap();
+        reconcile_dump_progress(0, chain);
+        assert!(matches!(
+            chain.store().get_state_sync_dump_progress(0),
+            Ok(Some(StateSyncDumpProgress::InProgressV2 { epoch_height: 3, .. }))
+        ));
+
+        // Garbage collection moves the tail past the epoch.
+        let sync_height = chain.get_block_header(&sync_hash).unwrap().height();
+        let mut store_update = chain.mut_store().store_update();
+        store_update.update_tail(sync_height).unwrap();
+        store_update.commit().unwrap();
+        reconcile_dump_progress(0, chain);
+        assert!(matches!(
+            chain.store().get_state_sync_dump_progress(0),
+            Err(near_chain::Error::DBNotFoundErr(_))
+        ));
+    }
+
+    /// Parts identical to a part of an earlier epoch are stored as pointers
+    /// to the full copy, also if the part of the previous epoch is a pointer.
+    #[test]
+    fn test_incremental_part_upload() {
+        let root_dir = tempfile::Builder::new().prefix("incremental_dump").tempdir().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let external = ExternalConnection::Filesystem {
+            root_dir: root_dir.path().to_path_buf(),
+            fsync: false,
+        };
+        let location = |epoch_height| {
+            external_storage_location(
+                &DefaultPartNaming,
+                "test",
+                &EpochId::default(),
+                epoch_height,
+                0,
+                0,
+                1,
+                None,
+            )
+        };
+        let state_part = vec![1, 2, 3];
+        runtime.block_on(upload_state_part(&state_part, None, 0, &location(1), &external)).unwrap();
+        for epoch_height in 2..=3 {
+            let identical_part = runtime.block_on(find_identical_part(
+                &state_part,
+                0,
+                location(epoch_height - 1),
+                &external,
+            ));
+            assert_eq!(identical_part, Some(location(1)));
+            runtime
+                .block_on(upload_state_part(
+                    &state_part,
+                    identical_part.as_deref(),
+                    0,
+                    &location(epoch_height),
+                    &external,
+                ))
+                .unwrap();
+            assert_eq!(
+                runtime.block_on(external.get_state_part_pointer(0, &location(epoch_height))),
+                Some(location(1))
+            );
+        }
+        // A different part is stored as a full copy.
+        assert_eq!(runtime.block_on(find_identical_part(&[4, 5], 0, location(3), &external)), None);
+    }
+
+    /// Long-running chains have epoch heights in the tens of thousands.
+    #[test]
+    fn test_set_metrics_large_epoch_height() {
+        set_metrics(&1000, Some(1), Some(2), None, Some(20_000));
+        assert_eq!(
+            crate::metrics::STATE_SYNC_DUMP_EPOCH_HEIGHT.with_label_values(&["1000"]).get(),
+            20_000
+        );
+    }
+
+    /// Missing and corrupt parts are reported separately from the valid ones.
+    #[test]
+    fn test_verify_epoch_dump() {
+        let tries = create_tries();
+        let changes = (0..20u8).map(|i| (vec![i, i], Some(vec![i; 10]))).collect();
+        let state_root =
+            test_populate_trie(&tries, &Trie::EMPTY_ROOT, ShardUId::single_shard(), changes);
+        let trie = tries.get_trie_for_shard(ShardUId::single_shard(), state_root);
+        let num_parts = 3;
+
+        let root_dir = tempfile::Builder::new().prefix("verify_dump").tempdir().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let external = ExternalConnection::Filesystem {
+            root_dir: root_dir.path().to_path_buf(),
+            fsync: false,
+        };
+        let location = |part_id| {
+            external_storage_location(
+                &DefaultPartNaming,
+                "test",
+                &EpochId::default(),
+                1,
+                0,
+                part_id,
+                num_parts,
+                None,
+            )
+        };
+        let valid_part =
+            trie.get_trie_nodes_for_part(PartId::new(0, num_parts)).unwrap().try_to_vec().unwrap();
+        runtime.block_on(external.put_state_part(&valid_part, 0, &location(0))).unwrap();
+        runtime.block_on(external.put_state_part(&[1, 2, 3], 0, &location(1))).unwrap();
+
+        let report = runtime
+            .block_on(verify_epoch_dump(
+                0,
+                "test",
+                None,
+                &EpochId::default(),
+                1,
+                &state_root,
+                num_parts,
+                &DefaultPartNaming,
+                &IdentityPartTransform,
+                &external,
+            ))
+            .unwrap();
+        assert_eq!(
+            report,
+            DumpVerificationReport { missing: vec![2], corrupt: vec![1], ok: 1, total: num_parts }
+        );
+        assert!(!report.is_complete());
+    }
+
+    /// Only the missing and corrupt parts are dumped again, and only once the
+    /// dump of the epoch is complete.
+    #[test]
+    fn test_repair_epoch_dump() {
+        let tries = create_tries();
+        let changes = (0..20u8).map(|i| (vec![i, i], Some(vec![i; 10]))).collect();
+        let state_root =
+            test_populate_trie(&tries, &Trie::EMPTY_ROOT, ShardUId::single_shard(), changes);
+        let trie = tries.get_trie_for_shard(ShardUId::single_shard(), state_root);
+        let num_parts = 4;
+
+        let root_dir = tempfile::Builder::new().prefix("repair_dump").tempdir().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let external = ExternalConnection::Filesystem {
+            root_dir: root_dir.path().to_path_buf(),
+            fsync: false,
+        };
+        let location = |part_id| {
+            external_storage_location(
+                &DefaultPartNaming,
+                "test",
+                &EpochId::default(),
+                1,
+                0,
+                part_id,
+                num_parts,
+                None,
             )
+        };
+        for part_id in [0, 3] {
+            let part = trie
+                .get_trie_nodes_for_part(PartId::new(part_id, num_parts))
+                .unwrap()
+                .try_to_vec()
+                .unwrap();
+            runtime.block_on(external.put_state_part(&part, 0, &location(part_id))).unwrap();
         }
+        runtime.block_on(external.put_state_part(&[1, 2, 3], 0, &location(1))).unwrap();
+        // The part source can't provide a valid copy of part 2.
+        let part_source = GarbagePartSource { tries, garbage_part_id: Some(2) };
+        let repair = || {
+            runtime.block_on(repair_epoch_dump(
+                &part_source,
+                0,
+                "test",
+                None,
+                &EpochId::default(),
+                1,
+                &CryptoHash::default(),
+                &state_root,
+                num_parts,
+                &DefaultPartNaming,
+                &IdentityPartTransform,
+                &external,
+            ))
+        };
+        assert!(repair().is_err());
+
+        runtime.block_on(put_epoch_manifest(
+            0,
+            "test",
+            None,
+            &EpochId::default(),
+            1,
+            &StatePartsManifest { num_parts, total_bytes: 0, merkle_root: None },
+            &external,
+        ));
+        let report = repair().unwrap();
+        assert_eq!(
+            report,
+            DumpRepairReport { repaired: vec![1], failed: vec![2], ok: 2, total: num_parts }
+        );
+        assert!(!report.is_complete());
+        let verification = runtime
+            .block_on(verify_epoch_dump(
+                0,
+                "test",
+                None,
+                &EpochId::default(),
+                1,
+                &state_root,
+                num_parts,
+                &DefaultPartNaming,
+                &IdentityPartTransform,
+                &external,
+            ))
+            .unwrap();
+        assert_eq!(
+            verification,
+            DumpVerificationReport { missing: vec![2], corrupt: vec![], ok: 3, total: num_parts }
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::state_sync::spawn_state_sync_dump;
-    use near_chain::{ChainGenesis, Provenance};
-    use near_chain_configs::{DumpConfig, ExternalStorageLocation};
-    use near_client::sync::state::external_storage_location;
-    use near_client::test_utils::TestEnv;
-    use near_network::test_utils::wait_or_timeout;
-    use near_o11y::testonly::init_test_logger;
-    use near_primitives::types::BlockHeight;
-    use std::ops::ControlFlow;
-    use std::time::Duration;
+    /// Flips all bits of the parts.
+    struct InvertingPartTransform;
+
+    impl PartTransform for InvertingPartTransform {
+        fn encode(&self, part: &[u8]) -> Vec<u8> {
+            part.iter().map(|byte| !byte).collect()
+        }
 
+        fn decode(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+            Ok(self.encode(data))
+        }
+    }
+
+    /// Transformed parts are valid only once decoded.
     #[test]
-    /// Produce several blocks, wait for the state dump thread to notice and
-    /// write files to a temp dir.
-    fn test_state_dump() {
+    fn test_compare_local_state_parts() {
+        let store = near_store::test_utils::create_test_store();
+        let root_dir = tempfile::Builder::new().prefix("compare_local").tempdir().unwrap();
+        let external = ExternalConnection::Filesystem {
+            root_dir: root_dir.path().to_path_buf(),
+            fsync: false,
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let sync_hash = CryptoHash::hash_bytes(&[1]);
+        let other_sync_hash = CryptoHash::hash_bytes(&[2]);
+        let epoch_id = EpochId(CryptoHash::hash_bytes(&[3]));
+        let num_parts = 4;
+        let location = |part_id| {
+            external_storage_location(
+                &DefaultPartNaming,
+                "test",
+                &epoch_id,
+                5,
+                0,
+                part_id,
+                num_parts,
+                None,
+            )
+        };
+
+        let mut store_update = store.store_update();
+        for part_id in 0..3 {
+            let key = StatePartKey(sync_hash, 0, part_id).try_to_vec().unwrap();
+            store_update.set(DBCol::StateParts, &key, &[part_id as u8]);
+        }
+        // Parts of other epochs and shards are not compared.
+        for (sync_hash, shard_id) in [(other_sync_hash, 0), (sync_hash, 1)] {
+            let key = StatePartKey(sync_hash, shard_id, 3).try_to_vec().unwrap();
+            store_update.set(DBCol::StateParts, &key, &[3]);
+        }
+        store_update.commit().unwrap();
+        runtime.block_on(external.put_state_part(&[0], 0, &location(0))).unwrap();
+        runtime.block_on(external.put_state_part(&[7], 0, &location(1))).unwrap();
+        runtime.block_on(external.put_state_part(&[3], 0, &location(3))).unwrap();
+
+        let comparison = runtime
+            .block_on(compare_local_state_parts(
+                &store,
+                sync_hash,
+                0,
+                "test",
+                None,
+                &epoch_id,
+                5,
+                num_parts,
+                &DefaultPartNaming,
+                &IdentityPartTransform,
+                &external,
+            ))
+            .unwrap();
+        assert_eq!(
+            comparison,
+            LocalPartsComparison { missing: vec![2], mismatched: vec![1], matching: 1, total: 3 }
+        );
+        assert!(!comparison.is_consistent());
+    }
+
+    #[test]
+    fn test_verify_epoch_dump_with_part_transform() {
+        let tries = create_tries();
+        let changes = (0..20u8).map(|i| (vec![i, i], Some(vec![i; 10]))).collect();
+        let state_root =
+            test_populate_trie(&tries, &Trie::EMPTY_ROOT, ShardUId::single_shard(), changes);
+        let trie = tries.get_trie_for_shard(ShardUId::single_shard(), state_root);
+        let num_parts = 2;
+
+        let root_dir = tempfile::Builder::new().prefix("verify_dump").tempdir().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let external = ExternalConnection::Filesystem {
+            root_dir: root_dir.path().to_path_buf(),
+            fsync: false,
+        };
+        for part_id in 0..num_parts {
+            let location = external_storage_location(
+                &DefaultPartNaming,
+                "test",
+                &EpochId::default(),
+                1,
+                0,
+                part_id,
+                num_parts,
+                None,
+            );
+            let part = trie
+                .get_trie_nodes_for_part(PartId::new(part_id, num_parts))
+                .unwrap()
+                .try_to_vec()
+                .unwrap();
+            runtime
+                .block_on(external.put_state_part(
+                    &InvertingPartTransform.encode(&part),
+                    0,
+                    &location,
+                ))
+                .unwrap();
+        }
+
+        let verify = |part_transform: &dyn PartTransform| {
+            runtime
+                .block_on(verify_epoch_dump(
+                    0,
+                    "test",
+                    None,
+                    &EpochId::default(),
+                    1,
+                    &state_root,
+                    num_parts,
+                    &DefaultPartNaming,
+                    part_transform,
+                    &external,
+                ))
+                .unwrap()
+        };
+        assert!(verify(&InvertingPartTransform).is_complete());
+        assert_eq!(verify(&IdentityPartTransform).corrupt, vec![0, 1]);
+    }
+
+    /// An epoch without parts is skipped, and its manifest tells restoring
+    /// nodes that the dump is complete.
+    #[test]
+    fn test_skip_zero_part_epoch() {
+        let root_dir = tempfile::Builder::new().prefix("zero_part_epoch").tempdir().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let external = ExternalConnection::Filesystem {
+            root_dir: root_dir.path().to_path_buf(),
+            fsync: false,
+        };
+        let epoch_id = EpochId::default();
+        // No manifest, the dump may still be in progress.
+        assert!(runtime.block_on(external.verify_dumped_epoch("test", 0, &epoch_id, 5)).is_err());
+
+        let next_state =
+            runtime.block_on(skip_zero_part_epoch(0, "test", None, epoch_id.clone(), 5, &external));
+        assert!(matches!(next_state, StateSyncDumpProgress::Skipped { epoch_height: 5, .. }));
+        assert_eq!(
+            runtime.block_on(external.verify_dumped_epoch("test", 0, &epoch_id, 5)).unwrap(),
+            StatePartsManifest { num_parts: 0, total_bytes: 0, merkle_root: None }
+        );
+    }
+
+    #[test]
+    fn test_delete_earlier_epoch_dumps() {
+        let root_dir = tempfile::Builder::new().prefix("delete_earlier").tempdir().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let external = ExternalConnection::Filesystem {
+            root_dir: root_dir.path().to_path_buf(),
+            fsync: false,
+        };
+        let epoch_id = |epoch_height: EpochHeight| EpochId(hash(&epoch_height.to_le_bytes()));
+        for (shard_id, epoch_height) in [(0, 3), (0, 4), (0, 5), (1, 3)] {
+            for part_id in 0..2 {
+                let location = external_storage_location(
+                    &DefaultPartNaming,
+                    "test",
+                    &epoch_id(epoch_height),
+                    epoch_height,
+                    shard_id,
+                    part_id,
+                    2,
+                    None,
+                );
+                runtime.block_on(external.put_state_part(b"part", shard_id, &location)).unwrap();
+            }
+            let manifest = StatePartsManifest { num_parts: 2, total_bytes: 8, merkle_root: None };
+            runtime.block_on(put_epoch_manifest(
+                shard_id,
+                "test",
+                None,
+                &epoch_id(epoch_height),
+                epoch_height,
+                &manifest,
+                &external,
+            ));
+        }
+
+        runtime.block_on(delete_earlier_epoch_dumps(0, "test", 5, &external)).unwrap();
+        let num_parts = |shard_id, epoch_height| {
+            let directory = external_storage_location_directory(
+                "test",
+                &epoch_id(epoch_height),
+                epoch_height,
+                shard_id,
+                None,
+            );
+            runtime
+                .block_on(external.list_existing_state_parts(shard_id, &directory))
+                .unwrap()
+                .len()
+        };
+        assert_eq!(num_parts(0, 3), 0);
+        assert_eq!(num_parts(0, 4), 0);
+        assert!(runtime
+            .block_on(external.verify_dumped_epoch("test", 0, &epoch_id(4), 4))
+            .is_err());
+        // The latest epoch and other shards are kept.
+        assert_eq!(num_parts(0, 5), 2);
+        assert!(runtime.block_on(external.verify_dumped_epoch("test", 0, &epoch_id(5), 5)).is_ok());
+        assert_eq!(num_parts(1, 3), 2);
+        // The deleted epochs are no longer listed, so the next deletion
+        // doesn't find them again.
+        let dumped_epochs = |shard_id| {
+            runtime
+                .block_on(external.list_dumped_epochs("test", shard_id))
+                .unwrap()
+                .into_iter()
+                .map(|(_, epoch_height)| epoch_height)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(dumped_epochs(0), vec![5]);
+        assert_eq!(dumped_epochs(1), vec![3]);
+        assert!(!root_dir.path().join("chain_id=test/epoch_height=4").exists());
+    }
+
+    /// Steps through the dump of an epoch without running the dump loop.
+    #[test]
+    fn test_shard_dumper_transitions() {
         init_test_logger();
+        let mut chain_genesis = ChainGenesis::test();
+        chain_genesis.epoch_length = 5;
+        let mut env = TestEnv::builder(chain_genesis.clone()).build();
+        for i in 1..=15 {
+            let block = env.clients[0].produce_block(i).unwrap().unwrap();
+            env.process_block(0, block, Provenance::PRODUCED);
+        }
+        let client_chain = &env.clients[0].chain;
+        let runtime = client_chain.runtime_adapter.clone();
+        let chain = Chain::new_for_view_client(
+            client_chain.epoch_manager.clone(),
+            client_chain.shard_tracker.clone(),
+            runtime.clone(),
+            &chain_genesis,
+            DoomslugThresholdMode::TwoThirds,
+            false,
+        )
+        .unwrap();
+        let root_dir = tempfile::Builder::new().prefix("shard_dumper").tempdir().unwrap();
+        let external = ExternalConnection::Filesystem {
+            root_dir: root_dir.path().to_path_buf(),
+            fsync: false,
+        };
+        let config = ShardDumpConfig::for_test(
+            Arc::new(RuntimeStatePartSource(runtime)),
+            external.clone(),
+            Some("test0".parse().unwrap()),
+        );
+        let mut dumper = ShardDumper::new(0, chain, &config);
+
+        let (epoch_id, epoch_height, num_parts) = match dumper.step(&config) {
+            Some(StateSyncDumpProgress::InProgressV2 {
+                epoch_id, epoch_height, num_parts, ..
+            }) => (epoch_id, epoch_height, num_parts),
+            progress => panic!("unexpected progress {:?}", progress),
+        };
+        match dumper.step(&config) {
+            Some(StateSyncDumpProgress::AllDumpedV2 {
+                epoch_id: dumped_epoch_id,
+                epoch_height: dumped_epoch_height,
+                num_parts: dumped_num_parts,
+                ..
+            }) => {
+                assert_eq!(dumped_epoch_id, epoch_id);
+                assert_eq!(dumped_epoch_height, epoch_height);
+                assert_eq!(dumped_num_parts, Some(num_parts));
+            }
+            progress => panic!("unexpected progress {:?}", progress),
+        }
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let manifest = runtime
+            .block_on(external.verify_dumped_epoch("test", 0, &epoch_id, epoch_height))
+            .unwrap();
+        assert_eq!(manifest.num_parts, num_parts);
+        // No newer epoch is complete, so the progress stays the same.
+        assert!(matches!(
+            dumper.step(&config),
+            Some(StateSyncDumpProgress::AllDumpedV2 { epoch_height: dumped_epoch_height, .. })
+                if dumped_epoch_height == epoch_height
+        ));
+    }
 
+    /// Runs the phases of the dump of an epoch one at a time, none of which
+    /// records the progress.
+    #[test]
+    fn test_shard_dumper_phases() {
+        init_test_logger();
         let mut chain_genesis = ChainGenesis::test();
         chain_genesis.epoch_length = 5;
         let mut env = TestEnv::builder(chain_genesis.clone()).build();
+        for i in 1..=15 {
+            let block = env.clients[0].produce_block(i).unwrap().unwrap();
+            env.process_block(0, block, Provenance::PRODUCED);
+        }
+        let client_chain = &env.clients[0].chain;
+        let runtime = client_chain.runtime_adapter.clone();
+        let chain = Chain::new_for_view_client(
+            client_chain.epoch_manager.clone(),
+            client_chain.shard_tracker.clone(),
+            runtime.clone(),
+            &chain_genesis,
+            DoomslugThresholdMode::TwoThirds,
+            false,
+        )
+        .unwrap();
+        let root_dir = tempfile::Builder::new().prefix("shard_dumper").tempdir().unwrap();
+        let mut config = ShardDumpConfig::for_test(
+            Arc::new(RuntimeStatePartSource(runtime)),
+            ExternalConnection::Filesystem {
+                root_dir: root_dir.path().to_path_buf(),
+                fsync: false,
+            },
+            Some("test0".parse().unwrap()),
+        );
+        config.max_parts_per_iteration = Some(1);
+        let mut dumper = ShardDumper::new(0, chain, &config);
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+
+        // None -> InProgress
+        let (epoch_id, epoch_height, sync_hash, num_parts) = match runtime
+            .block_on(dumper.start_next_epoch_dump(&config, None, None, None, None, true))
+            .unwrap()
+        {
+            Some(StateSyncDumpProgress::InProgressV2 {
+                epoch_id,
+                epoch_height,
+                sync_hash,
+                num_parts,
+            }) => (epoch_id, epoch_height, sync_hash, num_parts),
+            progress => panic!("unexpected progress {:?}", progress),
+        };
+        // InProgress -> InProgress, a part at a time.
+        for _ in 1..num_parts {
+            match runtime
+                .block_on(dumper.continue_epoch_dump(
+                    &config,
+                    epoch_id.clone(),
+                    epoch_height,
+                    sync_hash,
+                    num_parts,
+                ))
+                .unwrap()
+            {
+                Some(StateSyncDumpProgress::InProgressV2 {
+                    epoch_height: next_epoch_height,
+                    ..
+                }) => assert_eq!(next_epoch_height, epoch_height),
+                progress => panic!("unexpected progress {:?}", progress),
+            }
+        }
+        // InProgress -> AllDumped
+        let total_bytes = match runtime
+            .block_on(dumper.continue_epoch_dump(
+                &config,
+                epoch_id.clone(),
+                epoch_height,
+                sync_hash,
+                num_parts,
+            ))
+            .unwrap()
+        {
+            Some(StateSyncDumpProgress::AllDumpedV2 {
+                epoch_height: dumped_epoch_height,
+                num_parts: dumped_num_parts,
+                total_bytes,
+                ..
+            }) => {
+                assert_eq!(dumped_epoch_height, epoch_height);
+                assert_eq!(dumped_num_parts, Some(num_parts));
+                total_bytes
+            }
+            progress => panic!("unexpected progress {:?}", progress),
+        };
+        // No newer epoch is complete.
+        assert!(runtime
+            .block_on(dumper.start_next_epoch_dump(
+                &config,
+                Some(epoch_id),
+                Some(epoch_height),
+                Some(num_parts),
+                Some(total_bytes),
+                true,
+            ))
+            .unwrap()
+            .is_none());
+        assert!(dumper.chain.store().get_state_sync_dump_progress(0).unwrap().is_none());
+    }
+
+    /// Serves garbage instead of every part, and counts the obtained parts.
+    #[derive(Default)]
+    struct InvalidPartSource {
+        obtained: AtomicUsize,
+    }
+
+    impl StatePartSource for InvalidPartSource {
+        fn obtain_state_part(
+            &self,
+            _shard_id: ShardId,
+            _prev_hash: &CryptoHash,
+            _state_root: &StateRoot,
+            _part_id: PartId,
+        ) -> Result<Vec<u8>, near_chain::Error> {
+            self.obtained.fetch_add(1, Ordering::Relaxed);
+            Ok(vec![1, 2, 3])
+        }
+    }
+
+    /// A latest epoch that doesn't need a dump doesn't get checked again on
+    /// every iteration of the dump loop.
+    #[test]
+    fn test_rejected_latest_epoch_not_rechecked() {
+        init_test_logger();
+        let mut chain_genesis = ChainGenesis::test();
+        chain_genesis.epoch_length = 5;
+        let mut env = TestEnv::builder(chain_genesis).build();
+        for i in 1..=15 {
+            let block = env.clients[0].produce_block(i).unwrap().unwrap();
+            env.process_block(0, block, Provenance::PRODUCED);
+        }
         let chain = &env.clients[0].chain;
-        let epoch_manager = chain.epoch_manager.clone();
-        let shard_tracker = chain.shard_tracker.clone();
-        let runtime = chain.runtime_adapter.clone();
-        let mut config = env.clients[0].config.clone();
-        let root_dir = tempfile::Builder::new().prefix("state_dump").tempdir().unwrap();
-        config.state_sync.dump = Some(DumpConfig {
-            location: ExternalStorageLocation::Filesystem {
+        let part_source = Arc::new(InvalidPartSource::default());
+        let dyn_part_source: Arc<dyn StatePartSource> = part_source.clone();
+        let account_id = Some("test0".parse().unwrap());
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let check = |rejected_sync_hash: &mut Option<CryptoHash>| {
+            runtime
+                .block_on(get_latest_epoch_to_preempt(
+                    0,
+                    CryptoHash::default(),
+                    0,
+                    chain,
+                    chain.epoch_manager.as_ref(),
+                    &chain.shard_tracker,
+                    &dyn_part_source,
+                    &account_id,
+                    None,
+                    1,
+                    rejected_sync_hash,
+                ))
+                .unwrap()
+        };
+
+        // The probed part of the latest epoch is invalid.
+        let mut rejected_sync_hash = None;
+        assert!(check(&mut rejected_sync_hash).is_none());
+        assert!(rejected_sync_hash.is_some());
+        let obtained = part_source.obtained.load(Ordering::Relaxed);
+        assert!(obtained > 0);
+        assert!(check(&mut rejected_sync_hash).is_none());
+        assert_eq!(part_source.obtained.load(Ordering::Relaxed), obtained);
+    }
+
+    /// Missed epochs are dumped only until the backfill deadline, then the
+    /// dump jumps to the latest epoch.
+    #[test]
+    fn test_shard_dumper_backfill_deadline() {
+        init_test_logger();
+        let mut chain_genesis = ChainGenesis::test();
+        chain_genesis.epoch_length = 5;
+        let mut env = TestEnv::builder(chain_genesis.clone()).build();
+        for i in 1..=25 {
+            let block = env.clients[0].produce_block(i).unwrap().unwrap();
+            env.process_block(0, block, Provenance::PRODUCED);
+        }
+        let client_chain = &env.clients[0].chain;
+        let runtime = client_chain.runtime_adapter.clone();
+        let chain = Chain::new_for_view_client(
+            client_chain.epoch_manager.clone(),
+            client_chain.shard_tracker.clone(),
+            runtime.clone(),
+            &chain_genesis,
+            DoomslugThresholdMode::TwoThirds,
+            false,
+        )
+        .unwrap();
+        let root_dir = tempfile::Builder::new().prefix("shard_dumper").tempdir().unwrap();
+        let config = ShardDumpConfig::for_test(
+            Arc::new(RuntimeStatePartSource(runtime)),
+            ExternalConnection::Filesystem {
                 root_dir: root_dir.path().to_path_buf(),
+                fsync: false,
             },
-            restart_dump_for_shards: None,
-            iteration_delay: Some(Duration::from_millis(250)),
+            Some("test0".parse().unwrap()),
+        );
+        let mut dumper = ShardDumper::new(0, chain, &config);
+
+        let latest_epoch_height = dumper.step(&config).unwrap().epoch_height();
+        assert!(latest_epoch_height >= 3);
+        // Pretend that the dump loop fell behind by two epochs.
+        let fell_behind = StateSyncDumpProgress::Skipped {
+            epoch_id: EpochId::default(),
+            epoch_height: latest_epoch_height - 2,
+        };
+        dumper.chain.store().set_state_sync_dump_progress(0, Some(fell_behind.clone())).unwrap();
+        assert_eq!(
+            count_epochs_to_backfill(0, &dumper.chain, dumper.epoch_manager.as_ref()).unwrap(),
+            1
+        );
+        assert_eq!(dumper.step(&config).unwrap().epoch_height(), latest_epoch_height - 1);
+        assert_eq!(
+            count_epochs_to_backfill(0, &dumper.chain, dumper.epoch_manager.as_ref()).unwrap(),
+            1
+        );
+
+        dumper.chain.store().set_state_sync_dump_progress(0, Some(fell_behind)).unwrap();
+        dumper.backfill_deadline = Some(Instant::now());
+        assert_eq!(dumper.step(&config).unwrap().epoch_height(), latest_epoch_height);
+        assert!(dumper.backfill_stopped);
+    }
+
+    /// Counts the attempts to upload every part, and stops the dump loop once
+    /// every part was attempted and a part gets attempted again.
+    struct CountingPartTransform {
+        num_parts: usize,
+        attempts: Mutex<HashMap<CryptoHash, usize>>,
+        keep_running: Arc<AtomicBool>,
+    }
+
+    impl CountingPartTransform {
+        /// Numbers of attempts of all parts, sorted.
+        fn attempts(&self) -> Vec<usize> {
+            let mut attempts: Vec<usize> =
+                self.attempts.lock().unwrap().values().copied().collect();
+            attempts.sort();
+            attempts
+        }
+    }
+
+    impl PartTransform for CountingPartTransform {
+        fn encode(&self, part: &[u8]) -> Vec<u8> {
+            let mut attempts = self.attempts.lock().unwrap();
+            let part_attempts = {
+                let part_attempts = attempts.entry(hash(part)).or_default();
+                *part_attempts += 1;
+                *part_attempts
+            };
+            if part_attempts > 1 && attempts.len() == self.num_parts {
+                self.keep_running.store(false, Ordering::Relaxed);
+            }
+            part.to_vec()
+        }
+
+        fn decode(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+            Ok(data.to_vec())
+        }
+    }
+
+    /// Parts are drawn without replacement: a part whose upload fails stays
+    /// eligible and gets drawn again, while an uploaded part is never drawn
+    /// again. Only uploaded parts are counted in the metrics. The lease of a
+    /// part is released also if its upload fails.
+    #[test]
+    fn test_shard_dumper_retries_failed_uploads() {
+        init_test_logger();
+        let mut chain_genesis = ChainGenesis::test();
+        chain_genesis.epoch_length = 5;
+        let mut env = TestEnv::builder(chain_genesis.clone()).build();
+        // Dumps a later epoch than the other tests, so that the metrics of the
+        // epoch are updated only by this test.
+        for i in 1..=35 {
+            let block = env.clients[0].produce_block(i).unwrap().unwrap();
+            env.process_block(0, block, Provenance::PRODUCED);
+        }
+        let client_chain = &env.clients[0].chain;
+        let runtime = client_chain.runtime_adapter.clone();
+        let chain = Chain::new_for_view_client(
+            client_chain.epoch_manager.clone(),
+            client_chain.shard_tracker.clone(),
+            runtime.clone(),
+            &chain_genesis,
+            DoomslugThresholdMode::TwoThirds,
+            false,
+        )
+        .unwrap();
+        let root_dir = tempfile::Builder::new().prefix("shard_dumper").tempdir().unwrap();
+        let mut config = ShardDumpConfig::for_test(
+            Arc::new(RuntimeStatePartSource(runtime)),
+            ExternalConnection::Filesystem {
+                root_dir: root_dir.path().to_path_buf(),
+                fsync: false,
+            },
+            Some("test0".parse().unwrap()),
+        );
+        let mut dumper = ShardDumper::new(0, chain, &config);
+
+        let (epoch_id, epoch_height, num_parts) = match dumper.step(&config) {
+            Some(StateSyncDumpProgress::InProgressV2 {
+                epoch_id, epoch_height, num_parts, ..
+            }) => (epoch_id, epoch_height, num_parts),
+            progress => panic!("unexpected progress {:?}", progress),
+        };
+        let part_path = |part_id| {
+            root_dir.path().join(external_storage_location(
+                &DefaultPartNaming,
+                "test",
+                &epoch_id,
+                epoch_height,
+                0,
+                part_id,
+                num_parts,
+                None,
+            ))
+        };
+        let dumped_size = || {
+            crate::metrics::STATE_SYNC_DUMP_SIZE_TOTAL
+                .with_label_values(&[&epoch_height.to_string(), "0"])
+                .get()
+        };
+        let transform = Arc::new(CountingPartTransform {
+            num_parts: num_parts as usize,
+            attempts: Mutex::new(HashMap::new()),
+            keep_running: config.keep_running.clone(),
         });
+        config.part_transform = transform.clone();
+        config.part_lease_ttl = Some(Duration::from_secs(60));
+        // The upload of the part fails while a directory takes the place of
+        // its file.
+        let failing_part_id = 1;
+        std::fs::create_dir_all(part_path(failing_part_id)).unwrap();
+        let size_before = dumped_size();
+        let lease_paths = || {
+            (0..num_parts)
+                .map(|part_id| {
+                    part_path(part_id).with_file_name(part_lease_filename(
+                        &DefaultPartNaming,
+                        part_id,
+                        num_parts,
+                    ))
+                })
+                .filter(|lease_path| lease_path.exists())
+                .collect::<Vec<_>>()
+        };
 
-        const MAX_HEIGHT: BlockHeight = 15;
+        assert!(matches!(dumper.step(&config), Some(StateSyncDumpProgress::InProgressV2 { .. })));
+        let mut expected_attempts = vec![1; num_parts as usize - 1];
+        let failing_part_attempts = *transform.attempts().last().unwrap();
+        assert!(failing_part_attempts >= 2);
+        expected_attempts.push(failing_part_attempts);
+        assert_eq!(transform.attempts(), expected_attempts);
+        let uploaded_size: u64 = (0..num_parts)
+            .filter(|part_id| *part_id != failing_part_id)
+            .map(|part_id| std::fs::metadata(part_path(part_id)).unwrap().len())
+            .sum();
+        assert_eq!(dumped_size() - size_before, uploaded_size);
+        assert_eq!(lease_paths(), Vec::<PathBuf>::new());
 
-        near_actix_test_utils::run_actix(async move {
-            let _state_sync_dump_handle = spawn_state_sync_dump(
-                &config,
-                chain_genesis,
-                epoch_manager.clone(),
-                shard_tracker.clone(),
+        std::fs::remove_dir(part_path(failing_part_id)).unwrap();
+        config.keep_running.store(true, Ordering::Relaxed);
+        assert!(matches!(dumper.step(&config), Some(StateSyncDumpProgress::AllDumpedV2 { .. })));
+        *expected_attempts.last_mut().unwrap() += 1;
+        assert_eq!(transform.attempts(), expected_attempts);
+        let failing_part_size = std::fs::metadata(part_path(failing_part_id)).unwrap().len();
+        assert_eq!(dumped_size() - size_before, uploaded_size + failing_part_size);
+    }
+
+    /// The date partition of an epoch comes from its sync block, so a dump
+    /// continued by another run of the dumper, e.g. after a restart, stores the
+    /// remaining parts under the same date regardless of the clock.
+    #[test]
+    fn test_shard_dumper_date_partition_across_runs() {
+        init_test_logger();
+        let mut chain_genesis = ChainGenesis::test();
+        chain_genesis.epoch_length = 5;
+        let mut env = TestEnv::builder(chain_genesis.clone()).build();
+        for i in 1..=15 {
+            let block = env.clients[0].produce_block(i).unwrap().unwrap();
+            env.process_block(0, block, Provenance::PRODUCED);
+        }
+        let client_chain = &env.clients[0].chain;
+        let runtime = client_chain.runtime_adapter.clone();
+        let new_dumper = |root_dir: &Path| {
+            let chain = Chain::new_for_view_client(
+                client_chain.epoch_manager.clone(),
+                client_chain.shard_tracker.clone(),
                 runtime.clone(),
-                Some("test0".parse().unwrap()),
+                &chain_genesis,
+                DoomslugThresholdMode::TwoThirds,
+                false,
             )
             .unwrap();
-            for i in 1..=MAX_HEIGHT {
-                let block = env.clients[0].produce_block(i as u64).unwrap().unwrap();
-                env.process_block(0, block, Provenance::PRODUCED);
-            }
-            let head = &env.clients[0].chain.head().unwrap();
-            let epoch_id = head.clone().epoch_id;
-            let epoch_info = epoch_manager.get_epoch_info(&epoch_id).unwrap();
-            let epoch_height = epoch_info.epoch_height();
+            let mut config = ShardDumpConfig::for_test(
+                Arc::new(RuntimeStatePartSource(runtime.clone())),
+                ExternalConnection::Filesystem { root_dir: root_dir.to_path_buf(), fsync: false },
+                Some("test0".parse().unwrap()),
+            );
+            config.date_partitioned = true;
+            config.max_parts_per_iteration = Some(1);
+            (ShardDumper::new(0, chain, &config), config)
+        };
+        let root_dir = tempfile::Builder::new().prefix("shard_dumper").tempdir().unwrap();
 
-            wait_or_timeout(100, 10000, || async {
-                let mut all_parts_present = true;
+        let (mut dumper, config) = new_dumper(root_dir.path());
+        let (epoch_id, epoch_height, num_parts, sync_hash) = match dumper.step(&config) {
+            Some(StateSyncDumpProgress::InProgressV2 {
+                epoch_id,
+                epoch_height,
+                num_parts,
+                sync_hash,
+            }) => (epoch_id, epoch_height, num_parts, sync_hash),
+            progress => panic!("unexpected progress {:?}", progress),
+        };
+        dumper.step(&config);
+        let expected_date =
+            date_partition(dumper.chain.get_block_header(&sync_hash).unwrap().raw_timestamp());
+        drop(dumper);
 
-                let num_shards = epoch_manager.num_shards(&epoch_id).unwrap();
-                assert_ne!(num_shards, 0);
+        let (mut dumper, mut config) = new_dumper(root_dir.path());
+        config.max_parts_per_iteration = None;
+        assert!(matches!(dumper.step(&config), Some(StateSyncDumpProgress::AllDumpedV2 { .. })));
+        for part_id in 0..num_parts {
+            let location = external_storage_location(
+                &DefaultPartNaming,
+                "test",
+                &epoch_id,
+                epoch_height,
+                0,
+                part_id,
+                num_parts,
+                Some(&expected_date),
+            );
+            assert!(root_dir.path().join(location).is_file());
+        }
+        // Nothing is stored under another date.
+        let mut dir = root_dir.path().to_path_buf();
+        for component in expected_date.split('/') {
+            let entries: Vec<_> = std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            assert_eq!(entries, vec![component.to_string()]);
+            dir.push(component);
+        }
+    }
 
-                for shard_id in 0..num_shards {
-                    let num_parts = 3;
-                    for part_id in 0..num_parts {
-                        let path = root_dir.path().join(external_storage_location(
-                            "unittest",
-                            &epoch_id,
-                            epoch_height,
-                            shard_id,
-                            part_id,
-                            num_parts,
-                        ));
-                        if std::fs::read(&path).is_err() {
-                            println!("Missing {:?}", path);
-                            all_parts_present = false;
-                        }
-                    }
+    /// With fewer threads than shards, the shards take turns on the threads.
+    #[test]
+    fn test_spawn_shard_dump_threads_round_robin() {
+        init_test_logger();
+        let keep_running = Arc::new(AtomicBool::new(true));
+        let shard_threads = Arc::new(Mutex::new(HashMap::new()));
+        near_actix_test_utils::run_actix(async move {
+            let handles = spawn_shard_dump_threads(
+                &[0, 1, 2, 3, 4],
+                Some(2),
+                &keep_running,
+                |shard_id, arbiter_handle| {
+                    let shard_threads = shard_threads.clone();
+                    assert!(arbiter_handle.spawn(async move {
+                        shard_threads.lock().unwrap().insert(shard_id, std::thread::current().id());
+                    }));
+                    Ok(())
+                },
+            )
+            .unwrap();
+            assert_eq!(handles.len(), 2);
+            wait_or_timeout(10, 5000, || async {
+                if shard_threads.lock().unwrap().len() == 5 {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
                 }
-                if all_parts_present {
+            })
+            .await
+            .unwrap();
+            let shard_threads = shard_threads.lock().unwrap().clone();
+            assert_eq!(shard_threads[&0], shard_threads[&2]);
+            assert_eq!(shard_threads[&0], shard_threads[&4]);
+            assert_eq!(shard_threads[&1], shard_threads[&3]);
+            assert_ne!(shard_threads[&0], shard_threads[&1]);
+            for handle in handles {
+                handle.stop();
+            }
+            actix_rt::System::current().stop();
+        });
+    }
+
+    /// A shard whose thread fails to start fails the whole spawn, and the
+    /// threads of the other shards stop.
+    #[test]
+    fn test_spawn_shard_dump_threads_failure() {
+        init_test_logger();
+        let keep_running = Arc::new(AtomicBool::new(true));
+        let stopped = Arc::new(AtomicBool::new(false));
+        near_actix_test_utils::run_actix(async move {
+            let result = spawn_shard_dump_threads(
+                &[0, 1, 2],
+                None,
+                &keep_running,
+                |shard_id, arbiter_handle| {
+                    if shard_id == 1 {
+                        anyhow::bail!("injected failure to create the chain");
+                    }
+                    assert_eq!(shard_id, 0, "shards after the failed one are not started");
+                    let keep_running = keep_running.clone();
+                    let stopped = stopped.clone();
+                    assert!(arbiter_handle.spawn(async move {
+                        while keep_running.load(Ordering::Relaxed) {
+                            tokio::time::sleep(Duration::from_millis(10)).await;
+                        }
+                        stopped.store(true, Ordering::Relaxed);
+                    }));
+                    Ok(())
+                },
+            );
+            assert!(result.is_err());
+            assert!(!keep_running.load(Ordering::Relaxed));
+            wait_or_timeout(10, 5000, || async {
+                if stopped.load(Ordering::Relaxed) {
                     ControlFlow::Break(())
                 } else {
                     ControlFlow::Continue(())
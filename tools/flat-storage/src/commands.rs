@@ -8,8 +8,9 @@ use near_chain::{
 use near_epoch_manager::{EpochManager, EpochManagerAdapter, EpochManagerHandle};
 use near_primitives::{state::ValueRef, trie_key::trie_key_parsers::parse_account_id_from_raw_key};
 use near_store::flat::{
-    inline_flat_state_values, store_helper, FlatStateDelta, FlatStateDeltaMetadata,
-    FlatStorageManager, FlatStorageStatus,
+    inline_flat_state_values, store_helper, verify_flat_state_value_refs, FlatStateDelta,
+    FlatStateDeltaMetadata, FlatStorageManager, FlatStorageStatus, InliningMigrationOptions,
+    DEFAULT_INLINING_MIGRATION_ID, TOP_UP_INLINING_MIGRATION_ID,
 };
 use near_store::{DBCol, Mode, NodeStorage, ShardUId, Store, StoreOpener};
 use nearcore::{load_config, NearConfig, NightshadeRuntime};
@@ -76,6 +77,88 @@ pub struct MigrateValueInliningCmd {
 
     #[clap(default_value = "50000")]
     batch_size: usize,
+
+    /// Maximum number of values inlined in one commit. Smaller commits pause
+    /// FlatState updates for shorter. Defaults to the batch size.
+    #[clap(long)]
+    commit_batch_size: Option<usize>,
+
+    #[clap(default_value = "1")]
+    scan_threads: usize,
+
+    /// Maximum number of values read from State that are kept in memory at
+    /// once. By default, all values of a batch are buffered.
+    #[clap(long)]
+    max_buffered_values: Option<usize>,
+
+    /// Only check that every value to be inlined can be read from State,
+    /// without modifying the database.
+    #[clap(long, conflicts_with = "verify_first")]
+    verify_only: bool,
+
+    /// Check that every value to be inlined can be read from State, and run
+    /// the migration only if it can.
+    #[clap(long)]
+    verify_first: bool,
+
+    /// Stop the migration if it doesn't finish within this many seconds.
+    /// Progress is checkpointed, so running the migration again resumes it.
+    #[clap(long)]
+    timeout_secs: Option<u64>,
+
+    /// Log the progress of the whole migration every this many seconds.
+    /// 0 disables the progress logs.
+    #[clap(long, default_value = "60")]
+    progress_log_interval_secs: u64,
+
+    /// Runs with different ids keep separate checkpoints, so that they don't
+    /// resume from each other's progress. Defaults to the id of the regular
+    /// migration.
+    #[clap(long, conflicts_with = "top_up")]
+    migration_id: Option<String>,
+
+    /// Inline the values written since an earlier migration finished.
+    /// There is no way to tell which FlatState entries are new, so the whole
+    /// column gets scanned, but already inlined values are skipped cheaply.
+    /// Checkpointed separately from the regular migration.
+    #[clap(long, conflicts_with = "start_from_shard_uid")]
+    top_up: bool,
+
+    /// Sleep this many milliseconds after every batch, to leave CPU to other
+    /// processes on the machine. 0 disables the sleep.
+    #[clap(long, default_value = "0")]
+    inter_batch_sleep_ms: u64,
+
+    /// Inline only the values of this shard, starting from
+    /// `--start-from-key`, e.g. to re-run the migration of a part of a shard.
+    #[clap(long, requires = "start_from_key")]
+    start_from_shard_uid: Option<ShardUId>,
+
+    /// Hex-encoded FlatState key of `--start-from-shard-uid` to start the
+    /// migration from.
+    #[clap(long, requires = "start_from_shard_uid")]
+    start_from_key: Option<String>,
+
+    /// Assume that every value to be inlined can be read from State, and
+    /// read the values in larger batches. Only use it if nothing modified
+    /// the database since it was synced from state parts, or since
+    /// `--verify-only` found all values. If a value is missing or can't be
+    /// read after all, the migration stops with an error, and resumes from
+    /// its last checkpoint when run again.
+    #[clap(long)]
+    trust_refs: bool,
+
+    /// Hex-encoded trie key prefix. If given, only the values whose trie keys
+    /// start with one of the prefixes are inlined, e.g. the values of a
+    /// single account. Can be repeated.
+    #[clap(long, conflicts_with = "start_from_shard_uid")]
+    trie_key_prefix: Vec<String>,
+
+    /// Print the counts of the migration, or of the verification with
+    /// `--verify-only`, as JSON instead of text. The counts of the migration
+    /// include a histogram of value sizes and the version of their schema.
+    #[clap(long)]
+    json: bool,
 }
 
 fn print_delta(store: &Store, shard_uid: ShardUId, metadata: FlatStateDeltaMetadata) {
@@ -321,13 +404,87 @@ impl FlatStorageCommand {
                     near_store::Mode::ReadWriteExisting,
                 )
                 .4;
+                if cmd.verify_only || cmd.verify_first {
+                    let verification =
+                        verify_flat_state_value_refs(&store, cmd.num_threads, cmd.batch_size);
+                    if cmd.json {
+                        // Only the counts of the migration are printed after
+                        // a verification with `--verify-first`.
+                        if cmd.verify_only {
+                            println!("{}", serde_json::to_string_pretty(&verification)?);
+                        }
+                    } else {
+                        println!(
+                            "Resolved {} value references, failed to resolve {}",
+                            verification.resolved, verification.unresolved
+                        );
+                    }
+                    if cmd.verify_only {
+                        return Ok(());
+                    }
+                    if verification.unresolved > 0 {
+                        anyhow::bail!("Some FlatState values can't be read from State, not running the migration");
+                    }
+                }
+                let start_from = match (cmd.start_from_shard_uid, &cmd.start_from_key) {
+                    (Some(shard_uid), Some(key)) => Some((shard_uid, hex::decode(key)?)),
+                    _ => None,
+                };
+                let trie_key_prefixes = if cmd.trie_key_prefix.is_empty() {
+                    None
+                } else {
+                    Some(
+                        cmd.trie_key_prefix
+                            .iter()
+                            .map(hex::decode)
+                            .collect::<Result<Vec<_>, _>>()?,
+                    )
+                };
                 let flat_storage_manager = FlatStorageManager::new(store.clone());
-                inline_flat_state_values(
-                    store,
-                    &flat_storage_manager,
-                    cmd.num_threads,
-                    cmd.batch_size,
-                );
+                let options = InliningMigrationOptions {
+                    migration_id: if cmd.top_up {
+                        TOP_UP_INLINING_MIGRATION_ID
+                    } else {
+                        cmd.migration_id.as_deref().unwrap_or(DEFAULT_INLINING_MIGRATION_ID)
+                    }
+                    .to_string(),
+                    read_state_threads: cmd.num_threads,
+                    scan_batch_size: cmd.batch_size,
+                    commit_batch_size: cmd.commit_batch_size,
+                    max_buffered_values: cmd.max_buffered_values,
+                    scan_threads: cmd.scan_threads,
+                    // The database is opened exclusively by this tool.
+                    offline: true,
+                    timeout: cmd.timeout_secs.map(std::time::Duration::from_secs),
+                    progress_log_interval: (cmd.progress_log_interval_secs > 0)
+                        .then(|| std::time::Duration::from_secs(cmd.progress_log_interval_secs)),
+                    inter_batch_sleep: std::time::Duration::from_millis(cmd.inter_batch_sleep_ms),
+                    start_from,
+                    trust_refs: cmd.trust_refs,
+                    trie_key_prefixes,
+                };
+                let stats = inline_flat_state_values(store, &flat_storage_manager, &options)?;
+                if cmd.json {
+                    println!("{}", serde_json::to_string_pretty(&stats)?);
+                } else {
+                    println!(
+                        "Processed {} values of {} bytes in {:.1}s, inlined {} values of {} bytes, {} values are too large to be inlined",
+                        stats.processed_count,
+                        stats.processed_values_size,
+                        stats.elapsed_secs,
+                        stats.inlined_count,
+                        stats.inlined_values_size,
+                        stats.too_large_count
+                    );
+                    for bucket in stats.value_size_histogram.iter().filter(|b| b.count > 0) {
+                        match bucket.max_size {
+                            Some(max_size) => {
+                                println!("  up to {} bytes: {} values", max_size, bucket.count)
+                            }
+                            None => println!("  larger: {} values", bucket.count),
+                        }
+                    }
+                }
             }
         }
 
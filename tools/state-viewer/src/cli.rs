@@ -496,9 +496,9 @@ impl StateChangesCmd {
 
 #[derive(clap::Parser)]
 pub struct StatePartsCmd {
-    /// Shard id.
+    /// Shard id. Required by all subcommands except `ls`.
     #[clap(long)]
-    shard_id: ShardId,
+    shard_id: Option<ShardId>,
     /// Location of serialized state parts.
     #[clap(long)]
     root_dir: Option<PathBuf>,
@@ -508,7 +508,7 @@ pub struct StatePartsCmd {
     /// Store state parts in an S3 bucket.
     #[clap(long)]
     s3_region: Option<String>,
-    /// Dump or Apply state parts.
+    /// Dump, apply or list state parts.
     #[clap(subcommand)]
     command: crate::state_parts::StatePartsSubCommand,
 }
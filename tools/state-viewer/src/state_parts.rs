@@ -2,7 +2,9 @@ use crate::epoch_info::iterate_and_filter;
 use borsh::BorshDeserialize;
 use near_chain::{Chain, ChainGenesis, ChainStoreAccess, DoomslugThresholdMode};
 use near_client::sync::state::{
-    get_num_parts_from_filename, is_part_filename, location_prefix, part_filename, StateSync,
+    external_storage_location_directory, get_num_parts_from_filename, is_part_filename,
+    location_prefix, parse_location_component, parse_state_part_pointer, part_filename,
+    DefaultPartNaming, ExternalConnection, IdentityPartTransform, StateSync,
 };
 use near_epoch_manager::shard_tracker::{ShardTracker, TrackedConfig};
 use near_epoch_manager::EpochManager;
@@ -14,13 +16,17 @@ use near_primitives::syncing::get_num_state_parts;
 use near_primitives::types::{EpochId, StateRoot};
 use near_primitives_core::hash::CryptoHash;
 use near_primitives_core::types::{BlockHeight, EpochHeight, ShardId};
-use near_store::{PartialStorage, Store, Trie};
+use near_store::{Mode, NodeStorage, PartialStorage, Store, StoreConfig, Trie};
+use nearcore::state_sync::{
+    compare_local_state_parts, repair_epoch_dump, verify_epoch_dump, RuntimeStatePartSource,
+};
 use nearcore::{NearConfig, NightshadeRuntime};
 use s3::serde_types::ListBucketResult;
 use std::fs::DirEntry;
 use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Instant;
 
 #[derive(clap::ValueEnum, Clone, Debug, Default)]
@@ -58,9 +64,17 @@ pub(crate) enum StatePartsSubCommand {
         /// Dump part ids up to this part (exclusive).
         #[clap(long)]
         part_to: Option<u64>,
+        /// Read the state from a copy of a node's DB at this path instead of
+        /// the node's own DB. The copy is opened read-only.
+        #[clap(long)]
+        snapshot_path: Option<PathBuf>,
+        /// Dump the state as of this sync hash, i.e. the first block of an
+        /// epoch. Use instead of selecting an epoch.
+        #[clap(long)]
+        sync_hash: Option<String>,
         /// Select an epoch to work on.
         #[clap(subcommand)]
-        epoch_selection: EpochSelection,
+        epoch_selection: Option<EpochSelection>,
     },
     /// Read State Header from the DB
     ReadStateHeader {
@@ -68,12 +82,60 @@ pub(crate) enum StatePartsSubCommand {
         #[clap(subcommand)]
         epoch_selection: EpochSelection,
     },
+    /// List epochs and shards dumped to the external storage, and how many
+    /// of their state parts are present.
+    /// Lists all shards unless --shard-id is given.
+    Ls {
+        /// Print the listing as JSON instead of a table.
+        #[clap(long)]
+        json: bool,
+    },
+    /// Check that every state part of a shard of an epoch is present in the
+    /// external storage and matches the state root.
+    /// Exits with an error if a part is missing or corrupt.
+    Verify {
+        /// Print the report as JSON.
+        #[clap(long)]
+        json: bool,
+        /// Select an epoch to work on.
+        #[clap(subcommand)]
+        epoch_selection: EpochSelection,
+    },
+    /// Dump again only the state parts of a shard of a completely dumped epoch
+    /// that are missing from the external storage or corrupt, see `verify`.
+    /// Exits with an error if a part fails to be dumped again.
+    Repair {
+        /// Print the report as JSON.
+        #[clap(long)]
+        json: bool,
+        /// Select an epoch to work on.
+        #[clap(subcommand)]
+        epoch_selection: EpochSelection,
+    },
+    /// Compare the state parts of a shard stored in the DB with the parts in
+    /// the external storage, to find parts that were changed after they were
+    /// obtained. Parts that aren't stored in the DB are not checked.
+    /// Exits with an error if a part is missing or different.
+    CompareLocal {
+        /// Compare the parts of the state as of this sync hash, i.e. the first
+        /// block of an epoch.
+        #[clap(long)]
+        sync_hash: String,
+        /// Read the parts from the scratch DB of the dump at this path, see
+        /// `state_parts_scratch_dir` of the dump config, instead of the
+        /// node's own DB.
+        #[clap(long)]
+        scratch_dir: Option<PathBuf>,
+        /// Print the report as JSON.
+        #[clap(long)]
+        json: bool,
+    },
 }
 
 impl StatePartsSubCommand {
     pub(crate) fn run(
         self,
-        shard_id: ShardId,
+        shard_id: Option<ShardId>,
         root_dir: Option<PathBuf>,
         s3_bucket: Option<String>,
         s3_region: Option<String>,
@@ -81,6 +143,23 @@ impl StatePartsSubCommand {
         near_config: NearConfig,
         store: Store,
     ) {
+        let chain_id = &near_config.genesis.config.chain_id;
+        if let StatePartsSubCommand::Ls { json } = self {
+            list_dumped_state_parts(
+                shard_id,
+                chain_id,
+                Location::new(root_dir, (s3_bucket, s3_region)),
+                json,
+            );
+            return;
+        }
+        let shard_id = shard_id.expect("--shard-id is required");
+        let store = match &self {
+            StatePartsSubCommand::Dump { snapshot_path: Some(snapshot_path), .. } => {
+                open_snapshot_store(snapshot_path, &near_config)
+            }
+            _ => store,
+        };
         let epoch_manager =
             EpochManager::new_arc_handle(store.clone(), &near_config.genesis.config);
         let shard_tracker = ShardTracker::new(
@@ -103,7 +182,6 @@ impl StatePartsSubCommand {
             false,
         )
         .unwrap();
-        let chain_id = &near_config.genesis.config.chain_id;
         match self {
             StatePartsSubCommand::Load { action, state_root, part_id, epoch_selection } => {
                 load_state_parts(
@@ -118,9 +196,16 @@ impl StatePartsSubCommand {
                     Location::new(root_dir, (s3_bucket, s3_region)),
                 );
             }
-            StatePartsSubCommand::Dump { part_from, part_to, epoch_selection } => {
+            StatePartsSubCommand::Dump {
+                part_from,
+                part_to,
+                snapshot_path: _,
+                sync_hash,
+                epoch_selection,
+            } => {
                 dump_state_parts(
                     epoch_selection,
+                    sync_hash.map(|sync_hash| CryptoHash::from_str(&sync_hash).unwrap()),
                     shard_id,
                     part_from,
                     part_to,
@@ -133,6 +218,37 @@ impl StatePartsSubCommand {
             StatePartsSubCommand::ReadStateHeader { epoch_selection } => {
                 read_state_header(epoch_selection, shard_id, &chain, store)
             }
+            StatePartsSubCommand::Verify { json, epoch_selection } => verify_dumped_state_parts(
+                epoch_selection,
+                shard_id,
+                &chain,
+                chain_id,
+                store,
+                Location::new(root_dir, (s3_bucket, s3_region)),
+                json,
+            ),
+            StatePartsSubCommand::Repair { json, epoch_selection } => repair_dumped_state_parts(
+                epoch_selection,
+                shard_id,
+                &chain,
+                chain_id,
+                store,
+                Location::new(root_dir, (s3_bucket, s3_region)),
+                json,
+            ),
+            StatePartsSubCommand::CompareLocal { sync_hash, scratch_dir, json } => {
+                compare_local_state_parts_with_dump(
+                    CryptoHash::from_str(&sync_hash).unwrap(),
+                    shard_id,
+                    scratch_dir,
+                    &chain,
+                    chain_id,
+                    store,
+                    Location::new(root_dir, (s3_bucket, s3_region)),
+                    json,
+                )
+            }
+            StatePartsSubCommand::Ls { .. } => unreachable!(),
         }
     }
 }
@@ -210,6 +326,21 @@ impl Location {
             Location::S3 { bucket: s3_bucket.unwrap(), region: s3_region.unwrap() }
         }
     }
+
+    fn into_external_connection(self) -> ExternalConnection {
+        match self {
+            Location::Files(root_dir) => ExternalConnection::Filesystem { root_dir, fsync: false },
+            Location::S3 { bucket, region } => {
+                let bucket = s3::Bucket::new(
+                    &bucket,
+                    region.parse::<s3::Region>().unwrap(),
+                    s3::creds::Credentials::default().unwrap(),
+                )
+                .unwrap();
+                ExternalConnection::S3 { bucket: Arc::new(bucket) }
+            }
+        }
+    }
 }
 
 /// Returns block hash of some block of the given `epoch_info` epoch.
@@ -337,8 +468,21 @@ fn print_state_part(state_root: &StateRoot, _part_id: PartId, data: &[u8]) {
     trie.print_recursive(&mut std::io::stdout().lock(), &state_root, u32::MAX);
 }
 
+/// Opens a copy of a node's DB, e.g. one made by another operator, without
+/// modifying it, so that dumping from it gives the same parts every time.
+fn open_snapshot_store(snapshot_path: &Path, near_config: &NearConfig) -> Store {
+    let store_config =
+        StoreConfig { path: Some(snapshot_path.to_path_buf()), ..near_config.config.store.clone() };
+    tracing::info!(target: "state-parts", ?snapshot_path, "Opening a DB snapshot read-only");
+    NodeStorage::opener(snapshot_path, near_config.config.archive, &store_config, None)
+        .open_in_mode(Mode::ReadOnly)
+        .unwrap()
+        .get_hot_store()
+}
+
 fn dump_state_parts(
-    epoch_selection: EpochSelection,
+    epoch_selection: Option<EpochSelection>,
+    sync_hash: Option<CryptoHash>,
     shard_id: ShardId,
     part_from: Option<u64>,
     part_to: Option<u64>,
@@ -347,10 +491,25 @@ fn dump_state_parts(
     store: Store,
     location: Location,
 ) {
-    let epoch_id = epoch_selection.to_epoch_id(store, chain);
+    let sync_hash = match (epoch_selection, sync_hash) {
+        (Some(epoch_selection), None) => {
+            let epoch_id = epoch_selection.to_epoch_id(store, chain);
+            let epoch = chain.epoch_manager.get_epoch_info(&epoch_id).unwrap();
+            let sync_hash = get_any_block_hash_of_epoch(&epoch, chain);
+            StateSync::get_epoch_start_sync_hash(chain, &sync_hash).unwrap()
+        }
+        (None, Some(sync_hash)) => {
+            assert_eq!(
+                StateSync::get_epoch_start_sync_hash(chain, &sync_hash).unwrap(),
+                sync_hash,
+                "--sync-hash must be the first block of an epoch"
+            );
+            sync_hash
+        }
+        _ => panic!("Exactly one of --sync-hash and an epoch selection needs to be set"),
+    };
+    let epoch_id = chain.epoch_manager.get_epoch_id(&sync_hash).unwrap();
     let epoch = chain.epoch_manager.get_epoch_info(&epoch_id).unwrap();
-    let sync_hash = get_any_block_hash_of_epoch(&epoch, chain);
-    let sync_hash = StateSync::get_epoch_start_sync_hash(chain, &sync_hash).unwrap();
     let sync_block = chain.get_block_header(&sync_hash).unwrap();
     let sync_prev_hash = sync_block.prev_hash();
 
@@ -431,6 +590,293 @@ fn read_state_header(
     tracing::info!(target: "state-parts", ?epoch_id, ?sync_hash, ?state_header);
 }
 
+/// State parts of a shard of an epoch found in the external storage.
+#[derive(serde::Serialize)]
+struct DumpedShard {
+    epoch_height: EpochHeight,
+    epoch_id: CryptoHash,
+    shard_id: ShardId,
+    /// Number of state parts found.
+    parts_present: u64,
+    /// Total number of parts as recorded in the filenames of the parts.
+    /// `None` if no parts were found.
+    num_parts: Option<u64>,
+    /// Whether all parts of the shard are present.
+    complete: bool,
+}
+
+/// Prints which epochs and shards are dumped to the external storage.
+fn list_dumped_state_parts(
+    shard_id: Option<ShardId>,
+    chain_id: &str,
+    location: Location,
+    json: bool,
+) {
+    let external = location.into_external_connection();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let dumped_shards =
+        runtime.block_on(list_dumped_shards(&external, chain_id, shard_id)).unwrap();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&dumped_shards).unwrap());
+        return;
+    }
+    println!(
+        "{:>12}  {:<44}  {:>8}  {:>15}  {}",
+        "epoch_height", "epoch_id", "shard_id", "parts", "complete"
+    );
+    for dumped_shard in &dumped_shards {
+        let num_parts = dumped_shard.num_parts.map_or("?".to_string(), |n| n.to_string());
+        let parts = format!("{}/{}", dumped_shard.parts_present, num_parts);
+        println!(
+            "{:>12}  {:<44}  {:>8}  {:>15}  {}",
+            dumped_shard.epoch_height,
+            dumped_shard.epoch_id.to_string(),
+            dumped_shard.shard_id,
+            parts,
+            if dumped_shard.complete { "yes" } else { "no" },
+        );
+    }
+}
+
+/// Checks the state parts of the epoch dumped to the external storage against
+/// the state root of the shard.
+fn verify_dumped_state_parts(
+    epoch_selection: EpochSelection,
+    shard_id: ShardId,
+    chain: &Chain,
+    chain_id: &str,
+    store: Store,
+    location: Location,
+    json: bool,
+) {
+    let epoch_id = epoch_selection.to_epoch_id(store, chain);
+    let epoch = chain.epoch_manager.get_epoch_info(&epoch_id).unwrap();
+    let sync_hash = get_any_block_hash_of_epoch(&epoch, chain);
+    let sync_hash = StateSync::get_epoch_start_sync_hash(chain, &sync_hash).unwrap();
+    let state_header = chain.compute_state_response_header(shard_id, sync_hash).unwrap();
+    let state_root = state_header.chunk_prev_state_root();
+    let num_parts = get_num_state_parts(state_header.state_root_node().memory_usage);
+    tracing::info!(
+        target: "state-parts",
+        epoch_height = epoch.epoch_height(),
+        epoch_id = ?epoch_id.0,
+        shard_id,
+        num_parts,
+        ?sync_hash,
+        ?state_root,
+        "Verifying dumped state parts.",
+    );
+
+    let external = location.into_external_connection();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let report = runtime
+        .block_on(verify_epoch_dump(
+            shard_id,
+            chain_id,
+            None,
+            &epoch_id,
+            epoch.epoch_height(),
+            &state_root,
+            num_parts,
+            &DefaultPartNaming,
+            &IdentityPartTransform,
+            &external,
+        ))
+        .unwrap();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        println!("ok: {}/{}", report.ok, report.total);
+        println!("missing: {:?}", report.missing);
+        println!("corrupt: {:?}", report.corrupt);
+    }
+    if !report.is_complete() {
+        std::process::exit(1);
+    }
+}
+
+fn repair_dumped_state_parts(
+    epoch_selection: EpochSelection,
+    shard_id: ShardId,
+    chain: &Chain,
+    chain_id: &str,
+    store: Store,
+    location: Location,
+    json: bool,
+) {
+    let epoch_id = epoch_selection.to_epoch_id(store, chain);
+    let epoch = chain.epoch_manager.get_epoch_info(&epoch_id).unwrap();
+    let sync_hash = get_any_block_hash_of_epoch(&epoch, chain);
+    let sync_hash = StateSync::get_epoch_start_sync_hash(chain, &sync_hash).unwrap();
+    let sync_prev_hash = *chain.get_block_header(&sync_hash).unwrap().prev_hash();
+    let state_header = chain.compute_state_response_header(shard_id, sync_hash).unwrap();
+    let state_root = state_header.chunk_prev_state_root();
+    let num_parts = get_num_state_parts(state_header.state_root_node().memory_usage);
+    tracing::info!(
+        target: "state-parts",
+        epoch_height = epoch.epoch_height(),
+        epoch_id = ?epoch_id.0,
+        shard_id,
+        num_parts,
+        ?sync_hash,
+        ?state_root,
+        "Repairing dumped state parts.",
+    );
+
+    let external = location.into_external_connection();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let report = runtime
+        .block_on(repair_epoch_dump(
+            &RuntimeStatePartSource(chain.runtime_adapter.clone()),
+            shard_id,
+            chain_id,
+            None,
+            &epoch_id,
+            epoch.epoch_height(),
+            &sync_prev_hash,
+            &state_root,
+            num_parts,
+            &DefaultPartNaming,
+            &IdentityPartTransform,
+            &external,
+        ))
+        .unwrap();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        println!("ok: {}/{}", report.ok, report.total);
+        println!("repaired: {:?}", report.repaired);
+        println!("failed: {:?}", report.failed);
+    }
+    if !report.is_complete() {
+        std::process::exit(1);
+    }
+}
+
+fn compare_local_state_parts_with_dump(
+    sync_hash: CryptoHash,
+    shard_id: ShardId,
+    scratch_dir: Option<PathBuf>,
+    chain: &Chain,
+    chain_id: &str,
+    store: Store,
+    location: Location,
+    json: bool,
+) {
+    let sync_header = chain.get_block_header(&sync_hash).unwrap();
+    let epoch_id = sync_header.epoch_id().clone();
+    let epoch_height = chain.epoch_manager.get_epoch_info(&epoch_id).unwrap().epoch_height();
+    let state_header = chain.compute_state_response_header(shard_id, sync_hash).unwrap();
+    let num_parts = get_num_state_parts(state_header.state_root_node().memory_usage);
+    let store = match scratch_dir {
+        Some(scratch_dir) => {
+            NodeStorage::opener(&scratch_dir, false, &StoreConfig::default(), None)
+                .open_in_mode(Mode::ReadOnly)
+                .unwrap()
+                .get_hot_store()
+        }
+        None => store,
+    };
+    tracing::info!(
+        target: "state-parts",
+        epoch_height,
+        epoch_id = ?epoch_id.0,
+        shard_id,
+        num_parts,
+        ?sync_hash,
+        "Comparing state parts in the DB with dumped state parts.",
+    );
+
+    let external = location.into_external_connection();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let comparison = runtime
+        .block_on(compare_local_state_parts(
+            &store,
+            sync_hash,
+            shard_id,
+            chain_id,
+            None,
+            &epoch_id,
+            epoch_height,
+            num_parts,
+            &DefaultPartNaming,
+            &IdentityPartTransform,
+            &external,
+        ))
+        .unwrap();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&comparison).unwrap());
+    } else {
+        println!("matching: {}/{}", comparison.matching, comparison.total);
+        println!("missing: {:?}", comparison.missing);
+        println!("mismatched: {:?}", comparison.mismatched);
+    }
+    if !comparison.is_consistent() {
+        std::process::exit(1);
+    }
+}
+
+/// Walks the `chain_id=../epoch_height=../epoch_id=../shard_id=..` directory
+/// structure and counts state parts of every shard.
+/// Directories that don't follow the structure are ignored.
+async fn list_dumped_shards(
+    external: &ExternalConnection,
+    chain_id: &str,
+    shard_id_filter: Option<ShardId>,
+) -> anyhow::Result<Vec<DumpedShard>> {
+    let mut dumped_shards = vec![];
+    let chain_directory = format!("chain_id={}", chain_id);
+    for epoch_height_name in external.list_directories(&chain_directory).await? {
+        let epoch_height = match parse_location_component(&epoch_height_name, "epoch_height") {
+            Some(epoch_height) => epoch_height,
+            None => continue,
+        };
+        let epoch_height_directory = format!("{}/{}", chain_directory, epoch_height_name);
+        for epoch_id_name in external.list_directories(&epoch_height_directory).await? {
+            let epoch_id = match parse_location_component(&epoch_id_name, "epoch_id") {
+                Some(epoch_id) => EpochId(epoch_id),
+                None => continue,
+            };
+            let epoch_id_directory = format!("{}/{}", epoch_height_directory, epoch_id_name);
+            for shard_id_name in external.list_directories(&epoch_id_directory).await? {
+                let shard_id = match parse_location_component(&shard_id_name, "shard_id") {
+                    Some(shard_id) => shard_id,
+                    None => continue,
+                };
+                if shard_id_filter.map_or(false, |filter| filter != shard_id) {
+                    continue;
+                }
+                let directory_path = external_storage_location_directory(
+                    chain_id,
+                    &epoch_id,
+                    epoch_height,
+                    shard_id,
+                    None,
+                );
+                let mut parts_present = 0;
+                let mut num_parts = None;
+                for part in external.list_state_parts(shard_id, &directory_path).await? {
+                    if is_part_filename(&DefaultPartNaming, &part.file_name) {
+                        parts_present += 1;
+                        num_parts = num_parts
+                            .or(get_num_parts_from_filename(&DefaultPartNaming, &part.file_name));
+                    }
+                }
+                dumped_shards.push(DumpedShard {
+                    epoch_height,
+                    epoch_id: epoch_id.0,
+                    shard_id,
+                    parts_present,
+                    num_parts,
+                    complete: num_parts == Some(parts_present),
+                });
+            }
+        }
+    }
+    dumped_shards.sort_by_key(|dumped_shard| (dumped_shard.epoch_height, dumped_shard.shard_id));
+    Ok(dumped_shards)
+}
+
 fn get_part_ids(part_from: Option<u64>, part_to: Option<u64>, num_parts: u64) -> Range<u64> {
     part_from.unwrap_or(0)..part_to.unwrap_or(num_parts)
 }
@@ -489,6 +935,7 @@ fn get_state_part_writer(
 }
 
 struct FileSystemStorage {
+    root_dir: PathBuf,
     state_parts_dir: PathBuf,
 }
 
@@ -501,14 +948,14 @@ impl FileSystemStorage {
         epoch_height: u64,
         shard_id: u64,
     ) -> Self {
-        let prefix = location_prefix(chain_id, epoch_height, epoch_id, shard_id);
+        let prefix = location_prefix(chain_id, epoch_height, epoch_id, shard_id, None);
         let state_parts_dir = root_dir.join(&prefix);
         if create_dir {
             tracing::info!(target: "state-parts", ?root_dir, ?prefix, ?state_parts_dir, "Ensuring the directory exists");
             std::fs::create_dir_all(&state_parts_dir).unwrap();
         }
         tracing::info!(target: "state-parts", ?state_parts_dir, "Initialized FileSystemStorage");
-        Self { state_parts_dir }
+        Self { root_dir, state_parts_dir }
     }
 
     fn get_location(&self, part_id: u64, num_parts: u64) -> PathBuf {
@@ -528,7 +975,14 @@ impl StatePartReader for FileSystemStorage {
     fn read(&self, part_id: u64, num_parts: u64) -> Vec<u8> {
         let filename = self.get_location(part_id, num_parts);
         tracing::debug!(target: "state-parts", part_id, num_parts, ?filename, "Reading state part file");
-        std::fs::read(filename).unwrap()
+        let state_part = std::fs::read(filename).unwrap();
+        // Incremental dumps store pointers to identical parts of earlier epochs.
+        if let Some(target_location) = parse_state_part_pointer(&state_part) {
+            let target_filename = self.root_dir.join(target_location);
+            tracing::debug!(target: "state-parts", part_id, ?target_filename, "Followed a state part pointer");
+            return std::fs::read(target_filename).unwrap();
+        }
+        state_part
     }
 
     fn num_parts(&self) -> u64 {
@@ -539,13 +993,14 @@ impl StatePartReader for FileSystemStorage {
                 let full_path = path.as_ref().unwrap();
                 tracing::debug!(target: "state-parts", ?full_path);
                 let filename = full_path.file_name().to_str().unwrap().to_string();
-                if let Some(num_parts) = get_num_parts_from_filename(&filename) {
+                if let Some(num_parts) = get_num_parts_from_filename(&DefaultPartNaming, &filename)
+                {
                     if let Some(known_num_parts) = known_num_parts {
                         assert_eq!(known_num_parts, num_parts);
                     }
                     known_num_parts = Some(num_parts);
                 }
-                is_part_filename(&filename)
+                is_part_filename(&DefaultPartNaming, &filename)
             })
             .collect::<Vec<std::io::Result<DirEntry>>>()
             .len();
@@ -575,7 +1030,7 @@ impl S3Storage {
         epoch_height: u64,
         shard_id: u64,
     ) -> Self {
-        let location = location_prefix(chain_id, epoch_height, epoch_id, shard_id);
+        let location = location_prefix(chain_id, epoch_height, epoch_id, shard_id, None);
         let bucket = s3::Bucket::new(
             s3_bucket,
             s3_region.parse::<s3::Region>().unwrap(),
@@ -606,6 +1061,13 @@ impl StatePartReader for S3Storage {
         let response = self.bucket.get_object_blocking(location.clone()).unwrap();
         tracing::info!(target: "state-parts", part_id, location, response_code = response.status_code(), "Got an object from S3");
         assert_eq!(response.status_code(), 200);
+        // Incremental dumps store pointers to identical parts of earlier epochs.
+        if let Some(target_location) = parse_state_part_pointer(response.bytes()) {
+            let response = self.bucket.get_object_blocking(target_location).unwrap();
+            tracing::info!(target: "state-parts", part_id, target_location, response_code = response.status_code(), "Followed a state part pointer");
+            assert_eq!(response.status_code(), 200);
+            return response.into();
+        }
         response.into()
     }
 
@@ -623,13 +1085,13 @@ impl StatePartReader for S3Storage {
                 let filename = Path::new(&object.key);
                 let filename = filename.file_name().unwrap().to_str().unwrap();
                 tracing::debug!(target: "state-parts", object_key = ?object.key, ?filename);
-                if let Some(num_parts) = get_num_parts_from_filename(filename) {
+                if let Some(num_parts) = get_num_parts_from_filename(&DefaultPartNaming, filename) {
                     if let Some(known_num_parts) = known_num_parts {
                         assert_eq!(known_num_parts, num_parts);
                     }
                     known_num_parts = Some(num_parts);
                 }
-                is_part_filename(filename)
+                is_part_filename(&DefaultPartNaming, filename)
             })
             .collect::<Vec<&s3::serde_types::Object>>()
             .len();
@@ -639,3 +1101,43 @@ impl StatePartReader for S3Storage {
         num_objects as u64
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{FileSystemStorage, StatePartReader, StatePartWriter};
+    use near_client::sync::state::{
+        external_storage_location, state_part_pointer, DefaultPartNaming,
+    };
+    use near_primitives::types::EpochId;
+    use near_primitives_core::hash::hash;
+
+    /// Parts of an incremental dump that point to identical parts of an
+    /// earlier epoch are read from the earlier epoch.
+    #[test]
+    fn test_read_incremental_filesystem_dump() {
+        let root_dir = tempfile::Builder::new().prefix("state_parts").tempdir().unwrap();
+        let epoch_id = |epoch_height: u64| EpochId(hash(&epoch_height.to_le_bytes()));
+        let storage = |epoch_height| {
+            FileSystemStorage::new(
+                root_dir.path().to_path_buf(),
+                true,
+                "test",
+                &epoch_id(epoch_height),
+                epoch_height,
+                0,
+            )
+        };
+        let earlier_dump = storage(1);
+        earlier_dump.write(b"first part", 0, 2);
+        earlier_dump.write(b"second part", 1, 2);
+        let incremental_dump = storage(2);
+        incremental_dump.write(b"changed first part", 0, 2);
+        let earlier_location =
+            external_storage_location(&DefaultPartNaming, "test", &epoch_id(1), 1, 0, 1, 2, None);
+        incremental_dump.write(&state_part_pointer(&earlier_location), 1, 2);
+
+        assert_eq!(incremental_dump.num_parts(), 2);
+        assert_eq!(incremental_dump.read(0, 2), b"changed first part");
+        assert_eq!(incremental_dump.read(1, 2), b"second part");
+    }
+}